@@ -0,0 +1,61 @@
+/**
+ * Rejects motion commands that are too old to act on, eg because a stalled link delayed them, so
+ * that they are not executed as if they were new. Stop commands are always accepted, as stopping
+ * is always safe however late it is.
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct CommandAgeCheck {
+    /** Commands stamped more than this many microseconds before now are stale */
+    pub max_age: u64,
+    pub stale_commands: u32,
+}
+
+impl CommandAgeCheck {
+    pub const fn new(max_age: u64) -> Self {
+        CommandAgeCheck {
+            max_age,
+            stale_commands: 0,
+        }
+    }
+
+    /**
+     * Whether to act on a command sent at `packet_time`, counting it if it is stale. `now` is the
+     * synchronized clock's time, or `None` until it has been synchronized, when there is no way to
+     * tell how old a command is and only stops are accepted. Commands stamped in the future
+     * (small clock sync errors) count as new.
+     */
+    pub fn accept(&mut self, is_stop: bool, packet_time: u64, now: Option<u64>) -> bool {
+        let is_fresh = now.is_some_and(|now| now.saturating_sub(packet_time) <= self.max_age);
+        if !is_stop && !is_fresh {
+            self.stale_commands = self.stale_commands.wrapping_add(1);
+            return false;
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stale_commands_are_rejected() {
+        let mut check = CommandAgeCheck::new(300);
+        let now = Some(1_000);
+        assert!(check.accept(false, 700, now));
+        assert!(!check.accept(false, 699, now));
+        // Stamped a little in the future by clock sync error
+        assert!(check.accept(false, 1_010, now));
+        // Stopping is always accepted
+        assert!(check.accept(true, 0, now));
+        assert_eq!(check.stale_commands, 1);
+    }
+
+    #[test]
+    fn test_only_stops_before_clock_sync() {
+        let mut check = CommandAgeCheck::new(300);
+        assert!(!check.accept(false, 1_000, None));
+        assert!(check.accept(true, 1_000, None));
+        assert_eq!(check.stale_commands, 1);
+    }
+}
@@ -0,0 +1,12 @@
+use core::f32::consts::PI;
+
+pub const WHEEL_CIRCUMFERENCE: f32 = PI * 2.0 * 0.02; // meters
+pub const WHEEL_BASE_WIDTH: f32 = 0.2; // meters
+pub const ENCODER_TICKS_PER_REVOLUTION: f32 = 11.0 * 4.0 * 35.0; // encoder * quadrature * gearbox
+pub const NOMINAL_MAX_RPM: f32 = 120.0; // RPM
+pub const MAX_COMMAND_AGE: u64 = 300_000; // microseconds, older motion commands are ignored
+pub const MAX_WHEEL_ACCELERATION: f32 = 1.0; // m/s^2, wheel speed changes are ramped to this
+
+/** How fast a motor's output (as a fraction of full speed) may change per second */
+pub const MAX_OUTPUT_RATE: f32 =
+    MAX_WHEEL_ACCELERATION / (NOMINAL_MAX_RPM / 60.0 * WHEEL_CIRCUMFERENCE);
@@ -0,0 +1,11 @@
+/*!
+ * How the drive base behaves, shared by the motor controller firmware and the host (its motion
+ * controller and simulated base) so that they can't drift apart. Keep it `no_std`.
+ */
+#![no_std]
+
+pub mod command_age;
+pub mod consts;
+pub mod motor;
+pub mod odometry;
+pub mod slew;
@@ -0,0 +1,35 @@
+use crate::consts::{NOMINAL_MAX_RPM, WHEEL_CIRCUMFERENCE};
+
+/** Motor outputs closer to zero than this stop the motor */
+pub const DEAD_BAND: f32 = 0.01;
+
+/** The motor output, as a fraction of full speed, that turns a wheel at `velocity` (m/s) */
+pub fn velocity_to_speed_percent(velocity: f32) -> f32 {
+    let wheel_rpm = (velocity / WHEEL_CIRCUMFERENCE) * 60.0;
+    wheel_rpm / NOMINAL_MAX_RPM
+}
+
+/** What a motor is driven at for `speed`: no more than full speed, and stopped in the dead band */
+pub fn motor_output(speed: f32) -> f32 {
+    let clamped_speed = speed.clamp(-1.0, 1.0);
+    if clamped_speed < DEAD_BAND && clamped_speed > -DEAD_BAND {
+        0.0
+    } else {
+        clamped_speed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_motor_output() {
+        let full_speed = NOMINAL_MAX_RPM / 60.0 * WHEEL_CIRCUMFERENCE;
+        assert!((velocity_to_speed_percent(full_speed / 2.0) - 0.5).abs() < 1e-6);
+        assert_eq!(motor_output(0.5), 0.5);
+        assert_eq!(motor_output(-2.0), -1.0);
+        assert_eq!(motor_output(0.005), 0.0);
+        assert_eq!(motor_output(-0.005), 0.0);
+    }
+}
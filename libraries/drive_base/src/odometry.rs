@@ -0,0 +1,51 @@
+use libm::{cosf, sinf};
+use topics::OdometryDelta;
+
+use crate::consts::{ENCODER_TICKS_PER_REVOLUTION, WHEEL_BASE_WIDTH, WHEEL_CIRCUMFERENCE};
+
+/**
+ * Adds encoder counts to `odometry`. The travel is in the robot's frame at the start of the delta
+ * (+y is forward, positive rotation is anticlockwise), turned by the rotation so far. The right
+ * encoder counts backwards as the motor is mounted mirrored.
+ */
+pub fn update_odometry(odometry: &mut OdometryDelta, left_count: i64, right_count: i64) {
+    let left_distance = left_count as f32 * WHEEL_CIRCUMFERENCE / ENCODER_TICKS_PER_REVOLUTION;
+    let right_distance = -right_count as f32 * WHEEL_CIRCUMFERENCE / ENCODER_TICKS_PER_REVOLUTION;
+    let delta_distance = (left_distance + right_distance) / 2.0;
+    let delta_theta = (right_distance - left_distance) / WHEEL_BASE_WIDTH;
+
+    odometry.delta_position[0] -= delta_distance * sinf(odometry.delta_orientation);
+    odometry.delta_position[1] += delta_distance * cosf(odometry.delta_orientation);
+    odometry.delta_orientation += delta_theta;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_update_odometry() {
+        let mut odometry = OdometryDelta {
+            start_time: 0,
+            end_time: 0,
+            delta_position: [0.0, 0.0],
+            delta_orientation: 0.0,
+        };
+        let revolution = ENCODER_TICKS_PER_REVOLUTION as i64;
+        update_odometry(&mut odometry, revolution, -revolution);
+        assert!((odometry.delta_position[1] - WHEEL_CIRCUMFERENCE).abs() < 1e-6);
+        assert_eq!(odometry.delta_orientation, 0.0);
+
+        // Turning on the spot, then driving on, goes off in the new direction
+        update_odometry(&mut odometry, -revolution, -revolution);
+        let turn = 2.0 * WHEEL_CIRCUMFERENCE / WHEEL_BASE_WIDTH;
+        assert!((odometry.delta_orientation - turn).abs() < 1e-6);
+        update_odometry(&mut odometry, revolution, -revolution);
+        let expected = [
+            -WHEEL_CIRCUMFERENCE * sinf(turn),
+            WHEEL_CIRCUMFERENCE * (1.0 + cosf(turn)),
+        ];
+        assert!((odometry.delta_position[0] - expected[0]).abs() < 1e-6);
+        assert!((odometry.delta_position[1] - expected[1]).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,38 @@
+/**
+ * Limits how fast a value, eg a motor output, can change so that steps in the command become
+ * ramps. Sudden changes in wheel speed make the wheels slip, and slipping wheels corrupt the
+ * odometry.
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct SlewLimiter {
+    /** Largest change per second */
+    pub rate: f32,
+    pub value: f32,
+}
+
+impl SlewLimiter {
+    pub const fn new(rate: f32) -> Self {
+        SlewLimiter { rate, value: 0.0 }
+    }
+
+    /** Moves towards `target` by no more than `rate * dt` (in seconds), returning the new value */
+    pub fn step(&mut self, target: f32, dt: f32) -> f32 {
+        let max_change = self.rate * dt;
+        self.value += (target - self.value).clamp(-max_change, max_change);
+        self.value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_step_ramps_to_target() {
+        let mut limiter = SlewLimiter::new(2.0);
+        assert_eq!(limiter.step(1.0, 0.25), 0.5);
+        assert_eq!(limiter.step(1.0, 0.25), 1.0);
+        assert_eq!(limiter.step(1.0, 0.25), 1.0);
+        assert_eq!(limiter.step(-1.0, 0.5), 0.0);
+    }
+}
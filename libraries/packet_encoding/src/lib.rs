@@ -1,57 +1,69 @@
 #![no_std]
 
 use cobs::DecodeError;
-use cobs::{CobsEncoder, decode_in_place};
+use cobs::{CobsEncoder, DestBufTooSmallError, decode_in_place};
 use crc16::{ARC, State};
 use heapless::Vec;
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug)]
 pub enum PacketEncodeErr {
-    SerdeError(minicbor_serde::error::EncodeError<minicbor::encode::write::EndOfSlice>),
+    SerdeError(minicbor_serde::error::EncodeError<DestBufTooSmallError>),
     CobsError,
     DestBufTooSmallError,
 }
 
+/** Feeds the CBOR to the CRC and the COBS encoder as it is serialized */
+struct PacketWriter<'a> {
+    crc: State<ARC>,
+    cobs_encoder: CobsEncoder<'a>,
+    full: bool,
+}
+
+impl minicbor::encode::Write for PacketWriter<'_> {
+    type Error = DestBufTooSmallError;
+
+    fn write_all(&mut self, buf: &[u8]) -> Result<(), Self::Error> {
+        self.crc.update(buf);
+        self.cobs_encoder.push(buf).inspect_err(|_| self.full = true)
+    }
+}
+
 /**
  * COBS(
  *     CBOR(MESSAGE)
  *     CRC16(CBOR(MESSAGE))
  * )
+ *
+ * The message can be as large as `encode_buffer` allows.
  */
 pub fn encode_packet(
     message: &impl Serialize,
     encode_buffer: &mut [u8],
 ) -> Result<usize, PacketEncodeErr> {
+    let mut writer = PacketWriter {
+        crc: State::<ARC>::new(),
+        cobs_encoder: CobsEncoder::new(encode_buffer),
+        full: false,
+    };
+
     // CBOR
-    let mut serialize_buffer = [0u8; 500];
-    // TODO: Wish I knew how to get rid of this fixed size buffer without needing alloc
-    // We can get rid of this by using the various streaming/writer API's. The CBOR will need to feed both the CRC and the COBS encoder simultaneously.
-    // We should performance test this!
-
-    // Use a writer that implements Write
-    let initial_len = serialize_buffer.len();
-    let mut writer = &mut serialize_buffer[..];
     let mut serializer = minicbor_serde::Serializer::new(&mut writer);
-    message
-        .serialize(&mut serializer)
-        .map_err(PacketEncodeErr::SerdeError)?;
-    // After serialization, writer points to the remaining slice
-    let size = initial_len - writer.len();
-    let serialized = &serialize_buffer[..size];
+    if let Err(err) = message.serialize(&mut serializer) {
+        return Err(if writer.full {
+            PacketEncodeErr::DestBufTooSmallError
+        } else {
+            PacketEncodeErr::SerdeError(err)
+        });
+    }
 
     // CRC16
-    let crc = State::<ARC>::calculate(serialized);
-
-    // COBS
-    let mut cobs_encoder = CobsEncoder::new(encode_buffer);
-    cobs_encoder
-        .push(serialized)
-        .map_err(|_| PacketEncodeErr::DestBufTooSmallError)?;
-    cobs_encoder
+    let crc = writer.crc.get();
+    writer
+        .cobs_encoder
         .push(&crc.to_le_bytes())
         .map_err(|_| PacketEncodeErr::DestBufTooSmallError)?;
-    let encoded_size = cobs_encoder.finalize();
+    let encoded_size = writer.cobs_encoder.finalize();
     Ok(encoded_size)
 }
 
@@ -86,31 +98,33 @@ pub fn decode_packet<T: for<'a> Deserialize<'a>>(data: &mut [u8]) -> Result<T, P
     Ok(message)
 }
 
-pub struct PacketFinder {
-    buffer: Vec<u8, 512>,
-    max_buffer_size: usize,
+/**
+ * Picks COBS framed packets out of a byte stream. Packets longer than `N` bytes are dropped, so
+ * the firmware can use a small buffer while the host makes room for any packet.
+ */
+pub struct PacketFinder<const N: usize = 512> {
+    buffer: Vec<u8, N>,
 }
 
-impl Default for PacketFinder {
+impl<const N: usize> Default for PacketFinder<N> {
     fn default() -> Self {
-        PacketFinder::new()
+        PacketFinder { buffer: Vec::new() }
     }
 }
 
 impl PacketFinder {
     pub fn new() -> Self {
-        PacketFinder {
-            buffer: Vec::new(),
-            max_buffer_size: 512,
-        }
+        PacketFinder::default()
     }
+}
 
-    pub fn push_byte(&mut self, byte: u8) -> Option<Vec<u8, 512>> {
-        if self.buffer.len() < self.max_buffer_size {
+impl<const N: usize> PacketFinder<N> {
+    pub fn push_byte(&mut self, byte: u8) -> Option<Vec<u8, N>> {
+        if !self.buffer.is_full() {
             if byte == 0x00 {
                 if !self.buffer.is_empty() {
                     // Found a packet
-                    let packet = Vec::<u8, 512>::from_slice(&self.buffer[1..]).unwrap();
+                    let packet = Vec::<u8, N>::from_slice(&self.buffer[1..]).unwrap();
                     self.buffer.clear();
                     self.buffer.push(byte).unwrap();
                     return Some(packet);
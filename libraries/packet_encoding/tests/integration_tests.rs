@@ -30,7 +30,7 @@ fn test_encode_decode_success() {
     assert!(encoded_size > 0);
     assert!(encoded_size <= 100);
 
-    let mut decode_buffer = encode_buffer.clone();
+    let mut decode_buffer = encode_buffer;
     let decoded_message: TestMessage = decode_packet(&mut decode_buffer[..encoded_size]).unwrap();
 
     assert_eq!(message, decoded_message);
@@ -43,7 +43,7 @@ fn test_with_zero_bytes() {
     let mut encode_buffer = [0u8; 50];
     let encoded_size = encode_packet(&message, &mut encode_buffer).unwrap();
 
-    let mut decode_buffer = encode_buffer.clone();
+    let mut decode_buffer = encode_buffer;
     let decoded_message: SimpleMessage = decode_packet(&mut decode_buffer[..encoded_size]).unwrap();
 
     assert_eq!(message, decoded_message);
@@ -61,6 +61,22 @@ fn test_encode_decode_simple_message() {
     assert_eq!(message, decoded_message);
 }
 
+#[test]
+fn test_encode_large_message() {
+    // Only limited by the caller's buffer
+    let message = vec![0x5Au16; 1000];
+    let mut encode_buffer = [0u8; 4000];
+    let encoded_size = encode_packet(&message, &mut encode_buffer).unwrap();
+    assert!(encoded_size > 2000);
+
+    let decoded_message: Vec<u16> = decode_packet(&mut encode_buffer[..encoded_size]).unwrap();
+    assert_eq!(message, decoded_message);
+
+    let mut encode_buffer = [0u8; 1000];
+    let result = encode_packet(&message, &mut encode_buffer);
+    assert!(matches!(result, Err(PacketEncodeErr::DestBufTooSmallError)));
+}
+
 #[test]
 fn test_encode_buffer_too_small() {
     let message = TestMessage {
@@ -100,7 +116,7 @@ fn test_decode_crc_mismatch() {
     // Corrupt the CRC bytes
     encode_buffer[encoded_size - 3] ^= 0xFF;
 
-    let mut decode_buffer = encode_buffer.clone();
+    let mut decode_buffer = encode_buffer;
     let result: Result<SimpleMessage, PacketDecodeErr> =
         decode_packet(&mut decode_buffer[..encoded_size]);
 
@@ -116,7 +132,7 @@ fn test_empty_struct() {
     let mut encode_buffer = [0u8; 50];
     let encoded_size = encode_packet(&message, &mut encode_buffer).unwrap();
 
-    let mut decode_buffer = encode_buffer.clone();
+    let mut decode_buffer = encode_buffer;
     let decoded_message: EmptyStruct = decode_packet(&mut decode_buffer[..encoded_size]).unwrap();
 
     assert_eq!(message, decoded_message);
@@ -151,12 +167,12 @@ fn test_schema_missing_optional_field() {
     let mut encode_buffer = [0u8; 100];
     let encoded_size = encode_packet(&old_message, &mut encode_buffer).unwrap();
 
-    let mut decode_buffer = encode_buffer.clone();
+    let mut decode_buffer = encode_buffer;
     let decoded_message: NewMessage = decode_packet(&mut decode_buffer[..encoded_size]).unwrap();
 
     assert_eq!(decoded_message.id, old_message.id);
     assert_eq!(decoded_message.value, old_message.value);
-    assert_eq!(decoded_message.flag, true); // Default value for missing field
+    assert!(decoded_message.flag); // Default value for missing field
 }
 
 #[test]
@@ -252,7 +268,7 @@ fn test_packet_finder_buffer_overflow() {
     assert!(finder.push_byte(0x00).is_none());
 
     // Fill buffer to near capacity (511 more bytes since we already have one 0x00)
-    for i in 1..512 {
+    for _ in 1..512 {
         let result = finder.push_byte(0x02);
         assert!(
             result.is_none(),
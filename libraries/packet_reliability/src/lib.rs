@@ -0,0 +1,286 @@
+#![no_std]
+
+#[cfg(test)]
+extern crate std;
+
+use core::time::Duration;
+use heapless::{Deque, Vec};
+
+/**
+ * Identifies a packet on a link so that it can be acknowledged. The `from` address is the one
+ * the router assigned to the original sender, and `id` is the sending end of the link's own
+ * counter. Forwarded packets are renumbered, as their senders don't always number them.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PacketKey {
+    pub from: Option<u16>,
+    pub id: u32,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct RetransmitConfig {
+    /** How long to wait for an ACK before the first retransmission. */
+    pub initial_timeout: Duration,
+    /** The timeout doubles after every retransmission, up to this value. */
+    pub max_timeout: Duration,
+    /** Total number of transmissions (including the first) before giving up on a packet. */
+    pub max_attempts: u8,
+}
+
+impl Default for RetransmitConfig {
+    fn default() -> Self {
+        RetransmitConfig {
+            initial_timeout: Duration::from_millis(50),
+            max_timeout: Duration::from_millis(400),
+            max_attempts: 6,
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct PollResult {
+    pub retransmitted: u32,
+    pub expired: u32,
+}
+
+struct Pending<P> {
+    key: PacketKey,
+    topic: &'static str,
+    packet: P,
+    attempts: u8,
+    timeout: u64,
+    next_send_time: u64,
+}
+
+/**
+ * Keeps track of packets that have been sent but not yet acknowledged, and decides when they
+ * need to be retransmitted.
+ *
+ * Only the newest packet of each topic is kept: commands are "latest value wins", so retransmitting
+ * an older command after a newer one has been sent would do more harm than good.
+ *
+ * All times are in microseconds from any monotonic source.
+ */
+pub struct ReliableSender<P, const N: usize> {
+    config: RetransmitConfig,
+    pending: Vec<Pending<P>, N>,
+}
+
+impl<P, const N: usize> ReliableSender<P, N> {
+    pub fn new(config: RetransmitConfig) -> Self {
+        ReliableSender {
+            config,
+            pending: Vec::new(),
+        }
+    }
+
+    /**
+     * Start tracking a packet that has just been transmitted. Any pending packet on the same topic
+     * is superseded. If there is no room left the packet is handed back and will not be retried.
+     */
+    pub fn track(&mut self, key: PacketKey, topic: &'static str, packet: P, now: u64) -> Result<(), P> {
+        self.pending.retain(|pending| pending.topic != topic && pending.key != key);
+        let timeout = self.config.initial_timeout.as_micros() as u64;
+        self.pending
+            .push(Pending {
+                key,
+                topic,
+                packet,
+                attempts: 1,
+                timeout,
+                next_send_time: now.wrapping_add(timeout),
+            })
+            .map_err(|pending| pending.packet)
+    }
+
+    /** Stop tracking a packet. Returns false if the packet was not pending (eg a duplicate ACK). */
+    pub fn acknowledge(&mut self, key: PacketKey) -> bool {
+        let count_before = self.pending.len();
+        self.pending.retain(|pending| pending.key != key);
+        self.pending.len() != count_before
+    }
+
+    /**
+     * Calls `resend` for every packet whose ACK timeout has elapsed, and drops packets that have
+     * used up all their attempts.
+     */
+    pub fn poll(&mut self, now: u64, mut resend: impl FnMut(&P)) -> PollResult {
+        let mut result = PollResult::default();
+        let max_attempts = self.config.max_attempts;
+        let max_timeout = self.config.max_timeout.as_micros() as u64;
+
+        self.pending.retain_mut(|pending| {
+            // Signed comparison so that wrapping clocks still work
+            if (now.wrapping_sub(pending.next_send_time) as i64) < 0 {
+                return true;
+            }
+            if pending.attempts >= max_attempts {
+                result.expired += 1;
+                return false;
+            }
+            resend(&pending.packet);
+            result.retransmitted += 1;
+            pending.attempts += 1;
+            pending.timeout = (pending.timeout * 2).min(max_timeout);
+            pending.next_send_time = now.wrapping_add(pending.timeout);
+            true
+        });
+        result
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
+
+/**
+ * Remembers recently received reliable packets so that retransmissions can be acknowledged again
+ * without being delivered twice. Entries are forgotten after `window` so that a sender that restarts
+ * its message counter is not ignored forever.
+ */
+pub struct DuplicateFilter<const N: usize> {
+    window: u64,
+    recent: Deque<(PacketKey, u64), N>,
+}
+
+impl<const N: usize> DuplicateFilter<N> {
+    pub fn new(window: Duration) -> Self {
+        DuplicateFilter {
+            window: window.as_micros() as u64,
+            recent: Deque::new(),
+        }
+    }
+
+    /** Returns true the first time a key is seen, and false for duplicates. */
+    pub fn accept(&mut self, key: PacketKey, now: u64) -> bool {
+        while let Some((_, seen_time)) = self.recent.front()
+            && now.wrapping_sub(*seen_time) > self.window
+        {
+            self.recent.pop_front();
+        }
+
+        if self.recent.iter().any(|(seen_key, _)| *seen_key == key) {
+            return false;
+        }
+
+        if self.recent.is_full() {
+            self.recent.pop_front();
+        }
+        self.recent.push_back((key, now)).ok();
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MS: u64 = 1000;
+
+    fn key(id: u32) -> PacketKey {
+        PacketKey { from: Some(3), id }
+    }
+
+    fn config() -> RetransmitConfig {
+        RetransmitConfig {
+            initial_timeout: Duration::from_millis(10),
+            max_timeout: Duration::from_millis(40),
+            max_attempts: 4,
+        }
+    }
+
+    #[test]
+    fn test_ack_stops_retransmission() {
+        let mut sender = ReliableSender::<u32, 4>::new(config());
+        sender.track(key(1), "Stop", 100, 0).unwrap();
+        assert!(sender.acknowledge(key(1)));
+        assert!(sender.is_empty());
+
+        let mut resent = 0;
+        let result = sender.poll(100 * MS, |_| resent += 1);
+        assert_eq!(resent, 0);
+        assert_eq!(result, PollResult::default());
+
+        // A second ACK for the same packet is harmless
+        assert!(!sender.acknowledge(key(1)));
+    }
+
+    #[test]
+    fn test_retransmit_with_backoff() {
+        let mut sender = ReliableSender::<u32, 4>::new(config());
+        sender.track(key(1), "Stop", 100, 0).unwrap();
+
+        let mut resend_times = std::vec::Vec::new();
+        for now in (0..200 * MS).step_by(MS as usize) {
+            sender.poll(now, |packet| {
+                assert_eq!(*packet, 100);
+                resend_times.push(now / MS);
+            });
+        }
+        // 10ms, then 20ms later, then 40ms later (capped)
+        assert_eq!(resend_times, [10, 30, 70]);
+        assert!(sender.is_empty());
+    }
+
+    #[test]
+    fn test_expired_packets_are_reported() {
+        let mut sender = ReliableSender::<u32, 4>::new(config());
+        sender.track(key(1), "Stop", 100, 0).unwrap();
+
+        let mut total = PollResult::default();
+        for now in (0..200 * MS).step_by(MS as usize) {
+            let result = sender.poll(now, |_| {});
+            total.retransmitted += result.retransmitted;
+            total.expired += result.expired;
+        }
+        assert_eq!(total.retransmitted, 3);
+        assert_eq!(total.expired, 1);
+    }
+
+    #[test]
+    fn test_newer_packet_supersedes_same_topic() {
+        let mut sender = ReliableSender::<u32, 4>::new(config());
+        sender.track(key(1), "Velocity", 1, 0).unwrap();
+        sender.track(key(2), "Other", 2, 0).unwrap();
+        sender.track(key(3), "Velocity", 3, 0).unwrap();
+        assert_eq!(sender.len(), 2);
+
+        let mut resent = std::vec::Vec::new();
+        sender.poll(10 * MS, |packet| resent.push(*packet));
+        resent.sort();
+        assert_eq!(resent, [2, 3]);
+    }
+
+    #[test]
+    fn test_full_sender_returns_packet() {
+        let mut sender = ReliableSender::<u32, 1>::new(config());
+        sender.track(key(1), "A", 1, 0).unwrap();
+        assert_eq!(sender.track(key(2), "B", 2, 0), Err(2));
+    }
+
+    #[test]
+    fn test_duplicate_filter() {
+        let mut filter = DuplicateFilter::<2>::new(Duration::from_millis(100));
+        assert!(filter.accept(key(1), 0));
+        assert!(!filter.accept(key(1), MS));
+        assert!(filter.accept(PacketKey { from: None, id: 1 }, MS));
+
+        // Forgotten once the window has passed
+        assert!(filter.accept(key(1), 200 * MS));
+    }
+
+    #[test]
+    fn test_duplicate_filter_capacity() {
+        let mut filter = DuplicateFilter::<2>::new(Duration::from_secs(10));
+        assert!(filter.accept(key(1), 0));
+        assert!(filter.accept(key(2), 0));
+        assert!(filter.accept(key(3), 0));
+        // Key 1 was evicted to make room
+        assert!(filter.accept(key(1), 0));
+        assert!(!filter.accept(key(3), 0));
+    }
+}
@@ -2,9 +2,11 @@ use packet_trait::PacketTrait;
 use std::collections::HashSet;
 use std::rc::Rc;
 
+use crate::PriorityLanes;
+
 pub struct Client<T: PacketTrait> {
     pub client_to_router: Vec<T>,
-    pub router_to_client: Vec<Rc<T>>,
+    pub router_to_client: PriorityLanes<Rc<T>>,
     pub subscriptions: HashSet<String>,
 }
 
@@ -12,7 +14,7 @@ impl<T: PacketTrait> Default for Client<T> {
     fn default() -> Self {
         Client::<T> {
             client_to_router: Vec::new(),
-            router_to_client: Vec::new(),
+            router_to_client: PriorityLanes::new(),
             subscriptions: HashSet::new(),
         }
     }
@@ -23,15 +25,22 @@ impl<T: PacketTrait> Client<T> {
     pub fn send(&mut self, packet: T) {
         self.client_to_router.push(packet);
     }
+    /** Fetch all packets waiting for this client, highest priority first. */
     pub fn fetch_all(&mut self) -> Vec<Rc<T>> {
-        self.router_to_client.drain(..).collect()
+        self.router_to_client.drain()
+    }
+    /** Fetch the highest priority packet waiting for this client. */
+    pub fn fetch_next(&mut self) -> Option<Rc<T>> {
+        self.router_to_client.pop()
     }
 
     pub fn fetch_client_to_router(&mut self) -> Vec<T> {
-        self.client_to_router.drain(..).collect()
+        std::mem::take(&mut self.client_to_router)
     }
     pub fn write_router_to_client(&mut self, packets: Vec<Rc<T>>) {
-        self.router_to_client.extend(packets);
+        for packet in packets {
+            self.router_to_client.push(packet.get_priority(), packet);
+        }
     }
     pub fn get_subscriptions(&self) -> &HashSet<String> {
         &self.subscriptions
@@ -0,0 +1,112 @@
+use packet_trait::Priority;
+use std::collections::VecDeque;
+
+/**
+ * A queue with one FIFO lane per priority. Items come out highest priority first, and in arrival
+ * order within a priority.
+ *
+ * Optionally each lane can be limited in length, in which case the oldest item in a full lane is
+ * dropped to make room. This stops a slow link from buffering an unbounded backlog of stale data.
+ */
+pub struct PriorityLanes<T> {
+    lanes: [VecDeque<T>; 3],
+    lane_limit: Option<usize>,
+    dropped: u32,
+}
+
+impl<T> Default for PriorityLanes<T> {
+    fn default() -> Self {
+        PriorityLanes::new()
+    }
+}
+
+impl<T> PriorityLanes<T> {
+    pub fn new() -> Self {
+        PriorityLanes {
+            lanes: [VecDeque::new(), VecDeque::new(), VecDeque::new()],
+            lane_limit: None,
+            dropped: 0,
+        }
+    }
+
+    pub fn set_lane_limit(&mut self, limit: Option<usize>) {
+        self.lane_limit = limit;
+    }
+
+    fn lane_mut(&mut self, priority: Priority) -> &mut VecDeque<T> {
+        &mut self.lanes[priority as usize]
+    }
+
+    pub fn push(&mut self, priority: Priority, item: T) {
+        let limit = self.lane_limit;
+        let lane = self.lane_mut(priority);
+        if let Some(limit) = limit
+            && lane.len() >= limit
+        {
+            lane.pop_front();
+            self.dropped = self.dropped.wrapping_add(1);
+        }
+        self.lane_mut(priority).push_back(item);
+    }
+
+    pub fn pop(&mut self) -> Option<T> {
+        Priority::ALL
+            .iter()
+            .find_map(|priority| self.lane_mut(*priority).pop_front())
+    }
+
+    /** Removes all items, highest priority first. */
+    pub fn drain(&mut self) -> Vec<T> {
+        let mut items = Vec::with_capacity(self.len());
+        for priority in Priority::ALL {
+            items.extend(self.lane_mut(priority).drain(..));
+        }
+        items
+    }
+
+    pub fn len(&self) -> usize {
+        self.lanes.iter().map(|lane| lane.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lanes.iter().all(|lane| lane.is_empty())
+    }
+
+    /** How many items have been dropped because their lane was full. */
+    pub fn dropped(&self) -> u32 {
+        self.dropped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_highest_priority_first() {
+        let mut lanes = PriorityLanes::new();
+        lanes.push(Priority::Low, "diag1");
+        lanes.push(Priority::Normal, "odom");
+        lanes.push(Priority::Low, "diag2");
+        lanes.push(Priority::High, "stop");
+
+        assert_eq!(lanes.len(), 4);
+        assert_eq!(lanes.pop(), Some("stop"));
+        assert_eq!(lanes.drain(), vec!["odom", "diag1", "diag2"]);
+        assert!(lanes.is_empty());
+        assert_eq!(lanes.pop(), None);
+    }
+
+    #[test]
+    fn test_lane_limit_drops_oldest() {
+        let mut lanes = PriorityLanes::new();
+        lanes.set_lane_limit(Some(2));
+        lanes.push(Priority::Low, 1);
+        lanes.push(Priority::Low, 2);
+        lanes.push(Priority::Low, 3);
+        lanes.push(Priority::High, 4);
+
+        assert_eq!(lanes.dropped(), 1);
+        assert_eq!(lanes.drain(), vec![4, 2, 3]);
+    }
+}
@@ -4,12 +4,18 @@ use std::rc::Weak;
 use std::{collections::HashMap, rc::Rc};
 
 mod client;
+mod lanes;
 
 pub use client::Client;
+pub use lanes::PriorityLanes;
+
+/** Decides whether a packet is routed. Packets it returns false for are dropped. */
+pub type PacketFilter<T> = Box<dyn FnMut(&T) -> bool>;
 
 pub struct Router<T: PacketTrait> {
     clients_by_address: HashMap<u16, Weak<RefCell<Client<T>>>>,
     address_max: u16,
+    filter: Option<PacketFilter<T>>,
 }
 
 impl<T: PacketTrait> Default for Router<T> {
@@ -23,14 +29,34 @@ impl<T: PacketTrait> Router<T> {
         Router::<T> {
             clients_by_address: HashMap::new(),
             address_max: 0,
+            filter: None,
         }
     }
+
+    /**
+     * Drops packets `filter` returns false for before they're delivered to anyone, including
+     * subscribers to "all". Replaces any filter set before.
+     */
+    pub fn set_filter(&mut self, filter: impl FnMut(&T) -> bool + 'static) {
+        self.filter = Some(Box::new(filter));
+    }
     pub fn register_client(&mut self, client: Weak<RefCell<Client<T>>>) {
         self.address_max += 1;
         self.clients_by_address.insert(self.address_max, client);
     }
 
-    /** Distributes packets. Reads from all packets outgoing queue's and   */
+    /** The live clients and their addresses, in address order */
+    pub fn clients(&self) -> Vec<(u16, Rc<RefCell<Client<T>>>)> {
+        let mut clients: Vec<(u16, Rc<RefCell<Client<T>>>)> = self
+            .clients_by_address
+            .iter()
+            .filter_map(|(address, client_weak)| client_weak.upgrade().map(|c| (*address, c)))
+            .collect();
+        clients.sort_by_key(|(address, _)| *address);
+        clients
+    }
+
+    /** Distributes packets. Reads from all packets outgoing queue's and delivers them, highest priority first */
     pub fn poll(&mut self) {
         // Clean dead clients
         self.clients_by_address
@@ -60,10 +86,18 @@ impl<T: PacketTrait> Router<T> {
             let client_outgoing_packets = client.borrow_mut().fetch_client_to_router();
             for mut packet in client_outgoing_packets.into_iter() {
                 packet.set_from(*address);
+                if let Some(filter) = &mut self.filter
+                    && !filter(&packet)
+                {
+                    continue;
+                }
                 all_outgoing_packets.push(Rc::new(packet));
             }
         }
 
+        // Stable sort, so packets keep their order within a priority
+        all_outgoing_packets.sort_by_key(|packet| std::cmp::Reverse(packet.get_priority()));
+
         let subscribers_to_all_topic: Vec<u16> =
             address_by_topic.get("all").cloned().unwrap_or_default();
 
@@ -106,6 +140,7 @@ impl<T: PacketTrait> Router<T> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use packet_trait::Priority;
     use std::cell::RefCell;
     use std::collections::HashSet;
     use std::rc::Rc;
@@ -116,6 +151,7 @@ mod tests {
         from: Option<u16>,
         topic: String,
         data: String,
+        priority: Priority,
     }
 
     impl TestPacket {
@@ -125,6 +161,7 @@ mod tests {
                 from: None,
                 topic,
                 data,
+                priority: Priority::Normal,
             }
         }
 
@@ -132,6 +169,11 @@ mod tests {
             self.to = Some(to);
             self
         }
+
+        fn with_priority(mut self, priority: Priority) -> Self {
+            self.priority = priority;
+            self
+        }
     }
 
     impl PacketTrait for TestPacket {
@@ -146,13 +188,17 @@ mod tests {
         fn set_from(&mut self, from: u16) {
             self.from = Some(from);
         }
+
+        fn get_priority(&self) -> Priority {
+            self.priority
+        }
     }
 
     impl<T: PacketTrait> Client<T> {
         fn new() -> Self {
             Client {
                 client_to_router: Vec::new(),
-                router_to_client: Vec::new(),
+                router_to_client: PriorityLanes::new(),
                 subscriptions: HashSet::new(),
             }
         }
@@ -166,11 +212,7 @@ mod tests {
         }
 
         fn receive_packet(&mut self) -> Option<Rc<T>> {
-            if self.router_to_client.is_empty() {
-                None
-            } else {
-                Some(self.router_to_client.remove(0))
-            }
+            self.fetch_next()
         }
 
         fn has_packets(&self) -> bool {
@@ -200,6 +242,21 @@ mod tests {
         assert_eq!(router.clients_by_address.len(), 1);
     }
 
+    #[test]
+    fn test_clients_skips_dropped() {
+        let mut router: Router<TestPacket> = Router::new();
+        let client1 = Rc::new(RefCell::new(Client::new()));
+        let client2 = Rc::new(RefCell::new(Client::new()));
+        router.register_client(Rc::downgrade(&client1));
+        router.register_client(Rc::downgrade(&client2));
+
+        drop(client1);
+        let clients = router.clients();
+        assert_eq!(clients.len(), 1);
+        assert_eq!(clients[0].0, 2);
+        assert!(Rc::ptr_eq(&clients[0].1, &client2));
+    }
+
     #[test]
     fn test_direct_address_routing() {
         let mut router: Router<TestPacket> = Router::new();
@@ -402,4 +459,104 @@ mod tests {
         // Client 2 should receive all packets
         assert_eq!(client2.borrow().packet_count(), 3);
     }
+
+    #[test]
+    fn test_priority_ordering_within_poll() {
+        let mut router: Router<TestPacket> = Router::new();
+
+        let client1 = Rc::new(RefCell::new(Client::new()));
+        let client2 = Rc::new(RefCell::new(Client::new()));
+
+        router.register_client(Rc::downgrade(&client1));
+        router.register_client(Rc::downgrade(&client2));
+        client2.borrow_mut().subscribe("all".to_string());
+
+        client1.borrow_mut().send_packet(
+            TestPacket::new("diag".to_string(), "1".to_string()).with_priority(Priority::Low),
+        );
+        client1
+            .borrow_mut()
+            .send_packet(TestPacket::new("odom".to_string(), "2".to_string()));
+        client1.borrow_mut().send_packet(
+            TestPacket::new("stop".to_string(), "3".to_string()).with_priority(Priority::High),
+        );
+
+        router.poll();
+
+        let received: Vec<String> = client2
+            .borrow_mut()
+            .fetch_all()
+            .iter()
+            .map(|p| p.data.clone())
+            .collect();
+        assert_eq!(received, vec!["3", "2", "1"]);
+    }
+
+    #[test]
+    fn test_stop_overtakes_queued_low_priority_traffic() {
+        let mut router: Router<TestPacket> = Router::new();
+
+        let controller = Rc::new(RefCell::new(Client::new()));
+        let diagnostics = Rc::new(RefCell::new(Client::new()));
+        let serial_link = Rc::new(RefCell::new(Client::new()));
+
+        router.register_client(Rc::downgrade(&controller));
+        router.register_client(Rc::downgrade(&diagnostics));
+        router.register_client(Rc::downgrade(&serial_link));
+        serial_link.borrow_mut().subscribe("diag".to_string());
+        serial_link.borrow_mut().subscribe("stop".to_string());
+
+        // A burst of diagnostics that the (slow) link has not sent yet
+        for i in 0..20 {
+            diagnostics.borrow_mut().send_packet(
+                TestPacket::new("diag".to_string(), i.to_string()).with_priority(Priority::Low),
+            );
+        }
+        router.poll();
+        assert_eq!(serial_link.borrow().packet_count(), 20);
+
+        // The link only manages to send one packet before the stop command arrives
+        let sent = serial_link.borrow_mut().receive_packet().unwrap();
+        assert_eq!(sent.data, "0");
+
+        controller.borrow_mut().send_packet(
+            TestPacket::new("stop".to_string(), "stop".to_string()).with_priority(Priority::High),
+        );
+        router.poll();
+
+        let next = serial_link.borrow_mut().receive_packet().unwrap();
+        assert_eq!(next.topic, "stop");
+        assert_eq!(next.from, Some(1));
+        assert_eq!(serial_link.borrow().packet_count(), 19);
+    }
+
+    #[test]
+    fn test_filter_drops_packets() {
+        let mut router: Router<TestPacket> = Router::new();
+        let sender = Rc::new(RefCell::new(Client::new()));
+        let subscriber = Rc::new(RefCell::new(Client::new()));
+        let logger = Rc::new(RefCell::new(Client::new()));
+        router.register_client(Rc::downgrade(&sender));
+        router.register_client(Rc::downgrade(&subscriber));
+        router.register_client(Rc::downgrade(&logger));
+        subscriber.borrow_mut().subscribe("drive".to_string());
+        logger.borrow_mut().subscribe("all".to_string());
+        router.set_filter(|packet: &TestPacket| packet.data != "fast");
+
+        for data in ["fast", "stop"] {
+            sender
+                .borrow_mut()
+                .send_packet(TestPacket::new("drive".to_string(), data.to_string()));
+        }
+        // Filtered on the way in, so addressing a client directly doesn't get round it
+        sender
+            .borrow_mut()
+            .send_packet(TestPacket::new("drive".to_string(), "fast".to_string()).with_to(2));
+        router.poll();
+
+        for client in [&subscriber, &logger] {
+            assert_eq!(client.borrow().packet_count(), 1);
+            assert_eq!(client.borrow_mut().receive_packet().unwrap().data, "stop");
+        }
+    }
 }
@@ -1,5 +1,21 @@
 #![no_std]
 
+/**
+ * How urgently a packet should be delivered. Queues are drained highest priority first, so a
+ * `High` packet overtakes any `Normal` or `Low` packets that are still waiting.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    Low = 0,
+    Normal = 1,
+    High = 2,
+}
+
+impl Priority {
+    /** All priorities, highest first. This is the order queues are drained in. */
+    pub const ALL: [Priority; 3] = [Priority::High, Priority::Normal, Priority::Low];
+}
+
 pub trait PacketTrait {
     /**
      * Packets can have an optional address field. If the packet has an address field it will be delivered only to that address, regardless of the topic 
@@ -18,4 +34,12 @@ pub trait PacketTrait {
      * Set the "from" address field of this packet. This is used by the router to indicate return addresses.
      */
     fn set_from(&mut self, from: u16);
+
+
+    /**
+     * Get the priority of this packet. Higher priority packets are routed and transmitted before lower priority ones.
+     */
+    fn get_priority(&self) -> Priority {
+        Priority::Normal
+    }
 }
@@ -1,5 +1,5 @@
 use wasm_bindgen::prelude::*;
-use topics::{PacketFormat, PacketData};
+use topics::{MAX_PACKET_SIZE, PacketFormat, PacketData};
 
 /// Initialize panic hook for better error messages in browser console
 #[wasm_bindgen(start)]
@@ -21,7 +21,7 @@ pub fn encode_packet(json: &str) -> Result<Vec<u8>, JsValue> {
         .map_err(|e| JsValue::from_str(&format!("Failed to parse JSON: {}, {}", e, json)))?;
     
     // Encode to CBOR
-    let mut buffer = [0u8; 1024];
+    let mut buffer = [0u8; MAX_PACKET_SIZE];
     let size = packet_encoding::encode_packet(&packet, &mut buffer)
         .map_err(|e| JsValue::from_str(&format!("Failed to encode to CBOR: {:?}, {:?}", e, packet)))?;
     
@@ -0,0 +1,622 @@
+/*!
+ * Bag files: recordings of router traffic for later analysis or playback.
+ *
+ * All integers are little endian. A bag is laid out as:
+ *
+ * ```text
+ * Header:  magic "SLAMBAG\0" (8 bytes), version: u16
+ * Chunk*:  tag 'C', record_count: u32, data_len: u32, data_len bytes of records
+ *            Record: receive_time: u64 (us), len: u16, len bytes of packet
+ * Index:   tag 'I', chunk_count: u32,
+ *            per chunk: offset: u64, start_time: u64, end_time: u64, record_count: u32
+ *          topic_count: u16,
+ *            per topic: name_len: u8, name bytes (utf8), count: u32
+ * Footer:  index_offset: u64, magic "SBAGINDX" (8 bytes)
+ * ```
+ *
+ * Packets are stored exactly as they are sent over the serial link (`packet_encoding`, ie COBS
+ * encoded CBOR with a CRC, without the zero delimiters) so each one is checked on read.
+ *
+ * Records are buffered and written a chunk at a time, so a crash loses at most the current
+ * chunk. The index and footer are only written when the bag is closed; a bag without them
+ * (eg from a crashed recording) can still be read by scanning the chunks.
+ */
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use packet_encoding::{PacketDecodeErr, PacketEncodeErr, decode_packet, encode_packet};
+use topics::{MAX_PACKET_SIZE, PacketData, PacketDataTrait, PacketFormat};
+
+pub const MAGIC: &[u8; 8] = b"SLAMBAG\0";
+pub const FOOTER_MAGIC: &[u8; 8] = b"SBAGINDX";
+pub const VERSION: u16 = 1;
+
+const HEADER_LEN: u64 = 10;
+const FOOTER_LEN: u64 = 16;
+const CHUNK_TAG: u8 = b'C';
+const INDEX_TAG: u8 = b'I';
+
+/** Chunks are written once they reach this size */
+pub const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Debug)]
+pub enum BagError {
+    Io(io::Error),
+    BadMagic,
+    UnsupportedVersion(u16),
+    /** The file ends part way through a chunk, or a chunk's contents are inconsistent */
+    Corrupt(&'static str),
+    Encode(PacketEncodeErr),
+    Decode(PacketDecodeErr),
+}
+
+impl fmt::Display for BagError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BagError::Io(err) => write!(f, "I/O error: {}", err),
+            BagError::BadMagic => write!(f, "not a bag file"),
+            BagError::UnsupportedVersion(version) => {
+                write!(f, "unsupported bag version {}", version)
+            }
+            BagError::Corrupt(reason) => write!(f, "corrupt bag: {}", reason),
+            BagError::Encode(err) => write!(f, "failed to encode packet: {:?}", err),
+            BagError::Decode(err) => write!(f, "failed to decode packet: {:?}", err),
+        }
+    }
+}
+
+impl std::error::Error for BagError {}
+
+impl From<io::Error> for BagError {
+    fn from(err: io::Error) -> Self {
+        BagError::Io(err)
+    }
+}
+
+#[derive(Debug)]
+pub struct BagRecord {
+    /** When the recorder received the packet, in microseconds */
+    pub receive_time: u64,
+    pub packet: PacketFormat<PacketData>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChunkInfo {
+    pub offset: u64,
+    pub start_time: u64,
+    pub end_time: u64,
+    pub record_count: u32,
+}
+
+/** Topics, message counts and time range of a bag */
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct BagSummary {
+    pub chunks: Vec<ChunkInfo>,
+    pub topic_counts: BTreeMap<String, u32>,
+}
+
+impl BagSummary {
+    pub fn record_count(&self) -> u64 {
+        self.chunks.iter().map(|chunk| chunk.record_count as u64).sum()
+    }
+
+    pub fn start_time(&self) -> Option<u64> {
+        self.chunks.iter().map(|chunk| chunk.start_time).min()
+    }
+
+    pub fn end_time(&self) -> Option<u64> {
+        self.chunks.iter().map(|chunk| chunk.end_time).max()
+    }
+
+    fn add_record(&mut self, topic: &str) {
+        *self.topic_counts.entry(topic.to_string()).or_default() += 1;
+    }
+}
+
+impl fmt::Display for BagSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let duration = match (self.start_time(), self.end_time()) {
+            (Some(start), Some(end)) => end.saturating_sub(start) as f64 / 1e6,
+            _ => 0.0,
+        };
+        writeln!(
+            f,
+            "{} records in {} chunks, {:.3}s",
+            self.record_count(),
+            self.chunks.len(),
+            duration
+        )?;
+        if let (Some(start), Some(end)) = (self.start_time(), self.end_time()) {
+            writeln!(f, "  start: {}", format_time(start))?;
+            writeln!(f, "  end:   {}", format_time(end))?;
+        }
+        for (topic, count) in self.topic_counts.iter() {
+            writeln!(f, "  {:<24} {}", topic, count)?;
+        }
+        Ok(())
+    }
+}
+
+fn format_time(time: u64) -> String {
+    chrono::DateTime::from_timestamp_micros(time as i64)
+        .map(|time| time.to_rfc3339())
+        .unwrap_or_else(|| time.to_string())
+}
+
+/**
+ * Writes records to a bag. Call `finish` to write the index; if the writer is dropped without
+ * finishing, the chunks written so far can still be read.
+ */
+pub struct BagWriter<W: Write> {
+    writer: W,
+    position: u64,
+    chunk_size: usize,
+    chunk: Vec<u8>,
+    chunk_info: Option<ChunkInfo>,
+    summary: BagSummary,
+}
+
+impl BagWriter<BufWriter<File>> {
+    pub fn create(path: &Path) -> Result<Self, BagError> {
+        BagWriter::new(BufWriter::new(File::create(path)?))
+    }
+}
+
+impl<W: Write> BagWriter<W> {
+    pub fn new(mut writer: W) -> Result<Self, BagError> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+        Ok(BagWriter {
+            writer,
+            position: HEADER_LEN,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            chunk: Vec::new(),
+            chunk_info: None,
+            summary: BagSummary::default(),
+        })
+    }
+
+    pub fn write(
+        &mut self,
+        receive_time: u64,
+        packet: &PacketFormat<PacketData>,
+    ) -> Result<(), BagError> {
+        let mut encode_buffer = [0u8; MAX_PACKET_SIZE];
+        let size = encode_packet(packet, &mut encode_buffer).map_err(BagError::Encode)?;
+
+        self.chunk.extend_from_slice(&receive_time.to_le_bytes());
+        self.chunk.extend_from_slice(&(size as u16).to_le_bytes());
+        self.chunk.extend_from_slice(&encode_buffer[..size]);
+
+        let info = self.chunk_info.get_or_insert(ChunkInfo {
+            offset: self.position,
+            start_time: receive_time,
+            end_time: receive_time,
+            record_count: 0,
+        });
+        info.start_time = info.start_time.min(receive_time);
+        info.end_time = info.end_time.max(receive_time);
+        info.record_count += 1;
+        self.summary.add_record(packet.data.topic());
+
+        if self.chunk.len() >= self.chunk_size {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /** Writes out the current chunk, even if it isn't full. */
+    pub fn flush(&mut self) -> Result<(), BagError> {
+        if let Some(info) = self.chunk_info.take() {
+            self.writer.write_all(&[CHUNK_TAG])?;
+            self.writer.write_all(&info.record_count.to_le_bytes())?;
+            self.writer.write_all(&(self.chunk.len() as u32).to_le_bytes())?;
+            self.writer.write_all(&self.chunk)?;
+            self.position += 9 + self.chunk.len() as u64;
+            self.chunk.clear();
+            self.summary.chunks.push(info);
+        }
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    /** Bytes written so far, including the unwritten chunk */
+    pub fn bytes_written(&self) -> u64 {
+        self.position + self.chunk.len() as u64
+    }
+
+    /** Writes the last chunk and the index. */
+    pub fn finish(mut self) -> Result<BagSummary, BagError> {
+        self.flush()?;
+        let index_offset = self.position;
+
+        let mut index = vec![INDEX_TAG];
+        index.extend_from_slice(&(self.summary.chunks.len() as u32).to_le_bytes());
+        for chunk in self.summary.chunks.iter() {
+            index.extend_from_slice(&chunk.offset.to_le_bytes());
+            index.extend_from_slice(&chunk.start_time.to_le_bytes());
+            index.extend_from_slice(&chunk.end_time.to_le_bytes());
+            index.extend_from_slice(&chunk.record_count.to_le_bytes());
+        }
+        index.extend_from_slice(&(self.summary.topic_counts.len() as u16).to_le_bytes());
+        for (topic, count) in self.summary.topic_counts.iter() {
+            let name = &topic.as_bytes()[..topic.len().min(u8::MAX as usize)];
+            index.push(name.len() as u8);
+            index.extend_from_slice(name);
+            index.extend_from_slice(&count.to_le_bytes());
+        }
+        index.extend_from_slice(&index_offset.to_le_bytes());
+        index.extend_from_slice(FOOTER_MAGIC);
+
+        self.writer.write_all(&index)?;
+        self.writer.flush()?;
+        Ok(self.summary)
+    }
+}
+
+fn read_u8(reader: &mut impl Read) -> io::Result<u8> {
+    let mut bytes = [0u8; 1];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes[0])
+}
+
+fn read_u16(reader: &mut impl Read) -> io::Result<u16> {
+    let mut bytes = [0u8; 2];
+    reader.read_exact(&mut bytes)?;
+    Ok(u16::from_le_bytes(bytes))
+}
+
+fn read_u32(reader: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64(reader: &mut impl Read) -> io::Result<u64> {
+    let mut bytes = [0u8; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+/** Turns an unexpected end of file into a `Corrupt` error */
+fn truncated(err: io::Error) -> BagError {
+    if err.kind() == io::ErrorKind::UnexpectedEof {
+        BagError::Corrupt("truncated")
+    } else {
+        BagError::Io(err)
+    }
+}
+
+/** Reads a bag, using its index if it has one. */
+pub struct BagReader<R: Read + Seek> {
+    reader: R,
+    /** Where the chunks end: the start of the index, or the end of the file */
+    data_end: u64,
+    summary: BagSummary,
+    has_index: bool,
+}
+
+impl BagReader<BufReader<File>> {
+    pub fn open(path: &Path) -> Result<Self, BagError> {
+        BagReader::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read + Seek> BagReader<R> {
+    pub fn new(mut reader: R) -> Result<Self, BagError> {
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic).map_err(truncated)?;
+        if &magic != MAGIC {
+            return Err(BagError::BadMagic);
+        }
+        let version = read_u16(&mut reader).map_err(truncated)?;
+        if version != VERSION {
+            return Err(BagError::UnsupportedVersion(version));
+        }
+
+        let file_len = reader.seek(SeekFrom::End(0))?;
+        let mut bag = BagReader {
+            reader,
+            data_end: file_len,
+            summary: BagSummary::default(),
+            has_index: false,
+        };
+        if !bag.read_index()? {
+            bag.scan_chunks()?;
+        }
+        Ok(bag)
+    }
+
+    /** Returns false if the bag has no valid footer. */
+    fn read_index(&mut self) -> Result<bool, BagError> {
+        if self.data_end < HEADER_LEN + FOOTER_LEN {
+            return Ok(false);
+        }
+        self.reader.seek(SeekFrom::End(-(FOOTER_LEN as i64)))?;
+        let index_offset = read_u64(&mut self.reader)?;
+        let mut magic = [0u8; 8];
+        self.reader.read_exact(&mut magic)?;
+        if &magic != FOOTER_MAGIC || index_offset < HEADER_LEN || index_offset >= self.data_end {
+            return Ok(false);
+        }
+
+        self.reader.seek(SeekFrom::Start(index_offset))?;
+        if read_u8(&mut self.reader)? != INDEX_TAG {
+            return Ok(false);
+        }
+        let mut summary = BagSummary::default();
+        let chunk_count = read_u32(&mut self.reader).map_err(truncated)?;
+        for _ in 0..chunk_count {
+            summary.chunks.push(ChunkInfo {
+                offset: read_u64(&mut self.reader).map_err(truncated)?,
+                start_time: read_u64(&mut self.reader).map_err(truncated)?,
+                end_time: read_u64(&mut self.reader).map_err(truncated)?,
+                record_count: read_u32(&mut self.reader).map_err(truncated)?,
+            });
+        }
+        let topic_count = read_u16(&mut self.reader).map_err(truncated)?;
+        for _ in 0..topic_count {
+            let name_len = read_u8(&mut self.reader).map_err(truncated)?;
+            let mut name = vec![0u8; name_len as usize];
+            self.reader.read_exact(&mut name).map_err(truncated)?;
+            let name = String::from_utf8(name).map_err(|_| BagError::Corrupt("topic name"))?;
+            let count = read_u32(&mut self.reader).map_err(truncated)?;
+            summary.topic_counts.insert(name, count);
+        }
+
+        self.summary = summary;
+        self.data_end = index_offset;
+        self.has_index = true;
+        Ok(true)
+    }
+
+    /**
+     * Rebuilds the summary by reading every chunk. Used for bags that were never finished; a
+     * partially written final chunk is ignored.
+     */
+    fn scan_chunks(&mut self) -> Result<(), BagError> {
+        let mut offset = HEADER_LEN;
+        let mut summary = BagSummary::default();
+        while offset < self.data_end {
+            let records = match self.read_chunk(offset) {
+                Ok(records) => records,
+                Err(BagError::Corrupt(_)) => break,
+                Err(err) => return Err(err),
+            };
+            let chunk_len = self.reader.stream_position()? - offset;
+            let mut info = ChunkInfo {
+                offset,
+                start_time: u64::MAX,
+                end_time: 0,
+                record_count: 0,
+            };
+            for record in records.iter() {
+                info.start_time = info.start_time.min(record.receive_time);
+                info.end_time = info.end_time.max(record.receive_time);
+                info.record_count += 1;
+                summary.add_record(record.packet.data.topic());
+            }
+            if info.record_count > 0 {
+                summary.chunks.push(info);
+            }
+            offset += chunk_len;
+        }
+        self.data_end = offset;
+        self.summary = summary;
+        Ok(())
+    }
+
+    fn read_chunk(&mut self, offset: u64) -> Result<Vec<BagRecord>, BagError> {
+        self.reader.seek(SeekFrom::Start(offset))?;
+        if read_u8(&mut self.reader).map_err(truncated)? != CHUNK_TAG {
+            return Err(BagError::Corrupt("expected chunk"));
+        }
+        let record_count = read_u32(&mut self.reader).map_err(truncated)?;
+        let data_len = read_u32(&mut self.reader).map_err(truncated)?;
+        if offset + 9 + data_len as u64 > self.data_end {
+            return Err(BagError::Corrupt("truncated"));
+        }
+        let mut data = vec![0u8; data_len as usize];
+        self.reader.read_exact(&mut data).map_err(truncated)?;
+
+        let mut records = Vec::with_capacity(record_count as usize);
+        let mut cursor = data.as_mut_slice();
+        for _ in 0..record_count {
+            if cursor.len() < 10 {
+                return Err(BagError::Corrupt("record header"));
+            }
+            let (header, rest) = cursor.split_at_mut(10);
+            let receive_time = u64::from_le_bytes(header[..8].try_into().unwrap());
+            let len = u16::from_le_bytes([header[8], header[9]]) as usize;
+            if rest.len() < len {
+                return Err(BagError::Corrupt("record length"));
+            }
+            let (packet, rest) = rest.split_at_mut(len);
+            let packet = decode_packet(packet).map_err(BagError::Decode)?;
+            records.push(BagRecord {
+                receive_time,
+                packet,
+            });
+            cursor = rest;
+        }
+        Ok(records)
+    }
+
+    pub fn summary(&self) -> &BagSummary {
+        &self.summary
+    }
+
+    /** The records in one chunk of the summary, for reading a bag a chunk at a time. */
+    pub fn chunk_records(&mut self, chunk_index: usize) -> Result<Vec<BagRecord>, BagError> {
+        let offset = self.summary.chunks[chunk_index].offset;
+        self.read_chunk(offset)
+    }
+
+    /** False if the recording was never finished, and the summary was rebuilt by scanning. */
+    pub fn has_index(&self) -> bool {
+        self.has_index
+    }
+
+    /** Every record in the bag, in the order it was recorded. */
+    pub fn records(&mut self) -> BagRecords<'_, R> {
+        self.records_from(0)
+    }
+
+    /** Records received at or after `start_time`. Uses the index to skip earlier chunks. */
+    pub fn records_from(&mut self, start_time: u64) -> BagRecords<'_, R> {
+        let chunks = self
+            .summary
+            .chunks
+            .iter()
+            .filter(|chunk| chunk.end_time >= start_time)
+            .map(|chunk| chunk.offset)
+            .collect();
+        BagRecords {
+            bag: self,
+            chunks,
+            current: Vec::new().into_iter(),
+            start_time,
+        }
+    }
+}
+
+pub struct BagRecords<'a, R: Read + Seek> {
+    bag: &'a mut BagReader<R>,
+    chunks: std::collections::VecDeque<u64>,
+    current: std::vec::IntoIter<BagRecord>,
+    start_time: u64,
+}
+
+impl<R: Read + Seek> Iterator for BagRecords<'_, R> {
+    type Item = Result<BagRecord, BagError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(record) = self.current.by_ref().find(|r| r.receive_time >= self.start_time) {
+                return Some(Ok(record));
+            }
+            let offset = self.chunks.pop_front()?;
+            match self.bag.read_chunk(offset) {
+                Ok(records) => self.current = records.into_iter(),
+                Err(err) => {
+                    self.chunks.clear();
+                    return Some(Err(err));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn packet(id: u32) -> PacketFormat<PacketData> {
+        PacketFormat {
+            to: None,
+            from: Some(3),
+            data: PacketData::ClockRequest(topics::ClockRequest { request_time: id as u64 }),
+            time: 1000 + id as u64,
+            id,
+        }
+    }
+
+    fn odometry(id: u32) -> PacketFormat<PacketData> {
+        PacketFormat {
+            to: None,
+            from: Some(4),
+            data: PacketData::OdometryDelta(topics::OdometryDelta {
+                start_time: 0,
+                end_time: 10,
+                delta_position: [0.5, 0.25],
+                delta_orientation: 0.1,
+            }),
+            time: 2000,
+            id,
+        }
+    }
+
+    /** Writes 10 records, 3 per chunk, and returns the bag's bytes */
+    fn write_bag(finish: bool) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        let mut writer = BagWriter::new(&mut buffer).unwrap();
+        for i in 0..10 {
+            if i % 3 == 2 {
+                writer.write(i as u64 * 100, &odometry(i)).unwrap();
+                writer.flush().unwrap();
+            } else {
+                writer.write(i as u64 * 100, &packet(i)).unwrap();
+            }
+        }
+        if finish {
+            writer.finish().unwrap();
+        } else {
+            writer.flush().unwrap();
+        }
+        buffer
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let bag = write_bag(true);
+        let mut reader = BagReader::new(Cursor::new(bag)).unwrap();
+        assert!(reader.has_index());
+
+        let records: Vec<BagRecord> = reader.records().map(|r| r.unwrap()).collect();
+        assert_eq!(records.len(), 10);
+        for (i, record) in records.iter().enumerate() {
+            assert_eq!(record.receive_time, i as u64 * 100);
+            assert_eq!(record.packet.id, i as u32);
+        }
+        assert!(matches!(records[2].packet.data, PacketData::OdometryDelta(_)));
+    }
+
+    #[test]
+    fn test_summary() {
+        let bag = write_bag(true);
+        let reader = BagReader::new(Cursor::new(bag)).unwrap();
+        let summary = reader.summary();
+        assert_eq!(summary.record_count(), 10);
+        assert_eq!(summary.start_time(), Some(0));
+        assert_eq!(summary.end_time(), Some(900));
+        assert_eq!(summary.topic_counts["ClockRequest"], 7);
+        assert_eq!(summary.topic_counts["OdometryDelta"], 3);
+        assert!(summary.to_string().starts_with("10 records"));
+    }
+
+    #[test]
+    fn test_unfinished_bag_is_scanned() {
+        let finished = write_bag(true);
+        let mut bag = write_bag(false);
+        // Half written chunk at the end
+        bag.extend_from_slice(&[CHUNK_TAG, 1, 0, 0, 0, 100, 0]);
+
+        let mut reader = BagReader::new(Cursor::new(bag)).unwrap();
+        assert!(!reader.has_index());
+        let indexed = BagReader::new(Cursor::new(finished)).unwrap();
+        assert_eq!(reader.summary(), indexed.summary());
+        assert_eq!(reader.records().count(), 10);
+    }
+
+    #[test]
+    fn test_records_from_skips_chunks() {
+        let bag = write_bag(true);
+        let mut reader = BagReader::new(Cursor::new(bag)).unwrap();
+        let ids: Vec<u32> = reader
+            .records_from(450)
+            .map(|r| r.unwrap().packet.id)
+            .collect();
+        assert_eq!(ids, [5, 6, 7, 8, 9]);
+    }
+
+    #[test]
+    fn test_not_a_bag() {
+        let result = BagReader::new(Cursor::new(b"hello world, this is not a bag".to_vec()));
+        assert!(matches!(result, Err(BagError::BadMagic)));
+    }
+}
@@ -0,0 +1,1000 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::nodes::safety_supervisor::MAX_WATCHED_TOPICS;
+use crate::schema::topic_schemas;
+
+/**
+ * Runtime configuration, loaded from a TOML file. Every section and field is optional and falls
+ * back to the defaults below, so an empty file gives the same robot as running without one.
+ * Unknown fields are rejected so that typos don't silently fall back to a default.
+ *
+ * Each node section has an `enabled` flag to choose which nodes run.
+ */
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub scheduler: SchedulerConfig,
+    pub clock: ClockConfig,
+    pub log: LogConfig,
+    pub websocket: WebsocketConfig,
+    pub tcp: TcpConfig,
+    pub udp: UdpConfig,
+    pub foxglove: FoxgloveConfig,
+    pub rosbridge: RosbridgeConfig,
+    pub mqtt: MqttConfig,
+    pub serial: SerialConfig,
+    pub position_estimator: PositionEstimatorConfig,
+    pub motion_controller: MotionControllerConfig,
+    pub mission_executor: MissionExecutorConfig,
+    pub safety_supervisor: SafetySupervisorConfig,
+    pub recorder: RecorderConfig,
+    pub player: PlayerConfig,
+    pub simulated_base: SimulatedBaseConfig,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SchedulerConfig {
+    /** Longest the scheduler will sleep before polling sockets and serial ports again */
+    pub io_poll_interval_ms: u64,
+    pub stats_period_ms: u64,
+}
+
+impl Default for SchedulerConfig {
+    fn default() -> Self {
+        SchedulerConfig {
+            io_poll_interval_ms: 2,
+            stats_period_ms: 1000,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClockConfig {
+    pub enabled: bool,
+}
+
+impl Default for ClockConfig {
+    fn default() -> Self {
+        ClockConfig { enabled: true }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    pub enabled: bool,
+    /** Print every packet rather than just diagnostics */
+    pub log_all: bool,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            enabled: true,
+            log_all: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebsocketConfig {
+    pub enabled: bool,
+    pub address: String,
+    /** Built web interface (`npm run build` output) to serve over HTTP on the same address */
+    pub static_dir: Option<String>,
+    /** Answer the JSON endpoints (/topics, /clients, /latest, /publish) */
+    pub rest_api: bool,
+}
+
+impl Default for WebsocketConfig {
+    fn default() -> Self {
+        WebsocketConfig {
+            enabled: true,
+            address: "127.0.0.1:9001".to_string(),
+            static_dir: None,
+            rest_api: true,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TcpConfig {
+    pub enabled: bool,
+    pub address: String,
+}
+
+impl Default for TcpConfig {
+    fn default() -> Self {
+        TcpConfig {
+            enabled: false,
+            address: "0.0.0.0:9002".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UdpConfig {
+    pub enabled: bool,
+    pub address: String,
+    /** Peers are forgotten after this long without sending anything */
+    pub peer_timeout_ms: u64,
+}
+
+impl Default for UdpConfig {
+    fn default() -> Self {
+        UdpConfig {
+            enabled: false,
+            address: "0.0.0.0:9003".to_string(),
+            peer_timeout_ms: 5000,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FoxgloveConfig {
+    pub enabled: bool,
+    pub address: String,
+}
+
+impl Default for FoxgloveConfig {
+    fn default() -> Self {
+        FoxgloveConfig {
+            enabled: false,
+            address: "127.0.0.1:8765".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RosbridgeConfig {
+    pub enabled: bool,
+    pub address: String,
+}
+
+impl Default for RosbridgeConfig {
+    fn default() -> Self {
+        RosbridgeConfig {
+            enabled: false,
+            address: "127.0.0.1:9090".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MqttEncoding {
+    /** `PacketFormat` as JSON, the same JSON that `Log` prints */
+    Json,
+    /** `PacketFormat` as CBOR, without the serial link's COBS framing and CRC */
+    Cbor,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MqttConfig {
+    pub enabled: bool,
+    /** Host and port of the broker */
+    pub broker: String,
+    /** Topics are published as `slambot/<robot>/<topic>` */
+    pub robot: String,
+    /** Defaults to `slambot-<robot>` */
+    pub client_id: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub encoding: MqttEncoding,
+    /** Topics published to the broker */
+    pub publish_topics: Vec<String>,
+    /** Topics taken from the broker and sent to the router, eg commands from a fleet manager */
+    pub subscribe_topics: Vec<String>,
+    pub keep_alive_s: u16,
+    /** Reconnect attempts start this far apart, doubling up to `reconnect_max_ms` */
+    pub reconnect_min_ms: u64,
+    pub reconnect_max_ms: u64,
+}
+
+impl Default for MqttConfig {
+    fn default() -> Self {
+        MqttConfig {
+            enabled: false,
+            broker: "127.0.0.1:1883".to_string(),
+            robot: "robot".to_string(),
+            client_id: None,
+            username: None,
+            password: None,
+            encoding: MqttEncoding::Json,
+            publish_topics: vec![
+                "PositionEstimate".to_string(),
+                "DiagnosticMsg".to_string(),
+            ],
+            subscribe_topics: vec!["MotionTargetRequest".to_string()],
+            keep_alive_s: 30,
+            reconnect_min_ms: 500,
+            reconnect_max_ms: 30000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SerialConfig {
+    pub enabled: bool,
+    pub scan_interval_ms: u64,
+    /** Which serial ports to connect to, and what to call them. Each rule connects one device. */
+    pub devices: Vec<SerialDeviceConfig>,
+}
+
+impl Default for SerialConfig {
+    fn default() -> Self {
+        SerialConfig {
+            enabled: true,
+            scan_interval_ms: 2000,
+            devices: vec![SerialDeviceConfig {
+                name: "motor_controller".to_string(),
+                // Espressif USB JTAG serial debug unit, or a port named after one when its USB
+                // details can't be read
+                vid: Some(0x303A),
+                fallback_path: Some("*usb-Espressif_USB_JTAG_serial_debug_unit*".to_string()),
+                ..Default::default()
+            }],
+        }
+    }
+}
+
+/**
+ * A rule matching one serial device. A port must match every criterion that is set. The name stays
+ * the same when the device is unplugged or re-enumerates under a different path.
+ */
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SerialDeviceConfig {
+    pub name: String,
+    pub vid: Option<u16>,
+    pub pid: Option<u16>,
+    /** Glob (`*` and `?`) on the USB serial number */
+    pub serial_number: Option<String>,
+    /** Glob (`*` and `?`) on the USB manufacturer string */
+    pub manufacturer: Option<String>,
+    /**
+     * Glob on the device path, eg `/dev/ttyACM*` or a udev symlink. Wildcards are only allowed in
+     * the last path component. Paths that exist are matched even if they aren't listed as serial
+     * ports, such as symlinks and pseudo-terminals.
+     */
+    pub path: Option<String>,
+    /**
+     * Glob on the path of a listed serial port, which matches it even if the rest of the rule
+     * doesn't, eg for ports whose USB details can't be read
+     */
+    pub fallback_path: Option<String>,
+    pub baud_rate: u32,
+}
+
+impl Default for SerialDeviceConfig {
+    fn default() -> Self {
+        SerialDeviceConfig {
+            name: String::new(),
+            vid: None,
+            pid: None,
+            serial_number: None,
+            manufacturer: None,
+            path: None,
+            fallback_path: None,
+            baud_rate: 115_200,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PositionEstimatorConfig {
+    pub enabled: bool,
+    pub publish_interval_ms: u64,
+    /** Odometry noise: standard deviation of travel, as a fraction of the distance travelled */
+    pub translation_noise: f64,
+    /** Odometry noise: standard deviation of rotation, as a fraction of the rotation */
+    pub rotation_noise: f64,
+    /** Odometry noise: standard deviation of rotation per m travelled (rad/m) */
+    pub drift_noise: f64,
+    /** Track the heading with the gyro in `ImuSample`s, correcting it with the wheels */
+    pub use_imu: bool,
+    /** Gyro angle random walk (rad/sqrt(s)) */
+    pub gyro_noise: f64,
+    /** How fast the gyro's bias wanders (rad/s/sqrt(s)) */
+    pub gyro_bias_drift: f64,
+    /** Standard deviation of the gyro's bias before it has been estimated (rad/s) */
+    pub initial_gyro_bias: f64,
+    /** Heading comes from the wheels alone if no `ImuSample` arrives for this long */
+    pub imu_timeout_ms: u64,
+    /** How far back poses are kept, to match measurements and late odometry with */
+    pub history_ms: u64,
+}
+
+impl Default for PositionEstimatorConfig {
+    fn default() -> Self {
+        PositionEstimatorConfig {
+            enabled: true,
+            publish_interval_ms: 100,
+            translation_noise: 0.05,
+            rotation_noise: 0.1,
+            drift_noise: 0.05,
+            use_imu: true,
+            gyro_noise: 0.005,
+            gyro_bias_drift: 0.0005,
+            initial_gyro_bias: 0.05,
+            imu_timeout_ms: 200,
+            history_ms: 10_000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MotionControllerConfig {
+    pub enabled: bool,
+    pub publish_interval_ms: u64,
+    pub max_command_age_ms: u64,
+    pub kp_linear: f64,
+    pub kp_angular: f64,
+    /** m/s */
+    pub max_linear_velocity: f64,
+    /** rad/s */
+    pub max_angular_velocity: f64,
+    /** m/s^2. Commands ramp at this rate rather than stepping, so the wheels don't slip. */
+    pub max_linear_acceleration: f64,
+    /** rad/s^2 */
+    pub max_angular_acceleration: f64,
+    /** How close (in m) counts as having reached a position target */
+    pub position_tolerance: f64,
+    /** How close (in rad) counts as facing a position target's or path's final heading */
+    pub heading_tolerance: f64,
+    /** How far ahead along a path (in m) to steer towards. Longer is smoother but cuts corners. */
+    pub lookahead_distance: f64,
+}
+
+impl Default for MotionControllerConfig {
+    fn default() -> Self {
+        MotionControllerConfig {
+            enabled: true,
+            publish_interval_ms: 100,
+            max_command_age_ms: 500,
+            kp_linear: 2.0,
+            kp_angular: 2.0,
+            max_linear_velocity: 0.5,
+            max_angular_velocity: 2.0,
+            max_linear_acceleration: 0.5,
+            max_angular_acceleration: 4.0,
+            position_tolerance: 0.05,
+            heading_tolerance: 0.05,
+            lookahead_distance: 0.3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MissionExecutorConfig {
+    /** Off unless the config turns it on, like the other optional nodes */
+    pub enabled: bool,
+    /** A mission to run at startup, as JSON or TOML (see src/mission.rs) */
+    pub file: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SafetySupervisorConfig {
+    /**
+     * Off unless the config turns it on, as the default `topics` hold the robot stopped when
+     * nothing publishes them, eg without a position estimator
+     */
+    pub enabled: bool,
+    pub check_interval_ms: u64,
+    /** The robot is held stopped while any of these topics is stale */
+    pub topics: Vec<WatchedTopicConfig>,
+}
+
+impl Default for SafetySupervisorConfig {
+    fn default() -> Self {
+        SafetySupervisorConfig {
+            enabled: false,
+            check_interval_ms: 50,
+            topics: vec![
+                WatchedTopicConfig {
+                    topic: "PositionEstimate".to_string(),
+                    timeout_ms: 500,
+                },
+                WatchedTopicConfig {
+                    topic: "OdometryDelta".to_string(),
+                    timeout_ms: 500,
+                },
+            ],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WatchedTopicConfig {
+    pub topic: String,
+    /** Stale if nothing has arrived on the topic for this long, or ever */
+    pub timeout_ms: u64,
+}
+
+impl Default for WatchedTopicConfig {
+    fn default() -> Self {
+        WatchedTopicConfig {
+            topic: String::new(),
+            timeout_ms: 500,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RecorderConfig {
+    pub enabled: bool,
+    /** Bags are written here, named after the time recording started */
+    pub directory: String,
+    /** Topics to record, or "all" */
+    pub topics: Vec<String>,
+    /** Start a new file once the current one reaches this size */
+    pub max_file_mb: Option<u64>,
+    /** Start a new file once the current one has been recording for this long */
+    pub max_file_duration_s: Option<u64>,
+}
+
+impl Default for RecorderConfig {
+    fn default() -> Self {
+        RecorderConfig {
+            enabled: false,
+            directory: "bags".to_string(),
+            topics: vec!["all".to_string()],
+            max_file_mb: None,
+            max_file_duration_s: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PlayerConfig {
+    pub enabled: bool,
+    /** Bag to play, as written by the recorder */
+    pub file: String,
+    /** Playback speed, 2.0 is twice as fast as recorded */
+    pub rate: f64,
+    /** Go back to the start offset when the end is reached */
+    #[serde(rename = "loop")]
+    pub loop_playback: bool,
+    /** Seconds from the start of the bag to start playing at */
+    pub start_offset_s: f64,
+    /** Seconds from the start of the bag to stop playing at */
+    pub end_offset_s: Option<f64>,
+    /** Topics to play, or "all" */
+    pub topics: Vec<String>,
+    /**
+     * Topics never played. By default motion commands are excluded so that replaying a session
+     * doesn't drive a connected robot.
+     */
+    pub exclude_topics: Vec<String>,
+}
+
+impl Default for PlayerConfig {
+    fn default() -> Self {
+        PlayerConfig {
+            enabled: false,
+            file: String::new(),
+            rate: 1.0,
+            loop_playback: false,
+            start_offset_s: 0.0,
+            end_offset_s: None,
+            topics: vec!["all".to_string()],
+            exclude_topics: vec![
+                "MotionTargetRequest".to_string(),
+                "MotionVelocityRequest".to_string(),
+            ],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SimulatedBaseConfig {
+    pub enabled: bool,
+    pub publish_interval_ms: u64,
+    /** The motors stop if no command is received for this long, as in the firmware */
+    pub command_timeout_ms: u64,
+    /** Standard deviation of each wheel's travel, as a fraction of the travel */
+    pub noise_std: f64,
+    /** Fraction of wheel travel lost to slip: the encoders count it, but the robot doesn't move */
+    pub slip: f64,
+    /** Seed for the noise, so that runs can be repeated */
+    pub seed: u64,
+}
+
+impl Default for SimulatedBaseConfig {
+    fn default() -> Self {
+        SimulatedBaseConfig {
+            enabled: false,
+            publish_interval_ms: 100,
+            command_timeout_ms: 1000,
+            noise_std: 0.0,
+            slip: 0.0,
+            seed: 1,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Invalid { field: &'static str, reason: String },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, err) => {
+                write!(f, "Failed to read config {}: {}", path.display(), err)
+            }
+            ConfigError::Parse(path, err) => {
+                write!(f, "Failed to parse config {}: {}", path.display(), err)
+            }
+            ConfigError::Invalid { field, reason } => {
+                write!(f, "Invalid config value for {}: {}", field, reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+fn invalid(field: &'static str, reason: impl Into<String>) -> ConfigError {
+    ConfigError::Invalid {
+        field,
+        reason: reason.into(),
+    }
+}
+
+fn check_interval(field: &'static str, value_ms: u64) -> Result<(), ConfigError> {
+    if value_ms == 0 {
+        return Err(invalid(field, "must be greater than zero"));
+    }
+    Ok(())
+}
+
+fn check_positive(field: &'static str, value: f64) -> Result<(), ConfigError> {
+    if !value.is_finite() || value <= 0.0 {
+        return Err(invalid(field, format!("must be a positive number, got {}", value)));
+    }
+    Ok(())
+}
+
+fn check_non_negative(field: &'static str, value: f64) -> Result<(), ConfigError> {
+    if !value.is_finite() || value < 0.0 {
+        return Err(invalid(field, "must not be negative"));
+    }
+    Ok(())
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config, ConfigError> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|err| ConfigError::Read(path.to_path_buf(), err))?;
+        let config: Config =
+            toml::from_str(&text).map_err(|err| ConfigError::Parse(path.to_path_buf(), err))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /** Checks the values that parse fine but can't work. */
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        check_interval("scheduler.io_poll_interval_ms", self.scheduler.io_poll_interval_ms)?;
+        check_interval("scheduler.stats_period_ms", self.scheduler.stats_period_ms)?;
+
+        if self.websocket.enabled {
+            self.websocket
+                .address
+                .parse::<SocketAddr>()
+                .map_err(|err| invalid("websocket.address", err.to_string()))?;
+            if let Some(static_dir) = &self.websocket.static_dir
+                && !Path::new(static_dir).join("index.html").is_file()
+            {
+                return Err(invalid(
+                    "websocket.static_dir",
+                    format!("no index.html in \"{}\", build the web interface first", static_dir),
+                ));
+            }
+        }
+
+        if self.tcp.enabled {
+            self.tcp
+                .address
+                .parse::<SocketAddr>()
+                .map_err(|err| invalid("tcp.address", err.to_string()))?;
+        }
+
+        if self.udp.enabled {
+            self.udp
+                .address
+                .parse::<SocketAddr>()
+                .map_err(|err| invalid("udp.address", err.to_string()))?;
+            check_interval("udp.peer_timeout_ms", self.udp.peer_timeout_ms)?;
+        }
+
+        if self.foxglove.enabled {
+            self.foxglove
+                .address
+                .parse::<SocketAddr>()
+                .map_err(|err| invalid("foxglove.address", err.to_string()))?;
+        }
+
+        if self.rosbridge.enabled {
+            self.rosbridge
+                .address
+                .parse::<SocketAddr>()
+                .map_err(|err| invalid("rosbridge.address", err.to_string()))?;
+        }
+
+        let mqtt = &self.mqtt;
+        if mqtt.enabled {
+            let port = mqtt.broker.rsplit_once(':').map(|(_, port)| port.parse::<u16>());
+            if !matches!(port, Some(Ok(_))) {
+                return Err(invalid("mqtt.broker", "must be a host and port, eg \"broker:1883\""));
+            }
+            if mqtt.robot.is_empty() || mqtt.robot.contains(['/', '+', '#']) {
+                return Err(invalid("mqtt.robot", "must be a name without '/', '+' or '#'"));
+            }
+            let known: Vec<&str> = topic_schemas().into_iter().map(|(topic, _)| topic).collect();
+            for (field, topics) in [
+                ("mqtt.publish_topics", &mqtt.publish_topics),
+                ("mqtt.subscribe_topics", &mqtt.subscribe_topics),
+            ] {
+                if let Some(topic) = topics.iter().find(|topic| !known.contains(&topic.as_str())) {
+                    return Err(invalid(field, format!("unknown topic \"{}\"", topic)));
+                }
+            }
+            // Otherwise what we publish would come straight back to us
+            if let Some(topic) = mqtt
+                .subscribe_topics
+                .iter()
+                .find(|topic| mqtt.publish_topics.contains(topic))
+            {
+                return Err(invalid(
+                    "mqtt.subscribe_topics",
+                    format!("\"{}\" is also in publish_topics", topic),
+                ));
+            }
+            if mqtt.keep_alive_s == 0 {
+                return Err(invalid("mqtt.keep_alive_s", "must be greater than zero"));
+            }
+            check_interval("mqtt.reconnect_min_ms", mqtt.reconnect_min_ms)?;
+            if mqtt.reconnect_max_ms < mqtt.reconnect_min_ms {
+                return Err(invalid("mqtt.reconnect_max_ms", "must be at least reconnect_min_ms"));
+            }
+        }
+
+        if self.serial.enabled {
+            check_interval("serial.scan_interval_ms", self.serial.scan_interval_ms)?;
+            let mut names = HashSet::new();
+            for device in self.serial.devices.iter() {
+                // Names are reported in diagnostics, which only have room for 16 characters
+                if device.name.is_empty() || device.name.len() > 16 {
+                    return Err(invalid("serial.devices.name", "must be 1 to 16 characters long"));
+                }
+                if !names.insert(device.name.as_str()) {
+                    return Err(invalid(
+                        "serial.devices.name",
+                        format!("\"{}\" is used by more than one device", device.name),
+                    ));
+                }
+                if device.vid.is_none()
+                    && device.pid.is_none()
+                    && device.serial_number.is_none()
+                    && device.manufacturer.is_none()
+                    && device.path.is_none()
+                    && device.fallback_path.is_none()
+                {
+                    return Err(invalid(
+                        "serial.devices",
+                        format!(
+                            "\"{}\" must set at least one of vid, pid, serial_number, \
+                             manufacturer, path or fallback_path",
+                            device.name
+                        ),
+                    ));
+                }
+                if let Some(path) = &device.path
+                    && let Some((directory, _)) = path.rsplit_once('/')
+                    && directory.contains(['*', '?'])
+                {
+                    return Err(invalid(
+                        "serial.devices.path",
+                        format!("\"{}\" has wildcards outside the last path component", path),
+                    ));
+                }
+                if device.baud_rate == 0 {
+                    return Err(invalid("serial.devices.baud_rate", "must be greater than zero"));
+                }
+            }
+        }
+
+        let estimator = &self.position_estimator;
+        if estimator.enabled {
+            check_interval(
+                "position_estimator.publish_interval_ms",
+                estimator.publish_interval_ms,
+            )?;
+            for (field, value) in [
+                ("position_estimator.translation_noise", estimator.translation_noise),
+                ("position_estimator.rotation_noise", estimator.rotation_noise),
+                ("position_estimator.drift_noise", estimator.drift_noise),
+            ] {
+                check_non_negative(field, value)?;
+            }
+            check_interval("position_estimator.history_ms", estimator.history_ms)?;
+            if estimator.use_imu {
+                check_positive("position_estimator.gyro_noise", estimator.gyro_noise)?;
+                for (field, value) in [
+                    ("position_estimator.gyro_bias_drift", estimator.gyro_bias_drift),
+                    ("position_estimator.initial_gyro_bias", estimator.initial_gyro_bias),
+                ] {
+                    check_non_negative(field, value)?;
+                }
+                check_interval("position_estimator.imu_timeout_ms", estimator.imu_timeout_ms)?;
+            }
+        }
+
+        let motion = &self.motion_controller;
+        if motion.enabled {
+            check_interval("motion_controller.publish_interval_ms", motion.publish_interval_ms)?;
+            check_interval("motion_controller.max_command_age_ms", motion.max_command_age_ms)?;
+            check_positive("motion_controller.kp_linear", motion.kp_linear)?;
+            check_positive("motion_controller.kp_angular", motion.kp_angular)?;
+            check_positive("motion_controller.max_linear_velocity", motion.max_linear_velocity)?;
+            check_positive("motion_controller.max_angular_velocity", motion.max_angular_velocity)?;
+            check_positive(
+                "motion_controller.max_linear_acceleration",
+                motion.max_linear_acceleration,
+            )?;
+            check_positive(
+                "motion_controller.max_angular_acceleration",
+                motion.max_angular_acceleration,
+            )?;
+            check_positive("motion_controller.position_tolerance", motion.position_tolerance)?;
+            check_positive("motion_controller.heading_tolerance", motion.heading_tolerance)?;
+            check_positive("motion_controller.lookahead_distance", motion.lookahead_distance)?;
+        }
+
+        let missions = &self.mission_executor;
+        if missions.enabled
+            && let Some(file) = &missions.file
+        {
+            crate::mission::load(Path::new(file))
+                .map_err(|err| invalid("mission_executor.file", err.to_string()))?;
+        }
+
+        let safety = &self.safety_supervisor;
+        if safety.enabled {
+            check_interval("safety_supervisor.check_interval_ms", safety.check_interval_ms)?;
+            if safety.topics.len() > MAX_WATCHED_TOPICS {
+                return Err(invalid(
+                    "safety_supervisor.topics",
+                    format!("can watch at most {} topics", MAX_WATCHED_TOPICS),
+                ));
+            }
+            let known: Vec<&str> = topic_schemas().into_iter().map(|(topic, _)| topic).collect();
+            for watched in safety.topics.iter() {
+                if !known.contains(&watched.topic.as_str()) {
+                    return Err(invalid(
+                        "safety_supervisor.topics.topic",
+                        format!("unknown topic \"{}\"", watched.topic),
+                    ));
+                }
+                check_interval("safety_supervisor.topics.timeout_ms", watched.timeout_ms)?;
+            }
+        }
+
+        let recorder = &self.recorder;
+        if recorder.enabled {
+            if recorder.topics.is_empty() {
+                return Err(invalid("recorder.topics", "must list at least one topic, or \"all\""));
+            }
+            if recorder.directory.is_empty() {
+                return Err(invalid("recorder.directory", "must not be empty"));
+            }
+            if recorder.max_file_mb == Some(0) {
+                return Err(invalid("recorder.max_file_mb", "must be greater than zero"));
+            }
+            if recorder.max_file_duration_s == Some(0) {
+                return Err(invalid("recorder.max_file_duration_s", "must be greater than zero"));
+            }
+        }
+
+        let player = &self.player;
+        if player.enabled {
+            if !Path::new(&player.file).is_file() {
+                return Err(invalid("player.file", format!("no such file \"{}\"", player.file)));
+            }
+            check_positive("player.rate", player.rate)?;
+            if !player.start_offset_s.is_finite() || player.start_offset_s < 0.0 {
+                return Err(invalid("player.start_offset_s", "must not be negative"));
+            }
+            if let Some(end_offset_s) = player.end_offset_s
+                && (end_offset_s.is_nan() || end_offset_s <= player.start_offset_s)
+            {
+                return Err(invalid("player.end_offset_s", "must be after start_offset_s"));
+            }
+            if player.topics.is_empty() {
+                return Err(invalid("player.topics", "must list at least one topic, or \"all\""));
+            }
+        }
+
+        let sim = &self.simulated_base;
+        if sim.enabled {
+            if self.serial.enabled {
+                return Err(invalid(
+                    "simulated_base.enabled",
+                    "the simulator stands in for the motor controller, disable serial to use it",
+                ));
+            }
+            check_interval("simulated_base.publish_interval_ms", sim.publish_interval_ms)?;
+            check_interval("simulated_base.command_timeout_ms", sim.command_timeout_ms)?;
+            check_non_negative("simulated_base.noise_std", sim.noise_std)?;
+            if !(0.0..1.0).contains(&sim.slip) {
+                return Err(invalid("simulated_base.slip", "must be at least 0 and less than 1"));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl SchedulerConfig {
+    pub fn io_poll_interval(&self) -> Duration {
+        Duration::from_millis(self.io_poll_interval_ms)
+    }
+
+    pub fn stats_period(&self) -> Duration {
+        Duration::from_millis(self.stats_period_ms)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Result<Config, ConfigError> {
+        let config: Config =
+            toml::from_str(text).map_err(|err| ConfigError::Parse(PathBuf::from("test"), err))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    #[test]
+    fn test_example_config_is_valid() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("robot.toml");
+        let config = Config::load(&path).unwrap();
+        assert_eq!(config.serial.devices[0].name, "motor_controller");
+        assert_eq!(config.serial.devices[0].vid, Some(0x303A));
+        assert_eq!(config.websocket.address, "127.0.0.1:9001");
+
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("sim.toml");
+        let config = Config::load(&path).unwrap();
+        assert!(config.simulated_base.enabled);
+    }
+
+    #[test]
+    fn test_empty_config_uses_defaults() {
+        let config = parse("").unwrap();
+        assert!(config.log.enabled);
+        assert!(!config.log.log_all);
+        assert_eq!(config.motion_controller.kp_linear, 2.0);
+        assert_eq!(config.serial.devices[0].baud_rate, 115_200);
+        assert_eq!(config.serial.devices[0].vid, Some(0x303A));
+        assert_eq!(
+            config.serial.devices[0].fallback_path.as_deref(),
+            Some("*usb-Espressif_USB_JTAG_serial_debug_unit*")
+        );
+    }
+
+    #[test]
+    fn test_partial_section() {
+        let config = parse("[motion_controller]\nkp_angular = 3.5\n[log]\nenabled = false\n").unwrap();
+        assert_eq!(config.motion_controller.kp_angular, 3.5);
+        assert_eq!(config.motion_controller.kp_linear, 2.0);
+        assert!(!config.log.enabled);
+    }
+
+    #[test]
+    fn test_serial_devices() {
+        let config = parse(
+            "[[serial.devices]]\nname = \"motors\"\nvid = 0x303A\nserial_number = \"F4:12:*\"\n\
+             [[serial.devices]]\nname = \"sensors\"\npath = \"/dev/ttyUSB*\"\nbaud_rate = 921600\n",
+        )
+        .unwrap();
+        let devices = &config.serial.devices;
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices[0].baud_rate, 115_200);
+        assert_eq!(devices[0].serial_number.as_deref(), Some("F4:12:*"));
+        assert_eq!(devices[1].baud_rate, 921_600);
+
+        let err = parse(
+            "[[serial.devices]]\nname = \"a\"\nvid = 1\n[[serial.devices]]\nname = \"a\"\npid = 2\n",
+        )
+        .unwrap_err();
+        assert!(matches!(err, ConfigError::Invalid { field: "serial.devices.name", .. }));
+
+        let err = parse("[[serial.devices]]\nname = \"any\"\n").unwrap_err();
+        assert!(matches!(err, ConfigError::Invalid { field: "serial.devices", .. }));
+
+        let err = parse("[[serial.devices]]\nname = \"a\"\npath = \"/dev/*/tty\"\n").unwrap_err();
+        assert!(matches!(err, ConfigError::Invalid { field: "serial.devices.path", .. }));
+    }
+
+    #[test]
+    fn test_unknown_field_is_rejected() {
+        let err = parse("[motion_controller]\nkp_linaer = 1.0\n").unwrap_err();
+        assert!(err.to_string().contains("kp_linaer"), "{}", err);
+    }
+
+    #[test]
+    fn test_invalid_values_are_rejected() {
+        let err = parse("[websocket]\naddress = \"localhost\"\n").unwrap_err();
+        assert!(matches!(err, ConfigError::Invalid { field: "websocket.address", .. }));
+
+        let err = parse("[motion_controller]\nkp_linear = -1.0\n").unwrap_err();
+        assert!(matches!(err, ConfigError::Invalid { field: "motion_controller.kp_linear", .. }));
+
+        let err = parse("[position_estimator]\ndrift_noise = -0.1\n").unwrap_err();
+        assert!(matches!(
+            err,
+            ConfigError::Invalid { field: "position_estimator.drift_noise", .. }
+        ));
+
+        let err = parse("[player]\nenabled = true\nfile = \"/no/such/bag.sbag\"\n").unwrap_err();
+        assert!(matches!(err, ConfigError::Invalid { field: "player.file", .. }));
+
+        let err = parse("[mission_executor]\nenabled = true\nfile = \"/no/such/mission.toml\"\n")
+            .unwrap_err();
+        assert!(matches!(err, ConfigError::Invalid { field: "mission_executor.file", .. }));
+
+        let err = parse(
+            "[safety_supervisor]\nenabled = true\n\
+             [[safety_supervisor.topics]]\ntopic = \"Odometry\"\n",
+        )
+        .unwrap_err();
+        assert!(matches!(
+            err,
+            ConfigError::Invalid { field: "safety_supervisor.topics.topic", .. }
+        ));
+
+        let err = parse("[simulated_base]\nenabled = true\n").unwrap_err();
+        assert!(matches!(err, ConfigError::Invalid { field: "simulated_base.enabled", .. }));
+        parse("[simulated_base]\nenabled = true\n[serial]\nenabled = false\n").unwrap();
+
+        let err = parse("[mqtt]\nenabled = true\npublish_topics = [\"Nope\"]\n").unwrap_err();
+        assert!(matches!(err, ConfigError::Invalid { field: "mqtt.publish_topics", .. }));
+
+        let err = parse(
+            "[mqtt]\nenabled = true\nsubscribe_topics = [\"PositionEstimate\"]\n",
+        )
+        .unwrap_err();
+        assert!(matches!(err, ConfigError::Invalid { field: "mqtt.subscribe_topics", .. }));
+
+        // Disabled nodes aren't validated
+        parse("[serial]\nenabled = false\nscan_interval_ms = 0\n").unwrap();
+    }
+}
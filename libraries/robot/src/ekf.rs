@@ -0,0 +1,750 @@
+/*!
+ * Extended Kalman filter for the robot's planar pose. The pose is `[x, y, orientation]` with the
+ * same conventions as `OdometryDelta` (at orientation 0 the robot faces +y, positive rotation is
+ * anticlockwise). Odometry, and the gyro when there is one, drive the prediction step, and
+ * absolute measurements of any size can be folded in with `Ekf::update`.
+ */
+use std::f64::consts::PI;
+
+/** Row-major matrix, `R` rows by `C` columns */
+pub type Matrix<const R: usize, const C: usize> = [[f64; C]; R];
+
+/** Number of state variables: the pose, the gyro's bias and a copy of the orientation */
+pub const STATE_SIZE: usize = 5;
+/** The pose is the first three state variables */
+pub const POSE_SIZE: usize = 3;
+
+pub const X: usize = 0;
+pub const Y: usize = 1;
+pub const ORIENTATION: usize = 2;
+/** The gyro's yaw rate bias (rad/s) */
+pub const GYRO_BIAS: usize = 3;
+/**
+ * The orientation when odometry was last applied. The wheels' rotation is compared against how
+ * far the orientation has moved since, which is how the gyro's bias is observed.
+ */
+pub const LAST_ORIENTATION: usize = 4;
+
+pub fn identity<const N: usize>() -> Matrix<N, N> {
+    let mut result = [[0.0; N]; N];
+    for (i, row) in result.iter_mut().enumerate() {
+        row[i] = 1.0;
+    }
+    result
+}
+
+pub fn diagonal<const N: usize>(values: [f64; N]) -> Matrix<N, N> {
+    let mut result = [[0.0; N]; N];
+    for (i, value) in values.into_iter().enumerate() {
+        result[i][i] = value;
+    }
+    result
+}
+
+fn multiply<const R: usize, const K: usize, const C: usize>(
+    a: &Matrix<R, K>,
+    b: &Matrix<K, C>,
+) -> Matrix<R, C> {
+    let mut result = [[0.0; C]; R];
+    for (r, row) in result.iter_mut().enumerate() {
+        for (c, value) in row.iter_mut().enumerate() {
+            *value = (0..K).map(|k| a[r][k] * b[k][c]).sum();
+        }
+    }
+    result
+}
+
+fn transpose<const R: usize, const C: usize>(a: &Matrix<R, C>) -> Matrix<C, R> {
+    let mut result = [[0.0; R]; C];
+    for (r, row) in a.iter().enumerate() {
+        for (c, value) in row.iter().enumerate() {
+            result[c][r] = *value;
+        }
+    }
+    result
+}
+
+fn add<const R: usize, const C: usize>(a: &Matrix<R, C>, b: &Matrix<R, C>) -> Matrix<R, C> {
+    let mut result = *a;
+    for (row, b_row) in result.iter_mut().zip(b) {
+        for (value, b_value) in row.iter_mut().zip(b_row) {
+            *value += b_value;
+        }
+    }
+    result
+}
+
+/** `a * b * a^T`, how a covariance `b` is carried through the linear map `a` */
+fn sandwich<const R: usize, const C: usize>(a: &Matrix<R, C>, b: &Matrix<C, C>) -> Matrix<R, R> {
+    multiply(&multiply(a, b), &transpose(a))
+}
+
+/** Inverts by Gauss-Jordan elimination, or `None` if the matrix is (numerically) singular */
+fn invert<const N: usize>(a: &Matrix<N, N>) -> Option<Matrix<N, N>> {
+    let mut a = *a;
+    let mut result = identity::<N>();
+    for column in 0..N {
+        let pivot = (column..N)
+            .max_by(|&i, &j| a[i][column].abs().total_cmp(&a[j][column].abs()))
+            .unwrap();
+        if a[pivot][column].abs() < 1e-12 {
+            return None;
+        }
+        a.swap(column, pivot);
+        result.swap(column, pivot);
+        let scale = a[column][column];
+        for c in 0..N {
+            a[column][c] /= scale;
+            result[column][c] /= scale;
+        }
+        for row in 0..N {
+            if row != column {
+                let factor = a[row][column];
+                for c in 0..N {
+                    a[row][c] -= factor * a[column][c];
+                    result[row][c] -= factor * result[column][c];
+                }
+            }
+        }
+    }
+    Some(result)
+}
+
+/** Wraps an angle to [-PI, PI) */
+pub fn wrap_angle(angle: f64) -> f64 {
+    (angle + PI).rem_euclid(2.0 * PI) - PI
+}
+
+#[derive(Debug, PartialEq)]
+pub enum EkfError {
+    /** The innovation covariance can't be inverted, eg both state and measurement are exact */
+    Singular,
+    /** The measurement contains a NaN or infinity */
+    NotFinite,
+}
+
+impl std::fmt::Display for EkfError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EkfError::Singular => write!(f, "innovation covariance is singular"),
+            EkfError::NotFinite => write!(f, "measurement is not finite"),
+        }
+    }
+}
+
+impl std::error::Error for EkfError {}
+
+/**
+ * How uncertain odometry is. Each standard deviation scales with the motion in the delta, so a
+ * stationary robot doesn't become less certain of where it is.
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct OdometryNoise {
+    /** Standard deviation of each axis of travel, as a fraction of the distance travelled */
+    pub translation: f64,
+    /** Standard deviation of rotation, as a fraction of the rotation */
+    pub rotation: f64,
+    /** Standard deviation of rotation per m travelled (rad/m), eg from uneven wheel slip */
+    pub drift: f64,
+}
+
+/** How uncertain the gyro's yaw rate is */
+#[derive(Debug, Clone, Copy)]
+pub struct GyroNoise {
+    /** Angle random walk: standard deviation of the integrated yaw after 1s (rad/sqrt(s)) */
+    pub rate: f64,
+    /** How fast the bias wanders: standard deviation of its change over 1s (rad/s/sqrt(s)) */
+    pub bias_drift: f64,
+    /** Standard deviation of the bias before it has been estimated (rad/s) */
+    pub initial_bias: f64,
+}
+
+/**
+ * The wheels' rotation is never trusted more than this (rad^2), even when they haven't turned,
+ * so that the heading correction stays well conditioned.
+ */
+const MIN_ROTATION_VARIANCE: f64 = 1e-8;
+
+pub struct Ekf {
+    state: [f64; STATE_SIZE],
+    covariance: Matrix<STATE_SIZE, STATE_SIZE>,
+    odometry_noise: OdometryNoise,
+    gyro_noise: GyroNoise,
+}
+
+impl Ekf {
+    /** Starts at the origin, with no uncertainty in the pose */
+    pub fn new(odometry_noise: OdometryNoise, gyro_noise: GyroNoise) -> Self {
+        let mut covariance = [[0.0; STATE_SIZE]; STATE_SIZE];
+        covariance[GYRO_BIAS][GYRO_BIAS] = gyro_noise.initial_bias.powi(2);
+        Ekf {
+            state: [0.0; STATE_SIZE],
+            covariance,
+            odometry_noise,
+            gyro_noise,
+        }
+    }
+
+    pub fn position(&self) -> [f64; 2] {
+        [self.state[X], self.state[Y]]
+    }
+
+    /** Not wrapped, so that it is continuous as the robot turns */
+    pub fn orientation(&self) -> f64 {
+        self.state[ORIENTATION]
+    }
+
+    /** Covariance of `[x, y, orientation]` */
+    pub fn covariance(&self) -> Matrix<POSE_SIZE, POSE_SIZE> {
+        let mut result = [[0.0; POSE_SIZE]; POSE_SIZE];
+        for (row, filter_row) in result.iter_mut().zip(&self.covariance) {
+            row.copy_from_slice(&filter_row[..POSE_SIZE]);
+        }
+        result
+    }
+
+    /** The gyro's estimated yaw rate bias (rad/s), and its standard deviation */
+    pub fn gyro_bias(&self) -> (f64, f64) {
+        (self.state[GYRO_BIAS], self.covariance[GYRO_BIAS][GYRO_BIAS].sqrt())
+    }
+
+    /**
+     * Jumps to `pose`, with `covariance` as its uncertainty. The gyro's bias is kept, but no
+     * longer correlated with the pose.
+     */
+    pub fn set_pose(
+        &mut self,
+        pose: [f64; POSE_SIZE],
+        covariance: &Matrix<POSE_SIZE, POSE_SIZE>,
+    ) -> Result<(), EkfError> {
+        if pose.iter().chain(covariance.iter().flatten()).any(|value| !value.is_finite()) {
+            return Err(EkfError::NotFinite);
+        }
+        self.state[..POSE_SIZE].copy_from_slice(&pose);
+        let bias_variance = self.covariance[GYRO_BIAS][GYRO_BIAS];
+        self.covariance = [[0.0; STATE_SIZE]; STATE_SIZE];
+        for (row, pose_row) in self.covariance.iter_mut().zip(covariance) {
+            row[..POSE_SIZE].copy_from_slice(pose_row);
+        }
+        self.covariance[GYRO_BIAS][GYRO_BIAS] = bias_variance;
+        self.clone_orientation();
+        Ok(())
+    }
+
+    /** Variance of the wheels' rotation over a delta */
+    fn rotation_variance(&self, delta_position: [f64; 2], delta_orientation: f64) -> f64 {
+        let distance = delta_position[0].hypot(delta_position[1]);
+        (self.odometry_noise.rotation * delta_orientation).powi(2)
+            + (self.odometry_noise.drift * distance).powi(2)
+    }
+
+    /** Moves by `delta_position` in the robot's frame at the state's `orientation` entry */
+    fn predict_travel(&mut self, delta_position: [f64; 2], orientation: usize) {
+        let [dx, dy] = delta_position;
+        let (sin, cos) = self.state[orientation].sin_cos();
+        let world_dx = dx * cos - dy * sin;
+        let world_dy = dx * sin + dy * cos;
+
+        // Jacobians of the motion with respect to the state and to the travel
+        let mut state_jacobian = identity::<STATE_SIZE>();
+        state_jacobian[X][orientation] = -world_dy;
+        state_jacobian[Y][orientation] = world_dx;
+        let mut travel_jacobian = [[0.0; 2]; STATE_SIZE];
+        travel_jacobian[X] = [cos, -sin];
+        travel_jacobian[Y] = [sin, cos];
+
+        let travel_variance = (self.odometry_noise.translation * dx.hypot(dy)).powi(2);
+        self.state[X] += world_dx;
+        self.state[Y] += world_dy;
+        self.covariance = add(
+            &sandwich(&state_jacobian, &self.covariance),
+            &sandwich(&travel_jacobian, &diagonal([travel_variance, travel_variance])),
+        );
+    }
+
+    /** Copies the orientation into `LAST_ORIENTATION`, including its covariance */
+    fn clone_orientation(&mut self) {
+        self.state[LAST_ORIENTATION] = self.state[ORIENTATION];
+        for row in self.covariance.iter_mut() {
+            row[LAST_ORIENTATION] = row[ORIENTATION];
+        }
+        self.covariance[LAST_ORIENTATION] = self.covariance[ORIENTATION];
+    }
+
+    /**
+     * Prediction step from odometry alone. Applies an `OdometryDelta`: the travel in the robot's
+     * frame at the start of the delta, as the firmware integrates it, then the rotation.
+     */
+    pub fn predict_odometry(&mut self, delta_position: [f64; 2], delta_orientation: f64) {
+        self.predict_travel(delta_position, ORIENTATION);
+        self.state[ORIENTATION] += delta_orientation;
+        self.covariance[ORIENTATION][ORIENTATION] +=
+            self.rotation_variance(delta_position, delta_orientation);
+        self.clone_orientation();
+    }
+
+    /**
+     * Applies an `OdometryDelta` that arrived after later ones had been applied. Its travel
+     * happened at `travel_orientation` (from the pose history) rather than at the current
+     * orientation. The robot was at `pivot` at the end of the delta, as far as the later ones
+     * knew, and its rotation turns all the travel since then. Returns the change in the pose at
+     * the end of the delta, for `PoseHistory::shift_from`.
+     */
+    pub fn predict_late_odometry(
+        &mut self,
+        delta_position: [f64; 2],
+        delta_orientation: f64,
+        travel_orientation: f64,
+        pivot: [f64; 2],
+    ) -> [f64; POSE_SIZE] {
+        let [dx, dy] = delta_position;
+        let (sin, cos) = travel_orientation.sin_cos();
+        let world_dx = dx * cos - dy * sin;
+        let world_dy = dx * sin + dy * cos;
+
+        // The travel since the pivot, turned by the delta's rotation
+        let (turn_sin, turn_cos) = delta_orientation.sin_cos();
+        let [offset_x, offset_y] = [self.state[X] - pivot[0], self.state[Y] - pivot[1]];
+        let turned_x = offset_x * turn_cos - offset_y * turn_sin;
+        let turned_y = offset_x * turn_sin + offset_y * turn_cos;
+        let mut jacobian = identity::<STATE_SIZE>();
+        jacobian[X][X] = turn_cos;
+        jacobian[X][Y] = -turn_sin;
+        jacobian[Y][X] = turn_sin;
+        jacobian[Y][Y] = turn_cos;
+
+        // Jacobian of the new state with respect to the delta. The travel is in the frame at the
+        // start of the delta, so where the delta itself ends doesn't depend on its rotation: an
+        // error in the rotation only swings the travel since the pivot about it.
+        let mut odometry_jacobian = [[0.0; 3]; STATE_SIZE];
+        odometry_jacobian[X] = [cos, -sin, -turned_y];
+        odometry_jacobian[Y] = [sin, cos, turned_x];
+        odometry_jacobian[ORIENTATION][2] = 1.0;
+        odometry_jacobian[LAST_ORIENTATION][2] = 1.0;
+        let travel_variance = (self.odometry_noise.translation * dx.hypot(dy)).powi(2);
+        let rotation_variance = self.rotation_variance(delta_position, delta_orientation);
+        let odometry_covariance = diagonal([travel_variance, travel_variance, rotation_variance]);
+
+        self.state[X] = pivot[0] + world_dx + turned_x;
+        self.state[Y] = pivot[1] + world_dy + turned_y;
+        self.state[ORIENTATION] += delta_orientation;
+        self.state[LAST_ORIENTATION] += delta_orientation;
+        let odometry_covariance = sandwich(&odometry_jacobian, &odometry_covariance);
+        self.covariance = add(&sandwich(&jacobian, &self.covariance), &odometry_covariance);
+        [world_dx, world_dy, delta_orientation]
+    }
+
+    /** Prediction step from the gyro: turns by the bias corrected yaw rate over `dt` seconds */
+    pub fn predict_gyro(&mut self, yaw_rate: f64, dt: f64) {
+        self.state[ORIENTATION] += (yaw_rate - self.state[GYRO_BIAS]) * dt;
+        let mut jacobian = identity::<STATE_SIZE>();
+        jacobian[ORIENTATION][GYRO_BIAS] = -dt;
+        self.covariance = sandwich(&jacobian, &self.covariance);
+        self.covariance[ORIENTATION][ORIENTATION] += self.gyro_noise.rate.powi(2) * dt;
+        self.covariance[GYRO_BIAS][GYRO_BIAS] += self.gyro_noise.bias_drift.powi(2) * dt;
+    }
+
+    /**
+     * Applies an `OdometryDelta` while the gyro is tracking the orientation (with
+     * `predict_gyro`). The travel is applied at the orientation the delta started at, and the
+     * wheels' rotation is a measurement of how far the robot turned since then, which corrects
+     * the gyro's heading and its bias.
+     */
+    pub fn fuse_odometry(
+        &mut self,
+        delta_position: [f64; 2],
+        delta_orientation: f64,
+    ) -> Result<f64, EkfError> {
+        self.predict_travel(delta_position, LAST_ORIENTATION);
+        let turned = self.state[ORIENTATION] - self.state[LAST_ORIENTATION];
+        let mut jacobian = [[0.0; STATE_SIZE]];
+        jacobian[0][ORIENTATION] = 1.0;
+        jacobian[0][LAST_ORIENTATION] = -1.0;
+        // Scaled by the gyro's turn rather than the wheels', which would make the wheels more
+        // trusted whenever they under-report and bias the estimate
+        let variance = self.rotation_variance(delta_position, turned).max(MIN_ROTATION_VARIANCE);
+        let result = self.update([delta_orientation - turned], &jacobian, &[[variance]]);
+        self.clone_orientation();
+        result
+    }
+
+    /**
+     * Measurement update, for any measurement `z = h(state)`. `residual` is `z - h(state)` (with
+     * angles wrapped), `jacobian` is the derivative of `h` and `noise` is the measurement's
+     * covariance. Returns the squared Mahalanobis distance of the residual, which callers can use
+     * to reject outliers.
+     */
+    pub fn update<const M: usize>(
+        &mut self,
+        residual: [f64; M],
+        jacobian: &Matrix<M, STATE_SIZE>,
+        noise: &Matrix<M, M>,
+    ) -> Result<f64, EkfError> {
+        if residual.iter().chain(noise.iter().flatten()).any(|value| !value.is_finite()) {
+            return Err(EkfError::NotFinite);
+        }
+        let innovation_covariance = add(&sandwich(jacobian, &self.covariance), noise);
+        let inverse = invert(&innovation_covariance).ok_or(EkfError::Singular)?;
+        let residual_column = residual.map(|value| [value]);
+        let distance = multiply(&multiply(&[residual], &inverse), &residual_column)[0][0];
+
+        let gain = multiply(&multiply(&self.covariance, &transpose(jacobian)), &inverse);
+        let correction = multiply(&gain, &residual_column);
+        for (value, correction) in self.state.iter_mut().zip(correction) {
+            *value += correction[0];
+        }
+
+        // Joseph form, which keeps the covariance symmetric and positive definite
+        let mut keep = identity::<STATE_SIZE>();
+        let gain_jacobian = multiply(&gain, jacobian);
+        for (row, gain_row) in keep.iter_mut().zip(gain_jacobian) {
+            for (value, gain_value) in row.iter_mut().zip(gain_row) {
+                *value -= gain_value;
+            }
+        }
+        self.covariance = add(&sandwich(&keep, &self.covariance), &sandwich(&gain, noise));
+        Ok(distance)
+    }
+
+    /** Measurement update from an absolute pose fix `[x, y, orientation]` */
+    pub fn update_pose(
+        &mut self,
+        pose: [f64; POSE_SIZE],
+        covariance: &Matrix<POSE_SIZE, POSE_SIZE>,
+    ) -> Result<f64, EkfError> {
+        let residual = [
+            pose[X] - self.state[X],
+            pose[Y] - self.state[Y],
+            wrap_angle(pose[ORIENTATION] - self.state[ORIENTATION]),
+        ];
+        let mut jacobian = [[0.0; STATE_SIZE]; POSE_SIZE];
+        for (i, row) in jacobian.iter_mut().enumerate() {
+            row[i] = 1.0;
+        }
+        self.update(residual, &jacobian, covariance)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOISE: OdometryNoise = OdometryNoise {
+        translation: 0.05,
+        rotation: 0.05,
+        drift: 0.02,
+    };
+
+    const GYRO: GyroNoise = GyroNoise {
+        rate: 0.005,
+        bias_drift: 0.0005,
+        initial_bias: 0.05,
+    };
+
+    /** Small xorshift generator, so that the simulated noise is repeatable */
+    struct Rng(u64);
+
+    impl Rng {
+        fn gaussian(&mut self) -> f64 {
+            let mut uniform = || {
+                self.0 ^= self.0 << 13;
+                self.0 ^= self.0 >> 7;
+                self.0 ^= self.0 << 17;
+                ((self.0 >> 11) as f64 / (1u64 << 53) as f64).max(f64::MIN_POSITIVE)
+            };
+            let (u1, u2) = (uniform(), uniform());
+            (-2.0 * u1.ln()).sqrt() * (2.0 * PI * u2).cos()
+        }
+    }
+
+    /**
+     * Odometry as the firmware's `update_odometry` accumulates it over `ticks` encoder reads:
+     * each tick's travel is in the frame at the start of the delta, turned by the rotation so far
+     */
+    fn firmware_delta(distance: f64, turn: f64, ticks: usize) -> ([f64; 2], f64) {
+        let mut delta_position = [0.0, 0.0];
+        let mut delta_orientation = 0.0;
+        for _ in 0..ticks {
+            let step = distance / ticks as f64;
+            delta_position[0] -= step * f64::sin(delta_orientation);
+            delta_position[1] += step * f64::cos(delta_orientation);
+            delta_orientation += turn / ticks as f64;
+        }
+        (delta_position, delta_orientation)
+    }
+
+    /**
+     * Odometry for driving a 1m radius circle anticlockwise in `steps`: the chord of each arc, in
+     * the frame at its start, which is what `firmware_delta` tends to with many ticks
+     */
+    fn circle_step(steps: usize) -> ([f64; 2], f64) {
+        let angle = 2.0 * PI / steps as f64;
+        let chord = 2.0 * (angle / 2.0).sin();
+        let (sin, cos) = (angle / 2.0).sin_cos();
+        ([-chord * sin, chord * cos], angle)
+    }
+
+    fn pose(ekf: &Ekf) -> [f64; POSE_SIZE] {
+        [ekf.state[X], ekf.state[Y], ekf.state[ORIENTATION]]
+    }
+
+    /** Squared Mahalanobis distance of the estimate's error from `truth` */
+    fn error_distance(ekf: &Ekf, truth: [f64; POSE_SIZE]) -> f64 {
+        let error = [
+            ekf.state[X] - truth[X],
+            ekf.state[Y] - truth[Y],
+            wrap_angle(ekf.state[ORIENTATION] - truth[ORIENTATION]),
+        ];
+        let inverse = invert(&ekf.covariance()).unwrap();
+        multiply(&multiply(&[error], &inverse), &error.map(|value| [value]))[0][0]
+    }
+
+    #[test]
+    fn test_invert() {
+        let a = [[4.0, 7.0, 2.0], [3.0, 6.0, 1.0], [2.0, 5.0, 3.0]];
+        let product = multiply(&a, &invert(&a).unwrap());
+        for (r, row) in product.iter().enumerate() {
+            for (c, value) in row.iter().enumerate() {
+                assert!((value - identity::<3>()[r][c]).abs() < 1e-9);
+            }
+        }
+        assert_eq!(invert(&[[1.0, 2.0], [2.0, 4.0]]), None);
+    }
+
+    #[test]
+    fn test_noiseless_trajectory() {
+        // Without noise, driving a circle comes back to the start exactly
+        let mut ekf = Ekf::new(NOISE, GYRO);
+        let (delta_position, delta_orientation) = circle_step(100);
+        let (firmware_position, firmware_orientation) =
+            firmware_delta(2.0 * PI / 100.0, delta_orientation, 1000);
+        assert!((firmware_position[0] - delta_position[0]).abs() < 1e-5);
+        assert!((firmware_position[1] - delta_position[1]).abs() < 1e-5);
+        assert!((firmware_orientation - delta_orientation).abs() < 1e-9);
+        for _ in 0..100 {
+            ekf.predict_odometry(delta_position, delta_orientation);
+        }
+        assert!(ekf.position()[X].abs() < 1e-9 && ekf.position()[Y].abs() < 1e-9);
+        assert!((ekf.orientation() - 2.0 * PI).abs() < 1e-9);
+
+        // Uncertainty grew, and the covariance is still symmetric
+        let covariance = ekf.covariance();
+        assert!(covariance[X][X] > 0.0 && covariance[Y][Y] > 0.0);
+        assert!(covariance[ORIENTATION][ORIENTATION] > 0.0);
+        for (r, row) in ekf.covariance.iter().enumerate() {
+            for (c, value) in row.iter().enumerate() {
+                assert!((value - ekf.covariance[c][r]).abs() < 1e-12);
+            }
+        }
+
+        // Standing still adds no uncertainty
+        let before = ekf.covariance;
+        ekf.predict_odometry([0.0, 0.0], 0.0);
+        assert_eq!(ekf.covariance, before);
+    }
+
+    #[test]
+    fn test_covariance_matches_simulated_error() {
+        // Drive noisy circles many times. If the covariance is right, the average squared
+        // Mahalanobis distance of the error is the number of pose variables.
+        let mut rng = Rng(7);
+        let trials = 200;
+        let steps = 50;
+        let mut total_distance = 0.0;
+        for _ in 0..trials {
+            let mut ekf = Ekf::new(NOISE, GYRO);
+            let mut truth = Ekf::new(NOISE, GYRO);
+            let (delta_position, delta_orientation) = circle_step(steps);
+            for _ in 0..steps {
+                let distance = delta_position[0].hypot(delta_position[1]);
+                let travel_std = NOISE.translation * distance;
+                let rotation_std = ((NOISE.rotation * delta_orientation).powi(2)
+                    + (NOISE.drift * distance).powi(2))
+                .sqrt();
+                truth.predict_odometry(
+                    [
+                        delta_position[0] + travel_std * rng.gaussian(),
+                        delta_position[1] + travel_std * rng.gaussian(),
+                    ],
+                    delta_orientation + rotation_std * rng.gaussian(),
+                );
+                ekf.predict_odometry(delta_position, delta_orientation);
+            }
+            total_distance += error_distance(&ekf, pose(&truth));
+        }
+        let mean = total_distance / trials as f64;
+        assert!((2.0..4.0).contains(&mean), "mean squared distance {}", mean);
+    }
+
+    #[test]
+    fn test_late_odometry_covariance() {
+        // A turning delta arrives after 2m of travel that followed it. The covariance should
+        // match the spread of where the robot really ended up.
+        let mut rng = Rng(11);
+        let trials = 300;
+        let (late_position, late_orientation) = circle_step(4);
+        let after = [0.0, 2.0];
+        let mut total_distance = 0.0;
+        for _ in 0..trials {
+            let mut truth = Ekf::new(NOISE, GYRO);
+            let distance = late_position[0].hypot(late_position[1]);
+            let travel_std = NOISE.translation * distance;
+            let rotation_std = ((NOISE.rotation * late_orientation).powi(2)
+                + (NOISE.drift * distance).powi(2))
+            .sqrt();
+            truth.predict_odometry(
+                [
+                    late_position[0] + travel_std * rng.gaussian(),
+                    late_position[1] + travel_std * rng.gaussian(),
+                ],
+                late_orientation + rotation_std * rng.gaussian(),
+            );
+            let travel_std = NOISE.translation * 2.0;
+            let drift_std = NOISE.drift * 2.0;
+            truth.predict_odometry(
+                [travel_std * rng.gaussian(), 2.0 + travel_std * rng.gaussian()],
+                drift_std * rng.gaussian(),
+            );
+
+            let mut ekf = Ekf::new(NOISE, GYRO);
+            ekf.predict_odometry(after, 0.0);
+            ekf.predict_late_odometry(late_position, late_orientation, 0.0, [0.0, 0.0]);
+            total_distance += error_distance(&ekf, pose(&truth));
+        }
+        let mean = total_distance / trials as f64;
+        assert!((2.0..4.0).contains(&mean), "mean squared distance {}", mean);
+
+        // With nothing after it, the rotation adds no uncertainty to the position
+        let mut ekf = Ekf::new(NOISE, GYRO);
+        ekf.predict_late_odometry([0.0, 1.0], 1.0, 0.0, [0.0, 0.0]);
+        let travel_variance = NOISE.translation.powi(2);
+        let covariance = ekf.covariance();
+        assert!((covariance[X][X] - travel_variance).abs() < 1e-12);
+        assert!((covariance[Y][Y] - travel_variance).abs() < 1e-12);
+        assert_eq!(covariance[X][ORIENTATION], 0.0);
+    }
+
+    #[test]
+    fn test_pose_fixes() {
+        let mut rng = Rng(3);
+        let mut ekf = Ekf::new(NOISE, GYRO);
+        let mut truth = Ekf::new(NOISE, GYRO);
+        let fix_covariance = diagonal([0.01f64.powi(2), 0.01f64.powi(2), 0.01f64.powi(2)]);
+        let (delta_position, delta_orientation) = circle_step(40);
+        for step in 1..=400 {
+            // Odometry that consistently under-reports travel and over-reports turning
+            truth.predict_odometry(delta_position, delta_orientation);
+            ekf.predict_odometry(
+                [
+                    delta_position[0] * 0.95,
+                    delta_position[1] * 0.95 + 0.001 * rng.gaussian(),
+                ],
+                delta_orientation * 1.05,
+            );
+            if step % 20 == 0 {
+                let fix = pose(&truth).map(|value| value + 0.01 * rng.gaussian());
+                ekf.update_pose(fix, &fix_covariance).unwrap();
+                assert!(ekf.covariance[X][X] < fix_covariance[X][X]);
+                assert!(error_distance(&ekf, pose(&truth)) < 20.0);
+            }
+        }
+        let position = ekf.position();
+        let truth_position = truth.position();
+        let error = (position[X] - truth_position[X]).hypot(position[Y] - truth_position[Y]);
+        assert!(error < 0.1, "position error {}", error);
+    }
+
+    #[test]
+    fn test_gyro_fusion() {
+        // A skid-steer base turning on the spot and driving circles: its wheels over or under
+        // report each turn by 30%, and its gyro reads 0.03 rad/s high
+        let mut rng = Rng(11);
+        let odometry_noise = OdometryNoise {
+            rotation: 0.3,
+            ..NOISE
+        };
+        let mut fused = Ekf::new(odometry_noise, GYRO);
+        let mut wheels_only = Ekf::new(odometry_noise, GYRO);
+        let bias = 0.03;
+        let imu_dt: f64 = 0.01;
+        let mut true_orientation = 0.0;
+        for step in 0..3000 {
+            // 10 IMU samples per odometry delta, stopping now and then
+            let yaw_rate = if step % 500 < 100 { 0.0 } else { 0.8 * ((step / 700) as f64).cos() };
+            for _ in 0..10 {
+                let noise = GYRO.rate * rng.gaussian() / imu_dt.sqrt();
+                fused.predict_gyro(yaw_rate + bias + noise, imu_dt);
+            }
+            let turned = yaw_rate * imu_dt * 10.0;
+            true_orientation += turned;
+            let wheel_turn = turned * (1.0 + 0.3 * rng.gaussian());
+            let travel = [0.0, if step % 2 == 0 { 0.02 } else { 0.0 }];
+            fused.fuse_odometry(travel, wheel_turn).unwrap();
+            wheels_only.predict_odometry(travel, wheel_turn);
+        }
+
+        let (estimated_bias, bias_std) = fused.gyro_bias();
+        assert!((estimated_bias - bias).abs() < 3.0 * bias_std.max(0.002), "{}", estimated_bias);
+        let fused_error = (fused.orientation() - true_orientation).abs();
+        let wheels_error = (wheels_only.orientation() - true_orientation).abs();
+        assert!(fused_error < 0.1, "fused heading error {}", fused_error);
+        assert!(fused_error < wheels_error, "{} vs {}", fused_error, wheels_error);
+        let heading_std = fused.covariance()[ORIENTATION][ORIENTATION].sqrt();
+        assert!(fused_error < 4.0 * heading_std, "{} vs std {}", fused_error, heading_std);
+    }
+
+    #[test]
+    fn test_gyro_circle() {
+        // The gyro turns the robot through each delta before its travel arrives, which still
+        // happened in the frame at the delta's start
+        let mut ekf = Ekf::new(NOISE, GYRO);
+        let (delta_position, delta_orientation) = circle_step(100);
+        for _ in 0..100 {
+            for _ in 0..10 {
+                ekf.predict_gyro(delta_orientation, 0.1);
+            }
+            ekf.fuse_odometry(delta_position, delta_orientation).unwrap();
+        }
+        assert!(ekf.position()[X].abs() < 1e-9 && ekf.position()[Y].abs() < 1e-9);
+        assert!((ekf.orientation() - 2.0 * PI).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_stationary_gyro_bias() {
+        // Wheels that don't move say the robot isn't turning, however much the gyro drifts
+        let mut ekf = Ekf::new(NOISE, GYRO);
+        for _ in 0..100 {
+            for _ in 0..10 {
+                ekf.predict_gyro(-0.02, 0.01);
+            }
+            ekf.fuse_odometry([0.0, 0.0], 0.0).unwrap();
+        }
+        assert!((ekf.gyro_bias().0 + 0.02).abs() < 0.001, "{:?}", ekf.gyro_bias());
+        assert!(ekf.orientation().abs() < 0.001);
+    }
+
+    #[test]
+    fn test_update_edge_cases() {
+        // Fixes across the +-PI boundary pull the orientation the short way round
+        let mut ekf = Ekf::new(NOISE, GYRO);
+        ekf.covariance = diagonal([1.0, 1.0, 1.0, 0.0, 0.0]);
+        ekf.state[ORIENTATION] = PI - 0.1;
+        ekf.update_pose([0.0, 0.0, -PI + 0.1], &diagonal([1.0, 1.0, 1.0])).unwrap();
+        assert!((ekf.orientation() - PI).abs() < 1e-9);
+
+        // A certain state can't be corrected by a certain measurement
+        let mut ekf = Ekf::new(NOISE, GYRO);
+        let zero = [[0.0; 3]; 3];
+        assert_eq!(ekf.update_pose([1.0, 0.0, 0.0], &zero), Err(EkfError::Singular));
+        let x = [[1.0, 0.0, 0.0, 0.0, 0.0]];
+        assert_eq!(ekf.update([f64::NAN], &x, &[[1.0]]), Err(EkfError::NotFinite));
+
+        // Partial measurements only correct what they observe
+        ekf.covariance = diagonal([1.0, 1.0, 1.0, 0.0, 0.0]);
+        ekf.update([2.0], &x, &[[1.0]]).unwrap();
+        assert!((ekf.position()[X] - 1.0).abs() < 1e-9);
+        assert_eq!(ekf.position()[Y], 0.0);
+        assert!((ekf.covariance[X][X] - 0.5).abs() < 1e-9);
+        assert_eq!(ekf.covariance[Y][Y], 1.0);
+    }
+}
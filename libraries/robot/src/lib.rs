@@ -0,0 +1,10 @@
+pub mod bag;
+pub mod config;
+pub mod ekf;
+pub mod mission;
+pub mod node;
+pub mod nodes;
+pub mod path_tracker;
+pub mod pose_history;
+pub mod scheduler;
+pub mod schema;
@@ -1,48 +1,150 @@
 use std::cell::RefCell;
+use std::path::Path;
 use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::Duration;
 
-mod nodes;
-use nodes::clock::Clock;
-use nodes::log::Log;
-use nodes::serial_adapter::SerialAdapter;
-use nodes::websocket_client::WebsocketAcceptor;
-use nodes::position_estimator::PositionEstimator;
-use nodes::motion_controller::MotionController;
+use robot::bag;
+use robot::config::Config;
+use robot::nodes::clock::Clock;
+use robot::nodes::log::Log;
+use robot::nodes::serial_adapter::SerialAdapter;
+use robot::nodes::foxglove::FoxgloveServer;
+use robot::nodes::rosbridge::RosbridgeServer;
+use robot::nodes::tcp_acceptor::TcpAcceptor;
+use robot::nodes::udp_transport::UdpTransport;
+use robot::nodes::websocket_client::WebsocketAcceptor;
+use robot::nodes::position_estimator::PositionEstimator;
+use robot::nodes::mission_executor::MissionExecutor;
+use robot::nodes::motion_controller::MotionController;
+use robot::nodes::mqtt_bridge::MqttBridge;
+use robot::nodes::player::Player;
+use robot::nodes::safety_supervisor::SafetySupervisor;
+use robot::nodes::recorder::Recorder;
+use robot::nodes::simulated_base::SimulatedBase;
+use robot::scheduler::Scheduler;
 
 use topics::{PacketData, PacketFormat};
 
-fn main() {
-    let router_raw = packet_router::Router::<PacketFormat<PacketData>>::new();
-    let router = Rc::new(RefCell::new(router_raw));
+/** Prints a bag's summary, and with `dump` every record in it as JSON */
+fn inspect_bag(path: &str, dump: bool) -> Result<(), bag::BagError> {
+    let mut reader = bag::BagReader::open(Path::new(path))?;
+    if !reader.has_index() {
+        println!("{} has no index, the recording was not finished", path);
+    }
+    if dump {
+        for record in reader.records() {
+            let record = record?;
+            println!("{}| {}", record.receive_time, serde_json::to_string(&record.packet).unwrap());
+        }
+    } else {
+        print!("{}", reader.summary());
+    }
+    Ok(())
+}
 
-    let mut serial_adapter = SerialAdapter::new(Rc::clone(&router), Duration::from_secs(2));
+fn main() {
+    // Usage: robot [config.toml]
+    //        robot bag-info <file.sbag>
+    //        robot bag-dump <file.sbag>
+    let args: Vec<String> = std::env::args().collect();
+    if let Some(command @ ("bag-info" | "bag-dump")) = args.get(1).map(String::as_str) {
+        let Some(path) = args.get(2) else {
+            eprintln!("Usage: robot {} <file.sbag>", command);
+            std::process::exit(1);
+        };
+        if let Err(err) = inspect_bag(path, command == "bag-dump") {
+            eprintln!("Failed to read {}: {}", path, err);
+            std::process::exit(1);
+        }
+        return;
+    }
 
-    let mut log_client = Log::new(false);
-    router.borrow_mut().register_client(Rc::downgrade(&log_client.client));
+    let config = match args.get(1) {
+        Some(path) => match Config::load(Path::new(path)) {
+            Ok(config) => config,
+            Err(err) => {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            }
+        },
+        None => {
+            println!("No config file given, using defaults");
+            Config::default()
+        }
+    };
 
-    let mut clock_node = Clock::new();
-    router.borrow_mut().register_client(Rc::downgrade(&clock_node.client));
+    let router_raw = packet_router::Router::<PacketFormat<PacketData>>::new();
+    let router = Rc::new(RefCell::new(router_raw));
 
-    let mut websocket_acceptor = WebsocketAcceptor::new(Rc::clone(&router), "127.0.0.1:9001");
-    
-    let mut position_estimator = PositionEstimator::new();
-    router
-        .borrow_mut()
-        .register_client(Rc::downgrade(&position_estimator.client));
+    let stop = Arc::new(AtomicBool::new(false));
+    {
+        let stop = Arc::clone(&stop);
+        ctrlc::set_handler(move || stop.store(true, Ordering::Relaxed))
+            .expect("Failed to set Ctrl-C handler");
+    }
 
-    let mut motion_controller = MotionController::new();
-    router
-        .borrow_mut()
-        .register_client(Rc::downgrade(&motion_controller.client));
+    let mut scheduler = Scheduler::new(Rc::clone(&router));
+    scheduler.io_poll_interval = config.scheduler.io_poll_interval();
+    scheduler.stats_period = config.scheduler.stats_period();
 
-    loop {
-        clock_node.tick();
-        router.borrow_mut().poll();
-        log_client.step();
-        websocket_acceptor.tick();
-        serial_adapter.tick();
-        position_estimator.tick();
-        motion_controller.tick();
+    if config.clock.enabled {
+        scheduler.add(Clock::new());
+    }
+    if config.log.enabled {
+        scheduler.add(Log::new(config.log.log_all));
+    }
+    if config.websocket.enabled {
+        scheduler.add(WebsocketAcceptor::new(Rc::clone(&router), config.websocket.clone()));
+    }
+    if config.tcp.enabled {
+        scheduler.add(TcpAcceptor::new(Rc::clone(&router), &config.tcp.address));
     }
+    if config.udp.enabled {
+        scheduler.add(UdpTransport::new(
+            Rc::clone(&router),
+            &config.udp.address,
+            Duration::from_millis(config.udp.peer_timeout_ms),
+        ));
+    }
+    if config.foxglove.enabled {
+        scheduler.add(FoxgloveServer::new(Rc::clone(&router), &config.foxglove.address));
+    }
+    if config.rosbridge.enabled {
+        scheduler.add(RosbridgeServer::new(Rc::clone(&router), &config.rosbridge.address));
+    }
+    if config.mqtt.enabled {
+        scheduler.add(MqttBridge::new(config.mqtt.clone()));
+    }
+    if config.serial.enabled {
+        scheduler.add(SerialAdapter::new(Rc::clone(&router), config.serial.clone()));
+    }
+    if config.simulated_base.enabled {
+        scheduler.add(SimulatedBase::new(config.simulated_base.clone()));
+    }
+    if config.position_estimator.enabled {
+        scheduler.add(PositionEstimator::new(config.position_estimator.clone()));
+    }
+    if config.motion_controller.enabled {
+        scheduler.add(MotionController::new(config.motion_controller.clone()));
+    }
+    if config.mission_executor.enabled {
+        scheduler.add(MissionExecutor::new(config.mission_executor.clone()));
+    }
+    if config.safety_supervisor.enabled {
+        let supervisor = SafetySupervisor::new(config.safety_supervisor.clone());
+        let gate = supervisor.gate();
+        router.borrow_mut().set_filter(move |packet| gate.allows(packet));
+        scheduler.add(supervisor);
+    }
+    if config.recorder.enabled {
+        scheduler.add(Recorder::new(config.recorder.clone()));
+    }
+    if config.player.enabled {
+        scheduler.add(Player::new(config.player.clone()));
+    }
+
+    scheduler.run_until(&stop);
+    println!("Shut down");
 }
@@ -0,0 +1,147 @@
+/*!
+ * Mission files, so that demos can be repeated. A mission is a `topics::Mission` written as JSON
+ * or TOML, chosen by the file's extension. In TOML:
+ *
+ * ```toml
+ * id = 1
+ *
+ * [[steps]]
+ * action = "GoTo"
+ * position = [1.0, 0.0]
+ *
+ * [[steps]]
+ * action = "Wait"
+ * duration_ms = 2000
+ *
+ * [[steps]]
+ * action = "Face"
+ * heading = 0.0
+ * ```
+ */
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use topics::{Mission, MissionAction};
+
+#[derive(Debug)]
+pub enum MissionError {
+    Read(PathBuf, std::io::Error),
+    Parse(PathBuf, String),
+    UnknownFormat(PathBuf),
+    Invalid { step: usize, reason: &'static str },
+}
+
+impl fmt::Display for MissionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MissionError::Read(path, err) => {
+                write!(f, "Failed to read mission {}: {}", path.display(), err)
+            }
+            MissionError::Parse(path, err) => {
+                write!(f, "Failed to parse mission {}: {}", path.display(), err)
+            }
+            MissionError::UnknownFormat(path) => {
+                write!(
+                    f,
+                    "Mission {} must be a .json or .toml file",
+                    path.display()
+                )
+            }
+            MissionError::Invalid { step, reason } => {
+                write!(f, "Invalid mission step {}: {}", step, reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for MissionError {}
+
+/** Reads and checks a mission file */
+pub fn load(path: &Path) -> Result<Mission, MissionError> {
+    let text =
+        std::fs::read_to_string(path).map_err(|err| MissionError::Read(path.to_path_buf(), err))?;
+    let parse_err = |err: String| MissionError::Parse(path.to_path_buf(), err);
+    let mission: Mission = match path.extension().and_then(|extension| extension.to_str()) {
+        Some("json") => serde_json::from_str(&text).map_err(|err| parse_err(err.to_string()))?,
+        Some("toml") => toml::from_str(&text).map_err(|err| parse_err(err.to_string()))?,
+        _ => return Err(MissionError::UnknownFormat(path.to_path_buf())),
+    };
+    validate(&mission)?;
+    Ok(mission)
+}
+
+/** Checks every step can be carried out */
+pub fn validate(mission: &Mission) -> Result<(), MissionError> {
+    for (step, mission_step) in mission.steps.iter().enumerate() {
+        let invalid = |reason| MissionError::Invalid { step, reason };
+        let finite = mission_step
+            .position
+            .iter()
+            .chain(mission_step.heading.iter())
+            .all(|value| value.is_finite());
+        if !finite {
+            return Err(invalid("position and heading must be finite"));
+        }
+        if mission_step.action == MissionAction::Face && mission_step.heading.is_none() {
+            return Err(invalid("Face needs a heading"));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(name: &str, text: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("mission_{}_{}", std::process::id(), name));
+        std::fs::write(&path, text).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_load() {
+        let toml_path = write(
+            "a.toml",
+            "id = 4\n[[steps]]\naction = \"GoTo\"\nposition = [1.0, 2.0]\n\
+             [[steps]]\naction = \"Wait\"\nduration_ms = 500\n",
+        );
+        let json_path = write(
+            "a.json",
+            r#"{"id": 4, "steps": [{"action": "GoTo", "position": [1.0, 2.0]},
+                {"action": "Wait", "duration_ms": 500}]}"#,
+        );
+        for path in [toml_path, json_path] {
+            let mission = load(&path).unwrap();
+            assert_eq!(mission.id, 4);
+            assert_eq!(mission.steps[0].position, [1.0, 2.0]);
+            assert_eq!(mission.steps[0].heading, None);
+            assert_eq!(mission.steps[1].action, MissionAction::Wait);
+            assert_eq!(mission.steps[1].duration_ms, 500);
+            std::fs::remove_file(path).unwrap();
+        }
+
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("missions/demo.toml");
+        assert!(!load(&path).unwrap().steps.is_empty());
+    }
+
+    #[test]
+    fn test_invalid_missions() {
+        let path = write("face.toml", "[[steps]]\naction = \"Face\"\n");
+        let err = load(&path).unwrap_err();
+        assert!(
+            matches!(err, MissionError::Invalid { step: 0, .. }),
+            "{}",
+            err
+        );
+        std::fs::remove_file(path).unwrap();
+
+        let path = write("typo.json", r#"{"steps": [{"action": "Goto"}]}"#);
+        assert!(matches!(load(&path), Err(MissionError::Parse(..))));
+        std::fs::remove_file(path).unwrap();
+
+        let path = write("mission.yaml", "");
+        assert!(matches!(load(&path), Err(MissionError::UnknownFormat(..))));
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,96 @@
+use std::cell::RefCell;
+use std::time::Duration;
+
+use packet_router::Client;
+use topics::{PacketData, PacketFormat};
+
+use crate::nodes::clock::get_current_time;
+
+/**
+ * A unit of robot behaviour driven by the `Scheduler`. The scheduler owns the node's router
+ * client: it subscribes it to `subscriptions()`, delivers packets through `on_packet` and
+ * calls `on_timer` every `timer_period()`.
+ *
+ * Nodes that talk to the outside world (sockets, serial ports) do so in `on_poll`, which is
+ * called every scheduler iteration.
+ */
+pub trait Node {
+    /** Used in log messages and in the scheduler's timing diagnostics */
+    fn name(&self) -> &str;
+
+    /** Topics delivered to `on_packet`. Read once, when the node is added to the scheduler. */
+    fn subscriptions(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    /** How often `on_timer` should be called, if at all. */
+    fn timer_period(&self) -> Option<Duration> {
+        None
+    }
+
+    /** Called once before any other callback. */
+    fn on_start(&mut self, _ctx: &mut NodeContext) {}
+
+    fn on_packet(&mut self, _ctx: &mut NodeContext, _packet: &PacketFormat<PacketData>) {}
+
+    fn on_timer(&mut self, _ctx: &mut NodeContext) {}
+
+    /**
+     * Called every scheduler iteration. Returns true if any I/O happened, in which case the
+     * scheduler will come straight back rather than sleeping.
+     */
+    fn on_poll(&mut self, _ctx: &mut NodeContext) -> bool {
+        false
+    }
+
+    /** Called once when the scheduler shuts down. */
+    fn on_stop(&mut self, _ctx: &mut NodeContext) {}
+}
+
+/** What a node can do to the rest of the system from inside a callback. */
+pub struct NodeContext<'a> {
+    pub(crate) client: &'a RefCell<Client<PacketFormat<PacketData>>>,
+    pub(crate) message_id: &'a mut u32,
+}
+
+impl NodeContext<'_> {
+    /** Publish to all subscribers of the data's topic. */
+    pub fn publish(&mut self, data: PacketData) {
+        self.send_to(None, data);
+    }
+
+    /**
+     * Send to a single address, or to the topic's subscribers if `to` is None. Every packet gets
+     * a new id, so links with reliable delivery can tell them apart.
+     */
+    pub fn send_to(&mut self, to: Option<u16>, data: PacketData) {
+        let packet = self.new_packet(to, data);
+        self.send(packet);
+    }
+
+    /**
+     * A packet with the next id, for nodes that send through router clients of their own (eg one
+     * per connection) rather than `send_to`.
+     */
+    pub fn new_packet(&mut self, to: Option<u16>, data: PacketData) -> PacketFormat<PacketData> {
+        PacketFormat {
+            to,
+            from: None,
+            data,
+            time: get_current_time(),
+            id: self.next_id(),
+        }
+    }
+
+    /** Allocates an id, eg to renumber a packet forwarded from a client that doesn't number them */
+    pub fn next_id(&mut self) -> u32 {
+        let id = *self.message_id;
+        *self.message_id = self.message_id.wrapping_add(1);
+        id
+    }
+
+    /** Send a fully formed packet, eg a response that has to echo the request's id. */
+    pub fn send(&mut self, packet: PacketFormat<PacketData>) {
+        self.client.borrow_mut().send(packet);
+    }
+}
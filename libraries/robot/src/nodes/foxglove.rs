@@ -0,0 +1,562 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::rc::Rc;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use heapless::{String as HString, format as hformat};
+use packet_router::{Client, Router};
+use serde_json::{Value, json};
+use topics::{DiagnosticMsg, PacketData, PacketDataTrait, PacketFormat};
+use tungstenite::handshake::MidHandshake;
+use tungstenite::handshake::server::{Callback, ErrorResponse, Request, Response, ServerHandshake};
+use tungstenite::http::HeaderValue;
+use tungstenite::{HandshakeError, Message, WebSocket, accept_hdr};
+
+use crate::node::{Node, NodeContext};
+use crate::nodes::clock::get_current_time;
+use crate::schema::topic_schemas;
+
+/** The WebSocket subprotocol Foxglove clients ask for */
+pub const SUBPROTOCOL: &str = "foxglove.websocket.v1";
+
+/** Opcode of binary messages carrying a message, in both directions */
+const MESSAGE_DATA: u8 = 0x01;
+
+/** Don't let too many queued packets build up for a slow client, drop the oldest instead */
+const QUEUE_LANE_LIMIT: usize = 256;
+
+/** How long a new connection has to finish the WebSocket handshake */
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Default)]
+pub struct FoxgloveClientStats {
+    pub tx_messages: u32,
+    pub tx_bytes: u32,
+    pub rx_messages: u32,
+    pub rx_bytes: u32,
+    pub subscriptions: u32,
+    pub decode_error_count: u32,
+    pub write_error_count: u32,
+}
+
+impl FoxgloveClientStats {
+    fn to_log(&self) -> DiagnosticMsg {
+        let mut values = heapless::Vec::<topics::DiagnosticKeyValue, 16>::new();
+        values
+            .push(topics::DiagnosticKeyValue {
+                key: HString::from_str("tx_messages").unwrap(),
+                value: hformat!("{}", self.tx_messages).unwrap(),
+            })
+            .ok();
+        values
+            .push(topics::DiagnosticKeyValue {
+                key: HString::from_str("tx_bytes").unwrap(),
+                value: hformat!("{}", self.tx_bytes).unwrap(),
+            })
+            .ok();
+        values
+            .push(topics::DiagnosticKeyValue {
+                key: HString::from_str("rx_messages").unwrap(),
+                value: hformat!("{}", self.rx_messages).unwrap(),
+            })
+            .ok();
+        values
+            .push(topics::DiagnosticKeyValue {
+                key: HString::from_str("rx_bytes").unwrap(),
+                value: hformat!("{}", self.rx_bytes).unwrap(),
+            })
+            .ok();
+        values
+            .push(topics::DiagnosticKeyValue {
+                key: HString::from_str("subscriptions").unwrap(),
+                value: hformat!("{}", self.subscriptions).unwrap(),
+            })
+            .ok();
+        values
+            .push(topics::DiagnosticKeyValue {
+                key: HString::from_str("decode_errors").unwrap(),
+                value: hformat!("{}", self.decode_error_count).unwrap(),
+            })
+            .ok();
+        values
+            .push(topics::DiagnosticKeyValue {
+                key: HString::from_str("write_errors").unwrap(),
+                value: hformat!("{}", self.write_error_count).unwrap(),
+            })
+            .ok();
+
+        DiagnosticMsg {
+            level: topics::DiagnosticStatus::Ok,
+            name: HString::from_str("foxglove_stats").unwrap(),
+            message: HString::from_str("").unwrap(),
+            values,
+        }
+    }
+}
+
+/** A topic advertised to clients. Its channel id is its position in the list, plus one. */
+pub struct Channel {
+    pub topic: &'static str,
+    pub schema: Value,
+}
+
+/** Foxglove status message levels */
+#[derive(Debug, Clone, Copy)]
+enum StatusLevel {
+    Warning = 1,
+    Error = 2,
+}
+
+/**
+ * One Foxglove connection. Each subscription the client makes subscribes its router client to the
+ * channel's topic, and each message it publishes on a channel it advertised goes to the router.
+ */
+pub struct FoxgloveClient {
+    pub client: Rc<RefCell<Client<PacketFormat<PacketData>>>>,
+    pub websocket: WebSocket<TcpStream>,
+    pub stats: FoxgloveClientStats,
+    pub stats_send_time: Instant,
+    pub is_alive: bool,
+    channels: Rc<[Channel]>,
+    /** Topic of each of the client's subscription ids */
+    subscriptions: HashMap<u32, &'static str>,
+    /** Topic of each channel the client advertised for publishing */
+    client_channels: HashMap<u32, &'static str>,
+}
+
+impl FoxgloveClient {
+    pub fn new(websocket: WebSocket<TcpStream>, channels: Rc<[Channel]>) -> Self {
+        let client = Rc::new(RefCell::new(Client::<PacketFormat<PacketData>>::default()));
+        client
+            .borrow_mut()
+            .router_to_client
+            .set_lane_limit(Some(QUEUE_LANE_LIMIT));
+        let mut foxglove_client = FoxgloveClient {
+            client,
+            websocket,
+            stats: FoxgloveClientStats::default(),
+            stats_send_time: Instant::now(),
+            is_alive: true,
+            channels,
+            subscriptions: HashMap::new(),
+            client_channels: HashMap::new(),
+        };
+        foxglove_client.introduce();
+        foxglove_client
+    }
+
+    /** Tells the client what the server can do and which channels there are */
+    fn introduce(&mut self) {
+        self.send_json(json!({
+            "op": "serverInfo",
+            "name": "slambot",
+            "capabilities": ["clientPublish"],
+            "supportedEncodings": ["json"],
+            "metadata": {},
+            "sessionId": get_current_time().to_string(),
+        }));
+        let channels: Vec<Value> = self
+            .channels
+            .iter()
+            .enumerate()
+            .map(|(index, channel)| {
+                json!({
+                    "id": index + 1,
+                    "topic": channel.topic,
+                    "encoding": "json",
+                    "schemaName": channel.topic,
+                    "schema": channel.schema.to_string(),
+                    "schemaEncoding": "jsonschema",
+                })
+            })
+            .collect();
+        self.send_json(json!({ "op": "advertise", "channels": channels }));
+    }
+
+    fn send_json(&mut self, message: Value) {
+        self.send(Message::Text(message.to_string().into()));
+    }
+
+    /** Returns true if the socket is backed up */
+    fn send(&mut self, message: Message) -> bool {
+        let message_len = message.len() as u32;
+        let backed_up = match self.websocket.send(message) {
+            Ok(()) => false,
+            // The message is queued inside the websocket, it just couldn't be flushed yet
+            Err(tungstenite::Error::Io(e)) if e.kind() == std::io::ErrorKind::WouldBlock => true,
+            Err(tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed) => {
+                self.is_alive = false;
+                return true;
+            }
+            Err(_) => {
+                self.stats.write_error_count += 1;
+                return false;
+            }
+        };
+        self.stats.tx_messages += 1;
+        self.stats.tx_bytes += message_len;
+        backed_up
+    }
+
+    fn status(&mut self, level: StatusLevel, message: &str) {
+        self.send_json(json!({ "op": "status", "level": level as u8, "message": message }));
+    }
+
+    fn topic_of_channel(&self, channel_id: u64) -> Option<&'static str> {
+        let index = usize::try_from(channel_id).ok()?.checked_sub(1)?;
+        self.channels.get(index).map(|channel| channel.topic)
+    }
+
+    /** Subscribes the router client to exactly the topics the client has subscriptions on */
+    fn update_topics(&mut self) {
+        let topics: HashSet<String> = self
+            .subscriptions
+            .values()
+            .map(|topic| topic.to_string())
+            .collect();
+        self.stats.subscriptions = self.subscriptions.len() as u32;
+        self.client.borrow_mut().subscriptions = topics;
+    }
+
+    fn receive_text(&mut self, text: &str) {
+        let request = match serde_json::from_str::<Value>(text) {
+            Ok(request) => request,
+            Err(err) => {
+                self.stats.decode_error_count += 1;
+                return self.status(StatusLevel::Error, &format!("invalid JSON: {}", err));
+            }
+        };
+        match request["op"].as_str() {
+            Some("subscribe") => self.subscribe(&request),
+            Some("unsubscribe") => {
+                for id in ids(&request["subscriptionIds"]) {
+                    self.subscriptions.remove(&id);
+                }
+                self.update_topics();
+            }
+            Some("advertise") => self.advertise(&request),
+            Some("unadvertise") => {
+                for id in ids(&request["channelIds"]) {
+                    self.client_channels.remove(&id);
+                }
+            }
+            Some(op) => {
+                self.status(StatusLevel::Warning, &format!("\"{}\" isn't supported", op));
+            }
+            None => {
+                self.stats.decode_error_count += 1;
+                self.status(StatusLevel::Error, "messages need an \"op\"");
+            }
+        }
+    }
+
+    fn subscribe(&mut self, request: &Value) {
+        let subscriptions = request["subscriptions"].as_array().cloned().unwrap_or_default();
+        for subscription in subscriptions.iter() {
+            let id = subscription["id"].as_u64().and_then(|id| u32::try_from(id).ok());
+            let topic = subscription["channelId"]
+                .as_u64()
+                .and_then(|channel_id| self.topic_of_channel(channel_id));
+            match (id, topic) {
+                (Some(id), Some(topic)) => {
+                    self.subscriptions.insert(id, topic);
+                }
+                _ => self.status(
+                    StatusLevel::Error,
+                    &format!("can't subscribe to {}", subscription["channelId"]),
+                ),
+            }
+        }
+        self.update_topics();
+    }
+
+    fn advertise(&mut self, request: &Value) {
+        let channels = request["channels"].as_array().cloned().unwrap_or_default();
+        for channel in channels.iter() {
+            let Some(id) = channel["id"].as_u64().and_then(|id| u32::try_from(id).ok()) else {
+                self.status(StatusLevel::Error, "advertised channels need an id");
+                continue;
+            };
+            let name = channel["topic"].as_str().unwrap_or_default();
+            let Some(topic) = self
+                .channels
+                .iter()
+                .map(|channel| channel.topic)
+                .find(|topic| *topic == name)
+            else {
+                self.status(StatusLevel::Error, &format!("unknown topic \"{}\"", name));
+                continue;
+            };
+            if channel["encoding"] != "json" {
+                self.status(StatusLevel::Error, "only the json encoding can be published");
+                continue;
+            }
+            self.client_channels.insert(id, topic);
+        }
+    }
+
+    /** A message on a channel the client advertised */
+    fn receive_binary(&mut self, ctx: &mut NodeContext, data: &[u8]) {
+        self.stats.rx_messages += 1;
+        self.stats.rx_bytes += data.len() as u32;
+        if data.len() < 5 || data[0] != MESSAGE_DATA {
+            self.stats.decode_error_count += 1;
+            return self.status(StatusLevel::Error, "expected a message data opcode");
+        }
+        let channel_id = u32::from_le_bytes(data[1..5].try_into().unwrap());
+        let Some(topic) = self.client_channels.get(&channel_id).copied() else {
+            self.stats.decode_error_count += 1;
+            return self.status(
+                StatusLevel::Error,
+                &format!("channel {} hasn't been advertised", channel_id),
+            );
+        };
+        // PacketData is externally tagged, so the topic name wraps the message
+        let data = serde_json::from_slice::<Value>(&data[5..])
+            .and_then(|message| serde_json::from_value::<PacketData>(json!({ topic: message })));
+        match data {
+            Ok(data) => self.client.borrow_mut().send(ctx.new_packet(None, data)),
+            Err(err) => {
+                self.stats.decode_error_count += 1;
+                self.status(StatusLevel::Error, &format!("invalid {}: {}", topic, err));
+            }
+        }
+    }
+
+    /** Sends a packet to every subscription on its topic. Returns true if the socket backed up. */
+    fn forward(&mut self, packet: &PacketFormat<PacketData>) -> bool {
+        let topic = packet.data.topic();
+        let mut subscription_ids: Vec<u32> = self
+            .subscriptions
+            .iter()
+            .filter(|(_, subscribed)| **subscribed == topic)
+            .map(|(id, _)| *id)
+            .collect();
+        if subscription_ids.is_empty() {
+            return false;
+        }
+        subscription_ids.sort();
+        // The same JSON as `Log` prints, without the packet and topic around the message
+        let Ok(mut data) = serde_json::to_value(&packet.data) else {
+            self.stats.write_error_count += 1;
+            return false;
+        };
+        let payload = data[topic].take().to_string();
+        let log_time_ns = packet.time.saturating_mul(1000);
+        let mut backed_up = false;
+        for id in subscription_ids {
+            let mut message = Vec::with_capacity(13 + payload.len());
+            message.push(MESSAGE_DATA);
+            message.extend_from_slice(&id.to_le_bytes());
+            message.extend_from_slice(&log_time_ns.to_le_bytes());
+            message.extend_from_slice(payload.as_bytes());
+            backed_up |= self.send(Message::Binary(message.into()));
+        }
+        backed_up
+    }
+
+    /** Returns true if anything was read or written. */
+    pub fn tick(&mut self, ctx: &mut NodeContext) -> bool {
+        let mut did_work = false;
+
+        match self.websocket.read() {
+            Ok(Message::Text(text)) => {
+                did_work = true;
+                self.receive_text(text.as_str());
+            }
+            Ok(Message::Binary(data)) => {
+                did_work = true;
+                self.receive_binary(ctx, &data);
+            }
+            // Pings are answered by tungstenite, and closes show up as ConnectionClosed
+            Ok(_) => did_work = true,
+            Err(tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed) => {
+                self.is_alive = false;
+            }
+            Err(_) => {
+                // No data to read
+            }
+        }
+
+        // Leave packets in the priority lanes while the socket is backed up, so that higher
+        // priority packets can overtake them
+        let mut backed_up = match self.websocket.flush() {
+            Err(tungstenite::Error::Io(e)) => e.kind() == std::io::ErrorKind::WouldBlock,
+            _ => false,
+        };
+        while !backed_up && self.is_alive {
+            let Some(packet) = self.client.borrow_mut().fetch_next() else {
+                break;
+            };
+            did_work = true;
+            backed_up = self.forward(&packet);
+        }
+
+        if self.stats_send_time.elapsed() >= Duration::from_secs(1) {
+            let diag_msg = self.stats.to_log();
+            let packet = ctx.new_packet(None, PacketData::DiagnosticMsg(diag_msg));
+            self.client.borrow_mut().send(packet);
+            self.stats_send_time = Instant::now();
+        }
+
+        did_work
+    }
+}
+
+/** The ids in a JSON array, skipping anything that isn't one */
+fn ids(value: &Value) -> Vec<u32> {
+    value
+        .as_array()
+        .map(|ids| {
+            ids.iter()
+                .filter_map(|id| id.as_u64().and_then(|id| u32::try_from(id).ok()))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/** Confirms the Foxglove subprotocol, and turns away clients that don't ask for it */
+pub struct SubprotocolCheck;
+
+impl Callback for SubprotocolCheck {
+    fn on_request(
+        self,
+        request: &Request,
+        mut response: Response,
+    ) -> Result<Response, ErrorResponse> {
+        let offered = request
+            .headers()
+            .get("Sec-WebSocket-Protocol")
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|protocols| protocols.split(',').any(|p| p.trim() == SUBPROTOCOL));
+        if !offered {
+            let message = format!("use the {} subprotocol", SUBPROTOCOL);
+            let mut error = ErrorResponse::new(Some(message));
+            *error.status_mut() = tungstenite::http::StatusCode::BAD_REQUEST;
+            return Err(error);
+        }
+        response
+            .headers_mut()
+            .insert("Sec-WebSocket-Protocol", HeaderValue::from_static(SUBPROTOCOL));
+        Ok(response)
+    }
+}
+
+/** A connection part way through the WebSocket handshake */
+struct PendingHandshake {
+    handshake: MidHandshake<ServerHandshake<TcpStream, SubprotocolCheck>>,
+    addr: SocketAddr,
+    accepted: Instant,
+}
+
+/**
+ * Serves the Foxglove WebSocket protocol (https://github.com/foxglove/ws-protocol), so that
+ * Foxglove and other tools speaking it can plot and publish topics. Every `PacketData` topic is
+ * advertised as a channel with JSON encoding and a JSON schema from `crate::schema`. Messages
+ * are the message part of the JSON that `Log` prints.
+ */
+pub struct FoxgloveServer {
+    pub router: Rc<RefCell<Router<PacketFormat<PacketData>>>>,
+    pub clients_by_addr: HashMap<String, FoxgloveClient>,
+    pub server: TcpListener,
+    channels: Rc<[Channel]>,
+    pending: Vec<PendingHandshake>,
+}
+
+impl FoxgloveServer {
+    pub fn new(router: Rc<RefCell<Router<PacketFormat<PacketData>>>>, address: &str) -> Self {
+        let server = TcpListener::bind(address).unwrap();
+        println!("Foxglove server listening on {}", address);
+        server.set_nonblocking(true).expect("Failed to set non-blocking");
+        let channels: Rc<[Channel]> = topic_schemas()
+            .into_iter()
+            .map(|(topic, schema)| Channel { topic, schema })
+            .collect();
+        FoxgloveServer {
+            router,
+            clients_by_addr: HashMap::new(),
+            server,
+            channels,
+            pending: Vec::new(),
+        }
+    }
+
+    pub fn channels(&self) -> &[Channel] {
+        &self.channels
+    }
+
+    fn connected(&mut self, websocket: WebSocket<TcpStream>, addr: SocketAddr) {
+        let client = FoxgloveClient::new(websocket, Rc::clone(&self.channels));
+        self.router.borrow_mut().register_client(Rc::downgrade(&client.client));
+        self.clients_by_addr.insert(addr.to_string(), client);
+        println!("New Foxglove client connected: {}", addr);
+    }
+
+    /** Moves handshakes along as the sockets allow. Returns true if any finished. */
+    fn handle_pending(&mut self) -> bool {
+        let mut did_work = false;
+        for pending in std::mem::take(&mut self.pending) {
+            match pending.handshake.handshake() {
+                Ok(websocket) => {
+                    did_work = true;
+                    self.connected(websocket, pending.addr);
+                }
+                Err(HandshakeError::Interrupted(handshake)) => {
+                    if pending.accepted.elapsed() < HANDSHAKE_TIMEOUT {
+                        self.pending.push(PendingHandshake {
+                            handshake,
+                            ..pending
+                        });
+                    }
+                }
+                Err(HandshakeError::Failure(err)) => {
+                    eprintln!("Foxglove handshake with {} failed: {}", pending.addr, err);
+                }
+            }
+        }
+        did_work
+    }
+
+    /** Returns true if there was any activity on the socket or its clients. */
+    pub fn tick(&mut self, ctx: &mut NodeContext) -> bool {
+        let mut did_work = false;
+        if let Ok((stream, addr)) = self.server.accept() {
+            did_work = true;
+            stream.set_nonblocking(true).expect("Failed to set non-blocking");
+            match accept_hdr(stream, SubprotocolCheck) {
+                Ok(websocket) => self.connected(websocket, addr),
+                Err(HandshakeError::Interrupted(handshake)) => {
+                    self.pending.push(PendingHandshake {
+                        handshake,
+                        addr,
+                        accepted: Instant::now(),
+                    })
+                }
+                Err(HandshakeError::Failure(err)) => {
+                    eprintln!("Foxglove handshake with {} failed: {}", addr, err);
+                }
+            }
+        }
+        if !self.pending.is_empty() {
+            did_work |= self.handle_pending();
+        }
+
+        for client in self.clients_by_addr.values_mut() {
+            did_work |= client.tick(ctx);
+        }
+        self.clients_by_addr.retain(|_addr, client| client.is_alive);
+        did_work
+    }
+}
+
+impl Node for FoxgloveServer {
+    fn name(&self) -> &str {
+        "foxglove"
+    }
+
+    fn on_poll(&mut self, ctx: &mut NodeContext) -> bool {
+        self.tick(ctx)
+    }
+}
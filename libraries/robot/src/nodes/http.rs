@@ -0,0 +1,432 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Component, Path, PathBuf};
+
+use packet_router::Router;
+use serde_json::json;
+use topics::{PacketData, PacketDataTrait, PacketFormat};
+
+/** Largest request we'll buffer, headers and body together */
+pub const MAX_REQUEST_BYTES: usize = 64 * 1024;
+
+#[derive(Debug)]
+pub struct HttpRequest {
+    pub method: String,
+    /** The request target without its query string */
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpRequest {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn is_websocket_upgrade(&self) -> bool {
+        self.header("Upgrade")
+            .is_some_and(|value| value.eq_ignore_ascii_case("websocket"))
+    }
+}
+
+#[derive(Debug)]
+pub enum HttpError {
+    TooLarge,
+    Malformed(&'static str),
+}
+
+impl fmt::Display for HttpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HttpError::TooLarge => write!(f, "request larger than {} bytes", MAX_REQUEST_BYTES),
+            HttpError::Malformed(reason) => write!(f, "malformed request: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for HttpError {}
+
+/**
+ * Parses a request from the start of `data`. Returns the request and how many bytes it took up, or
+ * `None` if the request hasn't fully arrived yet.
+ */
+pub fn parse_request(data: &[u8]) -> Result<Option<(HttpRequest, usize)>, HttpError> {
+    let Some(header_end) = data.windows(4).position(|window| window == b"\r\n\r\n") else {
+        if data.len() >= MAX_REQUEST_BYTES {
+            return Err(HttpError::TooLarge);
+        }
+        return Ok(None);
+    };
+    let head = std::str::from_utf8(&data[..header_end])
+        .map_err(|_| HttpError::Malformed("headers are not UTF-8"))?;
+    let mut lines = head.split("\r\n");
+
+    let mut request_line = lines.next().unwrap_or_default().split(' ');
+    let (Some(method), Some(target), Some(version)) =
+        (request_line.next(), request_line.next(), request_line.next())
+    else {
+        return Err(HttpError::Malformed("bad request line"));
+    };
+    if !version.starts_with("HTTP/1.") {
+        return Err(HttpError::Malformed("unsupported HTTP version"));
+    }
+
+    let mut headers = Vec::new();
+    for line in lines {
+        let Some((key, value)) = line.split_once(':') else {
+            return Err(HttpError::Malformed("bad header line"));
+        };
+        headers.push((key.trim().to_string(), value.trim().to_string()));
+    }
+
+    let mut request = HttpRequest {
+        method: method.to_string(),
+        path: target.split('?').next().unwrap_or_default().to_string(),
+        headers,
+        body: Vec::new(),
+    };
+    let content_length = match request.header("Content-Length") {
+        Some(value) => value
+            .parse::<usize>()
+            .map_err(|_| HttpError::Malformed("bad Content-Length"))?,
+        None => 0,
+    };
+    let body_start = header_end + 4;
+    if body_start + content_length > MAX_REQUEST_BYTES {
+        return Err(HttpError::TooLarge);
+    }
+    if data.len() < body_start + content_length {
+        return Ok(None);
+    }
+    request.body = data[body_start..body_start + content_length].to_vec();
+    Ok(Some((request, body_start + content_length)))
+}
+
+#[derive(Debug)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    pub fn json(status: u16, body: serde_json::Value) -> Self {
+        HttpResponse {
+            status,
+            content_type: "application/json",
+            body: body.to_string().into_bytes(),
+        }
+    }
+
+    pub fn error(status: u16, message: &str) -> Self {
+        Self::json(status, json!({ "error": message }))
+    }
+
+    fn reason(&self) -> &'static str {
+        match self.status {
+            200 => "OK",
+            400 => "Bad Request",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            413 => "Payload Too Large",
+            _ => "Internal Server Error",
+        }
+    }
+
+    /** The whole response. Every connection is closed after one response. */
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\
+             Access-Control-Allow-Origin: *\r\nConnection: close\r\n\r\n",
+            self.status,
+            self.reason(),
+            self.content_type,
+            self.body.len()
+        )
+        .into_bytes();
+        bytes.extend_from_slice(&self.body);
+        bytes
+    }
+}
+
+fn content_type(path: &Path) -> &'static str {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("html") => "text/html; charset=utf-8",
+        Some("js" | "mjs") => "text/javascript",
+        Some("css") => "text/css",
+        Some("wasm") => "application/wasm",
+        Some("json" | "map") => "application/json",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("ico") => "image/x-icon",
+        Some("txt") => "text/plain; charset=utf-8",
+        _ => "application/octet-stream",
+    }
+}
+
+/**
+ * Serves a file from the built web interface in `root`. Paths without a file extension that don't
+ * exist get `index.html`, so that the UI's own routes survive a reload.
+ */
+pub fn serve_static(root: &Path, request: &HttpRequest) -> HttpResponse {
+    if request.method != "GET" {
+        return HttpResponse::error(405, "only GET is supported here");
+    }
+    let relative = Path::new(request.path.trim_start_matches('/'));
+    if !relative
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)))
+    {
+        return HttpResponse::error(404, "not found");
+    }
+
+    let mut path: PathBuf = root.join(relative);
+    if path.is_dir() {
+        path = path.join("index.html");
+    } else if !path.exists() && relative.extension().is_none() {
+        path = root.join("index.html");
+    }
+    match std::fs::read(&path) {
+        Ok(body) => HttpResponse {
+            status: 200,
+            content_type: content_type(&path),
+            body,
+        },
+        Err(_) => HttpResponse::error(404, "not found"),
+    }
+}
+
+/** What the REST API has seen of a topic */
+struct TopicRecord {
+    count: u64,
+    last_time: u64,
+    /** The most recent packet, as JSON */
+    latest: String,
+}
+
+/**
+ * JSON endpoints for looking into the router, using the same JSON as `Log`:
+ *
+ * - `GET /topics`: every topic seen so far, with a packet count and the last packet's time
+ * - `GET /clients`: the router's clients with their subscriptions and queue lengths
+ * - `GET /latest/<topic>`: the most recent packet on a topic
+ * - `POST /publish/<topic>`: publishes the JSON body (the message, eg
+ *   `{"linear_velocity": 0.1, "angular_velocity": 0.0}`) on a topic
+ */
+#[derive(Default)]
+pub struct RestApi {
+    topics: BTreeMap<&'static str, TopicRecord>,
+    to_publish: Vec<PacketData>,
+}
+
+impl RestApi {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&mut self, packet: &PacketFormat<PacketData>) {
+        let latest = match serde_json::to_string(packet) {
+            Ok(latest) => latest,
+            Err(_) => return,
+        };
+        let record = self
+            .topics
+            .entry(packet.data.topic())
+            .or_insert(TopicRecord {
+                count: 0,
+                last_time: 0,
+                latest: String::new(),
+            });
+        record.count += 1;
+        record.last_time = packet.time;
+        record.latest = latest;
+    }
+
+    /** Packets requested through `POST /publish`, for the caller to send to the router */
+    pub fn take_published(&mut self) -> Vec<PacketData> {
+        std::mem::take(&mut self.to_publish)
+    }
+
+    /** Answers an API request, or returns `None` if the path isn't part of the API */
+    pub fn handle(
+        &mut self,
+        request: &HttpRequest,
+        router: &Router<PacketFormat<PacketData>>,
+    ) -> Option<HttpResponse> {
+        let path = request.path.as_str();
+        let response = match (request.method.as_str(), path) {
+            ("GET", "/topics") => self.topics(),
+            ("GET", "/clients") => Self::clients(router),
+            (_, "/topics" | "/clients") => HttpResponse::error(405, "use GET"),
+            (method, _) if let Some(topic) = path.strip_prefix("/latest/") => match method {
+                "GET" => self.latest(topic),
+                _ => HttpResponse::error(405, "use GET"),
+            },
+            (method, _) if let Some(topic) = path.strip_prefix("/publish/") => match method {
+                "POST" => self.publish(topic, &request.body),
+                _ => HttpResponse::error(405, "use POST"),
+            },
+            _ => return None,
+        };
+        Some(response)
+    }
+
+    fn topics(&self) -> HttpResponse {
+        let topics: Vec<serde_json::Value> = self
+            .topics
+            .iter()
+            .map(|(topic, record)| {
+                json!({ "topic": topic, "count": record.count, "last_time": record.last_time })
+            })
+            .collect();
+        HttpResponse::json(200, json!(topics))
+    }
+
+    fn clients(router: &Router<PacketFormat<PacketData>>) -> HttpResponse {
+        let clients: Vec<serde_json::Value> = router
+            .clients()
+            .iter()
+            .map(|(address, client)| {
+                let client = client.borrow();
+                let mut subscriptions: Vec<&String> = client.subscriptions.iter().collect();
+                subscriptions.sort();
+                json!({
+                    "address": address,
+                    "subscriptions": subscriptions,
+                    "queued": client.router_to_client.len(),
+                    "dropped": client.router_to_client.dropped(),
+                })
+            })
+            .collect();
+        HttpResponse::json(200, json!(clients))
+    }
+
+    fn latest(&self, topic: &str) -> HttpResponse {
+        match self.topics.get(topic) {
+            Some(record) => HttpResponse {
+                status: 200,
+                content_type: "application/json",
+                body: record.latest.clone().into_bytes(),
+            },
+            None => HttpResponse::error(404, "nothing received on this topic yet"),
+        }
+    }
+
+    fn publish(&mut self, topic: &str, body: &[u8]) -> HttpResponse {
+        let message: serde_json::Value = match serde_json::from_slice(body) {
+            Ok(message) => message,
+            Err(err) => return HttpResponse::error(400, &format!("body is not JSON: {}", err)),
+        };
+        // PacketData is externally tagged, so the topic name wraps the message
+        match serde_json::from_value::<PacketData>(json!({ topic: message })) {
+            Ok(data) => {
+                self.to_publish.push(data);
+                HttpResponse::json(200, json!({ "published": topic }))
+            }
+            Err(err) => HttpResponse::error(400, &err.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_request() {
+        let data = b"POST /publish/MotionVelocityRequest?x=1 HTTP/1.1\r\nHost: robot\r\n\
+                     Content-Length: 4\r\n\r\nbodyGET";
+        let (request, used) = parse_request(data).unwrap().unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/publish/MotionVelocityRequest");
+        assert_eq!(request.header("host"), Some("robot"));
+        assert_eq!(request.body, b"body");
+        assert_eq!(used, data.len() - 3);
+
+        // Incomplete headers, then an incomplete body
+        assert!(parse_request(b"GET / HTTP/1.1\r\nHost: robot\r\n").unwrap().is_none());
+        assert!(
+            parse_request(b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nabc")
+                .unwrap()
+                .is_none()
+        );
+
+        assert!(matches!(parse_request(b"nonsense\r\n\r\n"), Err(HttpError::Malformed(_))));
+        let huge = vec![b'a'; MAX_REQUEST_BYTES];
+        assert!(matches!(parse_request(&huge), Err(HttpError::TooLarge)));
+    }
+
+    #[test]
+    fn test_websocket_upgrade() {
+        let data = b"GET / HTTP/1.1\r\nUpgrade: WebSocket\r\nConnection: Upgrade\r\n\r\n";
+        let (request, _) = parse_request(data).unwrap().unwrap();
+        assert!(request.is_websocket_upgrade());
+
+        let (request, _) = parse_request(b"GET / HTTP/1.1\r\n\r\n").unwrap().unwrap();
+        assert!(!request.is_websocket_upgrade());
+    }
+
+    fn request(method: &str, path: &str, body: &str) -> HttpRequest {
+        HttpRequest {
+            method: method.to_string(),
+            path: path.to_string(),
+            headers: Vec::new(),
+            body: body.as_bytes().to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_rest_api() {
+        let router = Router::new();
+        let mut api = RestApi::new();
+        api.record(&PacketFormat {
+            to: None,
+            from: Some(3),
+            data: PacketData::MotionVelocityRequest(topics::MotionVelocityRequest {
+                linear_velocity: 0.5,
+                angular_velocity: 0.0,
+            }),
+            time: 1234,
+            id: 7,
+        });
+
+        let response = api.handle(&request("GET", "/topics", ""), &router).unwrap();
+        let topics: serde_json::Value = serde_json::from_slice(&response.body).unwrap();
+        assert_eq!(topics[0]["topic"], "MotionVelocityRequest");
+        assert_eq!(topics[0]["count"], 1);
+
+        let response = api
+            .handle(&request("GET", "/latest/MotionVelocityRequest", ""), &router)
+            .unwrap();
+        let latest: serde_json::Value = serde_json::from_slice(&response.body).unwrap();
+        assert_eq!(latest["data"]["MotionVelocityRequest"]["linear_velocity"], 0.5);
+        assert_eq!(latest["id"], 7);
+
+        let response = api.handle(&request("GET", "/latest/Nothing", ""), &router).unwrap();
+        assert_eq!(response.status, 404);
+
+        let body = r#"{"linear_velocity": 0.1, "angular_velocity": -0.2}"#;
+        let response = api
+            .handle(&request("POST", "/publish/MotionVelocityRequest", body), &router)
+            .unwrap();
+        assert_eq!(response.status, 200);
+        let published = api.take_published();
+        assert!(matches!(published[..], [PacketData::MotionVelocityRequest(_)]));
+
+        let response = api
+            .handle(&request("POST", "/publish/NoSuchTopic", "{}"), &router)
+            .unwrap();
+        assert_eq!(response.status, 400);
+        let response = api
+            .handle(&request("POST", "/publish/MotionVelocityRequest", "{\"x\": 1}"), &router)
+            .unwrap();
+        assert_eq!(response.status, 400);
+        assert!(api.take_published().is_empty());
+
+        assert_eq!(api.handle(&request("POST", "/topics", ""), &router).unwrap().status, 405);
+        assert!(api.handle(&request("GET", "/index.html", ""), &router).is_none());
+    }
+}
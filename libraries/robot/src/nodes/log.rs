@@ -1,20 +1,28 @@
 use heapless::Vec;
-use packet_router::Client;
-use std::cell::RefCell;
-use std::rc::Rc;
 use topics::{DiagnosticStatus, PacketData, PacketDataTrait, PacketFormat};
 
+use crate::node::{Node, NodeContext};
+
 pub struct Log {
-    pub client: Rc<RefCell<Client<PacketFormat<PacketData>>>>,
+    log_all: bool,
 }
 
 impl Log {
     pub fn new(log_all: bool) -> Log {
-        let client = Rc::new(RefCell::new(Client::<PacketFormat<PacketData>>::default()));
-        if log_all {
-            client.borrow_mut().subscriptions.insert("all".to_string());
+        Log { log_all }
+    }
+}
+
+impl Node for Log {
+    fn name(&self) -> &str {
+        "log"
+    }
+
+    fn subscriptions(&self) -> std::vec::Vec<String> {
+        if self.log_all {
+            vec!["all".to_string()]
         } else {
-            client.borrow_mut().subscriptions.insert(
+            vec![
                 topics::PacketData::DiagnosticMsg(topics::DiagnosticMsg {
                     level: DiagnosticStatus::Ok,
                     name: "".try_into().expect("Arge"),
@@ -23,23 +31,17 @@ impl Log {
                 })
                 .topic()
                 .to_string(),
-            );
-        };
-        Log { client }
+            ]
+        }
     }
 
-    pub fn step(&mut self) {
-        let log_packets = self.client.borrow_mut().fetch_all();
-        for packet in log_packets {
-            println!(
-                "{}| {}",
-                chrono::DateTime::from_timestamp_micros(packet.time as i64)
-                    .expect("datetime overflow")
-                    .to_rfc3339(),
-                serde_json::to_string(&(*packet)).unwrap()
-            );
-        }
+    fn on_packet(&mut self, _ctx: &mut NodeContext, packet: &PacketFormat<PacketData>) {
+        println!(
+            "{}| {}",
+            chrono::DateTime::from_timestamp_micros(packet.time as i64)
+                .expect("datetime overflow")
+                .to_rfc3339(),
+            serde_json::to_string(packet).unwrap()
+        );
     }
 }
-
-
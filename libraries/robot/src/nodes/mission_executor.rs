@@ -0,0 +1,524 @@
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use topics::{
+    MissionAction, MissionCommand, MissionState, MissionStep, MotionRequestMode, MotionState,
+    PacketData, PacketDataTrait, PacketFormat,
+};
+
+use crate::config::MissionExecutorConfig;
+use crate::mission;
+use crate::node::{Node, NodeContext};
+
+/**
+ * Carries out the steps of a `Mission` one at a time. Each `GoTo` or `Face` step is sent to the
+ * motion controller as a `Position` mode `MotionTargetRequest`, and the next step starts when the
+ * controller reports it has arrived at that goal. If anything else takes over the controller
+ * part way through a step, the mission is paused until a `MissionControl` resumes it.
+ */
+pub struct MissionExecutor {
+    config: MissionExecutorConfig,
+    mission: Option<ActiveMission>,
+
+    // Current position estimate, needed to face a heading on the spot or towards a `GoTo`
+    position: Option<[f32; 2]>,
+
+    // The last `MissionProgress` sent, and when
+    progress: Option<topics::MissionProgress>,
+    progress_time: Instant,
+}
+
+/** How often `MissionProgress` is sent while nothing about it changes */
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+struct ActiveMission {
+    id: u32,
+    steps: Vec<MissionStep>,
+    /** Index of the step being carried out */
+    step: usize,
+    state: MissionState,
+    phase: StepPhase,
+}
+
+#[derive(Debug, PartialEq)]
+enum StepPhase {
+    /** Not started yet, or paused before it finished and to be started again */
+    Pending,
+    /** Waiting for the motion controller to reach this goal */
+    Driving {
+        goal: [f32; 2],
+        heading: f32,
+    },
+    Waiting {
+        remaining: Duration,
+        since: Instant,
+    },
+}
+
+impl MissionExecutor {
+    pub fn new(config: MissionExecutorConfig) -> Self {
+        MissionExecutor {
+            config,
+            mission: None,
+            position: None,
+            progress: None,
+            progress_time: Instant::now(),
+        }
+    }
+
+    /** Replaces the current mission, cancelling it if it hasn't finished */
+    fn start(&mut self, ctx: &mut NodeContext, new_mission: &topics::Mission) {
+        if let Some(current) = &mut self.mission
+            && matches!(current.state, MissionState::Running | MissionState::Paused)
+        {
+            current.state = MissionState::Cancelled;
+            self.report(ctx);
+        }
+        let valid = mission::validate(new_mission);
+        self.mission = Some(ActiveMission {
+            id: new_mission.id,
+            steps: new_mission.steps.to_vec(),
+            step: 0,
+            state: MissionState::Running,
+            phase: StepPhase::Pending,
+        });
+        if let Err(err) = valid {
+            eprintln!("Rejected mission {}: {}", new_mission.id, err);
+            stop(ctx);
+            self.mission.as_mut().unwrap().state = MissionState::Failed;
+        }
+        self.advance(ctx);
+        self.report(ctx);
+    }
+
+    fn on_control(&mut self, ctx: &mut NodeContext, command: &MissionCommand) {
+        let Some(mission) = &mut self.mission else {
+            return;
+        };
+        match (command, &mission.state) {
+            (MissionCommand::Pause, MissionState::Running) => {
+                stop(ctx);
+                mission.pause();
+            }
+            (MissionCommand::Resume, MissionState::Paused) => {
+                mission.state = MissionState::Running;
+                if let StepPhase::Waiting { since, .. } = &mut mission.phase {
+                    *since = Instant::now();
+                }
+                self.advance(ctx);
+            }
+            (MissionCommand::Cancel, MissionState::Running | MissionState::Paused) => {
+                stop(ctx);
+                mission.state = MissionState::Cancelled;
+            }
+            _ => {}
+        }
+        self.report(ctx);
+    }
+
+    /** Moves on from the step being driven when the motion controller reports on its goal */
+    fn on_motion_status(&mut self, ctx: &mut NodeContext, status: &topics::MotionStatus) {
+        let Some(mission) = &mut self.mission else {
+            return;
+        };
+        let StepPhase::Driving { goal, heading } = mission.phase else {
+            return;
+        };
+        let is_our_goal = status.mode == MotionRequestMode::Position
+            && status.goal == goal
+            && status.goal_heading == heading;
+        if mission.state != MissionState::Running || !is_our_goal {
+            return;
+        }
+        match status.state {
+            MotionState::Arrived => {
+                mission.step += 1;
+                mission.phase = StepPhase::Pending;
+                self.advance(ctx);
+            }
+            MotionState::Aborted => mission.pause(),
+            MotionState::Failed => mission.state = MissionState::Failed,
+            MotionState::Idle | MotionState::Moving => {}
+        }
+        self.report(ctx);
+    }
+
+    /**
+     * Starts pending steps and finishes waits that are over, until the mission is waiting on
+     * something.
+     */
+    fn advance(&mut self, ctx: &mut NodeContext) {
+        let Some(mission) = &mut self.mission else {
+            return;
+        };
+        while mission.state == MissionState::Running {
+            let Some(step) = mission.steps.get(mission.step) else {
+                mission.state = MissionState::Complete;
+                break;
+            };
+            match mission.phase {
+                StepPhase::Pending => {
+                    if step.action == MissionAction::Wait {
+                        mission.phase = StepPhase::Waiting {
+                            remaining: Duration::from_millis(step.duration_ms as u64),
+                            since: Instant::now(),
+                        };
+                        continue;
+                    }
+                    // Both turn from, or drive away from, the current position
+                    let Some(position) = self.position else {
+                        break;
+                    };
+                    let (goal, heading) = match step.action {
+                        MissionAction::Face => (position, step.heading.unwrap()),
+                        _ => {
+                            let offset = [
+                                step.position[0] - position[0],
+                                step.position[1] - position[1],
+                            ];
+                            // At orientation 0 the robot faces +y
+                            let travel = (-offset[0]).atan2(offset[1]);
+                            (step.position, step.heading.unwrap_or(travel))
+                        }
+                    };
+                    ctx.publish(PacketData::MotionTargetRequest(
+                        topics::MotionTargetRequest {
+                            linear: [goal[0] as f64, goal[1] as f64],
+                            angular: heading as f64,
+                            motion_mode: MotionRequestMode::Position,
+                        },
+                    ));
+                    mission.phase = StepPhase::Driving { goal, heading };
+                    break;
+                }
+                StepPhase::Driving { .. } => break,
+                StepPhase::Waiting { remaining, since } => {
+                    if since.elapsed() < remaining {
+                        break;
+                    }
+                    mission.step += 1;
+                    mission.phase = StepPhase::Pending;
+                }
+            }
+        }
+    }
+
+    /**
+     * Sends `MissionProgress` if it changed, and otherwise every `PROGRESS_INTERVAL` until the
+     * mission is finished.
+     */
+    fn report(&mut self, ctx: &mut NodeContext) {
+        let Some(mission) = &self.mission else {
+            return;
+        };
+        let progress = topics::MissionProgress {
+            id: mission.id,
+            state: mission.state.clone(),
+            step: mission.step as u16,
+            steps: mission.steps.len() as u16,
+        };
+        let changed = self.progress.as_ref().is_none_or(|last| {
+            last.id != progress.id || last.state != progress.state || last.step != progress.step
+        });
+        let active = matches!(progress.state, MissionState::Running | MissionState::Paused);
+        if changed || (active && self.progress_time.elapsed() >= PROGRESS_INTERVAL) {
+            ctx.publish(PacketData::MissionProgress(progress.clone()));
+            self.progress = Some(progress);
+            self.progress_time = Instant::now();
+        }
+    }
+}
+
+impl ActiveMission {
+    /** Holds the current step, to be started again (or the wait carried on) when resumed */
+    fn pause(&mut self) {
+        self.state = MissionState::Paused;
+        self.phase = match self.phase {
+            StepPhase::Waiting { remaining, since } => StepPhase::Waiting {
+                remaining: remaining.saturating_sub(since.elapsed()),
+                since,
+            },
+            _ => StepPhase::Pending,
+        };
+    }
+}
+
+fn stop(ctx: &mut NodeContext) {
+    ctx.publish(PacketData::MotionTargetRequest(
+        topics::MotionTargetRequest {
+            linear: [0.0, 0.0],
+            angular: 0.0,
+            motion_mode: MotionRequestMode::Stop,
+        },
+    ));
+}
+
+impl Node for MissionExecutor {
+    fn name(&self) -> &str {
+        "mission_exec"
+    }
+
+    fn subscriptions(&self) -> Vec<String> {
+        let mission_topic = PacketData::Mission(topics::Mission {
+            id: 0,
+            steps: heapless::Vec::new(),
+        })
+        .topic()
+        .to_string();
+
+        let control_topic = PacketData::MissionControl(topics::MissionControl {
+            command: MissionCommand::Pause,
+        })
+        .topic()
+        .to_string();
+
+        let status_topic = PacketData::MotionStatus(topics::MotionStatus {
+            state: MotionState::Idle,
+            mode: MotionRequestMode::Stop,
+            distance_error: 0.0,
+            heading_error: 0.0,
+            goal: [0.0, 0.0],
+            goal_heading: 0.0,
+        })
+        .topic()
+        .to_string();
+
+        let position_estimate_topic = PacketData::PositionEstimate(topics::PositionEstimate {
+            timestamp: 0,
+            position: [0.0, 0.0],
+            orientation: 0.0,
+            covariance: [0.0; 9],
+        })
+        .topic()
+        .to_string();
+
+        vec![
+            mission_topic,
+            control_topic,
+            status_topic,
+            position_estimate_topic,
+        ]
+    }
+
+    fn timer_period(&self) -> Option<Duration> {
+        Some(Duration::from_millis(50))
+    }
+
+    fn on_start(&mut self, ctx: &mut NodeContext) {
+        if let Some(file) = &self.config.file {
+            match mission::load(Path::new(file)) {
+                Ok(mission) => self.start(ctx, &mission),
+                Err(err) => eprintln!("{}", err),
+            }
+        }
+    }
+
+    fn on_packet(&mut self, ctx: &mut NodeContext, packet: &PacketFormat<PacketData>) {
+        match &packet.data {
+            PacketData::Mission(mission) => self.start(ctx, mission),
+            PacketData::MissionControl(control) => self.on_control(ctx, &control.command),
+            PacketData::MotionStatus(status) => self.on_motion_status(ctx, status),
+            PacketData::PositionEstimate(estimate) => {
+                self.position = Some(estimate.position);
+                // A step may have been waiting on the first estimate
+                self.advance(ctx);
+            }
+            _ => {}
+        }
+    }
+
+    fn on_timer(&mut self, ctx: &mut NodeContext) {
+        self.advance(ctx);
+        self.report(ctx);
+    }
+
+    /** Leave the robot stopped rather than driving to a goal nothing will follow up on */
+    fn on_stop(&mut self, ctx: &mut NodeContext) {
+        if self
+            .mission
+            .as_ref()
+            .is_some_and(|mission| matches!(mission.phase, StepPhase::Driving { .. }))
+        {
+            stop(ctx);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nodes::clock::get_current_time;
+    use packet_router::Client;
+    use std::cell::RefCell;
+
+    fn packet(data: PacketData) -> PacketFormat<PacketData> {
+        PacketFormat {
+            to: None,
+            from: Some(7),
+            data,
+            time: get_current_time(),
+            id: 0,
+        }
+    }
+
+    fn step(action: MissionAction, position: [f32; 2], heading: Option<f32>) -> MissionStep {
+        MissionStep {
+            action,
+            position,
+            heading,
+            duration_ms: 0,
+        }
+    }
+
+    fn status(state: MotionState, goal: [f32; 2], goal_heading: f32) -> PacketFormat<PacketData> {
+        packet(PacketData::MotionStatus(topics::MotionStatus {
+            state,
+            mode: MotionRequestMode::Position,
+            distance_error: 0.0,
+            heading_error: 0.0,
+            goal,
+            goal_heading,
+        }))
+    }
+
+    fn control(command: MissionCommand) -> PacketFormat<PacketData> {
+        packet(PacketData::MissionControl(topics::MissionControl {
+            command,
+        }))
+    }
+
+    type TestClient = RefCell<Client<PacketFormat<PacketData>>>;
+
+    /** The motion requests sent, as `(mode, linear, angular)` */
+    fn targets(client: &TestClient) -> Vec<(MotionRequestMode, [f64; 2], f64)> {
+        std::mem::take(&mut client.borrow_mut().client_to_router)
+            .into_iter()
+            .filter_map(|packet| match packet.data {
+                PacketData::MotionTargetRequest(target) => {
+                    Some((target.motion_mode, target.linear, target.angular))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_runs_steps_in_order() {
+        let mut executor = MissionExecutor::new(MissionExecutorConfig::default());
+        let client = RefCell::new(Client::default());
+        let mut message_id = 0;
+        let mut ctx = NodeContext {
+            client: &client,
+            message_id: &mut message_id,
+        };
+        let mut wait = step(MissionAction::Wait, [0.0, 0.0], None);
+        wait.duration_ms = 20;
+        let mut steps = heapless::Vec::new();
+        steps
+            .push(step(MissionAction::GoTo, [-1.0, 0.0], None))
+            .unwrap();
+        steps.push(wait).unwrap();
+        steps
+            .push(step(MissionAction::Face, [0.0, 0.0], Some(0.5)))
+            .unwrap();
+        let mission = topics::Mission { id: 2, steps };
+        executor.on_packet(&mut ctx, &packet(PacketData::Mission(mission)));
+
+        // Nothing is sent until there's an estimate to drive from
+        assert!(targets(&client).is_empty());
+        executor.on_packet(
+            &mut ctx,
+            &packet(PacketData::PositionEstimate(topics::PositionEstimate {
+                timestamp: 0,
+                position: [0.0, 0.0],
+                orientation: 0.0,
+                covariance: [0.0; 9],
+            })),
+        );
+        let heading = std::f32::consts::FRAC_PI_2;
+        assert_eq!(
+            targets(&client),
+            vec![(MotionRequestMode::Position, [-1.0, 0.0], heading as f64)]
+        );
+
+        // Statuses about other goals are ignored
+        executor.on_packet(&mut ctx, &status(MotionState::Arrived, [1.0, 0.0], heading));
+        assert_eq!(executor.mission.as_ref().unwrap().step, 0);
+
+        executor.on_packet(
+            &mut ctx,
+            &status(MotionState::Arrived, [-1.0, 0.0], heading),
+        );
+        assert_eq!(executor.mission.as_ref().unwrap().step, 1);
+        assert!(targets(&client).is_empty());
+        std::thread::sleep(Duration::from_millis(25));
+        executor.on_timer(&mut ctx);
+        // Faces from the last estimate
+        assert_eq!(
+            targets(&client),
+            vec![(MotionRequestMode::Position, [0.0, 0.0], 0.5)]
+        );
+
+        executor.on_packet(&mut ctx, &status(MotionState::Arrived, [0.0, 0.0], 0.5));
+        let mission = executor.mission.as_ref().unwrap();
+        assert_eq!(mission.state, MissionState::Complete);
+        assert_eq!(executor.progress.as_ref().unwrap().step, 3);
+    }
+
+    #[test]
+    fn test_pause_resume_and_cancel() {
+        let mut executor = MissionExecutor::new(MissionExecutorConfig::default());
+        let client = RefCell::new(Client::default());
+        let mut message_id = 0;
+        let mut ctx = NodeContext {
+            client: &client,
+            message_id: &mut message_id,
+        };
+        executor.position = Some([0.0, 0.0]);
+        let mut steps = heapless::Vec::new();
+        steps
+            .push(step(MissionAction::GoTo, [0.0, 1.0], Some(1.0)))
+            .unwrap();
+        let mission = topics::Mission { id: 1, steps };
+        executor.on_packet(&mut ctx, &packet(PacketData::Mission(mission.clone())));
+        let go = (MotionRequestMode::Position, [0.0, 1.0], 1.0);
+        let stop = (MotionRequestMode::Stop, [0.0, 0.0], 0.0);
+        assert_eq!(targets(&client), vec![go.clone()]);
+
+        executor.on_packet(&mut ctx, &control(MissionCommand::Pause));
+        assert_eq!(targets(&client), vec![stop.clone()]);
+        // The stop aborts the goal, which is already paused
+        executor.on_packet(&mut ctx, &status(MotionState::Aborted, [0.0, 1.0], 1.0));
+        executor.on_packet(&mut ctx, &control(MissionCommand::Resume));
+        assert_eq!(targets(&client), vec![go.clone()]);
+        assert_eq!(
+            executor.mission.as_ref().unwrap().state,
+            MissionState::Running
+        );
+
+        // Something else taking over pauses the mission
+        executor.on_packet(&mut ctx, &status(MotionState::Aborted, [0.0, 1.0], 1.0));
+        assert_eq!(
+            executor.mission.as_ref().unwrap().state,
+            MissionState::Paused
+        );
+        executor.on_packet(&mut ctx, &control(MissionCommand::Cancel));
+        assert_eq!(targets(&client), vec![stop.clone()]);
+        assert_eq!(
+            executor.mission.as_ref().unwrap().state,
+            MissionState::Cancelled
+        );
+        executor.on_packet(&mut ctx, &control(MissionCommand::Resume));
+        assert!(targets(&client).is_empty());
+
+        // Invalid missions fail straight away
+        let mut invalid = mission;
+        invalid.steps[0].position = [f32::NAN, 0.0];
+        executor.on_packet(&mut ctx, &packet(PacketData::Mission(invalid)));
+        assert_eq!(targets(&client), vec![stop.clone()]);
+        assert_eq!(
+            executor.progress.as_ref().unwrap().state,
+            MissionState::Failed
+        );
+    }
+}
@@ -1,7 +1,19 @@
 pub mod clock;
+pub mod foxglove;
+pub mod http;
 pub mod log;
 pub mod serial_client;
 pub mod serial_adapter;
+pub mod tcp_acceptor;
+pub mod udp_transport;
 pub mod websocket_client;
 pub mod position_estimator;
-pub mod motion_controller;
\ No newline at end of file
+pub mod motion_controller;
+pub mod mission_executor;
+pub mod safety_supervisor;
+pub mod mqtt;
+pub mod mqtt_bridge;
+pub mod player;
+pub mod rosbridge;
+pub mod recorder;
+pub mod simulated_base;
\ No newline at end of file
@@ -1,27 +1,49 @@
-use std::cell::RefCell;
-use std::rc::Rc;
+use std::str::FromStr;
 use std::time::{Duration, Instant};
-use packet_router::Client;
-use topics::{PacketData, PacketDataTrait, PacketFormat, MotionRequestMode};
+use drive_base::slew::SlewLimiter;
+use heapless::{String as HString, format as hformat};
+use topics::{
+    DiagnosticMsg, MotionRequestMode, MotionState, PacketData, PacketDataTrait, PacketFormat,
+};
 
+use crate::config::MotionControllerConfig;
+use crate::node::{Node, NodeContext};
 use crate::nodes::clock::get_current_time;
+use crate::path_tracker::{PathStep, PathTracker};
 
 pub struct MotionController {
-    pub client: Rc<RefCell<Client<PacketFormat<PacketData>>>>,
-    
     // Current target
     current_target: Option<MotionTarget>,
+    // The last `Path`, which is followed in `MotionRequestMode::Path`
+    path: Option<ActivePath>,
+    // Drives to the target in `MotionRequestMode::Position`
+    position_goal: Option<PathTracker>,
+    // The last `MotionStatus`, and when it was sent
+    status: topics::MotionStatus,
+    status_time: Instant,
     
     // Current position estimate
     current_position: [f64; 2],
     current_orientation: f64,
     position_updated: bool,
+
+    // Commands ramp towards the requested velocities at the configured accelerations, over the
+    // time since the last command was sent
+    linear_output: SlewLimiter,
+    angular_output: SlewLimiter,
+    ramp_time: Instant,
     
-    // Rate limiting
-    last_packet_time: Instant,
-    packet_interval: Duration,
+    // Velocity commands are sent every `publish_interval_ms`, and targets stamped more than
+    // `max_command_age_ms` ago are ignored
+    config: MotionControllerConfig,
+
+    stale_command_count: u32,
+    stats_send_time: Instant,
 }
 
+/** How often `PathProgress` and `MotionStatus` are sent while nothing about them changes */
+const STATUS_INTERVAL: Duration = Duration::from_secs(1);
+
 #[derive(Debug)]
 struct MotionTarget {
     linear: [f64; 2],
@@ -29,11 +51,345 @@ struct MotionTarget {
     mode: MotionRequestMode,
 }
 
+struct ActivePath {
+    id: u32,
+    /** Where the `Path` came from, so progress goes back there */
+    requester: Option<u16>,
+    tracker: PathTracker,
+    /** The last progress sent */
+    progress: Option<topics::PathProgress>,
+    progress_time: Instant,
+}
+
+impl ActivePath {
+    fn progress(
+        &self,
+        status: topics::PathStatus,
+        step: Option<&PathStep>,
+    ) -> topics::PathProgress {
+        topics::PathProgress {
+            id: self.id,
+            status,
+            waypoint: step.map_or(0, |step| step.waypoint as u16),
+            distance_remaining: step.map_or(self.tracker.length(), |step| step.distance_remaining)
+                as f32,
+            cross_track_error: step.map_or(0.0, |step| step.cross_track_error) as f32,
+        }
+    }
+
+    fn is_complete(&self) -> bool {
+        self.progress
+            .as_ref()
+            .is_some_and(|progress| progress.status == topics::PathStatus::Complete)
+    }
+
+    fn is_finished(&self) -> bool {
+        self.progress.as_ref().is_some_and(|progress| {
+            !matches!(progress.status, topics::PathStatus::Following)
+        })
+    }
+}
+
 impl MotionController {
-    pub fn new() -> Self {
-        let client = Rc::new(RefCell::new(Client::default()));
-        
-        // Subscribe to MotionTargetRequest
+    pub fn new(config: MotionControllerConfig) -> Self {
+        MotionController {
+            current_target: None,
+            path: None,
+            position_goal: None,
+            status: topics::MotionStatus {
+                state: MotionState::Idle,
+                mode: MotionRequestMode::Stop,
+                distance_error: 0.0,
+                heading_error: 0.0,
+                goal: [0.0, 0.0],
+                goal_heading: 0.0,
+            },
+            status_time: Instant::now(),
+            current_position: [0.0, 0.0],
+            current_orientation: 0.0,
+            position_updated: false,
+            linear_output: SlewLimiter::new(config.max_linear_acceleration as f32),
+            angular_output: SlewLimiter::new(config.max_angular_acceleration as f32),
+            ramp_time: Instant::now(),
+            config,
+            stale_command_count: 0,
+            stats_send_time: Instant::now(),
+        }
+    }
+
+    /**
+     * A target or path is stale if it was sent more than `max_command_age_ms` ago. Stop requests
+     * are never stale, as stopping is always safe. Packets stamped in the future count as new.
+     */
+    fn is_stale(&self, packet: &PacketFormat<PacketData>) -> bool {
+        if let PacketData::MotionTargetRequest(topics::MotionTargetRequest {
+            motion_mode: MotionRequestMode::Stop,
+            ..
+        }) = packet.data
+        {
+            return false;
+        }
+        let age = get_current_time().saturating_sub(packet.time);
+        age > self.config.max_command_age_ms * 1000
+    }
+
+    fn stats_to_log(&self) -> DiagnosticMsg {
+        let mut values = heapless::Vec::<topics::DiagnosticKeyValue, 16>::new();
+        values
+            .push(topics::DiagnosticKeyValue {
+                key: HString::from_str("stale_cmds").unwrap(),
+                value: hformat!("{}", self.stale_command_count).unwrap(),
+            })
+            .ok();
+
+        DiagnosticMsg {
+            level: topics::DiagnosticStatus::Ok,
+            name: HString::from_str("motion_ctrl").unwrap(),
+            message: HString::from_str("").unwrap(),
+            values,
+        }
+    }
+
+    fn send_stats(&mut self, ctx: &mut NodeContext) {
+        if self.stats_send_time.elapsed() >= Duration::from_secs(1) {
+            ctx.publish(PacketData::DiagnosticMsg(self.stats_to_log()));
+            self.stats_send_time = Instant::now();
+        }
+    }
+
+    fn current_mode(&self) -> MotionRequestMode {
+        self.current_target
+            .as_ref()
+            .map_or(MotionRequestMode::Stop, |target| target.mode.clone())
+    }
+
+    /** The position target's position and heading, if there is one */
+    fn current_goal(&self) -> ([f32; 2], f32) {
+        match &self.current_target {
+            Some(MotionTarget {
+                linear,
+                angular,
+                mode: MotionRequestMode::Position,
+            }) => ([linear[0] as f32, linear[1] as f32], *angular as f32),
+            _ => ([0.0, 0.0], 0.0),
+        }
+    }
+
+    /**
+     * Sends a `MotionStatus` if the state or goal changed, every tick while moving, and otherwise
+     * every `STATUS_INTERVAL`.
+     */
+    fn update_status(
+        &mut self,
+        ctx: &mut NodeContext,
+        state: MotionState,
+        step: Option<&PathStep>,
+    ) {
+        let (goal, goal_heading) = self.current_goal();
+        let changed = self.status.state != state
+            || self.status.goal != goal
+            || self.status.goal_heading != goal_heading;
+        self.status = topics::MotionStatus {
+            state,
+            mode: self.current_mode(),
+            distance_error: step.map_or(0.0, |step| step.distance_remaining) as f32,
+            heading_error: step.map_or(0.0, |step| step.heading_error) as f32,
+            goal,
+            goal_heading,
+        };
+        if changed
+            || self.status.state == MotionState::Moving
+            || self.status_time.elapsed() >= STATUS_INTERVAL
+        {
+            ctx.publish(PacketData::MotionStatus(self.status.clone()));
+            self.status_time = Instant::now();
+        }
+    }
+
+    /** Sends the last status again if it's due */
+    fn resend_status(&mut self, ctx: &mut NodeContext) {
+        if self.status_time.elapsed() >= STATUS_INTERVAL {
+            ctx.publish(PacketData::MotionStatus(self.status.clone()));
+            self.status_time = Instant::now();
+        }
+    }
+
+    /**
+     * Tells whoever is waiting on the current position target or path that it won't be reached.
+     * The status keeps its mode and errors from when it was aborted. Returns true if there was a
+     * goal to abort.
+     */
+    fn abort_goal(&mut self, ctx: &mut NodeContext) -> bool {
+        self.abort_path(ctx);
+        let mode = self.current_mode();
+        let is_goal = matches!(mode, MotionRequestMode::Position | MotionRequestMode::Path);
+        if !is_goal || self.status.state != MotionState::Moving {
+            return false;
+        }
+        self.status.state = MotionState::Aborted;
+        ctx.publish(PacketData::MotionStatus(self.status.clone()));
+        self.status_time = Instant::now();
+        true
+    }
+
+    /** Stops, and reports that the request that was just made can't be carried out */
+    fn fail(&mut self, ctx: &mut NodeContext, mode: MotionRequestMode) {
+        self.current_target = Some(MotionTarget {
+            linear: [0.0, 0.0],
+            angular: 0.0,
+            mode: MotionRequestMode::Stop,
+        });
+        self.status = topics::MotionStatus {
+            state: MotionState::Failed,
+            mode,
+            distance_error: 0.0,
+            heading_error: 0.0,
+            goal: [0.0, 0.0],
+            goal_heading: 0.0,
+        };
+        ctx.publish(PacketData::MotionStatus(self.status.clone()));
+        self.status_time = Instant::now();
+    }
+
+    /**
+     * Drives to the position target, then turns on the spot to its heading. Returns `None`
+     * while there's no position estimate to drive from.
+     */
+    fn drive_to_position(
+        &mut self,
+        ctx: &mut NodeContext,
+    ) -> Option<topics::MotionVelocityRequest> {
+        if !self.position_updated {
+            return None;
+        }
+        let goal = self.position_goal.as_mut()?;
+        let step = goal.update(self.current_position, self.current_orientation, &self.config);
+        let state = match step.status {
+            topics::PathStatus::Complete => MotionState::Arrived,
+            _ => MotionState::Moving,
+        };
+        self.update_status(ctx, state, Some(&step));
+        Some(topics::MotionVelocityRequest {
+            linear_velocity: step.linear_velocity as f32,
+            angular_velocity: step.angular_velocity as f32,
+        })
+    }
+
+    /**
+     * Limits the change from the last command, which the motors are running at until this one
+     * arrives, to the configured accelerations over the time since it was sent. Timers fire late
+     * (or early), so that is measured rather than assumed. Stopping is ramped too.
+     */
+    fn ramp(
+        &mut self,
+        cmd: topics::MotionVelocityRequest,
+        now: Instant,
+    ) -> topics::MotionVelocityRequest {
+        // After a pause in the commands, pick up from the last one as if it had just been sent
+        let interval = Duration::from_millis(self.config.publish_interval_ms);
+        let elapsed = now.saturating_duration_since(self.ramp_time);
+        let dt = elapsed.min(2 * interval).as_secs_f32();
+        self.ramp_time = now;
+        topics::MotionVelocityRequest {
+            linear_velocity: self.linear_output.step(cmd.linear_velocity, dt),
+            angular_velocity: self.angular_output.step(cmd.angular_velocity, dt),
+        }
+    }
+
+    /** Starts following a new path, aborting the one before */
+    fn on_path(&mut self, ctx: &mut NodeContext, requester: Option<u16>, path: &topics::Path) {
+        let waypoints: Vec<[f64; 2]> = path
+            .waypoints
+            .iter()
+            .map(|waypoint| [waypoint[0] as f64, waypoint[1] as f64])
+            .collect();
+        let final_heading = path.final_heading.map(|heading| heading as f64);
+        let Some(tracker) = PathTracker::new(self.current_position, &waypoints, final_heading)
+        else {
+            let rejected = topics::PathProgress {
+                id: path.id,
+                status: topics::PathStatus::Rejected,
+                waypoint: 0,
+                distance_remaining: 0.0,
+                cross_track_error: 0.0,
+            };
+            ctx.send_to(requester, PacketData::PathProgress(rejected));
+            self.abort_goal(ctx);
+            self.fail(ctx, MotionRequestMode::Path);
+            return;
+        };
+        self.abort_goal(ctx);
+        self.path = Some(ActivePath {
+            id: path.id,
+            requester,
+            tracker,
+            progress: None,
+            progress_time: Instant::now(),
+        });
+        self.current_target = Some(MotionTarget {
+            linear: [0.0, 0.0],
+            angular: 0.0,
+            mode: MotionRequestMode::Path,
+        });
+    }
+
+    /** Tells the requester of the current path, if it isn't finished, that it won't be */
+    fn abort_path(&mut self, ctx: &mut NodeContext) {
+        if let Some(path) = &mut self.path
+            && !path.is_finished()
+        {
+            let progress = path.progress(topics::PathStatus::Aborted, None);
+            ctx.send_to(path.requester, PacketData::PathProgress(progress.clone()));
+            path.progress = Some(progress);
+        }
+    }
+
+    /**
+     * Steps the path tracker, and sends progress when the waypoint or status changes. Returns
+     * `None` while there's no position estimate to follow the path from.
+     */
+    fn follow_path(&mut self, ctx: &mut NodeContext) -> Option<topics::MotionVelocityRequest> {
+        if !self.position_updated {
+            return None;
+        }
+        let path = self.path.as_mut()?;
+        let step = path.tracker.update(
+            self.current_position,
+            self.current_orientation,
+            &self.config,
+        );
+        let progress = path.progress(step.status.clone(), Some(&step));
+        let changed = path.progress.as_ref().is_none_or(|last| {
+            last.status != progress.status || last.waypoint != progress.waypoint
+        });
+        if changed || path.progress_time.elapsed() >= STATUS_INTERVAL {
+            ctx.send_to(path.requester, PacketData::PathProgress(progress.clone()));
+            path.progress = Some(progress);
+            path.progress_time = Instant::now();
+        }
+        if step.status == topics::PathStatus::Complete {
+            self.update_status(ctx, MotionState::Arrived, Some(&step));
+            self.current_target = Some(MotionTarget {
+                linear: [0.0, 0.0],
+                angular: 0.0,
+                mode: MotionRequestMode::Stop,
+            });
+        } else {
+            self.update_status(ctx, MotionState::Moving, Some(&step));
+        }
+        Some(topics::MotionVelocityRequest {
+            linear_velocity: step.linear_velocity as f32,
+            angular_velocity: step.angular_velocity as f32,
+        })
+    }
+}
+
+impl Node for MotionController {
+    fn name(&self) -> &str {
+        "motion_ctrl"
+    }
+
+    fn subscriptions(&self) -> Vec<String> {
         let motion_target_topic = PacketData::MotionTargetRequest(topics::MotionTargetRequest {
             linear: [0.0, 0.0],
             angular: 0.0,
@@ -41,75 +397,138 @@ impl MotionController {
         })
         .topic()
         .to_string();
-        client.borrow_mut().subscriptions.insert(motion_target_topic);
 
-        // Subscribe to PositionEstimate
         let position_estimate_topic = PacketData::PositionEstimate(topics::PositionEstimate {
             timestamp: 0,
             position: [0.0, 0.0],
             orientation: 0.0,
+            covariance: [0.0; 9],
         })
         .topic()
         .to_string();
-        client.borrow_mut().subscriptions.insert(position_estimate_topic);
 
-        MotionController {
-            client,
-            current_target: None,
-            current_position: [0.0, 0.0],
-            current_orientation: 0.0,
-            position_updated: false,
-            last_packet_time: Instant::now(),
-            packet_interval: Duration::from_millis(100),
-        }
+        let pose_reset_topic = PacketData::PoseReset(topics::PoseReset {
+            timestamp: 0,
+            position: [0.0, 0.0],
+            orientation: 0.0,
+        })
+        .topic()
+        .to_string();
+
+        let path_topic = PacketData::Path(topics::Path {
+            id: 0,
+            waypoints: heapless::Vec::new(),
+            final_heading: None,
+        })
+        .topic()
+        .to_string();
+
+        vec![motion_target_topic, position_estimate_topic, pose_reset_topic, path_topic]
     }
 
-    pub fn tick(&mut self) {
-        // Process incoming packets
-        let packets = self.client.borrow_mut().fetch_all();
-        for packet in packets {
-            match &packet.data {
-                PacketData::MotionTargetRequest(req) => {
-                    // Update the current target
+    fn timer_period(&self) -> Option<Duration> {
+        Some(Duration::from_millis(self.config.publish_interval_ms))
+    }
+
+    fn on_packet(&mut self, ctx: &mut NodeContext, packet: &PacketFormat<PacketData>) {
+        match &packet.data {
+            PacketData::MotionTargetRequest(req) => {
+                if self.is_stale(packet) {
+                    self.stale_command_count += 1;
+                    return;
+                }
+                let aborted = match req.motion_mode {
+                    MotionRequestMode::Path => {
+                        // Carry on with the last path from wherever the robot is now
+                        if self.current_mode() == MotionRequestMode::Path {
+                            return;
+                        }
+                        let Some(path) = self.path.as_mut().filter(|path| !path.is_complete())
+                        else {
+                            return;
+                        };
+                        path.progress = None;
+                        false
+                    }
+                    MotionRequestMode::Position => {
+                        let aborted = self.abort_goal(ctx);
+                        let target = [req.linear[0], req.linear[1]];
+                        let heading = Some(req.angular);
+                        let Some(goal) = PathTracker::new(self.current_position, &[target], heading)
+                        else {
+                            return self.fail(ctx, MotionRequestMode::Position);
+                        };
+                        self.position_goal = Some(goal);
+                        aborted
+                    }
+                    MotionRequestMode::Velocity | MotionRequestMode::Stop => self.abort_goal(ctx),
+                };
+                // Update the current target
+                self.current_target = Some(MotionTarget {
+                    linear: req.linear,
+                    angular: req.angular,
+                    mode: req.motion_mode.clone(),
+                });
+                if req.motion_mode == MotionRequestMode::Stop && !aborted {
+                    self.update_status(ctx, MotionState::Idle, None);
+                }
+            }
+            PacketData::Path(path) => {
+                if self.is_stale(packet) {
+                    self.stale_command_count += 1;
+                    return;
+                }
+                self.on_path(ctx, packet.from, path);
+            }
+            PacketData::PositionEstimate(estimate) => {
+                // Update current position estimate
+                self.current_position[0] = estimate.position[0] as f64;
+                self.current_position[1] = estimate.position[1] as f64;
+                self.current_orientation = estimate.orientation as f64;
+                self.position_updated = true;
+            }
+            PacketData::PoseReset(reset) => {
+                // Position targets and paths were set in the old frame, so they no longer mean the
+                // same place
+                if let Some(MotionTarget {
+                    mode: MotionRequestMode::Position | MotionRequestMode::Path,
+                    ..
+                }) = &self.current_target
+                {
+                    self.abort_goal(ctx);
+                    self.path = None;
                     self.current_target = Some(MotionTarget {
-                        linear: req.linear,
-                        angular: req.angular,
-                        mode: req.motion_mode.clone(),
+                        linear: [0.0, 0.0],
+                        angular: 0.0,
+                        mode: MotionRequestMode::Stop,
                     });
                 }
-                PacketData::PositionEstimate(estimate) => {
-                    // Update current position estimate
-                    self.current_position[0] = estimate.position[0] as f64;
-                    self.current_position[1] = estimate.position[1] as f64;
-                    self.current_orientation = estimate.orientation as f64;
-                    self.position_updated = true;
-                }
-                _ => {}
+                self.current_position[0] = reset.position[0] as f64;
+                self.current_position[1] = reset.position[1] as f64;
+                self.current_orientation = reset.orientation as f64;
             }
+            _ => {}
         }
+    }
 
+    fn on_timer(&mut self, ctx: &mut NodeContext) {
         // Generate velocity commands based on current target
-        if let Some(target) = &self.current_target && self.last_packet_time.elapsed() >= self.packet_interval {
-            self.last_packet_time = Instant::now();
+        if let Some(target) = &self.current_target {
             let velocity_cmd = match target.mode {
+                MotionRequestMode::Path => self.follow_path(ctx),
                 MotionRequestMode::Velocity => {
                     // Direct velocity control - just pass through the target
-                    Some(topics::MotionVelocityRequest {
+                    let cmd = topics::MotionVelocityRequest {
                         linear_velocity: target.linear[0] as f32,
                         angular_velocity: target.angular as f32,
-                    })
-                }
-                MotionRequestMode::Position => {
-                    // Position control - compute velocity to reach target
-                    if self.position_updated {
-                        self.compute_velocity_to_target(target)
-                    } else {
-                        // No position estimate yet, don't move
-                        None
-                    }
+                    };
+                    self.update_status(ctx, MotionState::Moving, None);
+                    Some(cmd)
                 }
+                MotionRequestMode::Position => self.drive_to_position(ctx),
                 MotionRequestMode::Stop => {
                     // Stop mode - send zero velocities
+                    self.resend_status(ctx);
                     Some(topics::MotionVelocityRequest {
                         linear_velocity: 0.0,
                         angular_velocity: 0.0,
@@ -117,76 +536,310 @@ impl MotionController {
                 }
             };
 
-            // Send velocity command
             if let Some(cmd) = velocity_cmd {
-                let packet = PacketFormat {
-                    to: None,
-                    from: None,
-                    data: PacketData::MotionVelocityRequest(cmd),
-                    time: get_current_time(),
-                    id: 0,
-                };
+                let cmd = self.ramp(cmd, Instant::now());
+                ctx.publish(PacketData::MotionVelocityRequest(cmd));
+            }
+        } else {
+            self.resend_status(ctx);
+        }
+
+        self.send_stats(ctx);
+    }
+
+    /**
+     * Leave the motors stopped rather than coasting on the last command. This isn't ramped, as
+     * nothing will be sending commands after it.
+     */
+    fn on_stop(&mut self, ctx: &mut NodeContext) {
+        self.abort_goal(ctx);
+        self.linear_output.value = 0.0;
+        self.angular_output.value = 0.0;
+        ctx.publish(PacketData::MotionVelocityRequest(topics::MotionVelocityRequest {
+            linear_velocity: 0.0,
+            angular_velocity: 0.0,
+        }));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use packet_router::Client;
+    use std::cell::RefCell;
+
+    fn packet(data: PacketData) -> PacketFormat<PacketData> {
+        PacketFormat {
+            to: None,
+            from: Some(7),
+            data,
+            time: get_current_time(),
+            id: 0,
+        }
+    }
+
+    fn estimate(position: [f32; 2], orientation: f32) -> PacketFormat<PacketData> {
+        packet(PacketData::PositionEstimate(topics::PositionEstimate {
+            timestamp: 0,
+            position,
+            orientation,
+            covariance: [0.0; 9],
+        }))
+    }
 
-                self.client.borrow_mut().send(packet);
-                self.last_packet_time = Instant::now();
+    type TestClient = RefCell<Client<PacketFormat<PacketData>>>;
+
+    fn sent(client: &TestClient) -> Vec<PacketFormat<PacketData>> {
+        std::mem::take(&mut client.borrow_mut().client_to_router)
+    }
+
+    /** Progress sent, and where to */
+    fn progress(sent: &[PacketFormat<PacketData>]) -> Vec<(Option<u16>, topics::PathStatus)> {
+        sent.iter()
+            .filter_map(|packet| match &packet.data {
+                PacketData::PathProgress(progress) => Some((packet.to, progress.status.clone())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn velocities(sent: &[PacketFormat<PacketData>]) -> Vec<(f32, f32)> {
+        sent.iter()
+            .filter_map(|packet| match &packet.data {
+                PacketData::MotionVelocityRequest(velocity) => {
+                    Some((velocity.linear_velocity, velocity.angular_velocity))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    /** Runs the timer as if a publish interval had passed since the last command */
+    fn tick(controller: &mut MotionController, ctx: &mut NodeContext) {
+        controller.ramp_time -= Duration::from_millis(controller.config.publish_interval_ms);
+        controller.on_timer(ctx);
+    }
+
+    #[test]
+    fn test_acceleration_is_limited() {
+        let config = MotionControllerConfig::default();
+        let interval = Duration::from_millis(config.publish_interval_ms);
+        // Allowing a millisecond for the test itself to run
+        let dt = interval.as_secs_f32() + 0.001;
+        let max_linear_change = config.max_linear_acceleration as f32 * dt;
+        let max_angular_change = config.max_angular_acceleration as f32 * dt;
+        let mut controller = MotionController::new(config);
+        let client = RefCell::new(Client::default());
+        let mut message_id = 0;
+        let mut ctx = NodeContext {
+            client: &client,
+            message_id: &mut message_id,
+        };
+
+        // A step up, then a step down to stopped
+        let mut commands = Vec::new();
+        for (linear, angular, mode) in [
+            (0.5, -2.0, MotionRequestMode::Velocity),
+            (0.0, 0.0, MotionRequestMode::Stop),
+        ] {
+            let target = topics::MotionTargetRequest {
+                linear: [linear, 0.0],
+                angular,
+                motion_mode: mode,
+            };
+            controller.on_packet(&mut ctx, &packet(PacketData::MotionTargetRequest(target)));
+            for _ in 0..20 {
+                tick(&mut controller, &mut ctx);
             }
+            commands.extend(velocities(&sent(&client)));
+            assert_eq!(commands.last(), Some(&(linear as f32, angular as f32)));
+        }
+
+        let mut previous = (0.0, 0.0);
+        for command in commands {
+            assert!((command.0 - previous.0).abs() <= max_linear_change, "{:?}", command);
+            assert!((command.1 - previous.1).abs() <= max_angular_change, "{:?}", command);
+            previous = command;
         }
     }
 
-    fn compute_velocity_to_target(&self, target: &MotionTarget) -> Option<topics::MotionVelocityRequest> {
-        // Calculate error in position
-        let dx = target.linear[0] - self.current_position[0];
-        let dy = target.linear[1] - self.current_position[1];
-        
-        let distance = (dx * dx + dy * dy).sqrt();
-        
-        // If we're close enough to target, stop
-        const POSITION_TOLERANCE: f64 = 0.05; // 5cm tolerance
-        if distance < POSITION_TOLERANCE {
-            return Some(topics::MotionVelocityRequest {
-                linear_velocity: 0.0,
+    #[test]
+    fn test_ramp_uses_measured_time() {
+        let mut controller = MotionController::new(MotionControllerConfig::default());
+        let mut now = controller.ramp_time;
+        let mut ramp_after = |millis| {
+            now += Duration::from_millis(millis);
+            let cmd = topics::MotionVelocityRequest {
+                linear_velocity: 1.0,
                 angular_velocity: 0.0,
-            });
+            };
+            controller.ramp(cmd, now).linear_velocity
+        };
+
+        // At 0.5m/s^2, whether the timer is early or late
+        assert!((ramp_after(50) - 0.025).abs() < 1e-6);
+        assert!((ramp_after(150) - 0.1).abs() < 1e-6);
+        // A pause counts as two intervals, so the motors don't jump
+        assert!((ramp_after(5_000) - 0.2).abs() < 1e-6);
+    }
+
+    /** The states in the `MotionStatus`es sent */
+    fn states(sent: &[PacketFormat<PacketData>]) -> Vec<MotionState> {
+        sent.iter()
+            .filter_map(|packet| match &packet.data {
+                PacketData::MotionStatus(status) => Some(status.state.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn target(linear: [f64; 2], angular: f64, mode: MotionRequestMode) -> PacketFormat<PacketData> {
+        packet(PacketData::MotionTargetRequest(topics::MotionTargetRequest {
+            linear,
+            angular,
+            motion_mode: mode,
+        }))
+    }
+
+    #[test]
+    fn test_stale_commands_rejected() {
+        let config = MotionControllerConfig::default();
+        let old_time = get_current_time() - 2 * config.max_command_age_ms * 1000;
+        let mut controller = MotionController::new(config);
+        let client = RefCell::new(Client::default());
+        let mut message_id = 0;
+        let mut ctx = NodeContext {
+            client: &client,
+            message_id: &mut message_id,
+        };
+        controller.on_packet(&mut ctx, &estimate([0.0, 0.0], 0.0));
+
+        let mut go = target([1.0, 0.0], 0.0, MotionRequestMode::Position);
+        go.time = old_time;
+        controller.on_packet(&mut ctx, &go);
+        let mut path = topics::Path {
+            id: 1,
+            waypoints: heapless::Vec::new(),
+            final_heading: None,
+        };
+        path.waypoints.push([0.0, 1.0]).unwrap();
+        let mut path = packet(PacketData::Path(path));
+        path.time = old_time;
+        controller.on_packet(&mut ctx, &path);
+        assert_eq!(controller.stale_command_count, 2);
+        assert_eq!(controller.current_mode(), MotionRequestMode::Stop);
+        assert!(controller.path.is_none());
+
+        // Stopping is always accepted, however old
+        let mut go = target([1.0, 0.0], 0.0, MotionRequestMode::Position);
+        go.time = get_current_time();
+        controller.on_packet(&mut ctx, &go);
+        assert_eq!(controller.current_mode(), MotionRequestMode::Position);
+        let mut stop = target([0.0, 0.0], 0.0, MotionRequestMode::Stop);
+        stop.time = old_time;
+        controller.on_packet(&mut ctx, &stop);
+        assert_eq!(controller.current_mode(), MotionRequestMode::Stop);
+        assert_eq!(controller.stale_command_count, 2);
+    }
+
+    #[test]
+    fn test_position_mode() {
+        let mut controller = MotionController::new(MotionControllerConfig::default());
+        let client = RefCell::new(Client::default());
+        let mut message_id = 0;
+        let mut ctx = NodeContext {
+            client: &client,
+            message_id: &mut message_id,
+        };
+        controller.on_packet(&mut ctx, &estimate([0.0, 0.0], 0.0));
+        let facing_left = std::f64::consts::FRAC_PI_2;
+        let position_target = target([0.0, 1.0], facing_left, MotionRequestMode::Position);
+        controller.on_packet(&mut ctx, &position_target);
+        controller.on_timer(&mut ctx);
+        let packets = sent(&client);
+        assert_eq!(states(&packets), vec![MotionState::Moving]);
+        assert!(velocities(&packets)[0].0 > 0.0);
+        assert!((controller.status.distance_error - 1.0).abs() < 1e-6);
+
+        // Drives to the point, then turns on the spot
+        controller.on_packet(&mut ctx, &estimate([0.0, 0.99], 0.0));
+        for _ in 0..10 {
+            controller.on_timer(&mut ctx);
         }
-        
-        // Calculate desired heading to target
-        let desired_heading = -dx.atan2(dy);
-        
-        // Calculate heading error
-        let mut heading_error = desired_heading - self.current_orientation;
-        
-        // Normalize heading error to [-PI, PI]
-        while heading_error > std::f64::consts::PI {
-            heading_error -= 2.0 * std::f64::consts::PI;
-        }
-        while heading_error < -std::f64::consts::PI {
-            heading_error += 2.0 * std::f64::consts::PI;
-        }
-        println!("Heading error: {}", heading_error);
-        
-        // Simple proportional controller
-        const KP_LINEAR: f64 = 2.0;  // Linear velocity gain
-        const KP_ANGULAR: f64 = 2.0; // Angular velocity gain
-        const MAX_LINEAR_VEL: f64 = 0.5;  // m/s
-        const MAX_ANGULAR_VEL: f64 = 2.0; // rad/s
-        
-        // Compute velocities
-        let mut linear_velocity = KP_LINEAR * distance;
-        let mut angular_velocity = KP_ANGULAR * heading_error;
-        
-        // Clamp velocities to max values
-        linear_velocity = linear_velocity.clamp(-MAX_LINEAR_VEL, MAX_LINEAR_VEL);
-        angular_velocity = angular_velocity.clamp(-MAX_ANGULAR_VEL, MAX_ANGULAR_VEL);
-        
-        // Reduce linear velocity if we need to turn significantly
-        // if heading_error.abs() > std::f64::consts::PI / 4.0 {
-        //     // If heading error is more than 45 degrees, slow down forward motion
-        //     linear_velocity *= 0.3;
-        // }
-        
-        Some(topics::MotionVelocityRequest {
-            linear_velocity: linear_velocity as f32,
-            angular_velocity: angular_velocity as f32,
-        })
+        let packets = sent(&client);
+        assert!(states(&packets).iter().all(|state| *state == MotionState::Moving));
+        assert!(velocities(&packets).iter().all(|(_, angular)| *angular > 0.0));
+
+        controller.on_packet(&mut ctx, &estimate([0.0, 0.99], 1.56));
+        controller.on_timer(&mut ctx);
+        controller.on_timer(&mut ctx);
+        assert_eq!(states(&sent(&client)), vec![MotionState::Arrived]);
+
+        // Stopping while arrived is just idle, stopping on the way is an abort
+        controller.on_packet(&mut ctx, &target([0.0, 0.0], 0.0, MotionRequestMode::Stop));
+        assert_eq!(states(&sent(&client)), vec![MotionState::Idle]);
+        controller.on_packet(&mut ctx, &target([2.0, 0.0], 0.0, MotionRequestMode::Position));
+        controller.on_timer(&mut ctx);
+        controller.on_packet(&mut ctx, &target([0.0, 0.0], 0.0, MotionRequestMode::Stop));
+        let packets = sent(&client);
+        assert_eq!(states(&packets), vec![MotionState::Moving, MotionState::Aborted]);
+
+        controller.on_packet(&mut ctx, &target([f64::NAN, 0.0], 0.0, MotionRequestMode::Position));
+        assert_eq!(states(&sent(&client)), vec![MotionState::Failed]);
+        assert_eq!(controller.current_mode(), MotionRequestMode::Stop);
+    }
+
+    #[test]
+    fn test_path_mode() {
+        let mut controller = MotionController::new(MotionControllerConfig::default());
+        let client = RefCell::new(Client::default());
+        let mut message_id = 0;
+        let mut ctx = NodeContext {
+            client: &client,
+            message_id: &mut message_id,
+        };
+        controller.on_packet(&mut ctx, &estimate([0.0, 0.0], 0.0));
+        let mut path = topics::Path {
+            id: 3,
+            waypoints: heapless::Vec::new(),
+            final_heading: None,
+        };
+        controller.on_packet(&mut ctx, &packet(PacketData::Path(path.clone())));
+        assert_eq!(progress(&sent(&client)), vec![(Some(7), topics::PathStatus::Rejected)]);
+
+        path.waypoints.push([0.0, 1.0]).unwrap();
+        controller.on_packet(&mut ctx, &packet(PacketData::Path(path.clone())));
+        controller.on_timer(&mut ctx);
+        let packets = sent(&client);
+        assert!(packets.iter().any(|packet| matches!(
+            &packet.data,
+            PacketData::MotionVelocityRequest(velocity) if velocity.linear_velocity > 0.0
+        )));
+        assert_eq!(progress(&packets), vec![(Some(7), topics::PathStatus::Following)]);
+
+        // Another request interrupts the path, and it can be picked up again after
+        let stop = topics::MotionTargetRequest {
+            linear: [0.0, 0.0],
+            angular: 0.0,
+            motion_mode: MotionRequestMode::Stop,
+        };
+        controller.on_packet(&mut ctx, &packet(PacketData::MotionTargetRequest(stop)));
+        assert_eq!(progress(&sent(&client)), vec![(Some(7), topics::PathStatus::Aborted)]);
+        let resume = topics::MotionTargetRequest {
+            linear: [0.0, 0.0],
+            angular: 0.0,
+            motion_mode: MotionRequestMode::Path,
+        };
+        controller.on_packet(&mut ctx, &packet(PacketData::MotionTargetRequest(resume)));
+        controller.on_packet(&mut ctx, &estimate([0.0, 0.99], 0.0));
+        controller.on_timer(&mut ctx);
+        assert_eq!(progress(&sent(&client)), vec![(Some(7), topics::PathStatus::Complete)]);
+
+        // Then it holds still
+        tick(&mut controller, &mut ctx);
+        assert!(matches!(
+            &sent(&client).last().unwrap().data,
+            PacketData::MotionVelocityRequest(velocity) if velocity.linear_velocity == 0.0
+        ));
     }
 }
@@ -0,0 +1,463 @@
+use std::fmt;
+
+/** Largest packet we'll accept. Ours are a few hundred bytes. */
+pub const MAX_PACKET_BYTES: usize = 256 * 1024;
+
+/** A message the broker publishes for the client if it disappears without disconnecting */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Will {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub qos: u8,
+    pub retain: bool,
+}
+
+/**
+ * The MQTT 3.1.1 control packets the bridge uses, in both directions so that tests can play the
+ * broker. Unsubscribing and the QoS 2 handshake aren't needed.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum MqttPacket {
+    Connect {
+        client_id: String,
+        keep_alive_s: u16,
+        clean_session: bool,
+        will: Option<Will>,
+        username: Option<String>,
+        password: Option<String>,
+    },
+    ConnAck {
+        session_present: bool,
+        return_code: u8,
+    },
+    Publish {
+        topic: String,
+        payload: Vec<u8>,
+        qos: u8,
+        retain: bool,
+        /** Only for QoS 1 and 2 */
+        packet_id: Option<u16>,
+    },
+    PubAck {
+        packet_id: u16,
+    },
+    Subscribe {
+        packet_id: u16,
+        /** Topic filters with the QoS asked for */
+        filters: Vec<(String, u8)>,
+    },
+    SubAck {
+        packet_id: u16,
+        return_codes: Vec<u8>,
+    },
+    PingReq,
+    PingResp,
+    Disconnect,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum MqttError {
+    TooLarge,
+    /** A string or payload field longer than its 16 bit length prefix can describe */
+    FieldTooLong,
+    Malformed(&'static str),
+}
+
+impl fmt::Display for MqttError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MqttError::TooLarge => write!(f, "packet is larger than {} bytes", MAX_PACKET_BYTES),
+            MqttError::FieldTooLong => write!(f, "field is longer than {} bytes", u16::MAX),
+            MqttError::Malformed(reason) => write!(f, "malformed packet: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for MqttError {}
+
+const CONNECT: u8 = 1;
+const CONNACK: u8 = 2;
+const PUBLISH: u8 = 3;
+const PUBACK: u8 = 4;
+const SUBSCRIBE: u8 = 8;
+const SUBACK: u8 = 9;
+const PINGREQ: u8 = 12;
+const PINGRESP: u8 = 13;
+const DISCONNECT: u8 = 14;
+
+fn put_u16(buffer: &mut Vec<u8>, value: u16) {
+    buffer.extend_from_slice(&value.to_be_bytes());
+}
+
+fn put_bytes(buffer: &mut Vec<u8>, bytes: &[u8]) -> Result<(), MqttError> {
+    let len = u16::try_from(bytes.len()).map_err(|_| MqttError::FieldTooLong)?;
+    put_u16(buffer, len);
+    buffer.extend_from_slice(bytes);
+    Ok(())
+}
+
+/** Reads the fields of one packet's body */
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn u8(&mut self) -> Result<u8, MqttError> {
+        let (first, rest) = self
+            .data
+            .split_first()
+            .ok_or(MqttError::Malformed("packet ends early"))?;
+        self.data = rest;
+        Ok(*first)
+    }
+
+    fn u16(&mut self) -> Result<u16, MqttError> {
+        Ok(u16::from_be_bytes([self.u8()?, self.u8()?]))
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8], MqttError> {
+        let len = self.u16()? as usize;
+        if self.data.len() < len {
+            return Err(MqttError::Malformed("packet ends early"));
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn string(&mut self) -> Result<String, MqttError> {
+        String::from_utf8(self.bytes()?.to_vec())
+            .map_err(|_| MqttError::Malformed("string isn't UTF-8"))
+    }
+
+    fn rest(&mut self) -> &'a [u8] {
+        std::mem::take(&mut self.data)
+    }
+}
+
+impl MqttPacket {
+    /** Fails if a field is too long for MQTT, or the whole packet is larger than we'd accept */
+    pub fn encode(&self) -> Result<Vec<u8>, MqttError> {
+        let mut body = Vec::new();
+        let header = match self {
+            MqttPacket::Connect {
+                client_id,
+                keep_alive_s,
+                clean_session,
+                will,
+                username,
+                password,
+            } => {
+                put_bytes(&mut body, b"MQTT")?;
+                body.push(4); // Protocol level 3.1.1
+                let mut flags = 0;
+                if username.is_some() {
+                    flags |= 0x80;
+                }
+                if password.is_some() {
+                    flags |= 0x40;
+                }
+                if let Some(will) = will {
+                    flags |= 0x04 | (will.qos << 3);
+                    if will.retain {
+                        flags |= 0x20;
+                    }
+                }
+                if *clean_session {
+                    flags |= 0x02;
+                }
+                body.push(flags);
+                put_u16(&mut body, *keep_alive_s);
+                put_bytes(&mut body, client_id.as_bytes())?;
+                if let Some(will) = will {
+                    put_bytes(&mut body, will.topic.as_bytes())?;
+                    put_bytes(&mut body, &will.payload)?;
+                }
+                if let Some(username) = username {
+                    put_bytes(&mut body, username.as_bytes())?;
+                }
+                if let Some(password) = password {
+                    put_bytes(&mut body, password.as_bytes())?;
+                }
+                CONNECT << 4
+            }
+            MqttPacket::ConnAck {
+                session_present,
+                return_code,
+            } => {
+                body.push(*session_present as u8);
+                body.push(*return_code);
+                CONNACK << 4
+            }
+            MqttPacket::Publish {
+                topic,
+                payload,
+                qos,
+                retain,
+                packet_id,
+            } => {
+                put_bytes(&mut body, topic.as_bytes())?;
+                if let Some(packet_id) = packet_id {
+                    put_u16(&mut body, *packet_id);
+                }
+                body.extend_from_slice(payload);
+                PUBLISH << 4 | qos << 1 | *retain as u8
+            }
+            MqttPacket::PubAck { packet_id } => {
+                put_u16(&mut body, *packet_id);
+                PUBACK << 4
+            }
+            MqttPacket::Subscribe { packet_id, filters } => {
+                put_u16(&mut body, *packet_id);
+                for (filter, qos) in filters {
+                    put_bytes(&mut body, filter.as_bytes())?;
+                    body.push(*qos);
+                }
+                SUBSCRIBE << 4 | 0x02
+            }
+            MqttPacket::SubAck {
+                packet_id,
+                return_codes,
+            } => {
+                put_u16(&mut body, *packet_id);
+                body.extend_from_slice(return_codes);
+                SUBACK << 4
+            }
+            MqttPacket::PingReq => PINGREQ << 4,
+            MqttPacket::PingResp => PINGRESP << 4,
+            MqttPacket::Disconnect => DISCONNECT << 4,
+        };
+
+        if body.len() > MAX_PACKET_BYTES {
+            return Err(MqttError::TooLarge);
+        }
+        let mut packet = vec![header];
+        // Remaining length, seven bits at a time
+        let mut remaining = body.len();
+        loop {
+            let mut byte = (remaining % 128) as u8;
+            remaining /= 128;
+            if remaining > 0 {
+                byte |= 0x80;
+            }
+            packet.push(byte);
+            if remaining == 0 {
+                break;
+            }
+        }
+        packet.extend_from_slice(&body);
+        Ok(packet)
+    }
+
+    /**
+     * Decodes the packet at the start of `data`. Returns `None` if it hasn't all arrived yet,
+     * otherwise the packet and how many bytes it used.
+     */
+    pub fn decode(data: &[u8]) -> Result<Option<(MqttPacket, usize)>, MqttError> {
+        let Some(&header) = data.first() else {
+            return Ok(None);
+        };
+        let mut remaining = 0usize;
+        let mut header_len = 1;
+        loop {
+            let Some(&byte) = data.get(header_len) else {
+                return Ok(None);
+            };
+            remaining |= ((byte & 0x7F) as usize) << (7 * (header_len - 1));
+            header_len += 1;
+            if byte & 0x80 == 0 {
+                break;
+            }
+            if header_len > 4 {
+                return Err(MqttError::Malformed("remaining length is too long"));
+            }
+        }
+        if remaining > MAX_PACKET_BYTES {
+            return Err(MqttError::TooLarge);
+        }
+        let used = header_len + remaining;
+        if data.len() < used {
+            return Ok(None);
+        }
+        let mut body = Reader {
+            data: &data[header_len..used],
+        };
+
+        let flags = header & 0x0F;
+        let packet = match header >> 4 {
+            CONNECT => {
+                if body.bytes()? != b"MQTT" || body.u8()? != 4 {
+                    return Err(MqttError::Malformed("only MQTT 3.1.1 is supported"));
+                }
+                let connect_flags = body.u8()?;
+                let keep_alive_s = body.u16()?;
+                let client_id = body.string()?;
+                let will = match connect_flags & 0x04 {
+                    0 => None,
+                    _ => Some(Will {
+                        topic: body.string()?,
+                        payload: body.bytes()?.to_vec(),
+                        qos: (connect_flags >> 3) & 0x03,
+                        retain: connect_flags & 0x20 != 0,
+                    }),
+                };
+                let username = match connect_flags & 0x80 {
+                    0 => None,
+                    _ => Some(body.string()?),
+                };
+                let password = match connect_flags & 0x40 {
+                    0 => None,
+                    _ => Some(body.string()?),
+                };
+                MqttPacket::Connect {
+                    client_id,
+                    keep_alive_s,
+                    clean_session: connect_flags & 0x02 != 0,
+                    will,
+                    username,
+                    password,
+                }
+            }
+            CONNACK => MqttPacket::ConnAck {
+                session_present: body.u8()? & 0x01 != 0,
+                return_code: body.u8()?,
+            },
+            PUBLISH => {
+                let qos = (flags >> 1) & 0x03;
+                let topic = body.string()?;
+                let packet_id = match qos {
+                    0 => None,
+                    _ => Some(body.u16()?),
+                };
+                MqttPacket::Publish {
+                    topic,
+                    payload: body.rest().to_vec(),
+                    qos,
+                    retain: flags & 0x01 != 0,
+                    packet_id,
+                }
+            }
+            PUBACK => MqttPacket::PubAck {
+                packet_id: body.u16()?,
+            },
+            SUBSCRIBE => {
+                let packet_id = body.u16()?;
+                let mut filters = Vec::new();
+                while !body.data.is_empty() {
+                    filters.push((body.string()?, body.u8()?));
+                }
+                MqttPacket::Subscribe { packet_id, filters }
+            }
+            SUBACK => MqttPacket::SubAck {
+                packet_id: body.u16()?,
+                return_codes: body.rest().to_vec(),
+            },
+            PINGREQ => MqttPacket::PingReq,
+            PINGRESP => MqttPacket::PingResp,
+            DISCONNECT => MqttPacket::Disconnect,
+            _ => return Err(MqttError::Malformed("unsupported packet type")),
+        };
+        Ok(Some((packet, used)))
+    }
+}
+
+/** Whether `topic` matches a subscription filter, with `+` and `#` wildcards */
+pub fn topic_matches(filter: &str, topic: &str) -> bool {
+    let mut topic_levels = topic.split('/');
+    for level in filter.split('/') {
+        match (level, topic_levels.next()) {
+            ("#", _) => return true,
+            ("+", Some(_)) => {}
+            (level, Some(topic_level)) if level == topic_level => {}
+            _ => return false,
+        }
+    }
+    topic_levels.next().is_none()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let packets = [
+            MqttPacket::Connect {
+                client_id: "slambot-test".to_string(),
+                keep_alive_s: 30,
+                clean_session: true,
+                will: Some(Will {
+                    topic: "slambot/test/DiagnosticMsg".to_string(),
+                    payload: b"{}".to_vec(),
+                    qos: 1,
+                    retain: true,
+                }),
+                username: Some("robot".to_string()),
+                password: Some("secret".to_string()),
+            },
+            MqttPacket::ConnAck {
+                session_present: false,
+                return_code: 0,
+            },
+            MqttPacket::Publish {
+                topic: "a/b".to_string(),
+                // Long enough to need two bytes of remaining length
+                payload: vec![7; 300],
+                qos: 1,
+                retain: false,
+                packet_id: Some(9),
+            },
+            MqttPacket::Subscribe {
+                packet_id: 2,
+                filters: vec![("a/+".to_string(), 0), ("b/#".to_string(), 1)],
+            },
+            MqttPacket::SubAck {
+                packet_id: 2,
+                return_codes: vec![0, 1],
+            },
+            MqttPacket::PingReq,
+            MqttPacket::Disconnect,
+        ];
+        for packet in packets {
+            let encoded = packet.encode().unwrap();
+            assert_eq!(
+                MqttPacket::decode(&encoded).unwrap(),
+                Some((packet, encoded.len()))
+            );
+            // Nothing is decoded until the whole packet is there
+            assert_eq!(MqttPacket::decode(&encoded[..encoded.len() - 1]).unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn test_encode_errors() {
+        let publish = |topic_len, payload_len| MqttPacket::Publish {
+            topic: "t".repeat(topic_len),
+            payload: vec![0; payload_len],
+            qos: 0,
+            retain: false,
+            packet_id: None,
+        };
+        assert!(publish(usize::from(u16::MAX), 0).encode().is_ok());
+        assert_eq!(publish(usize::from(u16::MAX) + 1, 0).encode(), Err(MqttError::FieldTooLong));
+        assert_eq!(publish(1, MAX_PACKET_BYTES).encode(), Err(MqttError::TooLarge));
+    }
+
+    #[test]
+    fn test_malformed() {
+        assert_eq!(
+            MqttPacket::decode(&[0x30, 0xFF, 0xFF, 0xFF, 0xFF]),
+            Err(MqttError::Malformed("remaining length is too long"))
+        );
+        assert_eq!(MqttPacket::decode(&[0x30, 0xFF, 0xFF, 0x7F]), Err(MqttError::TooLarge));
+        assert!(MqttPacket::decode(&[0x30, 0x01, 0x00]).is_err());
+    }
+
+    #[test]
+    fn test_topic_matches() {
+        assert!(topic_matches("slambot/+/MotionTargetRequest", "slambot/r1/MotionTargetRequest"));
+        assert!(topic_matches("slambot/#", "slambot/r1/PositionEstimate"));
+        assert!(!topic_matches("slambot/+", "slambot/r1/PositionEstimate"));
+        assert!(!topic_matches("slambot/r1/a", "slambot/r1"));
+    }
+}
@@ -0,0 +1,519 @@
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::str::FromStr;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+use heapless::{String as HString, format as hformat};
+use topics::{DiagnosticMsg, DiagnosticStatus, PacketData, PacketDataTrait, PacketFormat};
+
+use crate::config::{MqttConfig, MqttEncoding};
+use crate::node::{Node, NodeContext};
+use crate::nodes::mqtt::{MqttPacket, Will};
+use crate::nodes::websocket_client::parse_json_packet;
+
+/** Longest a connection attempt waits for the broker to answer */
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/** Publishes are dropped rather than queued beyond this, while the broker is slow */
+const MAX_PENDING_BYTES: usize = 64 * 1024;
+
+/** Packet ids of the subscription and of status messages, the only packets that need one */
+const SUBSCRIBE_ID: u16 = 1;
+const STATUS_ID: u16 = 2;
+
+#[derive(Default)]
+pub struct MqttStats {
+    pub connected: bool,
+    pub connects: u32,
+    pub tx_messages: u32,
+    pub tx_bytes: u32,
+    pub rx_messages: u32,
+    pub dropped: u32,
+    pub decode_error_count: u32,
+}
+
+impl MqttStats {
+    fn to_log(&self) -> DiagnosticMsg {
+        let mut values = heapless::Vec::<topics::DiagnosticKeyValue, 16>::new();
+        values
+            .push(topics::DiagnosticKeyValue {
+                key: HString::from_str("connects").unwrap(),
+                value: hformat!("{}", self.connects).unwrap(),
+            })
+            .ok();
+        values
+            .push(topics::DiagnosticKeyValue {
+                key: HString::from_str("tx_messages").unwrap(),
+                value: hformat!("{}", self.tx_messages).unwrap(),
+            })
+            .ok();
+        values
+            .push(topics::DiagnosticKeyValue {
+                key: HString::from_str("tx_bytes").unwrap(),
+                value: hformat!("{}", self.tx_bytes).unwrap(),
+            })
+            .ok();
+        values
+            .push(topics::DiagnosticKeyValue {
+                key: HString::from_str("rx_messages").unwrap(),
+                value: hformat!("{}", self.rx_messages).unwrap(),
+            })
+            .ok();
+        values
+            .push(topics::DiagnosticKeyValue {
+                key: HString::from_str("dropped").unwrap(),
+                value: hformat!("{}", self.dropped).unwrap(),
+            })
+            .ok();
+        values
+            .push(topics::DiagnosticKeyValue {
+                key: HString::from_str("decode_errors").unwrap(),
+                value: hformat!("{}", self.decode_error_count).unwrap(),
+            })
+            .ok();
+
+        let (level, message) = match self.connected {
+            true => (DiagnosticStatus::Ok, "connected"),
+            false => (DiagnosticStatus::Warn, "not connected"),
+        };
+        DiagnosticMsg {
+            level,
+            name: HString::from_str("mqtt_stats").unwrap(),
+            message: HString::from_str(message).unwrap(),
+            values,
+        }
+    }
+}
+
+/** A connection to the broker */
+struct Connection {
+    stream: TcpStream,
+    read_buffer: Vec<u8>,
+    write_buffer: Vec<u8>,
+    /** The broker has accepted the connection */
+    accepted: bool,
+    last_sent: Instant,
+    last_received: Instant,
+}
+
+/**
+ * Bridges topics to an MQTT broker, as `slambot/<robot>/<topic>`. Packets on the configured
+ * outbound topics are published as JSON (as `Log` prints them) or CBOR, and packets published to
+ * the inbound topics by others are sent to the router. Times and ids can be left out of inbound
+ * JSON.
+ *
+ * The broker is reconnected to with exponential backoff. A retained `DiagnosticMsg` says whether
+ * the robot is connected: `Ok` when it connects, and a `Stale` last will that the broker
+ * publishes if the connection is lost.
+ */
+pub struct MqttBridge {
+    config: MqttConfig,
+    /**
+     * A connection attempt in progress. It is made on its own thread, so that looking up the
+     * broker's name and waiting for it to answer don't hold up the scheduler.
+     */
+    connecting: Option<mpsc::Receiver<std::io::Result<TcpStream>>>,
+    connection: Option<Connection>,
+    backoff: Duration,
+    next_attempt: Instant,
+    pub stats: MqttStats,
+}
+
+impl MqttBridge {
+    pub fn new(config: MqttConfig) -> Self {
+        MqttBridge {
+            backoff: Duration::from_millis(config.reconnect_min_ms),
+            config,
+            connecting: None,
+            connection: None,
+            next_attempt: Instant::now(),
+            stats: MqttStats::default(),
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connection.as_ref().is_some_and(|connection| connection.accepted)
+    }
+
+    fn topic(&self, topic: &str) -> String {
+        format!("slambot/{}/{}", self.config.robot, topic)
+    }
+
+    fn client_id(&self) -> String {
+        match &self.config.client_id {
+            Some(client_id) => client_id.clone(),
+            None => format!("slambot-{}", self.config.robot),
+        }
+    }
+
+    fn keep_alive(&self) -> Duration {
+        Duration::from_secs(self.config.keep_alive_s as u64)
+    }
+
+    fn encode(&self, packet: &PacketFormat<PacketData>) -> Option<Vec<u8>> {
+        match self.config.encoding {
+            MqttEncoding::Json => serde_json::to_vec(packet).ok(),
+            MqttEncoding::Cbor => minicbor_serde::to_vec(packet).ok(),
+        }
+    }
+
+    fn decode(&self, payload: &[u8]) -> Option<PacketFormat<PacketData>> {
+        match self.config.encoding {
+            MqttEncoding::Json => serde_json::from_slice(payload)
+                .ok()
+                .and_then(|value| parse_json_packet(value).ok()),
+            MqttEncoding::Cbor => minicbor_serde::from_slice(payload).ok(),
+        }
+    }
+
+    /** The robot's connection status, retained by the broker for anyone who subscribes later */
+    fn status(&self, ctx: &mut NodeContext, level: DiagnosticStatus, message: &str) -> Will {
+        let data = PacketData::DiagnosticMsg(DiagnosticMsg {
+            level,
+            name: HString::from_str("mqtt").unwrap(),
+            message: HString::from_str(message).unwrap(),
+            values: heapless::Vec::new(),
+        });
+        let packet = ctx.new_packet(None, data);
+        Will {
+            topic: self.topic("DiagnosticMsg"),
+            payload: self.encode(&packet).unwrap_or_default(),
+            qos: 1,
+            retain: true,
+        }
+    }
+
+    fn publish_status(
+        &self,
+        ctx: &mut NodeContext,
+        level: DiagnosticStatus,
+        message: &str,
+    ) -> MqttPacket {
+        let status = self.status(ctx, level, message);
+        MqttPacket::Publish {
+            topic: status.topic,
+            payload: status.payload,
+            qos: status.qos,
+            retain: status.retain,
+            packet_id: Some(STATUS_ID),
+        }
+    }
+
+    /** Starts connecting on a helper thread, which hands the stream back to `poll_connect` */
+    fn connect(&mut self) {
+        let broker = self.config.broker.clone();
+        let (sender, receiver) = mpsc::channel();
+        let spawned = std::thread::Builder::new()
+            .name("mqtt-connect".to_string())
+            .spawn(move || {
+                let address = broker.to_socket_addrs().and_then(|mut addresses| {
+                    addresses
+                        .next()
+                        .ok_or_else(|| std::io::Error::new(ErrorKind::NotFound, "no address"))
+                });
+                let stream = address
+                    .and_then(|address| TcpStream::connect_timeout(&address, CONNECT_TIMEOUT));
+                sender.send(stream).ok();
+            });
+        match spawned {
+            Ok(_) => self.connecting = Some(receiver),
+            Err(err) => {
+                eprintln!("Can't start connecting to MQTT broker: {}", err);
+                self.retry_later();
+            }
+        }
+    }
+
+    /** Takes the stream once the helper thread has connected. Returns true if it finished. */
+    fn poll_connect(&mut self, ctx: &mut NodeContext) -> bool {
+        let Some(connecting) = &self.connecting else {
+            return false;
+        };
+        let stream = match connecting.try_recv() {
+            Ok(stream) => stream,
+            Err(mpsc::TryRecvError::Empty) => return false,
+            Err(mpsc::TryRecvError::Disconnected) => {
+                Err(std::io::Error::other("connecting thread stopped"))
+            }
+        };
+        self.connecting = None;
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                eprintln!("Can't connect to MQTT broker {}: {}", self.config.broker, err);
+                self.retry_later();
+                return true;
+            }
+        };
+        stream.set_nonblocking(true).expect("Failed to set non-blocking");
+        stream.set_nodelay(true).ok();
+
+        let connect = MqttPacket::Connect {
+            client_id: self.client_id(),
+            keep_alive_s: self.config.keep_alive_s,
+            clean_session: true,
+            will: Some(self.status(ctx, DiagnosticStatus::Stale, "disconnected")),
+            username: self.config.username.clone(),
+            password: self.config.password.clone(),
+        };
+        let write_buffer = match connect.encode() {
+            Ok(encoded) => encoded,
+            Err(err) => {
+                eprintln!("Can't connect to MQTT broker {}: {}", self.config.broker, err);
+                self.retry_later();
+                return true;
+            }
+        };
+        self.connection = Some(Connection {
+            stream,
+            read_buffer: Vec::new(),
+            write_buffer,
+            accepted: false,
+            last_sent: Instant::now(),
+            last_received: Instant::now(),
+        });
+        true
+    }
+
+    fn retry_later(&mut self) {
+        self.connection = None;
+        self.stats.connected = false;
+        self.next_attempt = Instant::now() + self.backoff;
+        self.backoff = (self.backoff * 2).min(Duration::from_millis(self.config.reconnect_max_ms));
+    }
+
+    fn disconnected(&mut self, reason: &str) {
+        eprintln!("MQTT connection to {} lost: {}", self.config.broker, reason);
+        self.retry_later();
+    }
+
+    /** Queues a packet to send, unless the broker isn't keeping up */
+    fn queue(&mut self, packet: MqttPacket) -> bool {
+        let Some(connection) = &mut self.connection else {
+            return false;
+        };
+        let Ok(encoded) = packet.encode() else {
+            return false;
+        };
+        if connection.write_buffer.len() + encoded.len() > MAX_PENDING_BYTES {
+            return false;
+        }
+        connection.write_buffer.extend_from_slice(&encoded);
+        true
+    }
+
+    fn on_accepted(&mut self, ctx: &mut NodeContext) {
+        println!("Connected to MQTT broker {}", self.config.broker);
+        self.backoff = Duration::from_millis(self.config.reconnect_min_ms);
+        self.stats.connected = true;
+        self.stats.connects += 1;
+        if let Some(connection) = &mut self.connection {
+            connection.accepted = true;
+        }
+        let online = self.publish_status(ctx, DiagnosticStatus::Ok, "connected");
+        self.queue(online);
+        if !self.config.subscribe_topics.is_empty() {
+            let filters = self
+                .config
+                .subscribe_topics
+                .iter()
+                .map(|topic| (self.topic(topic), 0))
+                .collect();
+            self.queue(MqttPacket::Subscribe {
+                packet_id: SUBSCRIBE_ID,
+                filters,
+            });
+        }
+    }
+
+    fn receive(&mut self, ctx: &mut NodeContext, packet: MqttPacket) {
+        match packet {
+            MqttPacket::ConnAck { return_code: 0, .. } => self.on_accepted(ctx),
+            MqttPacket::ConnAck { return_code, .. } => {
+                self.disconnected(&format!("broker refused the connection ({})", return_code));
+            }
+            MqttPacket::Publish {
+                topic,
+                payload,
+                packet_id,
+                ..
+            } => {
+                if let Some(packet_id) = packet_id {
+                    self.queue(MqttPacket::PubAck { packet_id });
+                }
+                self.stats.rx_messages += 1;
+                let Some(mut packet) = self.decode(&payload) else {
+                    self.stats.decode_error_count += 1;
+                    return;
+                };
+                // Only the topics subscribed to, and only on their own MQTT topic
+                if topic != self.topic(packet.data.topic())
+                    || !self
+                        .config
+                        .subscribe_topics
+                        .iter()
+                        .any(|subscribed| subscribed == packet.data.topic())
+                {
+                    self.stats.decode_error_count += 1;
+                    return;
+                }
+                // MQTT clients don't share the robot's ids, and JSON ones can leave them out
+                packet.from = None;
+                packet.id = ctx.next_id();
+                ctx.send(packet);
+            }
+            MqttPacket::SubAck { return_codes, .. } if return_codes.contains(&0x80) => {
+                eprintln!("MQTT broker refused a subscription: {:?}", return_codes);
+            }
+            // Nothing to do for acknowledgements of our status messages and pings
+            _ => {}
+        }
+    }
+
+    /** Reads and writes whatever the socket allows. Returns true if anything happened. */
+    fn service(&mut self, ctx: &mut NodeContext) -> bool {
+        let mut did_work = false;
+        let keep_alive = self.keep_alive();
+        let Some(connection) = &mut self.connection else {
+            return false;
+        };
+
+        let mut buffer = [0u8; 4096];
+        let mut closed = None;
+        loop {
+            match connection.stream.read(&mut buffer) {
+                Ok(0) => {
+                    closed = Some("closed by the broker".to_string());
+                    break;
+                }
+                Ok(count) => {
+                    did_work = true;
+                    connection.last_received = Instant::now();
+                    connection.read_buffer.extend_from_slice(&buffer[..count]);
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    closed = Some(e.to_string());
+                    break;
+                }
+            }
+        }
+
+        let mut received = Vec::new();
+        loop {
+            match MqttPacket::decode(&connection.read_buffer) {
+                Ok(Some((packet, used))) => {
+                    connection.read_buffer.drain(..used);
+                    received.push(packet);
+                }
+                Ok(None) => break,
+                Err(err) => {
+                    closed = Some(err.to_string());
+                    break;
+                }
+            }
+        }
+
+        // Keep the connection alive, and give up on it if the broker has gone quiet
+        if connection.accepted
+            && connection.write_buffer.is_empty()
+            && connection.last_sent.elapsed() >= keep_alive / 2
+        {
+            let ping = MqttPacket::PingReq.encode().expect("Pings have no fields");
+            connection.write_buffer.extend_from_slice(&ping);
+        }
+        if connection.last_received.elapsed() > keep_alive * 3 / 2 {
+            closed.get_or_insert_with(|| "broker stopped responding".to_string());
+        }
+
+        while !connection.write_buffer.is_empty() {
+            match connection.stream.write(&connection.write_buffer) {
+                Ok(0) => break,
+                Ok(written) => {
+                    did_work = true;
+                    connection.write_buffer.drain(..written);
+                    connection.last_sent = Instant::now();
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    closed.get_or_insert(e.to_string());
+                    break;
+                }
+            }
+        }
+
+        for packet in received {
+            self.receive(ctx, packet);
+        }
+        if let Some(reason) = closed {
+            self.disconnected(&reason);
+        }
+        did_work
+    }
+}
+
+impl Node for MqttBridge {
+    fn name(&self) -> &str {
+        "mqtt"
+    }
+
+    fn subscriptions(&self) -> Vec<String> {
+        self.config.publish_topics.clone()
+    }
+
+    fn timer_period(&self) -> Option<Duration> {
+        Some(Duration::from_secs(1))
+    }
+
+    fn on_packet(&mut self, _ctx: &mut NodeContext, packet: &PacketFormat<PacketData>) {
+        if !self.is_connected() {
+            return;
+        }
+        let Some(payload) = self.encode(packet) else {
+            self.stats.dropped += 1;
+            return;
+        };
+        let payload_len = payload.len() as u32;
+        let publish = MqttPacket::Publish {
+            topic: self.topic(packet.data.topic()),
+            payload,
+            qos: 0,
+            retain: false,
+            packet_id: None,
+        };
+        if self.queue(publish) {
+            self.stats.tx_messages += 1;
+            self.stats.tx_bytes += payload_len;
+        } else {
+            self.stats.dropped += 1;
+        }
+    }
+
+    fn on_timer(&mut self, ctx: &mut NodeContext) {
+        ctx.publish(PacketData::DiagnosticMsg(self.stats.to_log()));
+    }
+
+    fn on_poll(&mut self, ctx: &mut NodeContext) -> bool {
+        let idle = self.connection.is_none() && self.connecting.is_none();
+        if idle && Instant::now() >= self.next_attempt {
+            self.connect();
+        }
+        self.poll_connect(ctx) | self.service(ctx)
+    }
+
+    fn on_stop(&mut self, ctx: &mut NodeContext) {
+        // Leave cleanly, so the broker doesn't publish the last will
+        let offline = self.publish_status(ctx, DiagnosticStatus::Stale, "disconnected");
+        if self.is_connected()
+            && let Some(connection) = &mut self.connection
+        {
+            connection.stream.set_nonblocking(false).ok();
+            connection.write_buffer.extend_from_slice(&offline.encode().unwrap_or_default());
+            let disconnect = MqttPacket::Disconnect.encode().expect("Disconnects have no fields");
+            connection.write_buffer.extend_from_slice(&disconnect);
+            connection.stream.write_all(&connection.write_buffer).ok();
+        }
+        self.connection = None;
+    }
+}
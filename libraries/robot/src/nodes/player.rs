@@ -0,0 +1,428 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use heapless::{String as HString, format as hformat};
+use topics::{DiagnosticMsg, PacketData, PacketDataTrait, PacketFormat};
+
+use crate::bag::{BagReader, BagRecord};
+use crate::config::PlayerConfig;
+use crate::node::{Node, NodeContext};
+
+/**
+ * Replays a bag recorded by the `Recorder` into the router, with the original spacing between
+ * packets (scaled by `rate`).
+ *
+ * Packets are republished as if they were new: they get the current time and a fresh id. Times
+ * inside the packet data (eg `OdometryDelta.start_time`) are left as recorded. Packets that were
+ * addressed to a particular client (eg clock responses) are not replayed, as that client doesn't
+ * exist any more.
+ */
+pub struct Player {
+    config: PlayerConfig,
+    reader: Option<BagReader<BufReader<File>>>,
+
+    // The part of the bag being played, in bag (receive) time
+    bag_start_time: u64,
+    bag_end_time: u64,
+
+    next_chunk: usize,
+    pending: VecDeque<BagRecord>,
+    playback_start: Instant,
+    finished: bool,
+
+    played_count: u32,
+    loop_count: u32,
+    read_error_count: u32,
+    stats_send_time: Instant,
+}
+
+impl Player {
+    pub fn new(config: PlayerConfig) -> Self {
+        Player {
+            config,
+            reader: None,
+            bag_start_time: 0,
+            bag_end_time: 0,
+            next_chunk: 0,
+            pending: VecDeque::new(),
+            playback_start: Instant::now(),
+            finished: false,
+            played_count: 0,
+            loop_count: 0,
+            read_error_count: 0,
+            stats_send_time: Instant::now(),
+        }
+    }
+
+    fn open(&mut self) {
+        let reader = match BagReader::open(Path::new(&self.config.file)) {
+            Ok(reader) => reader,
+            Err(err) => {
+                eprintln!("Failed to open bag {}: {}", self.config.file, err);
+                self.read_error_count += 1;
+                self.finished = true;
+                return;
+            }
+        };
+
+        let summary = reader.summary();
+        let (Some(start_time), Some(end_time)) = (summary.start_time(), summary.end_time()) else {
+            println!("Bag {} is empty, nothing to play", self.config.file);
+            self.finished = true;
+            return;
+        };
+        let offset_us = |seconds: f64| (seconds * 1e6) as u64;
+        self.bag_start_time = start_time + offset_us(self.config.start_offset_s);
+        self.bag_end_time = match self.config.end_offset_s {
+            Some(end_offset_s) => end_time.min(start_time + offset_us(end_offset_s)),
+            None => end_time,
+        };
+        println!(
+            "Playing {} at {}x: {:.3}s of {} records",
+            self.config.file,
+            self.config.rate,
+            self.bag_end_time.saturating_sub(self.bag_start_time) as f64 / 1e6,
+            summary.record_count(),
+        );
+        self.reader = Some(reader);
+        self.restart();
+    }
+
+    /** Goes back to the start offset. */
+    fn restart(&mut self) {
+        let bag_start_time = self.bag_start_time;
+        if let Some(reader) = self.reader.as_ref() {
+            // Skip chunks that end before the start offset
+            self.next_chunk = reader
+                .summary()
+                .chunks
+                .iter()
+                .position(|chunk| chunk.end_time >= bag_start_time)
+                .unwrap_or(reader.summary().chunks.len());
+        }
+        self.pending.clear();
+        self.playback_start = Instant::now();
+    }
+
+    /** Where playback has got to, in bag time */
+    fn current_bag_time(&self) -> u64 {
+        let elapsed = self.playback_start.elapsed().as_secs_f64() * self.config.rate;
+        self.bag_start_time + (elapsed * 1e6) as u64
+    }
+
+    /** Loads the next chunk if needed. Returns false at the end of the bag. */
+    fn fill_pending(&mut self) -> bool {
+        while self.pending.is_empty() {
+            let Some(reader) = self.reader.as_mut() else {
+                return false;
+            };
+            if self.next_chunk >= reader.summary().chunks.len() {
+                return false;
+            }
+            match reader.chunk_records(self.next_chunk) {
+                Ok(records) => self.pending.extend(records),
+                Err(err) => {
+                    eprintln!("Failed to read bag {}: {}", self.config.file, err);
+                    self.read_error_count += 1;
+                }
+            }
+            self.next_chunk += 1;
+        }
+        true
+    }
+
+    fn should_play(&self, packet: &PacketFormat<PacketData>) -> bool {
+        if packet.to.is_some() {
+            return false;
+        }
+        let topic = packet.data.topic();
+        let included = self
+            .config
+            .topics
+            .iter()
+            .any(|included| included == "all" || included == topic);
+        included && !self.config.exclude_topics.iter().any(|excluded| excluded == topic)
+    }
+
+    fn end_of_bag(&mut self) {
+        if self.config.loop_playback {
+            self.loop_count += 1;
+            self.restart();
+        } else {
+            println!("Finished playing {}", self.config.file);
+            self.finished = true;
+        }
+    }
+
+    /** Publishes everything that is due. Returns true if anything was published. */
+    fn play_due(&mut self, ctx: &mut NodeContext) -> bool {
+        let now = self.current_bag_time();
+        let mut did_work = false;
+        loop {
+            if !self.fill_pending() {
+                self.end_of_bag();
+                break;
+            }
+            let receive_time = self.pending.front().map_or(0, |r| r.receive_time);
+            if receive_time > self.bag_end_time {
+                self.end_of_bag();
+                break;
+            }
+            if receive_time > now {
+                break;
+            }
+            let record = self.pending.pop_front().unwrap();
+            if receive_time < self.bag_start_time || !self.should_play(&record.packet) {
+                continue;
+            }
+            ctx.publish(record.packet.data);
+            self.played_count += 1;
+            did_work = true;
+        }
+        did_work
+    }
+
+    fn stats_to_log(&self) -> DiagnosticMsg {
+        let mut values = heapless::Vec::<topics::DiagnosticKeyValue, 16>::new();
+        values
+            .push(topics::DiagnosticKeyValue {
+                key: HString::from_str("played").unwrap(),
+                value: hformat!("{}", self.played_count).unwrap(),
+            })
+            .ok();
+        values
+            .push(topics::DiagnosticKeyValue {
+                key: HString::from_str("loops").unwrap(),
+                value: hformat!("{}", self.loop_count).unwrap(),
+            })
+            .ok();
+        values
+            .push(topics::DiagnosticKeyValue {
+                key: HString::from_str("read_errors").unwrap(),
+                value: hformat!("{}", self.read_error_count).unwrap(),
+            })
+            .ok();
+        let progress = self.current_bag_time().saturating_sub(self.bag_start_time) as f64 / 1e6;
+        values
+            .push(topics::DiagnosticKeyValue {
+                key: HString::from_str("position_s").unwrap(),
+                value: hformat!("{:.1}", progress).unwrap(),
+            })
+            .ok();
+
+        DiagnosticMsg {
+            level: if self.read_error_count == 0 {
+                topics::DiagnosticStatus::Ok
+            } else {
+                topics::DiagnosticStatus::Warn
+            },
+            name: HString::from_str("player").unwrap(),
+            message: if self.finished {
+                HString::from_str("finished").unwrap()
+            } else {
+                HString::from_str("playing").unwrap()
+            },
+            values,
+        }
+    }
+}
+
+impl Node for Player {
+    fn name(&self) -> &str {
+        "player"
+    }
+
+    fn on_start(&mut self, _ctx: &mut NodeContext) {
+        self.open();
+    }
+
+    fn on_poll(&mut self, ctx: &mut NodeContext) -> bool {
+        if self.stats_send_time.elapsed() >= Duration::from_secs(1) {
+            ctx.publish(PacketData::DiagnosticMsg(self.stats_to_log()));
+            self.stats_send_time = Instant::now();
+        }
+        if self.finished {
+            return false;
+        }
+        self.play_due(ctx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bag::BagWriter;
+    use packet_router::Client;
+    use std::cell::RefCell;
+    use std::path::PathBuf;
+
+    const MS: u64 = 1000;
+
+    /** A bag with a ClockRequest every 100ms and an OdometryDelta every 200ms, for 1s */
+    fn write_bag(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("player_{}_{}.sbag", name, std::process::id()));
+        let mut writer = BagWriter::create(&path).unwrap();
+        for i in 0..10u32 {
+            let receive_time = 1_000_000 + i as u64 * 100 * MS;
+            let clock = PacketFormat {
+                to: None,
+                from: Some(1),
+                data: PacketData::ClockRequest(topics::ClockRequest { request_time: i as u64 }),
+                time: receive_time,
+                id: i,
+            };
+            writer.write(receive_time, &clock).unwrap();
+            if i % 2 == 0 {
+                let odometry = PacketFormat {
+                    to: None,
+                    from: Some(2),
+                    data: PacketData::OdometryDelta(topics::OdometryDelta {
+                        start_time: 0,
+                        end_time: 0,
+                        delta_position: [0.1, 0.0],
+                        delta_orientation: 0.0,
+                    }),
+                    time: receive_time,
+                    id: i,
+                };
+                writer.write(receive_time, &odometry).unwrap();
+            }
+            // A reply to a client that won't exist during playback
+            let reply = PacketFormat {
+                to: Some(5),
+                from: None,
+                data: PacketData::ClockRequest(topics::ClockRequest { request_time: 99 }),
+                time: receive_time,
+                id: 0,
+            };
+            writer.write(receive_time, &reply).unwrap();
+            writer.flush().unwrap();
+        }
+        writer.finish().unwrap();
+        path
+    }
+
+    fn config(path: &Path) -> PlayerConfig {
+        PlayerConfig {
+            enabled: true,
+            file: path.to_string_lossy().to_string(),
+            rate: 100.0,
+            ..Default::default()
+        }
+    }
+
+    /** Polls the player until it finishes (or `max_polls` runs out), returning what it published */
+    fn play(player: &mut Player, max_polls: usize) -> Vec<PacketData> {
+        let client = RefCell::new(Client::default());
+        let mut message_id = 0;
+        let mut ctx = NodeContext {
+            client: &client,
+            message_id: &mut message_id,
+        };
+        player.on_start(&mut ctx);
+        for _ in 0..max_polls {
+            if player.finished {
+                break;
+            }
+            player.on_poll(&mut ctx);
+            std::thread::sleep(Duration::from_micros(200));
+        }
+        client
+            .into_inner()
+            .client_to_router
+            .into_iter()
+            .map(|packet| packet.data)
+            .filter(|data| !matches!(data, PacketData::DiagnosticMsg(_)))
+            .collect()
+    }
+
+    fn clock_requests(played: &[PacketData]) -> Vec<u64> {
+        played
+            .iter()
+            .filter_map(|data| match data {
+                PacketData::ClockRequest(req) => Some(req.request_time),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_plays_everything_once() {
+        let path = write_bag("all");
+        let mut player = Player::new(config(&path));
+        let played = play(&mut player, 10_000);
+        std::fs::remove_file(&path).ok();
+
+        assert!(player.finished);
+        // Addressed replies are skipped
+        assert_eq!(clock_requests(&played), (0..10).collect::<Vec<u64>>());
+        assert_eq!(played.len(), 15);
+    }
+
+    #[test]
+    fn test_offsets_and_topic_filter() {
+        let path = write_bag("filter");
+        let mut player = Player::new(PlayerConfig {
+            start_offset_s: 0.25,
+            end_offset_s: Some(0.75),
+            topics: vec!["ClockRequest".to_string()],
+            ..config(&path)
+        });
+        let played = play(&mut player, 10_000);
+        std::fs::remove_file(&path).ok();
+
+        assert_eq!(clock_requests(&played), [3, 4, 5, 6, 7]);
+        assert_eq!(played.len(), 5);
+    }
+
+    #[test]
+    fn test_exclude_topics() {
+        let path = write_bag("exclude");
+        let mut player = Player::new(PlayerConfig {
+            exclude_topics: vec!["ClockRequest".to_string()],
+            ..config(&path)
+        });
+        let played = play(&mut player, 10_000);
+        std::fs::remove_file(&path).ok();
+
+        assert_eq!(played.len(), 5);
+        assert!(played.iter().all(|data| matches!(data, PacketData::OdometryDelta(_))));
+    }
+
+    #[test]
+    fn test_rate_scales_timing() {
+        let path = write_bag("rate");
+        let mut player = Player::new(PlayerConfig {
+            rate: 10.0,
+            ..config(&path)
+        });
+        let start = Instant::now();
+        play(&mut player, 10_000);
+        std::fs::remove_file(&path).ok();
+
+        // 0.9s of bag at 10x
+        assert!(player.finished);
+        assert!(start.elapsed() >= Duration::from_millis(90));
+    }
+
+    #[test]
+    fn test_loop() {
+        let path = write_bag("loop");
+        let mut player = Player::new(PlayerConfig {
+            rate: 1000.0,
+            loop_playback: true,
+            ..config(&path)
+        });
+        let played = play(&mut player, 200);
+        std::fs::remove_file(&path).ok();
+
+        assert!(!player.finished);
+        assert!(player.loop_count >= 2);
+        let requests = clock_requests(&played);
+        assert_eq!(requests[..12], [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 0, 1]);
+    }
+}
@@ -1,79 +1,630 @@
-use std::time::Instant;
-use std::{cell::RefCell, time::Duration};
-use std::rc::Rc;
-use packet_router::Client;
-use topics::{PacketData, PacketFormat, PacketDataTrait};
+use std::collections::VecDeque;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use heapless::{String as HString, format as hformat};
+use topics::{DiagnosticMsg, PacketData, PacketFormat, PacketDataTrait};
 
+use crate::config::PositionEstimatorConfig;
+use crate::ekf::{Ekf, GyroNoise, OdometryNoise, ORIENTATION, POSE_SIZE};
+use crate::node::{Node, NodeContext};
 use crate::nodes::clock::get_current_time;
+use crate::pose_history::PoseHistory;
 
+/** Odometry deltas this far (us) apart or overlapping still count as consecutive */
+const TIME_TOLERANCE_US: u64 = 1000;
 
+/** Gyro samples are held until odometry covering their time arrives, but no more than this */
+const MAX_QUEUED_IMU: usize = 256;
+
+
+/**
+ * Estimates the robot's pose with an extended Kalman filter (see `ekf`). Odometry drives the
+ * prediction, and the estimate is published with its covariance.
+ *
+ * While `ImuSample`s are arriving the heading is tracked with the gyro, and the wheels' rotation
+ * corrects it and the gyro's bias. Otherwise the heading comes from the wheels alone.
+ *
+ * Everything is integrated by its own timestamps rather than when it arrives. Gyro samples are
+ * applied up to the end of each odometry delta before the delta is, odometry that overlaps time
+ * already integrated is trimmed, and gaps between deltas are remembered so that a delta arriving
+ * late can fill them in. The estimate is stamped with the time it is for, and recent poses are
+ * kept so that other measurements can be matched with the pose at their time.
+ */
 pub struct PositionEstimator {
-    pub client: Rc<RefCell<Client<PacketFormat<PacketData>>>>,
-    position: [f32; 2],
-    orientation: f32,
+    filter: Ekf,
+    history: PoseHistory,
+
+    publish_interval: Duration,
+    use_imu: bool,
+    imu_timeout: Duration,
+
+    /** End of the last odometry delta applied, which is the time the estimate is for */
+    odometry_time: Option<u64>,
+    /** Time spans that no odometry delta has covered */
+    gaps: VecDeque<(u64, u64)>,
 
-    last_estimate_send_time: Instant,
+    /** Gyro samples `(timestamp, yaw rate)` not yet applied, oldest first */
+    imu_queue: VecDeque<(u64, f64)>,
+    /** The gyro has been integrated up to this time, with this yaw rate most recently */
+    gyro_time: Option<u64>,
+    gyro_rate: f64,
+    /** When the last `ImuSample` was received */
+    imu_received: Option<Instant>,
+
+    imu_samples: u32,
+    imu_gaps: u32,
+    late_imu: u32,
+    rejected_odometry: u32,
+    odometry_gaps: u32,
+    odometry_overlaps: u32,
+    late_odometry: u32,
+    duplicate_odometry: u32,
+    pose_resets: u32,
+    rejected_set_pose: u32,
+    stats_send_time: Instant,
 }
 
 
 impl PositionEstimator {
-    pub fn new() -> Self {
-        let client = Rc::new(RefCell::new(Client::default()));
-        
+    pub fn new(config: PositionEstimatorConfig) -> Self {
+        PositionEstimator {
+            filter: Ekf::new(
+                OdometryNoise {
+                    translation: config.translation_noise,
+                    rotation: config.rotation_noise,
+                    drift: config.drift_noise,
+                },
+                GyroNoise {
+                    rate: config.gyro_noise,
+                    bias_drift: config.gyro_bias_drift,
+                    initial_bias: config.initial_gyro_bias,
+                },
+            ),
+            history: PoseHistory::new(Duration::from_millis(config.history_ms)),
+            publish_interval: Duration::from_millis(config.publish_interval_ms),
+            use_imu: config.use_imu,
+            imu_timeout: Duration::from_millis(config.imu_timeout_ms),
+            odometry_time: None,
+            gaps: VecDeque::new(),
+            imu_queue: VecDeque::new(),
+            gyro_time: None,
+            gyro_rate: 0.0,
+            imu_received: None,
+            imu_samples: 0,
+            imu_gaps: 0,
+            late_imu: 0,
+            rejected_odometry: 0,
+            odometry_gaps: 0,
+            odometry_overlaps: 0,
+            late_odometry: 0,
+            duplicate_odometry: 0,
+            pose_resets: 0,
+            rejected_set_pose: 0,
+            stats_send_time: Instant::now(),
+        }
+    }
+
+    pub fn filter(&self) -> &Ekf {
+        &self.filter
+    }
+
+    /** Recent poses, stamped by the synchronized clock */
+    pub fn history(&self) -> &PoseHistory {
+        &self.history
+    }
+
+    /** Where the robot was at `time`, or `None` if that is outside the pose history */
+    pub fn pose_at(&self, time: u64) -> Option<[f64; POSE_SIZE]> {
+        self.history.pose_at(time)
+    }
+
+    /** Whether the gyro is currently tracking the heading */
+    pub fn imu_active(&self) -> bool {
+        self.imu_received.is_some_and(|received| received.elapsed() < self.imu_timeout)
+    }
+
+    pub fn estimate(&self) -> topics::PositionEstimate {
+        let position = self.filter.position();
+        let mut covariance = [0.0; POSE_SIZE * POSE_SIZE];
+        let filter_covariance = self.filter.covariance();
+        for (value, filter_value) in covariance.iter_mut().zip(filter_covariance.as_flattened()) {
+            *value = *filter_value as f32;
+        }
+        topics::PositionEstimate {
+            timestamp: self.odometry_time.unwrap_or_else(get_current_time),
+            position: [position[0] as f32, position[1] as f32],
+            orientation: self.filter.orientation() as f32,
+            covariance,
+        }
+    }
+
+    fn on_imu(&mut self, imu: &topics::ImuSample) {
+        self.imu_samples = self.imu_samples.wrapping_add(1);
+        self.imu_received = Some(Instant::now());
+        if self.gyro_time.is_some_and(|gyro_time| imu.timestamp <= gyro_time) {
+            self.late_imu = self.late_imu.wrapping_add(1);
+            return;
+        }
+        let sample = (imu.timestamp, imu.angular_velocity[2] as f64);
+        let index = self.imu_queue.partition_point(|(timestamp, _)| *timestamp <= imu.timestamp);
+        self.imu_queue.insert(index, sample);
+        while self.imu_queue.len() > MAX_QUEUED_IMU {
+            let (timestamp, yaw_rate) = self.imu_queue.pop_front().unwrap();
+            self.apply_gyro(timestamp, yaw_rate, true);
+        }
+    }
+
+    /**
+     * Integrates a gyro sample, which holds from the previous sample up to `timestamp`. Samples
+     * after a gap aren't integrated across it: the wheels tracked the heading while the IMU was
+     * away.
+     */
+    fn apply_gyro(&mut self, timestamp: u64, yaw_rate: f64, integrate: bool) {
+        if let Some(gyro_time) = self.gyro_time {
+            let dt = timestamp.saturating_sub(gyro_time);
+            if dt > self.imu_timeout.as_micros() as u64 {
+                self.imu_gaps = self.imu_gaps.wrapping_add(1);
+            } else if integrate {
+                self.filter.predict_gyro(yaw_rate, dt as f64 / 1e6);
+            }
+        }
+        self.gyro_time = Some(timestamp);
+        self.gyro_rate = yaw_rate;
+    }
+
+    /** Applies the gyro up to `time`, holding the last yaw rate beyond the last sample */
+    fn apply_gyro_until(&mut self, time: u64, integrate: bool) {
+        while let Some(&(timestamp, yaw_rate)) = self.imu_queue.front()
+            && timestamp <= time
+        {
+            self.imu_queue.pop_front();
+            self.apply_gyro(timestamp, yaw_rate, integrate);
+        }
+        if self.gyro_time.is_some_and(|gyro_time| gyro_time < time) {
+            self.apply_gyro(time, self.gyro_rate, integrate);
+        }
+    }
+
+    fn on_odometry(&mut self, odom: &topics::OdometryDelta) {
+        let mut delta_position = odom.delta_position.map(f64::from);
+        let mut delta_orientation = odom.delta_orientation as f64;
+        if let Some(last_end) = self.odometry_time {
+            if odom.end_time <= last_end + TIME_TOLERANCE_US {
+                self.on_late_odometry(odom);
+                return;
+            }
+            if odom.start_time > last_end + TIME_TOLERANCE_US {
+                self.odometry_gaps = self.odometry_gaps.wrapping_add(1);
+                self.gaps.push_back((last_end, odom.start_time));
+            } else if odom.start_time + TIME_TOLERANCE_US < last_end {
+                // Only the part after what has already been integrated is new
+                self.odometry_overlaps = self.odometry_overlaps.wrapping_add(1);
+                let fraction =
+                    (odom.end_time - last_end) as f64 / (odom.end_time - odom.start_time) as f64;
+                delta_position = delta_position.map(|value| value * fraction);
+                delta_orientation *= fraction;
+            }
+        }
+
+        // The robot was here when the delta started, eg after a gap
+        let position = self.filter.position();
+        let pose = [position[0], position[1], self.filter.orientation()];
+        if self.history.latest().is_none_or(|latest| latest.time < odom.start_time) {
+            self.history.push(odom.start_time, pose);
+        }
+
+        let imu_active = self.imu_active();
+        self.apply_gyro_until(odom.end_time, imu_active);
+        if imu_active {
+            if self.filter.fuse_odometry(delta_position, delta_orientation).is_err() {
+                self.rejected_odometry = self.rejected_odometry.wrapping_add(1);
+            }
+        } else {
+            self.filter.predict_odometry(delta_position, delta_orientation);
+        }
+        self.odometry_time = Some(odom.end_time);
+        let position = self.filter.position();
+        let pose = [position[0], position[1], self.filter.orientation()];
+        self.history.push(odom.end_time, pose);
+        if let Some(oldest) = self.history.oldest() {
+            self.gaps.retain(|(_, end)| *end > oldest.time);
+        }
+    }
+
+    /**
+     * Odometry for time that has already been integrated. If it fills a gap it is applied as it
+     * was at the time, moving the poses after it. Otherwise it is a duplicate.
+     */
+    fn on_late_odometry(&mut self, odom: &topics::OdometryDelta) {
+        let gap = self.gaps.iter().position(|&(start, end)| {
+            odom.start_time + TIME_TOLERANCE_US >= start
+                && odom.end_time <= end + TIME_TOLERANCE_US
+        });
+        let Some(gap) = gap else {
+            self.duplicate_odometry = self.duplicate_odometry.wrapping_add(1);
+            return;
+        };
+        let start_pose = self.history.pose_at(odom.start_time.max(self.gaps[gap].0));
+        let (Some(start_pose), Some(end_pose)) = (start_pose, self.history.pose_at(odom.end_time))
+        else {
+            self.duplicate_odometry = self.duplicate_odometry.wrapping_add(1);
+            return;
+        };
+        self.late_odometry = self.late_odometry.wrapping_add(1);
+
+        // The travel is in the robot's frame at the start of the delta. The gyro already turned
+        // the robot through the gap, so then only the travel is missing. Otherwise everything
+        // after the delta turns about where it ended.
+        let delta_position = odom.delta_position.map(f64::from);
+        let delta_orientation = if self.imu_active() { 0.0 } else { odom.delta_orientation as f64 };
+        let pivot = [end_pose[0], end_pose[1]];
+        let change = self.filter.predict_late_odometry(
+            delta_position,
+            delta_orientation,
+            start_pose[ORIENTATION],
+            pivot,
+        );
+        self.history.shift_from(odom.end_time, pivot, change);
+
+        let (start, end) = self.gaps.remove(gap).unwrap();
+        if odom.end_time + TIME_TOLERANCE_US < end {
+            self.gaps.insert(gap, (odom.end_time, end));
+        }
+        if start + TIME_TOLERANCE_US < odom.start_time {
+            self.gaps.insert(gap, (start, odom.start_time));
+        }
+    }
+
+    /**
+     * Jumps to the requested pose, and tells subscribers with a `PoseReset` and a new estimate.
+     * The pose history starts again from the new pose.
+     */
+    fn on_set_pose(&mut self, ctx: &mut NodeContext, set_pose: &topics::SetPose) {
+        let pose = [
+            set_pose.position[0] as f64,
+            set_pose.position[1] as f64,
+            set_pose.orientation as f64,
+        ];
+        let mut covariance = [[0.0; POSE_SIZE]; POSE_SIZE];
+        if let Some(values) = &set_pose.covariance {
+            for (value, set_value) in covariance.as_flattened_mut().iter_mut().zip(values) {
+                *value = *set_value as f64;
+            }
+        }
+        let diagonal_is_valid = (0..POSE_SIZE).all(|i| covariance[i][i] >= 0.0);
+        if !diagonal_is_valid || self.filter.set_pose(pose, &covariance).is_err() {
+            self.rejected_set_pose = self.rejected_set_pose.wrapping_add(1);
+            return;
+        }
+        self.pose_resets = self.pose_resets.wrapping_add(1);
+
+        self.history.clear();
+        self.gaps.clear();
+        if let Some(time) = self.odometry_time {
+            self.history.push(time, pose);
+        }
+        let estimate = self.estimate();
+        ctx.publish(PacketData::PoseReset(topics::PoseReset {
+            timestamp: estimate.timestamp,
+            position: estimate.position,
+            orientation: estimate.orientation,
+        }));
+        ctx.publish(PacketData::PositionEstimate(estimate));
+    }
+
+    fn stats_to_log(&self) -> DiagnosticMsg {
+        let (gyro_bias, gyro_bias_std) = self.filter.gyro_bias();
+        let mut values = heapless::Vec::<topics::DiagnosticKeyValue, 16>::new();
+        values
+            .push(topics::DiagnosticKeyValue {
+                key: HString::from_str("imu").unwrap(),
+                value: hformat!("{}", self.imu_active()).unwrap(),
+            })
+            .ok();
+        values
+            .push(topics::DiagnosticKeyValue {
+                key: HString::from_str("imu_samples").unwrap(),
+                value: hformat!("{}", self.imu_samples).unwrap(),
+            })
+            .ok();
+        values
+            .push(topics::DiagnosticKeyValue {
+                key: HString::from_str("imu_gaps").unwrap(),
+                value: hformat!("{}", self.imu_gaps).unwrap(),
+            })
+            .ok();
+        values
+            .push(topics::DiagnosticKeyValue {
+                key: HString::from_str("gyro_bias").unwrap(),
+                value: hformat!("{:.5}", gyro_bias).unwrap(),
+            })
+            .ok();
+        values
+            .push(topics::DiagnosticKeyValue {
+                key: HString::from_str("gyro_bias_std").unwrap(),
+                value: hformat!("{:.5}", gyro_bias_std).unwrap(),
+            })
+            .ok();
+        values
+            .push(topics::DiagnosticKeyValue {
+                key: HString::from_str("late_imu").unwrap(),
+                value: hformat!("{}", self.late_imu).unwrap(),
+            })
+            .ok();
+        values
+            .push(topics::DiagnosticKeyValue {
+                key: HString::from_str("rejected_odom").unwrap(),
+                value: hformat!("{}", self.rejected_odometry).unwrap(),
+            })
+            .ok();
+        values
+            .push(topics::DiagnosticKeyValue {
+                key: HString::from_str("odom_gaps").unwrap(),
+                value: hformat!("{}", self.odometry_gaps).unwrap(),
+            })
+            .ok();
+        values
+            .push(topics::DiagnosticKeyValue {
+                key: HString::from_str("odom_overlaps").unwrap(),
+                value: hformat!("{}", self.odometry_overlaps).unwrap(),
+            })
+            .ok();
+        values
+            .push(topics::DiagnosticKeyValue {
+                key: HString::from_str("late_odom").unwrap(),
+                value: hformat!("{}", self.late_odometry).unwrap(),
+            })
+            .ok();
+        values
+            .push(topics::DiagnosticKeyValue {
+                key: HString::from_str("duplicate_odom").unwrap(),
+                value: hformat!("{}", self.duplicate_odometry).unwrap(),
+            })
+            .ok();
+        values
+            .push(topics::DiagnosticKeyValue {
+                key: HString::from_str("pose_resets").unwrap(),
+                value: hformat!("{}", self.pose_resets).unwrap(),
+            })
+            .ok();
+        values
+            .push(topics::DiagnosticKeyValue {
+                key: HString::from_str("bad_set_pose").unwrap(),
+                value: hformat!("{}", self.rejected_set_pose).unwrap(),
+            })
+            .ok();
+
+        DiagnosticMsg {
+            level: topics::DiagnosticStatus::Ok,
+            name: HString::from_str("position_est").unwrap(),
+            message: HString::from_str("").unwrap(),
+            values,
+        }
+    }
+}
+
+impl Node for PositionEstimator {
+    fn name(&self) -> &str {
+        "position_est"
+    }
+
+    fn subscriptions(&self) -> Vec<String> {
         let odom_topic = PacketData::OdometryDelta(topics::OdometryDelta { start_time: 0, end_time: 0,delta_position: [0.0, 0.0], delta_orientation: 0.0 })
             .topic()
             .to_string();
-        client.borrow_mut().subscriptions.insert(odom_topic);
-
-        PositionEstimator {
-            client,
+        let set_pose_topic = PacketData::SetPose(topics::SetPose {
             position: [0.0, 0.0],
             orientation: 0.0,
-            last_estimate_send_time: Instant::now(),
+            covariance: None,
+        })
+        .topic()
+        .to_string();
+        let mut topics = vec![odom_topic, set_pose_topic];
+        if self.use_imu {
+            let imu_topic = PacketData::ImuSample(topics::ImuSample {
+                timestamp: 0,
+                angular_velocity: [0.0; 3],
+                acceleration: [0.0; 3],
+                orientation: None,
+            })
+            .topic()
+            .to_string();
+            topics.push(imu_topic);
         }
+        topics
     }
 
-    pub fn tick(&mut self) {
-        // Update position estimation logic here
-        let packets = self.client.borrow_mut().fetch_all();
-        for packet in packets {
-            if let PacketData::OdometryDelta(odom) = &packet.data {
-                // Simple dead-reckoning update
-                let dx = odom.delta_position[0];
-                let dy = odom.delta_position[1];
-                let dtheta = odom.delta_orientation;
+    /** Position estimates are sent at this rate */
+    fn timer_period(&self) -> Option<Duration> {
+        Some(self.publish_interval)
+    }
 
-                // Update orientation
-                self.orientation += dtheta;
+    fn on_packet(&mut self, ctx: &mut NodeContext, packet: &PacketFormat<PacketData>) {
+        match &packet.data {
+            PacketData::OdometryDelta(odom) => self.on_odometry(odom),
+            PacketData::ImuSample(imu) => self.on_imu(imu),
+            PacketData::SetPose(set_pose) => self.on_set_pose(ctx, set_pose),
+            _ => {}
+        }
+    }
 
-                // Update position
-                self.position[0] += dx * self.orientation.cos() - dy * self.orientation.sin();
-                self.position[1] += dx * self.orientation.sin() + dy * self.orientation.cos();
-            }
+    fn on_timer(&mut self, ctx: &mut NodeContext) {
+        ctx.publish(PacketData::PositionEstimate(self.estimate()));
+        if self.stats_send_time.elapsed() >= Duration::from_secs(1) {
+            ctx.publish(PacketData::DiagnosticMsg(self.stats_to_log()));
+            self.stats_send_time = Instant::now();
         }
+    }
+}
 
-        // Send position estimate at regular intervals
-        if self.last_estimate_send_time.elapsed() >= Duration::from_millis(100) {
-            let time = get_current_time();
-            let estimate = topics::PositionEstimate {
-                timestamp: time.clone(),
-                position: self.position,
-                orientation: self.orientation,
-            };
-            let packet = PacketData::PositionEstimate(estimate);
-            self.client.borrow_mut().send(PacketFormat {
-                to: None,
-                from: None,
-                data: packet,
-                time: time,
-                id: 0,
-            });
-            self.last_estimate_send_time = Instant::now();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use packet_router::Client;
+    use std::cell::RefCell;
+    use std::f64::consts::FRAC_PI_2;
+
+    fn odometry(start_ms: u64, end_ms: u64, forward: f32, turn: f32) -> topics::OdometryDelta {
+        topics::OdometryDelta {
+            start_time: start_ms * 1000,
+            end_time: end_ms * 1000,
+            delta_position: [0.0, forward],
+            delta_orientation: turn,
         }
+    }
 
+    fn assert_near(actual: [f64; 3], expected: [f64; 3]) {
+        for (actual_value, expected_value) in actual.iter().zip(expected) {
+            assert!((actual_value - expected_value).abs() < 1e-6, "{:?} != {:?}", actual, expected);
+        }
+    }
 
+    #[test]
+    fn test_pose_at() {
+        let mut estimator = PositionEstimator::new(PositionEstimatorConfig::default());
+        estimator.on_odometry(&odometry(0, 100, 0.0, FRAC_PI_2 as f32));
+        estimator.on_odometry(&odometry(100, 200, 1.0, 0.0));
+        assert_eq!(estimator.estimate().timestamp, 200_000);
+        assert_near(estimator.pose_at(150_000).unwrap(), [-0.5, 0.0, FRAC_PI_2]);
+        assert_near(estimator.pose_at(50_000).unwrap(), [0.0, 0.0, FRAC_PI_2 / 2.0]);
+        assert_eq!(estimator.pose_at(200_001), None);
     }
-}
 
+    #[test]
+    fn test_gaps_overlaps_and_late_odometry() {
+        let mut estimator = PositionEstimator::new(PositionEstimatorConfig::default());
+        estimator.on_odometry(&odometry(0, 100, 0.0, FRAC_PI_2 as f32));
+        // 100-200ms is missing
+        estimator.on_odometry(&odometry(200, 300, 1.0, 0.0));
+        assert_eq!(estimator.odometry_gaps, 1);
+        assert_near(estimator.pose_at(300_000).unwrap(), [-1.0, 0.0, FRAC_PI_2]);
+
+        // It arrives late, and is applied at the orientation the robot had at the time
+        estimator.on_odometry(&odometry(100, 200, 1.0, 0.0));
+        assert_eq!(estimator.late_odometry, 1);
+        assert!(estimator.gaps.is_empty());
+        assert_near(estimator.pose_at(300_000).unwrap(), [-2.0, 0.0, FRAC_PI_2]);
+        let position = estimator.filter.position();
+        let pose = [position[0], position[1], estimator.filter.orientation()];
+        assert_near(pose, [-2.0, 0.0, FRAC_PI_2]);
+
+        // Only the last two thirds of this one are new
+        estimator.on_odometry(&odometry(250, 400, 0.6, 0.0));
+        assert_eq!(estimator.odometry_overlaps, 1);
+        assert_near(estimator.pose_at(400_000).unwrap(), [-2.4, 0.0, FRAC_PI_2]);
+
+        // Time that was already covered
+        estimator.on_odometry(&odometry(200, 300, 1.0, 0.0));
+        assert_eq!(estimator.duplicate_odometry, 1);
+        assert_eq!(estimator.estimate().timestamp, 400_000);
+        assert_near(estimator.pose_at(400_000).unwrap(), [-2.4, 0.0, FRAC_PI_2]);
+    }
+
+    #[test]
+    fn test_late_turning_odometry() {
+        let mut estimator = PositionEstimator::new(PositionEstimatorConfig::default());
+        estimator.on_odometry(&odometry(0, 100, 1.0, 0.0));
+        estimator.on_odometry(&odometry(200, 300, 1.0, 0.0));
+
+        // A quarter of a 1m radius circle, as the firmware reports it: the travel is in the frame
+        // at the start of the delta
+        let late = topics::OdometryDelta {
+            start_time: 100_000,
+            end_time: 200_000,
+            delta_position: [-1.0, 1.0],
+            delta_orientation: FRAC_PI_2 as f32,
+        };
+        estimator.on_odometry(&late);
+        assert_eq!(estimator.late_odometry, 1);
+        assert_near(estimator.pose_at(200_000).unwrap(), [-1.0, 2.0, FRAC_PI_2]);
+
+        // The metre driven after it was forwards from there, which is now along -x
+        assert_near(estimator.pose_at(250_000).unwrap(), [-1.5, 2.0, FRAC_PI_2]);
+        assert_near(estimator.pose_at(300_000).unwrap(), [-2.0, 2.0, FRAC_PI_2]);
+        let position = estimator.filter.position();
+        let pose = [position[0], position[1], estimator.filter.orientation()];
+        assert_near(pose, [-2.0, 2.0, FRAC_PI_2]);
+    }
+
+    #[test]
+    fn test_set_pose() {
+        let mut estimator = PositionEstimator::new(PositionEstimatorConfig::default());
+        estimator.on_odometry(&odometry(0, 100, 1.0, 0.5));
+        estimator.on_odometry(&odometry(100, 200, 1.0, 0.0));
+
+        let client = RefCell::new(Client::default());
+        let mut message_id = 0;
+        let mut ctx = NodeContext {
+            client: &client,
+            message_id: &mut message_id,
+        };
+        let mut covariance = [0.0; 9];
+        covariance[0] = 0.25;
+        estimator.on_set_pose(
+            &mut ctx,
+            &topics::SetPose {
+                position: [2.0, -1.0],
+                orientation: 1.0,
+                covariance: Some(covariance),
+            },
+        );
+        estimator.on_set_pose(
+            &mut ctx,
+            &topics::SetPose {
+                position: [f32::NAN, 0.0],
+                orientation: 0.0,
+                covariance: None,
+            },
+        );
+        assert_eq!(estimator.rejected_set_pose, 1);
+
+        // Subscribers hear about the jump, then get the new estimate
+        let published: Vec<PacketData> =
+            client.into_inner().client_to_router.into_iter().map(|packet| packet.data).collect();
+        match &published[..] {
+            [PacketData::PoseReset(reset), PacketData::PositionEstimate(estimate)] => {
+                assert_eq!(reset.timestamp, 200_000);
+                assert_eq!(reset.position, [2.0, -1.0]);
+                assert_eq!(estimate.position, [2.0, -1.0]);
+                assert_eq!(estimate.covariance, covariance);
+            }
+            other => panic!("unexpected packets {:?}", other),
+        }
 
+        // The history starts again, and odometry carries on from the new pose
+        assert_eq!(estimator.pose_at(100_000), None);
+        estimator.on_odometry(&odometry(200, 300, 1.0, 0.0));
+        let [x, y, orientation] = estimator.pose_at(300_000).unwrap();
+        assert_near([x, y, orientation], [2.0 - 1f64.sin(), -1.0 + 1f64.cos(), 1.0]);
+        assert_near(estimator.pose_at(250_000).unwrap(), [(2.0 + x) / 2.0, (y - 1.0) / 2.0, 1.0]);
+    }
+
+    #[test]
+    fn test_gyro_is_applied_by_timestamp() {
+        let mut estimator = PositionEstimator::new(PositionEstimatorConfig::default());
+        // Samples every 10ms, some of them arriving before the odometry that covers them
+        for time_ms in (0..16).rev().map(|i: u64| i * 10) {
+            estimator.on_imu(&topics::ImuSample {
+                timestamp: time_ms * 1000,
+                angular_velocity: [0.0, 0.0, 1.0],
+                acceleration: [0.0, 0.0, 9.8],
+                orientation: None,
+            });
+        }
+        assert_eq!(estimator.imu_queue.len(), 16);
+        estimator.on_odometry(&odometry(0, 105, 0.0, 0.105));
+        assert_eq!(estimator.gyro_time, Some(105_000));
+        assert_eq!(estimator.imu_queue.len(), 5);
+        let orientation = estimator.filter.orientation();
+        assert!((orientation - 0.105).abs() < 0.01, "{}", orientation);
 
+        // Anything from before the gyro's time is too late to use
+        estimator.on_imu(&topics::ImuSample {
+            timestamp: 50_000,
+            angular_velocity: [0.0, 0.0, 1.0],
+            acceleration: [0.0, 0.0, 9.8],
+            orientation: None,
+        });
+        assert_eq!(estimator.late_imu, 1);
+    }
+}
@@ -0,0 +1,193 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use heapless::{String as HString, format as hformat};
+use topics::{DiagnosticMsg, PacketData, PacketFormat};
+
+use crate::bag::BagWriter;
+use crate::config::RecorderConfig;
+use crate::node::{Node, NodeContext};
+use crate::nodes::clock::get_current_time;
+
+/**
+ * Writes router traffic to bag files (see `bag.rs`) in the configured directory. Files are named
+ * after the time recording started, with a sequence number that goes up each time the file is
+ * rotated because it got too big or too old.
+ *
+ * The current chunk is written out every second, so a crash loses at most a second of data.
+ */
+pub struct Recorder {
+    config: RecorderConfig,
+    session_name: String,
+    file_index: u32,
+    file_path: PathBuf,
+    file_start_time: Instant,
+    writer: Option<BagWriter<BufWriter<File>>>,
+
+    record_count: u32,
+    write_error_count: u32,
+}
+
+impl Recorder {
+    pub fn new(config: RecorderConfig) -> Self {
+        let session_name = chrono::Utc::now().format("%Y%m%d_%H%M%S").to_string();
+        Recorder {
+            config,
+            session_name,
+            file_index: 0,
+            file_path: PathBuf::new(),
+            file_start_time: Instant::now(),
+            writer: None,
+            record_count: 0,
+            write_error_count: 0,
+        }
+    }
+
+    fn open_file(&mut self) {
+        self.file_index += 1;
+        self.file_path = PathBuf::from(&self.config.directory).join(format!(
+            "{}_{:03}.sbag",
+            self.session_name, self.file_index
+        ));
+        match std::fs::create_dir_all(&self.config.directory)
+            .map_err(Into::into)
+            .and_then(|_| BagWriter::create(&self.file_path))
+        {
+            Ok(writer) => {
+                println!("Recording to {}", self.file_path.display());
+                self.writer = Some(writer);
+                self.file_start_time = Instant::now();
+            }
+            Err(err) => {
+                eprintln!("Failed to create bag {}: {}", self.file_path.display(), err);
+                self.write_error_count += 1;
+            }
+        }
+    }
+
+    fn close_file(&mut self) {
+        if let Some(writer) = self.writer.take() {
+            match writer.finish() {
+                Ok(summary) => print!("Closed {}: {}", self.file_path.display(), summary),
+                Err(err) => {
+                    eprintln!("Failed to finish bag {}: {}", self.file_path.display(), err);
+                    self.write_error_count += 1;
+                }
+            }
+        }
+    }
+
+    fn needs_rotation(&self, writer: &BagWriter<BufWriter<File>>) -> bool {
+        let too_big = self
+            .config
+            .max_file_mb
+            .is_some_and(|max_mb| writer.bytes_written() >= max_mb * 1024 * 1024);
+        let too_old = self
+            .config
+            .max_file_duration_s
+            .is_some_and(|max_s| self.file_start_time.elapsed() >= Duration::from_secs(max_s));
+        too_big || too_old
+    }
+
+    fn stats_to_log(&self) -> DiagnosticMsg {
+        let mut values = heapless::Vec::<topics::DiagnosticKeyValue, 16>::new();
+        values
+            .push(topics::DiagnosticKeyValue {
+                key: HString::from_str("file").unwrap(),
+                value: hformat!("{}", self.file_index).unwrap(),
+            })
+            .ok();
+        values
+            .push(topics::DiagnosticKeyValue {
+                key: HString::from_str("records").unwrap(),
+                value: hformat!("{}", self.record_count).unwrap(),
+            })
+            .ok();
+        values
+            .push(topics::DiagnosticKeyValue {
+                key: HString::from_str("bytes").unwrap(),
+                value: hformat!("{}", self.writer.as_ref().map_or(0, |w| w.bytes_written())).unwrap(),
+            })
+            .ok();
+        values
+            .push(topics::DiagnosticKeyValue {
+                key: HString::from_str("write_errors").unwrap(),
+                value: hformat!("{}", self.write_error_count).unwrap(),
+            })
+            .ok();
+
+        DiagnosticMsg {
+            level: if self.writer.is_some() {
+                topics::DiagnosticStatus::Ok
+            } else {
+                topics::DiagnosticStatus::Error
+            },
+            name: HString::from_str("recorder").unwrap(),
+            message: HString::from_str("").unwrap(),
+            values,
+        }
+    }
+}
+
+impl Node for Recorder {
+    fn name(&self) -> &str {
+        "recorder"
+    }
+
+    fn subscriptions(&self) -> Vec<String> {
+        self.config.topics.clone()
+    }
+
+    fn timer_period(&self) -> Option<Duration> {
+        Some(Duration::from_secs(1))
+    }
+
+    fn on_start(&mut self, _ctx: &mut NodeContext) {
+        self.open_file();
+    }
+
+    fn on_packet(&mut self, _ctx: &mut NodeContext, packet: &PacketFormat<PacketData>) {
+        let Some(writer) = self.writer.as_mut() else {
+            return;
+        };
+        match writer.write(get_current_time(), packet) {
+            Ok(()) => self.record_count += 1,
+            Err(err) => {
+                if self.write_error_count == 0 {
+                    eprintln!("Failed to record packet: {}", err);
+                }
+                self.write_error_count += 1;
+            }
+        }
+
+        if let Some(writer) = self.writer.as_ref()
+            && self.needs_rotation(writer)
+        {
+            self.close_file();
+            self.open_file();
+        }
+    }
+
+    fn on_timer(&mut self, ctx: &mut NodeContext) {
+        if let Some(writer) = self.writer.as_mut()
+            && let Err(err) = writer.flush()
+        {
+            eprintln!("Failed to write bag {}: {}", self.file_path.display(), err);
+            self.write_error_count += 1;
+        }
+        if let Some(writer) = self.writer.as_ref()
+            && self.needs_rotation(writer)
+        {
+            self.close_file();
+            self.open_file();
+        }
+        ctx.publish(PacketData::DiagnosticMsg(self.stats_to_log()));
+    }
+
+    fn on_stop(&mut self, _ctx: &mut NodeContext) {
+        self.close_file();
+    }
+}
@@ -0,0 +1,628 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::rc::Rc;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use heapless::{String as HString, format as hformat};
+use packet_router::{Client, Router};
+use serde_json::{Value, json};
+use topics::{DiagnosticMsg, PacketData, PacketDataTrait, PacketFormat};
+use tungstenite::handshake::MidHandshake;
+use tungstenite::handshake::server::{NoCallback, ServerHandshake};
+use tungstenite::{HandshakeError, Message, WebSocket, accept};
+
+use crate::node::{Node, NodeContext};
+use crate::schema::topic_schemas;
+
+/** Don't let too many queued packets build up for a slow client, drop the oldest instead */
+const QUEUE_LANE_LIMIT: usize = 256;
+
+/** How long a new connection has to finish the WebSocket handshake */
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/** Frame that poses are given in */
+const FRAME_ID: &str = "odom";
+
+/** Topics with a standard ROS message, as (topic, ROS topic, ROS type) */
+const ROS_MESSAGES: [(&str, &str, &str); 3] = [
+    ("PositionEstimate", "/pose", "geometry_msgs/PoseStamped"),
+    ("MotionVelocityRequest", "/cmd_vel", "geometry_msgs/Twist"),
+    ("DiagnosticMsg", "/diagnostic_status", "diagnostic_msgs/DiagnosticStatus"),
+];
+
+/** How one of our topics appears to ROS */
+#[derive(Debug, Clone)]
+pub struct RosTopic {
+    pub topic: &'static str,
+    pub name: String,
+    pub ros_type: String,
+}
+
+/**
+ * Every topic, as ROS sees it. Topics in `ROS_MESSAGES` are converted to and from their standard
+ * ROS message. The others keep our JSON and are named `/<topic>`, with type `slambot/<topic>`.
+ */
+pub fn ros_topics() -> Vec<RosTopic> {
+    topic_schemas()
+        .into_iter()
+        .map(|(topic, _)| match ROS_MESSAGES.iter().find(|(ours, _, _)| *ours == topic) {
+            Some((_, name, ros_type)) => RosTopic {
+                topic,
+                name: name.to_string(),
+                ros_type: ros_type.to_string(),
+            },
+            None => RosTopic {
+                topic,
+                name: format!("/{}", topic),
+                ros_type: format!("slambot/{}", topic),
+            },
+        })
+        .collect()
+}
+
+/** ROS 2 spells types `package/msg/Type`, ROS 1 `package/Type`. Both are accepted. */
+fn same_type(ros_type: &str, requested: &str) -> bool {
+    requested.replacen("/msg/", "/", 1) == ros_type
+}
+
+fn stamp(time_us: u64) -> Value {
+    json!({ "sec": time_us / 1_000_000, "nanosec": (time_us % 1_000_000) * 1000 })
+}
+
+fn stamp_to_us(stamp: &Value) -> u64 {
+    // ROS 1 calls the fields secs and nsecs
+    let sec = stamp["sec"].as_u64().or(stamp["secs"].as_u64()).unwrap_or(0);
+    let nanosec = stamp["nanosec"].as_u64().or(stamp["nsecs"].as_u64()).unwrap_or(0);
+    sec * 1_000_000 + nanosec / 1000
+}
+
+const DIAGNOSTIC_LEVELS: [&str; 4] = ["Ok", "Warn", "Error", "Stale"];
+
+/** Converts a message (the message part of the JSON `Log` prints) to the ROS message */
+pub fn to_ros(topic: &str, message: Value) -> Value {
+    match topic {
+        "PositionEstimate" => {
+            let half_yaw = message["orientation"].as_f64().unwrap_or(0.0) / 2.0;
+            json!({
+                "header": {
+                    "stamp": stamp(message["timestamp"].as_u64().unwrap_or(0)),
+                    "frame_id": FRAME_ID,
+                },
+                "pose": {
+                    "position": {
+                        "x": message["position"][0],
+                        "y": message["position"][1],
+                        "z": 0.0,
+                    },
+                    "orientation": { "x": 0.0, "y": 0.0, "z": half_yaw.sin(), "w": half_yaw.cos() },
+                },
+            })
+        }
+        "MotionVelocityRequest" => json!({
+            "linear": { "x": message["linear_velocity"], "y": 0.0, "z": 0.0 },
+            "angular": { "x": 0.0, "y": 0.0, "z": message["angular_velocity"] },
+        }),
+        "DiagnosticMsg" => {
+            let level = DIAGNOSTIC_LEVELS
+                .iter()
+                .position(|level| message["level"] == *level)
+                .unwrap_or(DIAGNOSTIC_LEVELS.len() - 1);
+            json!({
+                "level": level,
+                "name": message["name"],
+                "message": message["message"],
+                "hardware_id": "",
+                "values": message["values"],
+            })
+        }
+        _ => message,
+    }
+}
+
+/** Converts a ROS message to our message, the inverse of `to_ros` */
+pub fn from_ros(topic: &str, message: Value) -> Value {
+    match topic {
+        "PositionEstimate" => {
+            let pose = &message["pose"];
+            let q = &pose["orientation"];
+            let [x, y, z, w] = ["x", "y", "z", "w"].map(|axis| q[axis].as_f64().unwrap_or(0.0));
+            let yaw = (2.0 * (w * z + x * y)).atan2(1.0 - 2.0 * (y * y + z * z));
+            json!({
+                "timestamp": stamp_to_us(&message["header"]["stamp"]),
+                "position": [pose["position"]["x"], pose["position"]["y"]],
+                "orientation": yaw,
+            })
+        }
+        "MotionVelocityRequest" => json!({
+            "linear_velocity": message["linear"]["x"],
+            "angular_velocity": message["angular"]["z"],
+        }),
+        "DiagnosticMsg" => {
+            let level = message["level"]
+                .as_u64()
+                .and_then(|level| DIAGNOSTIC_LEVELS.get(level as usize))
+                .copied()
+                .unwrap_or("Stale");
+            json!({
+                "level": level,
+                "name": message["name"],
+                "message": message["message"],
+                "values": message["values"],
+            })
+        }
+        _ => message,
+    }
+}
+
+#[derive(Default)]
+pub struct RosbridgeClientStats {
+    pub tx_messages: u32,
+    pub rx_messages: u32,
+    pub subscriptions: u32,
+    pub decode_error_count: u32,
+    pub write_error_count: u32,
+}
+
+impl RosbridgeClientStats {
+    fn to_log(&self) -> DiagnosticMsg {
+        let mut values = heapless::Vec::<topics::DiagnosticKeyValue, 16>::new();
+        values
+            .push(topics::DiagnosticKeyValue {
+                key: HString::from_str("tx_messages").unwrap(),
+                value: hformat!("{}", self.tx_messages).unwrap(),
+            })
+            .ok();
+        values
+            .push(topics::DiagnosticKeyValue {
+                key: HString::from_str("rx_messages").unwrap(),
+                value: hformat!("{}", self.rx_messages).unwrap(),
+            })
+            .ok();
+        values
+            .push(topics::DiagnosticKeyValue {
+                key: HString::from_str("subscriptions").unwrap(),
+                value: hformat!("{}", self.subscriptions).unwrap(),
+            })
+            .ok();
+        values
+            .push(topics::DiagnosticKeyValue {
+                key: HString::from_str("decode_errors").unwrap(),
+                value: hformat!("{}", self.decode_error_count).unwrap(),
+            })
+            .ok();
+        values
+            .push(topics::DiagnosticKeyValue {
+                key: HString::from_str("write_errors").unwrap(),
+                value: hformat!("{}", self.write_error_count).unwrap(),
+            })
+            .ok();
+
+        DiagnosticMsg {
+            level: topics::DiagnosticStatus::Ok,
+            name: HString::from_str("rosbridge_stats").unwrap(),
+            message: HString::from_str("").unwrap(),
+            values,
+        }
+    }
+}
+
+/**
+ * One rosbridge connection. Subscribing to a ROS topic subscribes the router client to our topic,
+ * and messages published on a ROS topic are converted and sent to the router.
+ */
+pub struct RosbridgeClient {
+    pub client: Rc<RefCell<Client<PacketFormat<PacketData>>>>,
+    pub websocket: WebSocket<TcpStream>,
+    pub stats: RosbridgeClientStats,
+    pub stats_send_time: Instant,
+    pub is_alive: bool,
+    ros_topics: Rc<[RosTopic]>,
+    /** Subscription ids on each subscribed ROS topic. Unsubscribing drops one id, or all. */
+    subscriptions: HashMap<&'static str, Vec<Option<Value>>>,
+}
+
+impl RosbridgeClient {
+    pub fn new(websocket: WebSocket<TcpStream>, ros_topics: Rc<[RosTopic]>) -> Self {
+        let client = Rc::new(RefCell::new(Client::<PacketFormat<PacketData>>::default()));
+        client
+            .borrow_mut()
+            .router_to_client
+            .set_lane_limit(Some(QUEUE_LANE_LIMIT));
+        RosbridgeClient {
+            client,
+            websocket,
+            stats: RosbridgeClientStats::default(),
+            stats_send_time: Instant::now(),
+            is_alive: true,
+            ros_topics,
+            subscriptions: HashMap::new(),
+        }
+    }
+
+    /** Returns true if the socket is backed up */
+    fn send(&mut self, message: Value) -> bool {
+        match self.websocket.send(Message::Text(message.to_string().into())) {
+            Ok(()) => {}
+            // The message is queued inside the websocket, it just couldn't be flushed yet
+            Err(tungstenite::Error::Io(e)) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                self.stats.tx_messages += 1;
+                return true;
+            }
+            Err(tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed) => {
+                self.is_alive = false;
+                return true;
+            }
+            Err(_) => {
+                self.stats.write_error_count += 1;
+                return false;
+            }
+        }
+        self.stats.tx_messages += 1;
+        false
+    }
+
+    fn status(&mut self, level: &str, id: &Value, message: &str) {
+        let mut status = json!({ "op": "status", "level": level, "msg": message });
+        if !id.is_null() {
+            status["id"] = id.clone();
+        }
+        self.send(status);
+    }
+
+    /**
+     * Finds the ROS topic a request is about, checking its type if it gave one. Reports an error
+     * to the client if there's no such topic.
+     */
+    fn find_topic(&mut self, request: &Value) -> Option<RosTopic> {
+        let name = request["topic"].as_str().unwrap_or_default();
+        let found = self.ros_topics.iter().find(|topic| topic.name == name).cloned();
+        match (found, request["type"].as_str()) {
+            (None, _) => {
+                self.status("error", &request["id"], &format!("unknown topic \"{}\"", name));
+                None
+            }
+            (Some(topic), Some(requested)) if !same_type(&topic.ros_type, requested) => {
+                let message = format!("{} has type {}, not {}", name, topic.ros_type, requested);
+                self.status("error", &request["id"], &message);
+                None
+            }
+            (Some(topic), _) => Some(topic),
+        }
+    }
+
+    /** Subscribes the router client to exactly the topics the client has subscriptions on */
+    fn update_topics(&mut self) {
+        self.subscriptions.retain(|_, ids| !ids.is_empty());
+        self.stats.subscriptions = self.subscriptions.values().map(Vec::len).sum::<usize>() as u32;
+        self.client.borrow_mut().subscriptions =
+            self.subscriptions.keys().map(|topic| topic.to_string()).collect();
+    }
+
+    fn receive(&mut self, ctx: &mut NodeContext, text: &str) {
+        self.stats.rx_messages += 1;
+        let request = match serde_json::from_str::<Value>(text) {
+            Ok(request) => request,
+            Err(err) => {
+                self.stats.decode_error_count += 1;
+                return self.status("error", &Value::Null, &format!("invalid JSON: {}", err));
+            }
+        };
+        match request["op"].as_str() {
+            Some("subscribe") => {
+                if let Some(topic) = self.find_topic(&request) {
+                    let id = Some(request["id"].clone()).filter(|id| !id.is_null());
+                    self.subscriptions.entry(topic.topic).or_default().push(id);
+                    self.update_topics();
+                }
+            }
+            Some("unsubscribe") => {
+                if let Some(topic) = self.find_topic(&request)
+                    && let Some(ids) = self.subscriptions.get_mut(topic.topic)
+                {
+                    match request.get("id") {
+                        Some(id) => ids.retain(|subscribed| subscribed.as_ref() != Some(id)),
+                        None => ids.clear(),
+                    }
+                    self.update_topics();
+                }
+            }
+            // Publishing doesn't need an advertisement, but a wrong type is worth telling about
+            Some("advertise") => {
+                self.find_topic(&request);
+            }
+            Some("unadvertise") => {}
+            Some("publish") => self.publish(ctx, &request),
+            Some("call_service") => self.call_service(&request),
+            Some(op) => {
+                let message = format!("\"{}\" isn't supported", op);
+                self.status("warning", &request["id"], &message);
+            }
+            None => {
+                self.stats.decode_error_count += 1;
+                self.status("error", &request["id"], "messages need an \"op\"");
+            }
+        }
+    }
+
+    fn publish(&mut self, ctx: &mut NodeContext, request: &Value) {
+        let Some(topic) = self.find_topic(request) else {
+            self.stats.decode_error_count += 1;
+            return;
+        };
+        let message = from_ros(topic.topic, request["msg"].clone());
+        // PacketData is externally tagged, so the topic name wraps the message
+        match serde_json::from_value::<PacketData>(json!({ topic.topic: message })) {
+            Ok(data) => self.client.borrow_mut().send(ctx.new_packet(None, data)),
+            Err(err) => {
+                self.stats.decode_error_count += 1;
+                let message = format!("invalid {} message: {}", topic.ros_type, err);
+                self.status("error", &request["id"], &message);
+            }
+        }
+    }
+
+    /** Answers the `rosapi` services that tools use to find out what topics there are */
+    fn call_service(&mut self, request: &Value) {
+        let service = request["service"].as_str().unwrap_or_default();
+        let values = match service {
+            "/rosapi/topics" => Some(json!({
+                "topics": self.ros_topics.iter().map(|topic| &topic.name).collect::<Vec<_>>(),
+                "types": self.ros_topics.iter().map(|topic| &topic.ros_type).collect::<Vec<_>>(),
+            })),
+            "/rosapi/topic_type" => {
+                let name = request["args"]["topic"].as_str().unwrap_or_default();
+                let ros_type = self
+                    .ros_topics
+                    .iter()
+                    .find(|topic| topic.name == name)
+                    .map(|topic| topic.ros_type.as_str())
+                    .unwrap_or_default();
+                Some(json!({ "type": ros_type }))
+            }
+            _ => None,
+        };
+        let mut response = json!({
+            "op": "service_response",
+            "service": service,
+            "result": values.is_some(),
+            "values": values.unwrap_or_else(|| json!(format!("unknown service \"{}\"", service))),
+        });
+        if !request["id"].is_null() {
+            response["id"] = request["id"].clone();
+        }
+        self.send(response);
+    }
+
+    /** Sends a packet on its ROS topic, if subscribed. Returns true if the socket backed up. */
+    fn forward(&mut self, packet: &PacketFormat<PacketData>) -> bool {
+        let topic = packet.data.topic();
+        if !self.subscriptions.contains_key(topic) {
+            return false;
+        }
+        let Some(name) = self
+            .ros_topics
+            .iter()
+            .find(|ros_topic| ros_topic.topic == topic)
+            .map(|ros_topic| ros_topic.name.clone())
+        else {
+            return false;
+        };
+        let Ok(mut data) = serde_json::to_value(&packet.data) else {
+            self.stats.write_error_count += 1;
+            return false;
+        };
+        let message = to_ros(topic, data[topic].take());
+        self.send(json!({ "op": "publish", "topic": name, "msg": message }))
+    }
+
+    /** Returns true if anything was read or written. */
+    pub fn tick(&mut self, ctx: &mut NodeContext) -> bool {
+        let mut did_work = false;
+
+        match self.websocket.read() {
+            Ok(Message::Text(text)) => {
+                did_work = true;
+                self.receive(ctx, text.as_str());
+            }
+            Ok(Message::Binary(_)) => {
+                did_work = true;
+                self.stats.decode_error_count += 1;
+                self.status("error", &Value::Null, "only JSON text messages are supported");
+            }
+            // Pings are answered by tungstenite, and closes show up as ConnectionClosed
+            Ok(_) => did_work = true,
+            Err(tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed) => {
+                self.is_alive = false;
+            }
+            Err(_) => {
+                // No data to read
+            }
+        }
+
+        // Leave packets in the priority lanes while the socket is backed up, so that higher
+        // priority packets can overtake them
+        let mut backed_up = match self.websocket.flush() {
+            Err(tungstenite::Error::Io(e)) => e.kind() == std::io::ErrorKind::WouldBlock,
+            _ => false,
+        };
+        while !backed_up && self.is_alive {
+            let Some(packet) = self.client.borrow_mut().fetch_next() else {
+                break;
+            };
+            did_work = true;
+            backed_up = self.forward(&packet);
+        }
+
+        if self.stats_send_time.elapsed() >= Duration::from_secs(1) {
+            let diag_msg = self.stats.to_log();
+            let packet = ctx.new_packet(None, PacketData::DiagnosticMsg(diag_msg));
+            self.client.borrow_mut().send(packet);
+            self.stats_send_time = Instant::now();
+        }
+
+        did_work
+    }
+}
+
+/** A connection part way through the WebSocket handshake */
+struct PendingHandshake {
+    handshake: MidHandshake<ServerHandshake<TcpStream, NoCallback>>,
+    addr: SocketAddr,
+    accepted: Instant,
+}
+
+/**
+ * Speaks the rosbridge v2 protocol (JSON over WebSocket), so that ROS tools such as roslibjs,
+ * Foxglove's ROS connection or a rosbridge client in a ROS graph can watch and drive the robot.
+ * See `ros_topics` for how our topics are named and typed.
+ */
+pub struct RosbridgeServer {
+    pub router: Rc<RefCell<Router<PacketFormat<PacketData>>>>,
+    pub clients_by_addr: HashMap<String, RosbridgeClient>,
+    pub server: TcpListener,
+    ros_topics: Rc<[RosTopic]>,
+    pending: Vec<PendingHandshake>,
+}
+
+impl RosbridgeServer {
+    pub fn new(router: Rc<RefCell<Router<PacketFormat<PacketData>>>>, address: &str) -> Self {
+        let server = TcpListener::bind(address).unwrap();
+        println!("Rosbridge server listening on {}", address);
+        server.set_nonblocking(true).expect("Failed to set non-blocking");
+        RosbridgeServer {
+            router,
+            clients_by_addr: HashMap::new(),
+            server,
+            ros_topics: ros_topics().into(),
+            pending: Vec::new(),
+        }
+    }
+
+    fn connected(&mut self, websocket: WebSocket<TcpStream>, addr: SocketAddr) {
+        let client = RosbridgeClient::new(websocket, Rc::clone(&self.ros_topics));
+        self.router.borrow_mut().register_client(Rc::downgrade(&client.client));
+        self.clients_by_addr.insert(addr.to_string(), client);
+        println!("New rosbridge client connected: {}", addr);
+    }
+
+    /** Moves handshakes along as the sockets allow. Returns true if any finished. */
+    fn handle_pending(&mut self) -> bool {
+        let mut did_work = false;
+        for pending in std::mem::take(&mut self.pending) {
+            match pending.handshake.handshake() {
+                Ok(websocket) => {
+                    did_work = true;
+                    self.connected(websocket, pending.addr);
+                }
+                Err(HandshakeError::Interrupted(handshake)) => {
+                    if pending.accepted.elapsed() < HANDSHAKE_TIMEOUT {
+                        self.pending.push(PendingHandshake {
+                            handshake,
+                            ..pending
+                        });
+                    }
+                }
+                Err(HandshakeError::Failure(err)) => {
+                    eprintln!("Rosbridge handshake with {} failed: {}", pending.addr, err);
+                }
+            }
+        }
+        did_work
+    }
+
+    /** Returns true if there was any activity on the socket or its clients. */
+    pub fn tick(&mut self, ctx: &mut NodeContext) -> bool {
+        let mut did_work = false;
+        if let Ok((stream, addr)) = self.server.accept() {
+            did_work = true;
+            stream.set_nonblocking(true).expect("Failed to set non-blocking");
+            match accept(stream) {
+                Ok(websocket) => self.connected(websocket, addr),
+                Err(HandshakeError::Interrupted(handshake)) => {
+                    self.pending.push(PendingHandshake {
+                        handshake,
+                        addr,
+                        accepted: Instant::now(),
+                    })
+                }
+                Err(HandshakeError::Failure(err)) => {
+                    eprintln!("Rosbridge handshake with {} failed: {}", addr, err);
+                }
+            }
+        }
+        if !self.pending.is_empty() {
+            did_work |= self.handle_pending();
+        }
+
+        for client in self.clients_by_addr.values_mut() {
+            did_work |= client.tick(ctx);
+        }
+        self.clients_by_addr.retain(|_addr, client| client.is_alive);
+        did_work
+    }
+}
+
+impl Node for RosbridgeServer {
+    fn name(&self) -> &str {
+        "rosbridge"
+    }
+
+    fn on_poll(&mut self, ctx: &mut NodeContext) -> bool {
+        self.tick(ctx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ros_topics() {
+        let topics = ros_topics();
+        let pose = topics.iter().find(|t| t.topic == "PositionEstimate").unwrap();
+        assert_eq!(pose.name, "/pose");
+        assert!(same_type(&pose.ros_type, "geometry_msgs/msg/PoseStamped"));
+        let odometry = topics.iter().find(|t| t.topic == "OdometryDelta").unwrap();
+        assert_eq!(odometry.name, "/OdometryDelta");
+        assert_eq!(odometry.ros_type, "slambot/OdometryDelta");
+    }
+
+    #[test]
+    fn test_pose_round_trip() {
+        let estimate =
+            json!({ "timestamp": 2_500_000, "position": [1.0, 2.0], "orientation": 1.0 });
+        let pose = to_ros("PositionEstimate", estimate);
+        assert_eq!(pose["header"]["stamp"], json!({ "sec": 2, "nanosec": 500_000_000 }));
+        assert_eq!(pose["pose"]["position"], json!({ "x": 1.0, "y": 2.0, "z": 0.0 }));
+        assert!((pose["pose"]["orientation"]["z"].as_f64().unwrap() - 0.5f64.sin()).abs() < 1e-9);
+
+        let estimate = from_ros("PositionEstimate", pose);
+        assert_eq!(estimate["timestamp"], 2_500_000);
+        assert!((estimate["orientation"].as_f64().unwrap() - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_twist_and_diagnostics() {
+        let twist = json!({
+            "linear": { "x": 0.25, "y": 0.0, "z": 0.0 },
+            "angular": { "x": 0.0, "y": 0.0, "z": -0.5 },
+        });
+        assert_eq!(
+            from_ros("MotionVelocityRequest", twist.clone()),
+            json!({ "linear_velocity": 0.25, "angular_velocity": -0.5 })
+        );
+        assert_eq!(
+            to_ros("MotionVelocityRequest", from_ros("MotionVelocityRequest", twist.clone())),
+            twist
+        );
+
+        let status = to_ros(
+            "DiagnosticMsg",
+            json!({ "level": "Warn", "name": "serial", "message": "", "values": [] }),
+        );
+        assert_eq!(status["level"], 1);
+        assert_eq!(from_ros("DiagnosticMsg", status)["level"], "Warn");
+    }
+}
@@ -0,0 +1,566 @@
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use heapless::{String as HString, format as hformat};
+use topics::{DiagnosticMsg, MotionRequestMode, PacketData, PacketDataTrait, PacketFormat};
+
+use crate::config::SafetySupervisorConfig;
+use crate::node::{Node, NodeContext};
+
+/**
+ * Decides which velocity commands the router delivers, so that nothing (the motion controller,
+ * teleop over rosbridge, the HTTP API) can drive the robot while the supervisor holds it stopped.
+ * Install it with `Router::set_filter`.
+ */
+pub struct SafetyGate {
+    open: Cell<bool>,
+    blocked: Cell<u32>,
+}
+
+impl SafetyGate {
+    /** Everything is allowed through while the gate is open. Stopping is always allowed. */
+    pub fn allows(&self, packet: &PacketFormat<PacketData>) -> bool {
+        match &packet.data {
+            PacketData::MotionVelocityRequest(request) if !self.open.get() => {
+                let stopping = request.linear_velocity == 0.0 && request.angular_velocity == 0.0;
+                if !stopping {
+                    self.blocked.set(self.blocked.get() + 1);
+                }
+                stopping
+            }
+            _ => true,
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.open.get()
+    }
+}
+
+/**
+ * Holds the robot stopped while an `EmergencyStop` is engaged or any of the configured topics is
+ * stale (eg the position estimate or odometry has stopped arriving), and stops it if a client
+ * that was driving it stops sending `Heartbeat`s. Stopping sends a `Stop` target, so that the
+ * motion controller doesn't carry on when the robot is released, and a zero velocity, which the
+ * gate always lets through.
+ *
+ * The state is published as a "safety" `DiagnosticMsg` when it changes and every second.
+ */
+pub struct SafetySupervisor {
+    config: SafetySupervisorConfig,
+    gate: Rc<SafetyGate>,
+
+    // Latched until an `EmergencyStop` with `engaged: false`
+    emergency_stop: bool,
+    watched: Vec<WatchedTopic>,
+    heartbeats: HashMap<u16, ClientHeartbeat>,
+
+    stop_count: u32,
+    heartbeats_lost: u32,
+    fault: Option<String>,
+    diagnostic_time: Instant,
+}
+
+/** Values in the diagnostic before the watched topics' ages */
+const STATUS_VALUES: usize = 5;
+
+/** As many topics as leave room for each one's age in the diagnostic's 16 values */
+pub const MAX_WATCHED_TOPICS: usize = 16 - STATUS_VALUES;
+
+struct WatchedTopic {
+    topic: String,
+    timeout: Duration,
+    last_seen: Option<Instant>,
+}
+
+impl WatchedTopic {
+    fn is_stale(&self, now: Instant) -> bool {
+        self.last_seen
+            .is_none_or(|last_seen| now.duration_since(last_seen) > self.timeout)
+    }
+}
+
+struct ClientHeartbeat {
+    timeout: Duration,
+    last_seen: Instant,
+    /** Has sent a motion request, so the robot is stopped if it goes away */
+    driving: bool,
+}
+
+impl SafetySupervisor {
+    pub fn new(config: SafetySupervisorConfig) -> Self {
+        let watched = config
+            .topics
+            .iter()
+            .map(|watched| WatchedTopic {
+                topic: watched.topic.clone(),
+                timeout: Duration::from_millis(watched.timeout_ms),
+                last_seen: None,
+            })
+            .collect();
+        SafetySupervisor {
+            config,
+            // Closed until the first check finds nothing wrong
+            gate: Rc::new(SafetyGate {
+                open: Cell::new(false),
+                blocked: Cell::new(0),
+            }),
+            emergency_stop: false,
+            watched,
+            heartbeats: HashMap::new(),
+            stop_count: 0,
+            heartbeats_lost: 0,
+            fault: None,
+            diagnostic_time: Instant::now(),
+        }
+    }
+
+    pub fn gate(&self) -> Rc<SafetyGate> {
+        Rc::clone(&self.gate)
+    }
+
+    fn stop_robot(&mut self, ctx: &mut NodeContext) {
+        ctx.publish(PacketData::MotionTargetRequest(
+            topics::MotionTargetRequest {
+                linear: [0.0, 0.0],
+                angular: 0.0,
+                motion_mode: MotionRequestMode::Stop,
+            },
+        ));
+        ctx.publish(PacketData::MotionVelocityRequest(
+            topics::MotionVelocityRequest {
+                linear_velocity: 0.0,
+                angular_velocity: 0.0,
+            },
+        ));
+        self.stop_count += 1;
+    }
+
+    /** Why the robot is held stopped, if it is */
+    fn find_fault(&self, now: Instant) -> Option<String> {
+        if self.emergency_stop {
+            return Some("emergency stop".to_string());
+        }
+        self.watched
+            .iter()
+            .find(|watched| watched.is_stale(now))
+            .map(|watched| format!("stale {}", watched.topic))
+    }
+
+    /**
+     * Stops the robot for clients whose heartbeats have stopped, and opens or closes the gate.
+     * Closing it stops the robot.
+     */
+    fn check(&mut self, ctx: &mut NodeContext) {
+        let now = Instant::now();
+        let lost: Vec<u16> = self
+            .heartbeats
+            .iter()
+            .filter(|(_, heartbeat)| now.duration_since(heartbeat.last_seen) > heartbeat.timeout)
+            .map(|(address, _)| *address)
+            .collect();
+        for address in lost {
+            let heartbeat = self.heartbeats.remove(&address).unwrap();
+            if heartbeat.driving {
+                self.heartbeats_lost += 1;
+                self.stop_robot(ctx);
+            }
+        }
+
+        let fault = self.find_fault(now);
+        if fault.is_some() && self.gate.is_open() {
+            self.stop_robot(ctx);
+        }
+        self.gate.open.set(fault.is_none());
+        if fault != self.fault || self.diagnostic_time.elapsed() >= Duration::from_secs(1) {
+            self.fault = fault;
+            ctx.publish(PacketData::DiagnosticMsg(self.stats_to_log(now)));
+            self.diagnostic_time = Instant::now();
+        }
+    }
+
+    fn stats_to_log(&self, now: Instant) -> DiagnosticMsg {
+        let mut values = heapless::Vec::<topics::DiagnosticKeyValue, 16>::new();
+        let status: [(&str, u32); STATUS_VALUES] = [
+            ("estop", self.emergency_stop as u32),
+            ("blocked", self.gate.blocked.get()),
+            ("stops", self.stop_count),
+            ("heartbeats", self.heartbeats.len() as u32),
+            ("hb_lost", self.heartbeats_lost),
+        ];
+        for (key, value) in status {
+            values
+                .push(topics::DiagnosticKeyValue {
+                    key: HString::from_str(key).unwrap(),
+                    value: hformat!("{}", value).unwrap(),
+                })
+                .ok();
+        }
+        // Each watched topic's age in ms
+        for watched in self.watched.iter() {
+            let mut key = HString::new();
+            for c in watched.topic.chars() {
+                if key.push(c).is_err() {
+                    break;
+                }
+            }
+            let value = match watched.last_seen {
+                Some(last_seen) => hformat!("{}", now.duration_since(last_seen).as_millis()),
+                None => hformat!("never"),
+            };
+            values
+                .push(topics::DiagnosticKeyValue {
+                    key,
+                    value: value.unwrap(),
+                })
+                .ok();
+        }
+
+        let mut message = HString::new();
+        for c in self.fault.as_deref().unwrap_or("").chars() {
+            if message.push(c).is_err() {
+                break;
+            }
+        }
+        DiagnosticMsg {
+            level: match self.fault {
+                Some(_) => topics::DiagnosticStatus::Error,
+                None => topics::DiagnosticStatus::Ok,
+            },
+            name: HString::from_str("safety").unwrap(),
+            message,
+            values,
+        }
+    }
+}
+
+impl Node for SafetySupervisor {
+    fn name(&self) -> &str {
+        "safety"
+    }
+
+    fn subscriptions(&self) -> Vec<String> {
+        let emergency_stop_topic =
+            PacketData::EmergencyStop(topics::EmergencyStop { engaged: false })
+                .topic()
+                .to_string();
+
+        let heartbeat_topic = PacketData::Heartbeat(topics::Heartbeat { timeout_ms: 0 })
+            .topic()
+            .to_string();
+
+        // Motion requests, to tell which clients are driving
+        let motion_target_topic = PacketData::MotionTargetRequest(topics::MotionTargetRequest {
+            linear: [0.0, 0.0],
+            angular: 0.0,
+            motion_mode: MotionRequestMode::Velocity,
+        })
+        .topic()
+        .to_string();
+
+        let velocity_topic = PacketData::MotionVelocityRequest(topics::MotionVelocityRequest {
+            linear_velocity: 0.0,
+            angular_velocity: 0.0,
+        })
+        .topic()
+        .to_string();
+
+        let path_topic = PacketData::Path(topics::Path {
+            id: 0,
+            waypoints: heapless::Vec::new(),
+            final_heading: None,
+        })
+        .topic()
+        .to_string();
+
+        let mission_topic = PacketData::Mission(topics::Mission {
+            id: 0,
+            steps: heapless::Vec::new(),
+        })
+        .topic()
+        .to_string();
+
+        let mut topics = vec![
+            emergency_stop_topic,
+            heartbeat_topic,
+            motion_target_topic,
+            velocity_topic,
+            path_topic,
+            mission_topic,
+        ];
+        topics.extend(self.watched.iter().map(|watched| watched.topic.clone()));
+        topics
+    }
+
+    fn timer_period(&self) -> Option<Duration> {
+        Some(Duration::from_millis(self.config.check_interval_ms))
+    }
+
+    fn on_packet(&mut self, ctx: &mut NodeContext, packet: &PacketFormat<PacketData>) {
+        let now = Instant::now();
+        let topic = packet.data.topic();
+        for watched in self
+            .watched
+            .iter_mut()
+            .filter(|watched| watched.topic == topic)
+        {
+            watched.last_seen = Some(now);
+        }
+
+        let driving = match &packet.data {
+            PacketData::EmergencyStop(emergency_stop) => {
+                if emergency_stop.engaged != self.emergency_stop {
+                    self.emergency_stop = emergency_stop.engaged;
+                    // Don't wait for the next check to close the gate
+                    self.check(ctx);
+                }
+                false
+            }
+            PacketData::Heartbeat(heartbeat) => {
+                if let Some(from) = packet.from {
+                    if heartbeat.timeout_ms == 0 {
+                        self.heartbeats.remove(&from);
+                    } else {
+                        let driving = self
+                            .heartbeats
+                            .get(&from)
+                            .is_some_and(|heartbeat| heartbeat.driving);
+                        self.heartbeats.insert(
+                            from,
+                            ClientHeartbeat {
+                                timeout: Duration::from_millis(heartbeat.timeout_ms as u64),
+                                last_seen: now,
+                                driving,
+                            },
+                        );
+                    }
+                }
+                false
+            }
+            PacketData::MotionTargetRequest(target) => {
+                target.motion_mode != MotionRequestMode::Stop
+            }
+            PacketData::MotionVelocityRequest(request) => {
+                request.linear_velocity != 0.0 || request.angular_velocity != 0.0
+            }
+            PacketData::Path(_) | PacketData::Mission(_) => true,
+            _ => false,
+        };
+        if driving
+            && let Some(heartbeat) = packet.from.and_then(|from| self.heartbeats.get_mut(&from))
+        {
+            heartbeat.driving = true;
+        }
+    }
+
+    fn on_timer(&mut self, ctx: &mut NodeContext) {
+        self.check(ctx);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::WatchedTopicConfig;
+    use crate::nodes::clock::get_current_time;
+    use packet_router::Client;
+    use std::cell::RefCell;
+
+    fn packet(data: PacketData) -> PacketFormat<PacketData> {
+        PacketFormat {
+            to: None,
+            from: Some(7),
+            data,
+            time: get_current_time(),
+            id: 0,
+        }
+    }
+
+    fn velocity(linear_velocity: f32) -> PacketFormat<PacketData> {
+        packet(PacketData::MotionVelocityRequest(
+            topics::MotionVelocityRequest {
+                linear_velocity,
+                angular_velocity: 0.0,
+            },
+        ))
+    }
+
+    fn odometry() -> PacketFormat<PacketData> {
+        packet(PacketData::OdometryDelta(topics::OdometryDelta {
+            start_time: 0,
+            end_time: 0,
+            delta_position: [0.0, 0.0],
+            delta_orientation: 0.0,
+        }))
+    }
+
+    fn config() -> SafetySupervisorConfig {
+        SafetySupervisorConfig {
+            enabled: true,
+            check_interval_ms: 10,
+            topics: vec![WatchedTopicConfig {
+                topic: "OdometryDelta".to_string(),
+                timeout_ms: 30,
+            }],
+        }
+    }
+
+    type TestClient = RefCell<Client<PacketFormat<PacketData>>>;
+
+    /** How many times the robot was told to stop, and the diagnostics' messages */
+    fn sent(client: &TestClient) -> (usize, Vec<String>) {
+        let packets = std::mem::take(&mut client.borrow_mut().client_to_router);
+        let stops = packets
+            .iter()
+            .filter(|packet| {
+                matches!(
+                    &packet.data,
+                    PacketData::MotionTargetRequest(target)
+                        if target.motion_mode == MotionRequestMode::Stop
+                )
+            })
+            .count();
+        let messages = packets
+            .iter()
+            .filter_map(|packet| match &packet.data {
+                PacketData::DiagnosticMsg(diagnostic) => Some(diagnostic.message.to_string()),
+                _ => None,
+            })
+            .collect();
+        (stops, messages)
+    }
+
+    #[test]
+    fn test_emergency_stop_latches() {
+        let mut supervisor = SafetySupervisor::new(config());
+        let gate = supervisor.gate();
+        let client = RefCell::new(Client::default());
+        let mut message_id = 0;
+        let mut ctx = NodeContext {
+            client: &client,
+            message_id: &mut message_id,
+        };
+        // Closed until the watched topic has arrived
+        supervisor.on_timer(&mut ctx);
+        assert!(!gate.allows(&velocity(0.1)));
+        assert!(gate.allows(&velocity(0.0)));
+        supervisor.on_packet(&mut ctx, &odometry());
+        supervisor.on_timer(&mut ctx);
+        assert!(gate.allows(&velocity(0.1)));
+        assert_eq!(
+            sent(&client),
+            (0, vec!["stale OdometryDelta".to_string(), "".to_string()])
+        );
+
+        let engage = packet(PacketData::EmergencyStop(topics::EmergencyStop {
+            engaged: true,
+        }));
+        supervisor.on_packet(&mut ctx, &engage);
+        assert!(!gate.allows(&velocity(0.1)));
+        assert_eq!(sent(&client), (1, vec!["emergency stop".to_string()]));
+
+        // Stays stopped while everything else is fine, until reset
+        supervisor.on_packet(&mut ctx, &odometry());
+        supervisor.on_timer(&mut ctx);
+        assert!(!gate.is_open());
+        let reset = packet(PacketData::EmergencyStop(topics::EmergencyStop {
+            engaged: false,
+        }));
+        supervisor.on_packet(&mut ctx, &odometry());
+        supervisor.on_packet(&mut ctx, &reset);
+        assert!(gate.allows(&velocity(0.1)));
+        assert_eq!(sent(&client), (0, vec!["".to_string()]));
+        assert_eq!(gate.blocked.get(), 2);
+    }
+
+    #[test]
+    fn test_stale_topic_stops_robot() {
+        let mut supervisor = SafetySupervisor::new(config());
+        let gate = supervisor.gate();
+        let client = RefCell::new(Client::default());
+        let mut message_id = 0;
+        let mut ctx = NodeContext {
+            client: &client,
+            message_id: &mut message_id,
+        };
+        supervisor.on_packet(&mut ctx, &odometry());
+        supervisor.on_timer(&mut ctx);
+        assert!(gate.is_open());
+        sent(&client);
+
+        std::thread::sleep(Duration::from_millis(40));
+        supervisor.on_timer(&mut ctx);
+        supervisor.on_timer(&mut ctx);
+        assert!(!gate.is_open());
+        assert_eq!(sent(&client), (1, vec!["stale OdometryDelta".to_string()]));
+
+        // Released when it comes back
+        supervisor.on_packet(&mut ctx, &odometry());
+        supervisor.on_timer(&mut ctx);
+        assert!(gate.is_open());
+    }
+
+    #[test]
+    fn test_lost_heartbeat_stops_robot() {
+        let mut supervisor = SafetySupervisor::new(SafetySupervisorConfig {
+            topics: Vec::new(),
+            ..config()
+        });
+        let client = RefCell::new(Client::default());
+        let mut message_id = 0;
+        let mut ctx = NodeContext {
+            client: &client,
+            message_id: &mut message_id,
+        };
+        let heartbeat =
+            |timeout_ms| packet(PacketData::Heartbeat(topics::Heartbeat { timeout_ms }));
+
+        // A client that isn't driving can come and go
+        supervisor.on_packet(&mut ctx, &heartbeat(20));
+        std::thread::sleep(Duration::from_millis(30));
+        supervisor.on_timer(&mut ctx);
+        assert_eq!(sent(&client).0, 0);
+        assert!(supervisor.heartbeats.is_empty());
+
+        supervisor.on_packet(&mut ctx, &heartbeat(20));
+        supervisor.on_packet(&mut ctx, &velocity(0.2));
+        supervisor.on_timer(&mut ctx);
+        assert_eq!(sent(&client).0, 0);
+        std::thread::sleep(Duration::from_millis(30));
+        supervisor.on_timer(&mut ctx);
+        assert_eq!(sent(&client).0, 1);
+        assert_eq!(supervisor.heartbeats_lost, 1);
+
+        // Or sign off
+        supervisor.on_packet(&mut ctx, &heartbeat(20));
+        supervisor.on_packet(&mut ctx, &velocity(0.2));
+        supervisor.on_packet(&mut ctx, &heartbeat(0));
+        std::thread::sleep(Duration::from_millis(30));
+        supervisor.on_timer(&mut ctx);
+        assert_eq!(sent(&client).0, 0);
+    }
+
+    #[test]
+    fn test_diagnostic_with_most_topics() {
+        let watched = WatchedTopicConfig {
+            topic: "MotionVelocityRequest".to_string(),
+            timeout_ms: 30,
+        };
+        let mut supervisor = SafetySupervisor::new(SafetySupervisorConfig {
+            topics: vec![watched; MAX_WATCHED_TOPICS],
+            ..config()
+        });
+        supervisor.stop_count = u32::MAX;
+        supervisor.fault = Some("stale MotionVelocityRequest".to_string());
+        let diagnostic = supervisor.stats_to_log(Instant::now());
+        assert_eq!(diagnostic.values.len(), STATUS_VALUES + MAX_WATCHED_TOPICS);
+        assert_eq!(diagnostic.values.last().unwrap().value.as_str(), "never");
+
+        let mut buffer = [0u8; topics::MAX_PACKET_SIZE];
+        packet_encoding::encode_packet(&packet(PacketData::DiagnosticMsg(diagnostic)), &mut buffer)
+            .unwrap();
+    }
+}
@@ -1,93 +1,216 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
 use std::cell::RefCell;
 use std::time::{Duration, Instant};
-use serialport::{available_ports, SerialPortType};
+use serialport::{available_ports, SerialPortInfo, SerialPortType, UsbPortInfo};
 
 use packet_router::Router;
 use topics::{PacketData, PacketFormat};
 
+use crate::config::{SerialConfig, SerialDeviceConfig};
+use crate::node::{Node, NodeContext};
 use crate::nodes::serial_client::SerialClient;
 
 pub struct SerialAdapter {
     pub router: Rc<RefCell<Router<PacketFormat<PacketData>>>>,
-    pub clients_by_path: HashMap<String, SerialClient>,
+    /** Connected devices, by the name of the rule they matched */
+    pub clients_by_name: HashMap<String, SerialClient>,
     pub last_scan_time: Instant,
     pub scan_interval: Duration,
+    config: SerialConfig,
+}
+
+/** A port that might be one of our devices */
+struct Candidate {
+    path: String,
+    usb: Option<UsbPortInfo>,
+}
+
+/** Matches `*` (any run of characters) and `?` (any one character) */
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Where to resume if the current attempt fails: the last `*` and the text it has swallowed
+    let mut backtrack: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star, swallowed)) = backtrack {
+            p = star + 1;
+            t = swallowed + 1;
+            backtrack = Some((star, swallowed + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/** Existing paths matching a glob with wildcards in the last component only */
+fn expand_glob(pattern: &str) -> Vec<String> {
+    if !pattern.contains(['*', '?']) {
+        return if Path::new(pattern).exists() {
+            vec![pattern.to_string()]
+        } else {
+            Vec::new()
+        };
+    }
+    let (directory, file_pattern) = pattern.rsplit_once('/').unwrap_or((".", pattern));
+    let directory = if directory.is_empty() { "/" } else { directory };
+    let Ok(entries) = std::fs::read_dir(directory) else {
+        return Vec::new();
+    };
+    let mut paths: Vec<String> = entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| glob_match(file_pattern, &entry.file_name().to_string_lossy()))
+        .map(|entry| Path::new(directory).join(entry.file_name()).to_string_lossy().to_string())
+        .collect();
+    paths.sort();
+    paths
+}
+
+fn canonical(path: &str) -> PathBuf {
+    std::fs::canonicalize(path).unwrap_or_else(|_| PathBuf::from(path))
+}
+
+fn is_match(rule: &SerialDeviceConfig, candidate: &Candidate) -> bool {
+    if let Some(pattern) = &rule.fallback_path
+        && glob_match(pattern, &candidate.path)
+    {
+        return true;
+    }
+    if let Some(pattern) = &rule.path
+        && !glob_match(pattern, &candidate.path)
+    {
+        return false;
+    }
+    if rule.vid.is_none()
+        && rule.pid.is_none()
+        && rule.serial_number.is_none()
+        && rule.manufacturer.is_none()
+    {
+        return rule.path.is_some();
+    }
+    let Some(usb) = &candidate.usb else {
+        return false;
+    };
+    let matches_string = |pattern: &Option<String>, value: &Option<String>| match pattern {
+        Some(pattern) => value.as_ref().is_some_and(|value| glob_match(pattern, value)),
+        None => true,
+    };
+    rule.vid.is_none_or(|vid| vid == usb.vid)
+        && rule.pid.is_none_or(|pid| pid == usb.pid)
+        && matches_string(&rule.serial_number, &usb.serial_number)
+        && matches_string(&rule.manufacturer, &usb.manufacturer)
 }
 
 impl SerialAdapter {
-    pub fn new(router: Rc<RefCell<Router<PacketFormat<PacketData>>>>, scan_interval: Duration) -> Self {
+    pub fn new(router: Rc<RefCell<Router<PacketFormat<PacketData>>>>, config: SerialConfig) -> Self {
+        let scan_interval = Duration::from_millis(config.scan_interval_ms);
         println!("SerialAdapter initialized with scan interval: {:?}", scan_interval);
         SerialAdapter {
             router,
-            clients_by_path: HashMap::new(),
+            clients_by_name: HashMap::new(),
             last_scan_time: Instant::now(),
             scan_interval,
+            config,
         }
     }
 
-    fn is_target_device(port_info: &serialport::SerialPortInfo) -> bool {
-        // Filter for Espressif USB JTAG serial debug units
-        // You can customize this filter based on your specific devices
-        match &port_info.port_type {
-            SerialPortType::UsbPort(usb_info) => {
-                // Espressif USB JTAG typically has VID 0x303A
-                if usb_info.vid == 0x303A {
-                    return true;
+    /** Serial ports, plus any existing paths matching the path rules (eg udev symlinks) */
+    fn find_candidates(&self) -> Vec<Candidate> {
+        let ports = available_ports().unwrap_or_else(|e| {
+            eprintln!("Error scanning for serial ports: {:?}", e);
+            Vec::new()
+        });
+        let usb_info = |port_info: &SerialPortInfo| match &port_info.port_type {
+            SerialPortType::UsbPort(usb) => Some(usb.clone()),
+            _ => None,
+        };
+
+        let mut candidates: Vec<Candidate> = ports
+            .iter()
+            .map(|port_info| Candidate {
+                path: port_info.port_name.clone(),
+                usb: usb_info(port_info),
+            })
+            .collect();
+
+        for pattern in self.config.devices.iter().filter_map(|rule| rule.path.as_ref()) {
+            for path in expand_glob(pattern) {
+                if candidates.iter().any(|candidate| candidate.path == path) {
+                    continue;
                 }
+                // A symlink to a listed port has that port's USB details
+                let target = canonical(&path);
+                let usb = ports
+                    .iter()
+                    .find(|port_info| canonical(&port_info.port_name) == target)
+                    .and_then(usb_info);
+                candidates.push(Candidate { path, usb });
             }
-            _ => {}
-        }
-        
-        // Also check if the path contains the expected pattern
-        if port_info.port_name.contains("usb-Espressif_USB_JTAG_serial_debug_unit") {
-            return true;
         }
-        
-        false
+        candidates
     }
 
     fn scan_ports(&mut self) {
-        match available_ports() {
-            Ok(ports) => {
-                for port_info in ports {
-                    let port_path = port_info.port_name.clone();
-                    
-                    // Skip if not a target device
-                    if !Self::is_target_device(&port_info) {
-                        continue;
-                    }
-                    
-                    // Skip if we already have a client for this port
-                    if self.clients_by_path.contains_key(&port_path) {
-                        continue;
-                    }
-                    
-                    // Try to open the serial port
-                    match serialport::new(&port_path, 115_200)
-                        .timeout(Duration::from_millis(1))
-                        .open()
-                    {
-                        Ok(serial_port) => {
-                            println!("New serial device connected: {}", port_path);
-                            let client = SerialClient::new(serial_port);
-                            self.router.borrow_mut().register_client(Rc::downgrade(&client.client));
-                            self.clients_by_path.insert(port_path.clone(), client);
-                        }
-                        Err(e) => {
-                            eprintln!("Failed to open serial port {}: {:?}", port_path, e);
-                        }
-                    }
-                }
+        let candidates = self.find_candidates();
+
+        // The same device may be matched by several rules or reachable through several paths
+        let mut open_devices: HashSet<PathBuf> = self
+            .clients_by_name
+            .values()
+            .filter_map(|client| client.path())
+            .map(|path| canonical(&path))
+            .collect();
+
+        for rule in self.config.devices.iter() {
+            if self.clients_by_name.contains_key(&rule.name) {
+                continue;
             }
-            Err(e) => {
-                eprintln!("Error scanning for serial ports: {:?}", e);
+            let mut matching = candidates
+                .iter()
+                .filter(|candidate| is_match(rule, candidate))
+                .filter(|candidate| !open_devices.contains(&canonical(&candidate.path)));
+            let Some(candidate) = matching.next() else {
+                continue;
+            };
+            if matching.next().is_some() {
+                eprintln!(
+                    "Several serial devices match {}, using {}. \
+                     Add a serial_number or path to tell them apart.",
+                    rule.name, candidate.path
+                );
+            }
+
+            match serialport::new(&candidate.path, rule.baud_rate)
+                .timeout(Duration::from_millis(1))
+                .open()
+            {
+                Ok(serial_port) => {
+                    println!("Serial device {} connected: {}", rule.name, candidate.path);
+                    let client = SerialClient::new(&rule.name, serial_port);
+                    self.router.borrow_mut().register_client(Rc::downgrade(&client.client));
+                    self.clients_by_name.insert(rule.name.clone(), client);
+                    open_devices.insert(canonical(&candidate.path));
+                }
+                Err(e) => {
+                    eprintln!("Failed to open serial port {}: {:?}", candidate.path, e);
+                }
             }
         }
     }
 
-    pub fn tick(&mut self) {
+    /** Returns true if any of the serial devices were active. */
+    pub fn tick(&mut self) -> bool {
+        let mut did_work = false;
+
         // Periodically scan for new ports
         if self.last_scan_time.elapsed() >= self.scan_interval {
             self.scan_ports();
@@ -95,18 +218,125 @@ impl SerialAdapter {
         }
 
         // Tick all existing clients
-        for (_path, client) in self.clients_by_path.iter_mut() {
-            client.tick();
+        for client in self.clients_by_name.values_mut() {
+            did_work |= client.tick();
         }
 
         // Remove dead clients
-        self.clients_by_path.retain(|path, client| {
+        self.clients_by_name.retain(|name, client| {
             if !client.is_alive {
-                println!("Serial device disconnected: {}", path);
+                println!("Serial device {} disconnected", name);
                 false
             } else {
                 true
             }
         });
+        did_work
+    }
+}
+
+impl Node for SerialAdapter {
+    fn name(&self) -> &str {
+        "serial"
+    }
+
+    fn on_poll(&mut self, _ctx: &mut NodeContext) -> bool {
+        self.tick()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usb(vid: u16, pid: u16, serial_number: &str) -> Option<UsbPortInfo> {
+        Some(UsbPortInfo {
+            vid,
+            pid,
+            serial_number: Some(serial_number.to_string()),
+            manufacturer: Some("Espressif".to_string()),
+            product: None,
+        })
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("/dev/ttyACM*", "/dev/ttyACM0"));
+        assert!(glob_match("/dev/ttyACM*", "/dev/ttyACM"));
+        assert!(glob_match("/dev/tty???", "/dev/ttyS10"));
+        assert!(glob_match("*JTAG*unit*", "usb-Espressif_USB_JTAG_serial_debug_unit-if00"));
+        assert!(glob_match("a*b*c", "aXbYbZc"));
+        assert!(!glob_match("/dev/ttyACM*", "/dev/ttyUSB0"));
+        assert!(!glob_match("/dev/tty???", "/dev/ttyS1"));
+        assert!(!glob_match("a*b", "aXbY"));
+    }
+
+    #[test]
+    fn test_rule_matching() {
+        let motors = Candidate {
+            path: "/dev/ttyACM0".to_string(),
+            usb: usb(0x303A, 0x1001, "F4:12:FA:00:00:01"),
+        };
+        let sensors = Candidate {
+            path: "/dev/ttyACM1".to_string(),
+            usb: usb(0x303A, 0x1001, "F4:12:FA:00:00:02"),
+        };
+        let pty = Candidate {
+            path: "/dev/pts/3".to_string(),
+            usb: None,
+        };
+
+        let by_vid = SerialDeviceConfig {
+            name: "any".to_string(),
+            vid: Some(0x303A),
+            ..Default::default()
+        };
+        assert!(is_match(&by_vid, &motors));
+        assert!(is_match(&by_vid, &sensors));
+        assert!(!is_match(&by_vid, &pty));
+
+        let by_serial = SerialDeviceConfig {
+            name: "sensors".to_string(),
+            vid: Some(0x303A),
+            serial_number: Some("*:02".to_string()),
+            manufacturer: Some("Espressif".to_string()),
+            ..Default::default()
+        };
+        assert!(!is_match(&by_serial, &motors));
+        assert!(is_match(&by_serial, &sensors));
+
+        let by_path = SerialDeviceConfig {
+            name: "motors".to_string(),
+            path: Some("/dev/ttyACM0".to_string()),
+            ..Default::default()
+        };
+        assert!(is_match(&by_path, &motors));
+        assert!(!is_match(&by_path, &sensors));
+
+        let wrong_pid = SerialDeviceConfig {
+            name: "other".to_string(),
+            vid: Some(0x303A),
+            pid: Some(0x2000),
+            ..Default::default()
+        };
+        assert!(!is_match(&wrong_pid, &motors));
+
+        // The default rule also takes a port that is only recognisable by its name
+        let by_name = Candidate {
+            path: "/dev/serial/by-id/usb-Espressif_USB_JTAG_serial_debug_unit_F4:12-if00"
+                .to_string(),
+            usb: None,
+        };
+        let default_rule = &SerialConfig::default().devices[0];
+        assert!(is_match(default_rule, &motors));
+        assert!(is_match(default_rule, &by_name));
+        assert!(!is_match(default_rule, &pty));
+        let only_fallback = SerialDeviceConfig {
+            name: "jtag".to_string(),
+            fallback_path: default_rule.fallback_path.clone(),
+            ..Default::default()
+        };
+        assert!(!is_match(&only_fallback, &motors));
+        assert!(is_match(&only_fallback, &by_name));
     }
 }
@@ -1,12 +1,17 @@
 use packet_encoding::{PacketFinder, decode_packet, encode_packet};
+use packet_reliability::{DuplicateFilter, PacketKey, ReliableSender, RetransmitConfig};
 use packet_router::Client;
 use serde::Serialize;
 use serialport::SerialPort;
+use std::io::{Read, Write};
 use std::rc::Rc;
 use std::time::{Duration, Instant};
 use std::{cell::RefCell, collections::HashSet};
 
-use topics::{DiagnosticMsg, PacketData, PacketFormat, SubscriptionRequest};
+use topics::{
+    DiagnosticMsg, MAX_PACKET_SIZE, PacketAck, PacketData, PacketDataTrait, PacketFormat,
+    SubscriptionRequest,
+};
 
 use heapless::{String as HString, format as hformat};
 use std::str::FromStr;
@@ -22,11 +27,21 @@ pub struct SerialClientStats {
     pub rx_bytes: u32,
     pub encode_error_count: u32,
     pub write_error_count: u32,
+    pub retransmit_count: u32,
+    pub ack_timeout_count: u32,
+    pub duplicate_count: u32,
+    pub queue_drop_count: u32,
 }
 
 impl SerialClientStats {
-    fn to_log(&self) -> DiagnosticMsg {
-        let mut values = heapless::Vec::<topics::DiagnosticKeyValue, 8>::new();
+    fn to_log(&self, stats_name: &str, device_name: &str) -> DiagnosticMsg {
+        let mut values = heapless::Vec::<topics::DiagnosticKeyValue, 16>::new();
+        values
+            .push(topics::DiagnosticKeyValue {
+                key: HString::from_str("device").unwrap(),
+                value: truncated(device_name),
+            })
+            .ok();
         values
             .push(topics::DiagnosticKeyValue {
                 key: HString::from_str("decode_errors").unwrap(),
@@ -69,10 +84,34 @@ impl SerialClientStats {
                 value: hformat!("{}", self.write_error_count).unwrap(),
             })
             .ok();
+        values
+            .push(topics::DiagnosticKeyValue {
+                key: HString::from_str("retransmits").unwrap(),
+                value: hformat!("{}", self.retransmit_count).unwrap(),
+            })
+            .ok();
+        values
+            .push(topics::DiagnosticKeyValue {
+                key: HString::from_str("ack_timeouts").unwrap(),
+                value: hformat!("{}", self.ack_timeout_count).unwrap(),
+            })
+            .ok();
+        values
+            .push(topics::DiagnosticKeyValue {
+                key: HString::from_str("duplicates").unwrap(),
+                value: hformat!("{}", self.duplicate_count).unwrap(),
+            })
+            .ok();
+        values
+            .push(topics::DiagnosticKeyValue {
+                key: HString::from_str("queue_drops").unwrap(),
+                value: hformat!("{}", self.queue_drop_count).unwrap(),
+            })
+            .ok();
 
         DiagnosticMsg {
             level: topics::DiagnosticStatus::Ok,
-            name: HString::from_str("serial_stats").unwrap(),
+            name: HString::from_str(stats_name).unwrap(),
             message: HString::from_str("").unwrap(),
             values,
         }
@@ -80,22 +119,108 @@ impl SerialClientStats {
 }
 
 
+/**
+ * The packet as it is written to the link, numbered by the link rather than by its sender. ACKs
+ * and duplicate detection go by this id, and senders such as the bridges for clients that don't
+ * number their messages use the same id for every packet.
+ */
+fn link_packet(packet: &PacketFormat<PacketData>, id: u32) -> PacketFormat<&PacketData> {
+    PacketFormat {
+        to: packet.to,
+        from: packet.from,
+        data: &packet.data,
+        time: packet.time,
+        id,
+    }
+}
+
+/** Diagnostic values only have room for 16 characters */
+fn truncated(text: &str) -> HString<16> {
+    let mut result = HString::new();
+    for c in text.chars() {
+        if result.push(c).is_err() {
+            break;
+        }
+    }
+    result
+}
+
+/**
+ * The byte stream a `SerialClient` frames packets over. Serial ports, TCP connections and UDP peers
+ * all carry the same COBS framed packets.
+ */
+pub trait Link: Read + Write {
+    /** Path or address of the other end */
+    fn name(&self) -> Option<String>;
+
+    /** How fast the link carries data, if it is slow enough to need pacing */
+    fn bytes_per_second(&self) -> Option<u32>;
+}
+
+impl Link for Box<dyn SerialPort> {
+    fn name(&self) -> Option<String> {
+        self.as_ref().name()
+    }
+
+    fn bytes_per_second(&self) -> Option<u32> {
+        // 8N1: 10 bits on the wire per byte
+        Some(self.baud_rate().unwrap_or(115_200) / 10)
+    }
+}
+
 pub struct SerialClient {
-    serialport: Box<dyn SerialPort>,
+    /** Name of the device rule this port matched (see `SerialDeviceConfig`), or the peer's address */
+    pub name: String,
+    link: Box<dyn Link>,
+    stats_name: &'static str,
     pub client: Rc<RefCell<Client<PacketFormat<PacketData>>>>,
-    packet_finder: PacketFinder,
+    packet_finder: PacketFinder<MAX_PACKET_SIZE>,
 
     pub stats: SerialClientStats,
     pub stats_send_time: Instant,
     pub is_alive: bool,
+
+    /** Topics the device asked to be delivered reliably, see `SubscriptionRequest::reliable_topics` */
+    pub reliable_topics: HashSet<String>,
+    /** Reliable packets waiting for an ACK, with the link id they were written with */
+    reliable_sender: ReliableSender<(u32, Rc<PacketFormat<PacketData>>), 16>,
+    duplicate_filter: DuplicateFilter<32>,
+    /** The id the next packet is written with, see `link_packet` */
+    next_link_id: u32,
+    /** When the client was created, `link_time` counts from here */
+    link_epoch: Instant,
+
+    /**
+     * Bytes we may still write, refilled at the link's baud rate. Packets beyond this stay in the
+     * client's priority lanes so that a later high priority packet can still overtake them.
+     */
+    tx_budget: f64,
+    tx_budget_time: Instant,
+    tx_bytes_per_second: Option<u32>,
 }
 
+/** Don't let too many queued packets build up for a slow device, drop the oldest instead */
+const QUEUE_LANE_LIMIT: usize = 64;
+
 impl SerialClient {
-    pub fn new(serialport: Box<dyn SerialPort>) -> Self {
+    pub fn new(name: &str, serialport: Box<dyn SerialPort>) -> Self {
+        Self::with_link(name, Box::new(serialport), "serial_stats")
+    }
+
+    /** A client for any other link, reporting its stats in a diagnostic called `stats_name` */
+    pub fn with_link(name: &str, link: Box<dyn Link>, stats_name: &'static str) -> Self {
+        let tx_bytes_per_second = link.bytes_per_second();
+        let client = Rc::new(RefCell::new(Client::default()));
+        client
+            .borrow_mut()
+            .router_to_client
+            .set_lane_limit(Some(QUEUE_LANE_LIMIT));
         SerialClient {
-            serialport,
-            client: Rc::new(RefCell::new(Client::default())),
-            packet_finder: PacketFinder::new(),
+            name: name.to_string(),
+            link,
+            stats_name,
+            client,
+            packet_finder: PacketFinder::default(),
             stats: SerialClientStats {
                 decode_error_count: 0,
                 tx_packets: 0,
@@ -104,12 +229,39 @@ impl SerialClient {
                 rx_bytes: 0,
                 encode_error_count: 0,
                 write_error_count: 0,
+                retransmit_count: 0,
+                ack_timeout_count: 0,
+                duplicate_count: 0,
+                queue_drop_count: 0,
             },
             stats_send_time: Instant::now(),
             is_alive: true,
+            reliable_topics: HashSet::new(),
+            reliable_sender: ReliableSender::new(RetransmitConfig::default()),
+            duplicate_filter: DuplicateFilter::new(Duration::from_secs(5)),
+            // Start somewhere new each time, so the device doesn't take the first packets after a
+            // reconnection for ones it has already seen
+            next_link_id: get_current_time() as u32,
+            link_epoch: Instant::now(),
+            tx_budget: 0.0,
+            tx_budget_time: Instant::now(),
+            tx_bytes_per_second,
         }
     }
 
+    /**
+     * Monotonic time in microseconds for the retransmission timers. Unlike the clock it never
+     * jumps, so a clock change can't fire or stall every timer at once.
+     */
+    fn link_time(&self) -> u64 {
+        self.link_epoch.elapsed().as_micros() as u64
+    }
+
+    /** The path the port was opened with, or the peer's address */
+    pub fn path(&self) -> Option<String> {
+        self.link.name()
+    }
+
     pub fn update_topics(&mut self, sub_req: &SubscriptionRequest) {
         let topics_set = HashSet::<String>::from_iter(sub_req.topics.iter().map(|s| s.to_string()));
         if topics_set
@@ -119,12 +271,60 @@ impl SerialClient {
         {
             self.client.borrow_mut().subscriptions = topics_set;
         }
+        self.reliable_topics =
+            HashSet::<String>::from_iter(sub_req.reliable_topics.iter().map(|s| s.to_string()));
+    }
+
+    /**
+     * Handles the link-level side of reliable delivery for a packet read from the device.
+     * Returns true if the packet should be passed on to the router.
+     */
+    fn handle_reliable_rx(&mut self, packet: &PacketFormat<PacketData>) -> bool {
+        if let PacketData::PacketAck(ack) = &packet.data {
+            self.reliable_sender.acknowledge(PacketKey {
+                from: ack.from,
+                id: ack.id,
+            });
+            return false;
+        }
+        if !self.reliable_topics.contains(packet.data.topic()) {
+            return true;
+        }
+
+        // Always ACK, even duplicates, as it means our previous ACK went missing
+        self.write_packet(&PacketFormat {
+            to: None,
+            from: None,
+            data: PacketData::PacketAck(PacketAck {
+                from: packet.from,
+                id: packet.id,
+            }),
+            time: get_current_time(),
+            id: 0,
+        });
+        let key = PacketKey {
+            from: packet.from,
+            id: packet.id,
+        };
+        if self.duplicate_filter.accept(key, self.link_time()) {
+            true
+        } else {
+            self.stats.duplicate_count += 1;
+            false
+        }
     }
 
-    pub fn read(&mut self) {
+    /** Returns true if any bytes were read. */
+    pub fn read(&mut self) -> bool {
         // Read from serial port into incoming queue
         let mut mini_buffer: [u8; 256] = [0u8; 256];
-        match self.serialport.read(&mut mini_buffer) {
+        match self.link.read(&mut mini_buffer) {
+            Ok(0) => {
+                // End of stream, eg the TCP connection was closed. Serial ports report a timeout
+                // when there is nothing to read.
+                self.is_alive = false;
+                false
+            }
             Ok(read_bytes) => {
                 for byte in mini_buffer.iter().take(read_bytes) {
                     if let Some(packet) = self.packet_finder.push_byte(*byte)
@@ -137,8 +337,8 @@ impl SerialClient {
                         match decode_packet::<PacketFormat<PacketData>>(&mut packet_data) {
                             Ok(packet) => {
                                 if let PacketData::SubscriptionRequest(sub_req) = &packet.data {
-                                    self.update_topics(&sub_req);
-                                } else {
+                                    self.update_topics(sub_req);
+                                } else if self.handle_reliable_rx(&packet) {
                                     self.client.borrow_mut().client_to_router.push(packet);
                                 }
                             }
@@ -149,57 +349,120 @@ impl SerialClient {
                         }
                     }
                 }
+                read_bytes > 0
             }
-            Err(ref e) if e.kind() == std::io::ErrorKind::TimedOut => {
+            Err(ref e)
+                if matches!(
+                    e.kind(),
+                    std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock
+                ) =>
+            {
                 // Timeout is expected with non-blocking reads
+                false
             }
             Err(ref e) if e.kind() == std::io::ErrorKind::BrokenPipe => {
                 // Device disconnected
                 self.is_alive = false;
+                false
             }
             Err(_) => {
                 // Other errors might indicate disconnection
                 self.is_alive = false;
+                false
             }
         }
     }
 
-    pub fn write(&mut self) {
-        let packets = self.client.borrow_mut().fetch_all();
-        for packet in packets {
-            self.stats.tx_packets += 1;
+    fn write_packet<T: Serialize>(&mut self, packet: &PacketFormat<T>) {
+        self.stats.tx_packets += 1;
 
-            let mut encode_buffer: [u8; 512] = [0; 512];
-            encode_buffer[0] = 0; // COBS initial byte
-            match encode_packet(&(*packet), &mut encode_buffer[1..]) {
-                Ok(encoded_size) => {
-                    encode_buffer[encoded_size + 1] = 0x00; // COBS final byte
-                    if let Err(e) = self
-                        .serialport
-                        .write_all(&encode_buffer[..encoded_size + 2])
-                    {
-                        self.stats.write_error_count += 1;
-                        eprintln!("Failed to write packet: {:?}", e);
-                        // Mark as dead if we can't write
-                        if e.kind() == std::io::ErrorKind::BrokenPipe {
-                            self.is_alive = false;
-                        }
+        let mut encode_buffer = [0u8; MAX_PACKET_SIZE];
+        encode_buffer[0] = 0; // COBS initial byte
+        match encode_packet(packet, &mut encode_buffer[1..MAX_PACKET_SIZE - 1]) {
+            Ok(encoded_size) => {
+                encode_buffer[encoded_size + 1] = 0x00; // COBS final byte
+                if let Err(e) = self
+                    .link
+                    .write_all(&encode_buffer[..encoded_size + 2])
+                {
+                    self.stats.write_error_count += 1;
+                    eprintln!("Failed to write packet: {:?}", e);
+                    // Mark as dead if we can't write
+                    if e.kind() == std::io::ErrorKind::BrokenPipe {
+                        self.is_alive = false;
                     }
-                    self.stats.tx_bytes += (encoded_size + 2) as u32;
-                }
-                Err(e) => {
-                    self.stats.encode_error_count += 1;
-                    eprintln!("Failed to encode packet: {:?}", e);
                 }
+                self.stats.tx_bytes += (encoded_size + 2) as u32;
+                self.tx_budget -= (encoded_size + 2) as f64;
+            }
+            Err(e) => {
+                self.stats.encode_error_count += 1;
+                eprintln!("Failed to encode packet: {:?}", e);
             }
         }
     }
 
-    pub fn tick(&mut self) {
-        self.read();
-        self.write();
+    fn refill_tx_budget(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.tx_budget_time);
+        self.tx_budget_time = now;
+
+        let Some(tx_bytes_per_second) = self.tx_bytes_per_second else {
+            self.tx_budget = f64::INFINITY;
+            return;
+        };
+        // Allow bursts of up to 100ms worth of data, but always at least one full packet
+        let max_budget = (tx_bytes_per_second as f64 / 10.0).max(512.0);
+        let refill = elapsed.as_secs_f64() * tx_bytes_per_second as f64;
+        self.tx_budget = (self.tx_budget + refill).min(max_budget);
+    }
+
+    /** Returns true if any packets were written. */
+    pub fn write(&mut self) -> bool {
+        let mut did_work = false;
+        self.refill_tx_budget();
+        while self.tx_budget > 0.0 {
+            let Some(packet) = self.client.borrow_mut().fetch_next() else {
+                break;
+            };
+            let id = self.next_link_id;
+            self.next_link_id = self.next_link_id.wrapping_add(1);
+            self.write_packet(&link_packet(&packet, id));
+            did_work = true;
+
+            let topic = packet.data.topic();
+            if self.reliable_topics.contains(topic) {
+                let key = PacketKey {
+                    from: packet.from,
+                    id,
+                };
+                let now = self.link_time();
+                self.reliable_sender.track(key, topic, (id, packet), now).ok();
+            }
+        }
+        did_work
+    }
+
+    fn retransmit(&mut self) {
+        let mut resend: Vec<(u32, Rc<PacketFormat<PacketData>>)> = Vec::new();
+        let now = self.link_time();
+        let result = self
+            .reliable_sender
+            .poll(now, |(id, packet)| resend.push((*id, packet.clone())));
+        for (id, packet) in resend {
+            self.write_packet(&link_packet(&packet, id));
+        }
+        self.stats.retransmit_count += result.retransmitted;
+        self.stats.ack_timeout_count += result.expired;
+    }
+
+    /** Returns true if anything was read or written. */
+    pub fn tick(&mut self) -> bool {
+        let did_work = self.read() | self.write();
+        self.retransmit();
         if self.stats_send_time.elapsed() >= Duration::from_secs(1) {
-            let diag_msg: DiagnosticMsg = self.stats.to_log();
+            self.stats.queue_drop_count = self.client.borrow().router_to_client.dropped();
+            let diag_msg: DiagnosticMsg = self.stats.to_log(self.stats_name, &self.name);
             self.client
                 .borrow_mut()
                 .client_to_router
@@ -212,5 +475,194 @@ impl SerialClient {
                 });
             self.stats_send_time = Instant::now();
         }
+        did_work
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+
+    /** A link backed by memory: tests push bytes for the client to read and see what it wrote */
+    #[derive(Clone, Default)]
+    struct MemoryLink {
+        rx: Rc<RefCell<VecDeque<u8>>>,
+        tx: Rc<RefCell<Vec<u8>>>,
+    }
+
+    impl Read for MemoryLink {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let mut rx = self.rx.borrow_mut();
+            if rx.is_empty() {
+                return Err(std::io::ErrorKind::WouldBlock.into());
+            }
+            let count = buf.len().min(rx.len());
+            for (byte, value) in buf.iter_mut().zip(rx.drain(..count)) {
+                *byte = value;
+            }
+            Ok(count)
+        }
+    }
+
+    impl Write for MemoryLink {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.tx.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Link for MemoryLink {
+        fn name(&self) -> Option<String> {
+            Some("memory".to_string())
+        }
+
+        fn bytes_per_second(&self) -> Option<u32> {
+            None
+        }
+    }
+
+    fn client(reliable_topic: &str) -> (SerialClient, MemoryLink) {
+        let link = MemoryLink::default();
+        let mut client = SerialClient::with_link("test", Box::new(link.clone()), "test_stats");
+        client.reliable_topics.insert(reliable_topic.to_string());
+        (client, link)
+    }
+
+    fn velocity_request(id: u32) -> PacketFormat<PacketData> {
+        PacketFormat {
+            to: None,
+            from: Some(2),
+            data: PacketData::MotionVelocityRequest(topics::MotionVelocityRequest {
+                linear_velocity: 0.1,
+                angular_velocity: 0.0,
+            }),
+            time: 0,
+            id,
+        }
+    }
+
+    /** Moves the client's link time on, as if `ms` had passed */
+    fn advance(client: &mut SerialClient, ms: u64) {
+        client.link_epoch -= Duration::from_millis(ms);
+    }
+
+    fn send_to_client(link: &MemoryLink, packet: &PacketFormat<PacketData>) {
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+        let size = encode_packet(packet, &mut buffer[1..MAX_PACKET_SIZE - 1]).unwrap();
+        link.rx.borrow_mut().extend(&buffer[..size + 2]);
+    }
+
+    /** Everything the client has written since the last call */
+    fn written(link: &MemoryLink) -> Vec<PacketFormat<PacketData>> {
+        let mut finder = PacketFinder::<MAX_PACKET_SIZE>::default();
+        let mut packets = Vec::new();
+        for byte in std::mem::take(&mut *link.tx.borrow_mut()) {
+            if let Some(packet) = finder.push_byte(byte)
+                && !packet.is_empty()
+            {
+                let mut packet_data = packet.to_vec();
+                packets.push(decode_packet(&mut packet_data).unwrap());
+            }
+        }
+        packets
+    }
+
+    #[test]
+    fn test_acknowledged_packets_are_not_resent() {
+        let (mut client, link) = client("MotionVelocityRequest");
+        let packet = Rc::new(velocity_request(7));
+        client.client.borrow_mut().write_router_to_client(vec![packet]);
+        client.write();
+        let sent = written(&link);
+        assert_eq!(sent.len(), 1);
+
+        send_to_client(
+            &link,
+            &PacketFormat {
+                to: None,
+                from: None,
+                data: PacketData::PacketAck(PacketAck {
+                    from: Some(2),
+                    id: sent[0].id,
+                }),
+                time: 0,
+                id: 0,
+            },
+        );
+        client.read();
+        advance(&mut client, 1000);
+        client.retransmit();
+        assert!(written(&link).is_empty());
+        assert_eq!(client.stats.retransmit_count, 0);
+        assert!(client.client.borrow().client_to_router.is_empty());
+    }
+
+    #[test]
+    fn test_unacknowledged_packets_are_resent_with_backoff() {
+        let (mut client, link) = client("MotionVelocityRequest");
+        let packet = Rc::new(velocity_request(7));
+        client.client.borrow_mut().write_router_to_client(vec![packet]);
+        client.write();
+        assert_eq!(written(&link).len(), 1);
+
+        // The first resend is after 50ms, then the timeout doubles
+        for (ms, resends) in [(45, 0), (10, 1), (90, 0), (10, 1)] {
+            advance(&mut client, ms);
+            client.retransmit();
+            assert_eq!(written(&link).len(), resends);
+        }
+        assert_eq!(client.stats.retransmit_count, 2);
+    }
+
+    #[test]
+    fn test_duplicates_are_dropped() {
+        let (mut client, link) = client("MotionVelocityRequest");
+        send_to_client(&link, &velocity_request(3));
+        send_to_client(&link, &velocity_request(3));
+        client.read();
+
+        // Both are acknowledged, in case the first ACK went missing, but only one is delivered
+        let acks = written(&link);
+        assert_eq!(acks.len(), 2);
+        assert!(
+            acks.iter()
+                .all(|ack| matches!(ack.data, PacketData::PacketAck(PacketAck { id: 3, .. })))
+        );
+        assert_eq!(client.client.borrow().client_to_router.len(), 1);
+        assert_eq!(client.stats.duplicate_count, 1);
+    }
+
+    #[test]
+    fn test_packets_are_numbered_by_the_link() {
+        // Bridges send every packet with id 0, yet neither is mistaken for a retransmission
+        let (mut host, host_link) = client("MotionVelocityRequest");
+        let (mut device, device_link) = client("MotionVelocityRequest");
+        let packets = vec![Rc::new(velocity_request(0)), Rc::new(velocity_request(0))];
+        host.client.borrow_mut().write_router_to_client(packets);
+        host.write();
+        device_link
+            .rx
+            .borrow_mut()
+            .extend(std::mem::take(&mut *host_link.tx.borrow_mut()));
+        device.read();
+        assert_eq!(device.client.borrow().client_to_router.len(), 2);
+        assert_eq!(device.stats.duplicate_count, 0);
+
+        // Retransmissions keep their id, so the device still drops them
+        advance(&mut host, 60);
+        host.retransmit();
+        assert_eq!(host.stats.retransmit_count, 1);
+        device_link
+            .rx
+            .borrow_mut()
+            .extend(std::mem::take(&mut *host_link.tx.borrow_mut()));
+        device.read();
+        assert_eq!(device.client.borrow().client_to_router.len(), 2);
+        assert_eq!(device.stats.duplicate_count, 1);
     }
 }
@@ -0,0 +1,412 @@
+use std::str::FromStr;
+use std::time::Duration;
+
+use drive_base::command_age::CommandAgeCheck;
+// The firmware's physics and motor handling, so the simulation can't drift from the real robot
+use drive_base::consts::{
+    ENCODER_TICKS_PER_REVOLUTION, MAX_COMMAND_AGE, MAX_OUTPUT_RATE, NOMINAL_MAX_RPM,
+    WHEEL_BASE_WIDTH, WHEEL_CIRCUMFERENCE,
+};
+use drive_base::motor::{motor_output, velocity_to_speed_percent};
+use drive_base::odometry::update_odometry;
+use drive_base::slew::SlewLimiter;
+use heapless::{String as HString, format as hformat};
+use topics::{DiagnosticMsg, MotionVelocityRequest, OdometryDelta, PacketData, PacketDataTrait, PacketFormat};
+
+use crate::config::SimulatedBaseConfig;
+use crate::node::{Node, NodeContext};
+use crate::nodes::clock::get_current_time;
+
+/** How often the simulation is stepped */
+const SIMULATION_STEP: Duration = Duration::from_millis(10);
+
+/** Small xorshift generator, so that noisy simulations can be repeated from a seed */
+struct Rng(u64);
+
+impl Rng {
+    fn next_f64(&mut self) -> f64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /** Normally distributed with mean 0 and standard deviation 1 (Box-Muller) */
+    fn next_gaussian(&mut self) -> f64 {
+        let u1 = self.next_f64().max(f64::MIN_POSITIVE);
+        let u2 = self.next_f64();
+        (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos()
+    }
+}
+
+/**
+ * Stands in for the motor controller when no hardware is attached. It accepts
+ * `MotionVelocityRequest`s and publishes `OdometryDelta`s the same way the firmware does:
+ *
+ * - Commands are mixed into left/right motor outputs and saturated exactly as in
+ *   `MotorControllers::handle_speed_request`, including rejecting stale commands.
+ * - The outputs ramp towards the commanded speeds like `MotorControllers::tick`.
+ * - The motors stop if no command has been accepted for `command_timeout_ms`.
+ * - Wheel travel is quantized to encoder ticks and integrated with the firmware's
+ *   `update_odometry` (+y is forward, positive rotation is anticlockwise).
+ *
+ * Wheel slip makes the robot travel less than its encoders report, and noise is added to each
+ * wheel's travel. The true pose is reported in the "sim_base" diagnostic so it can be compared
+ * against the position estimate.
+ */
+pub struct SimulatedBase {
+    config: SimulatedBaseConfig,
+    rng: Rng,
+
+    // Motor outputs as a fraction of full speed, as set by the firmware's `MotorDriver::set_speed`
+    left_speed: f32,
+    right_speed: f32,
+    // The commanded outputs, which the motors are ramped towards
+    left_target: f32,
+    right_target: f32,
+    left_output: SlewLimiter,
+    right_output: SlewLimiter,
+    time_since_command: Duration,
+
+    // Encoder ticks not yet reported, the remainder after quantizing wheel travel
+    left_ticks: f64,
+    right_ticks: f64,
+
+    odometry: OdometryDelta,
+    time_since_publish: Duration,
+
+    true_position: [f64; 2],
+    true_orientation: f64,
+
+    command_age: CommandAgeCheck,
+    timeout_count: u32,
+    stats_time: Duration,
+}
+
+impl SimulatedBase {
+    pub fn new(config: SimulatedBaseConfig) -> Self {
+        let now = get_current_time();
+        SimulatedBase {
+            rng: Rng(config.seed.max(1)),
+            config,
+            left_speed: 0.0,
+            right_speed: 0.0,
+            left_target: 0.0,
+            right_target: 0.0,
+            left_output: SlewLimiter::new(MAX_OUTPUT_RATE),
+            right_output: SlewLimiter::new(MAX_OUTPUT_RATE),
+            time_since_command: Duration::ZERO,
+            left_ticks: 0.0,
+            right_ticks: 0.0,
+            odometry: OdometryDelta {
+                start_time: now,
+                end_time: now,
+                delta_position: [0.0, 0.0],
+                delta_orientation: 0.0,
+            },
+            time_since_publish: Duration::ZERO,
+            true_position: [0.0, 0.0],
+            true_orientation: 0.0,
+            command_age: CommandAgeCheck::new(MAX_COMMAND_AGE),
+            timeout_count: 0,
+            stats_time: Duration::ZERO,
+        }
+    }
+
+    /** Mirrors `MotorControllers::handle_speed_request`. Returns false if the command was stale. */
+    fn handle_speed_request(&mut self, request: &MotionVelocityRequest, packet_time: u64) -> bool {
+        let is_stop = request.linear_velocity == 0.0 && request.angular_velocity == 0.0;
+        if !self.command_age.accept(is_stop, packet_time, Some(get_current_time())) {
+            return false;
+        }
+
+        let w = request.angular_velocity;
+
+        let mut vel = velocity_to_speed_percent(request.linear_velocity).clamp(-1.0, 1.0);
+        let right_add_vel = velocity_to_speed_percent(w * WHEEL_BASE_WIDTH / 2.0).clamp(-1.0, 1.0);
+        let left_add_vel = -right_add_vel;
+
+        // The firmware prioritizes angular velocity if the combined speeds exceed 100% or -100%
+        let max_combined = vel.abs() + right_add_vel.abs();
+        if max_combined > 1.0 {
+            if vel > 0.0 {
+                vel -= right_add_vel.abs();
+            } else {
+                vel += right_add_vel.abs();
+            }
+        }
+
+        self.left_target = (vel + left_add_vel).clamp(-1.0, 1.0);
+        self.right_target = (vel + right_add_vel).clamp(-1.0, 1.0);
+        self.time_since_command = Duration::ZERO;
+        true
+    }
+
+    /** Advances the simulation by `dt` */
+    fn simulate(&mut self, dt: Duration) {
+        self.time_since_command += dt;
+        let timeout = Duration::from_millis(self.config.command_timeout_ms);
+        let moving = self.left_target != 0.0 || self.right_target != 0.0;
+        if self.time_since_command >= timeout && moving {
+            self.left_target = 0.0;
+            self.right_target = 0.0;
+            self.left_output.value = 0.0;
+            self.right_output.value = 0.0;
+            self.timeout_count += 1;
+        }
+        let dt_s = dt.as_secs_f32();
+        self.left_speed = motor_output(self.left_output.step(self.left_target, dt_s));
+        self.right_speed = motor_output(self.right_output.step(self.right_target, dt_s));
+
+        let max_wheel_speed = (NOMINAL_MAX_RPM / 60.0 * WHEEL_CIRCUMFERENCE) as f64;
+        let wheel_travel = |speed: f32, rng: &mut Rng| {
+            let travel = speed as f64 * max_wheel_speed * dt.as_secs_f64();
+            travel * (1.0 + self.config.noise_std * rng.next_gaussian())
+        };
+        let left_travel = wheel_travel(self.left_speed, &mut self.rng);
+        let right_travel = wheel_travel(self.right_speed, &mut self.rng);
+
+        // The encoders see the wheels turn, but slip means the robot moves less than that
+        let ground = 1.0 - self.config.slip;
+        let distance = (left_travel + right_travel) / 2.0 * ground;
+        let rotation = (right_travel - left_travel) / WHEEL_BASE_WIDTH as f64 * ground;
+        self.true_position[0] -= distance * self.true_orientation.sin();
+        self.true_position[1] += distance * self.true_orientation.cos();
+        self.true_orientation += rotation;
+
+        // The right encoder counts backwards as the motor is mounted mirrored
+        let ticks_per_meter = (ENCODER_TICKS_PER_REVOLUTION / WHEEL_CIRCUMFERENCE) as f64;
+        self.left_ticks += left_travel * ticks_per_meter;
+        self.right_ticks -= right_travel * ticks_per_meter;
+        let left_count = self.left_ticks.trunc();
+        let right_count = self.right_ticks.trunc();
+        self.left_ticks -= left_count;
+        self.right_ticks -= right_count;
+        update_odometry(&mut self.odometry, left_count as i64, right_count as i64);
+    }
+
+    /** Returns the odometry since the last call, and starts a new delta */
+    fn take_odometry(&mut self) -> OdometryDelta {
+        let now = get_current_time();
+        let mut odometry = OdometryDelta {
+            start_time: now,
+            end_time: now,
+            delta_position: [0.0, 0.0],
+            delta_orientation: 0.0,
+        };
+        std::mem::swap(&mut odometry, &mut self.odometry);
+        odometry.end_time = now;
+        odometry
+    }
+
+    fn stats_to_log(&self) -> DiagnosticMsg {
+        let mut values = heapless::Vec::<topics::DiagnosticKeyValue, 16>::new();
+        values
+            .push(topics::DiagnosticKeyValue {
+                key: HString::from_str("true_x").unwrap(),
+                value: hformat!("{:.3}", self.true_position[0]).unwrap(),
+            })
+            .ok();
+        values
+            .push(topics::DiagnosticKeyValue {
+                key: HString::from_str("true_y").unwrap(),
+                value: hformat!("{:.3}", self.true_position[1]).unwrap(),
+            })
+            .ok();
+        values
+            .push(topics::DiagnosticKeyValue {
+                key: HString::from_str("true_heading").unwrap(),
+                value: hformat!("{:.3}", self.true_orientation).unwrap(),
+            })
+            .ok();
+        values
+            .push(topics::DiagnosticKeyValue {
+                key: HString::from_str("stale_cmds").unwrap(),
+                value: hformat!("{}", self.command_age.stale_commands).unwrap(),
+            })
+            .ok();
+        values
+            .push(topics::DiagnosticKeyValue {
+                key: HString::from_str("timeouts").unwrap(),
+                value: hformat!("{}", self.timeout_count).unwrap(),
+            })
+            .ok();
+
+        DiagnosticMsg {
+            level: topics::DiagnosticStatus::Ok,
+            name: HString::from_str("sim_base").unwrap(),
+            message: HString::from_str("").unwrap(),
+            values,
+        }
+    }
+}
+
+impl Node for SimulatedBase {
+    fn name(&self) -> &str {
+        "sim_base"
+    }
+
+    fn subscriptions(&self) -> Vec<String> {
+        let velocity_topic = PacketData::MotionVelocityRequest(MotionVelocityRequest {
+            linear_velocity: 0.0,
+            angular_velocity: 0.0,
+        })
+        .topic()
+        .to_string();
+        vec![velocity_topic]
+    }
+
+    fn timer_period(&self) -> Option<Duration> {
+        Some(SIMULATION_STEP)
+    }
+
+    fn on_packet(&mut self, _ctx: &mut NodeContext, packet: &PacketFormat<PacketData>) {
+        if let PacketData::MotionVelocityRequest(request) = &packet.data {
+            self.handle_speed_request(request, packet.time);
+        }
+    }
+
+    fn on_timer(&mut self, ctx: &mut NodeContext) {
+        self.simulate(SIMULATION_STEP);
+
+        self.time_since_publish += SIMULATION_STEP;
+        if self.time_since_publish >= Duration::from_millis(self.config.publish_interval_ms) {
+            self.time_since_publish = Duration::ZERO;
+            let odometry = self.take_odometry();
+            ctx.publish(PacketData::OdometryDelta(odometry));
+        }
+
+        self.stats_time += SIMULATION_STEP;
+        if self.stats_time >= Duration::from_secs(1) {
+            self.stats_time = Duration::ZERO;
+            ctx.publish(PacketData::DiagnosticMsg(self.stats_to_log()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use drive_base::consts::MAX_WHEEL_ACCELERATION;
+
+    fn request(linear_velocity: f32, angular_velocity: f32) -> MotionVelocityRequest {
+        MotionVelocityRequest {
+            linear_velocity,
+            angular_velocity,
+        }
+    }
+
+    /** Runs the simulation for `seconds`, returning the odometry over that time */
+    fn run(base: &mut SimulatedBase, seconds: f64) -> OdometryDelta {
+        let steps = (seconds / SIMULATION_STEP.as_secs_f64()).round() as u32;
+        for _ in 0..steps {
+            base.simulate(SIMULATION_STEP);
+        }
+        base.take_odometry()
+    }
+
+    fn max_speed() -> f32 {
+        NOMINAL_MAX_RPM / 60.0 * WHEEL_CIRCUMFERENCE
+    }
+
+    /** How much less a wheel travels while ramping up to `speed`, than if it started at it */
+    fn ramp_loss(speed: f32) -> f32 {
+        speed * speed / (2.0 * MAX_WHEEL_ACCELERATION) - speed * SIMULATION_STEP.as_secs_f32() / 2.0
+    }
+
+    #[test]
+    fn test_drives_forward_along_y() {
+        let mut base = SimulatedBase::new(SimulatedBaseConfig::default());
+        assert!(base.handle_speed_request(&request(0.1, 0.0), get_current_time()));
+        let odometry = run(&mut base, 0.5);
+
+        let expected = 0.05 - ramp_loss(0.1);
+        assert!((odometry.delta_position[1] - expected).abs() < 0.001, "{:?}", odometry);
+        assert!(odometry.delta_position[0].abs() < 1e-6);
+        assert!(odometry.delta_orientation.abs() < 1e-6);
+        assert!((base.true_position[1] - expected as f64).abs() < 0.001);
+    }
+
+    #[test]
+    fn test_turns_anticlockwise() {
+        let mut base = SimulatedBase::new(SimulatedBaseConfig::default());
+        base.handle_speed_request(&request(0.0, 1.0), get_current_time());
+        let odometry = run(&mut base, 0.5);
+
+        // The wheels move at 0.1m/s
+        let expected = 0.5 - ramp_loss(0.1) / (WHEEL_BASE_WIDTH / 2.0);
+        assert!((odometry.delta_orientation - expected).abs() < 0.01, "{:?}", odometry);
+        assert!(odometry.delta_position[1].abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_speed_saturates() {
+        let mut base = SimulatedBase::new(SimulatedBaseConfig::default());
+        base.handle_speed_request(&request(10.0, 0.0), get_current_time());
+        let odometry = run(&mut base, 0.5);
+
+        let expected = max_speed() * 0.5 - ramp_loss(max_speed());
+        assert!((odometry.delta_position[1] - expected).abs() < 0.001, "{:?}", odometry);
+    }
+
+    #[test]
+    fn test_acceleration_is_limited() {
+        let mut base = SimulatedBase::new(SimulatedBaseConfig::default());
+        base.handle_speed_request(&request(10.0, 0.0), get_current_time());
+        let max_change = MAX_OUTPUT_RATE * SIMULATION_STEP.as_secs_f32();
+        let mut previous = 0.0;
+        for _ in 0..50 {
+            base.simulate(SIMULATION_STEP);
+            assert!(base.left_speed - previous <= max_change + 1e-6);
+            previous = base.left_speed;
+        }
+        assert_eq!(base.left_speed, 1.0);
+
+        // Stopping is ramped too, at the same rate
+        base.handle_speed_request(&request(0.0, 0.0), get_current_time());
+        base.simulate(SIMULATION_STEP);
+        assert!((base.left_speed - (1.0 - max_change)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_command_timeout() {
+        let mut base = SimulatedBase::new(SimulatedBaseConfig::default());
+        base.handle_speed_request(&request(0.1, 0.0), get_current_time());
+        let odometry = run(&mut base, 3.0);
+
+        // Only moves for the first second
+        let expected = 0.1 - ramp_loss(0.1);
+        assert!((odometry.delta_position[1] - expected).abs() < 0.002, "{:?}", odometry);
+        assert_eq!(base.timeout_count, 1);
+    }
+
+    #[test]
+    fn test_stale_commands_rejected() {
+        let mut base = SimulatedBase::new(SimulatedBaseConfig::default());
+        let old_time = get_current_time() - 2 * MAX_COMMAND_AGE;
+        assert!(!base.handle_speed_request(&request(0.1, 0.0), old_time));
+        assert_eq!(base.left_speed, 0.0);
+
+        // But stopping is always accepted
+        assert!(base.handle_speed_request(&request(0.0, 0.0), old_time));
+        assert_eq!(base.command_age.stale_commands, 1);
+    }
+
+    #[test]
+    fn test_slip_and_noise() {
+        let mut base = SimulatedBase::new(SimulatedBaseConfig {
+            slip: 0.2,
+            noise_std: 0.05,
+            ..Default::default()
+        });
+        base.handle_speed_request(&request(0.1, 0.0), get_current_time());
+        let odometry = run(&mut base, 0.5);
+
+        // The encoders report the full distance, but the robot only moves 80% of it
+        let expected = 0.05 - ramp_loss(0.1);
+        assert!((odometry.delta_position[1] - expected).abs() < 0.005, "{:?}", odometry);
+        assert!((base.true_position[1] - expected as f64 * 0.8).abs() < 0.005);
+        // Noise on each wheel makes the robot wander off course
+        assert!(odometry.delta_orientation != 0.0);
+    }
+}
@@ -0,0 +1,151 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::rc::Rc;
+
+use packet_router::Router;
+use topics::{PacketData, PacketFormat};
+
+use crate::node::{Node, NodeContext};
+use crate::nodes::serial_client::{Link, SerialClient};
+
+/** Most unsent bytes to hold for a slow connection before dropping packets */
+const MAX_PENDING_BYTES: usize = 64 * 1024;
+
+/**
+ * A non-blocking TCP connection. Writes are buffered whole, so that a full socket buffer can't
+ * leave half a packet on the wire, and the buffer is sent on each read.
+ */
+pub struct TcpLink {
+    stream: TcpStream,
+    pending: Vec<u8>,
+}
+
+impl TcpLink {
+    pub fn new(stream: TcpStream) -> std::io::Result<Self> {
+        stream.set_nonblocking(true)?;
+        stream.set_nodelay(true)?;
+        Ok(TcpLink {
+            stream,
+            pending: Vec::new(),
+        })
+    }
+
+    fn send_pending(&mut self) -> std::io::Result<()> {
+        while !self.pending.is_empty() {
+            match self.stream.write(&self.pending) {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(written) => {
+                    self.pending.drain(..written);
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Read for TcpLink {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.send_pending()?;
+        self.stream.read(buf)
+    }
+}
+
+impl Write for TcpLink {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.send_pending()?;
+        if self.pending.len() + buf.len() > MAX_PENDING_BYTES {
+            return Err(ErrorKind::WouldBlock.into());
+        }
+        self.pending.extend_from_slice(buf);
+        self.send_pending()?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.send_pending()
+    }
+}
+
+impl Link for TcpLink {
+    fn name(&self) -> Option<String> {
+        self.stream.peer_addr().ok().map(|addr| addr.to_string())
+    }
+
+    fn bytes_per_second(&self) -> Option<u32> {
+        None
+    }
+}
+
+/**
+ * Accepts TCP connections carrying the same COBS framed packets as the serial link, eg from ESP32
+ * boards on Wi-Fi or desktop tools. Like serial devices, peers choose their topics by sending a
+ * `SubscriptionRequest`.
+ */
+pub struct TcpAcceptor {
+    pub router: Rc<RefCell<Router<PacketFormat<PacketData>>>>,
+    pub clients_by_addr: HashMap<String, SerialClient>,
+    pub server: TcpListener,
+}
+
+impl TcpAcceptor {
+    pub fn new(router: Rc<RefCell<Router<PacketFormat<PacketData>>>>, address: &str) -> Self {
+        let server = TcpListener::bind(address).unwrap();
+        println!("TCP server listening on {}", address);
+        server.set_nonblocking(true).expect("Failed to set non-blocking");
+        TcpAcceptor {
+            router,
+            clients_by_addr: HashMap::new(),
+            server,
+        }
+    }
+
+    /** Returns true if there was any activity on the socket or its clients. */
+    pub fn tick(&mut self) -> bool {
+        let mut did_work = false;
+        if let Ok((stream, addr)) = self.server.accept() {
+            did_work = true;
+            match TcpLink::new(stream) {
+                Ok(link) => {
+                    let peer_addr = addr.to_string();
+                    let client =
+                        SerialClient::with_link(&addr.ip().to_string(), Box::new(link), "tcp_stats");
+                    self.router.borrow_mut().register_client(Rc::downgrade(&client.client));
+                    self.clients_by_addr.insert(peer_addr.clone(), client);
+                    println!("New TCP client connected: {}", peer_addr);
+                }
+                Err(e) => {
+                    eprintln!("Failed to set up TCP connection from {}: {:?}", addr, e);
+                }
+            }
+        }
+
+        for client in self.clients_by_addr.values_mut() {
+            did_work |= client.tick();
+        }
+
+        // Remove dead clients
+        self.clients_by_addr.retain(|addr, client| {
+            if !client.is_alive {
+                println!("TCP client disconnected: {}", addr);
+                false
+            } else {
+                true
+            }
+        });
+        did_work
+    }
+}
+
+impl Node for TcpAcceptor {
+    fn name(&self) -> &str {
+        "tcp"
+    }
+
+    fn on_poll(&mut self, _ctx: &mut NodeContext) -> bool {
+        self.tick()
+    }
+}
@@ -0,0 +1,175 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::io::{ErrorKind, Read, Write};
+use std::net::{SocketAddr, UdpSocket};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use packet_router::Router;
+use topics::{PacketData, PacketFormat};
+
+use crate::node::{Node, NodeContext};
+use crate::nodes::serial_client::{Link, SerialClient};
+
+/** Largest datagram we expect, comfortably more than one framed packet */
+const MAX_DATAGRAM: usize = 2048;
+
+/**
+ * One peer's view of the shared socket. `UdpTransport` queues the peer's datagrams in `inbox`, and
+ * each write is sent as its own datagram.
+ */
+struct UdpPeerLink {
+    socket: Rc<UdpSocket>,
+    peer: SocketAddr,
+    inbox: Rc<RefCell<VecDeque<u8>>>,
+}
+
+impl Read for UdpPeerLink {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut inbox = self.inbox.borrow_mut();
+        if inbox.is_empty() {
+            return Err(ErrorKind::WouldBlock.into());
+        }
+        let count = buf.len().min(inbox.len());
+        for (byte, queued) in buf.iter_mut().zip(inbox.drain(..count)) {
+            *byte = queued;
+        }
+        Ok(count)
+    }
+}
+
+impl Write for UdpPeerLink {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.socket.send_to(buf, self.peer)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Link for UdpPeerLink {
+    fn name(&self) -> Option<String> {
+        Some(self.peer.to_string())
+    }
+
+    fn bytes_per_second(&self) -> Option<u32> {
+        None
+    }
+}
+
+struct UdpPeer {
+    client: SerialClient,
+    inbox: Rc<RefCell<VecDeque<u8>>>,
+    last_heard: Instant,
+}
+
+/**
+ * Exchanges COBS framed packets with peers over UDP, one packet per datagram. There is no
+ * connection: a peer joins by sending anything (normally its `SubscriptionRequest`), and is dropped
+ * after `peer_timeout` of silence. Devices that resend their `SubscriptionRequest` every second, as
+ * the firmware does, stay connected.
+ */
+pub struct UdpTransport {
+    pub router: Rc<RefCell<Router<PacketFormat<PacketData>>>>,
+    pub socket: Rc<UdpSocket>,
+    pub peer_timeout: Duration,
+    peers: HashMap<SocketAddr, UdpPeer>,
+}
+
+impl UdpTransport {
+    pub fn new(
+        router: Rc<RefCell<Router<PacketFormat<PacketData>>>>,
+        address: &str,
+        peer_timeout: Duration,
+    ) -> Self {
+        let socket = UdpSocket::bind(address).unwrap();
+        println!("UDP transport listening on {}", address);
+        socket.set_nonblocking(true).expect("Failed to set non-blocking");
+        UdpTransport {
+            router,
+            socket: Rc::new(socket),
+            peer_timeout,
+            peers: HashMap::new(),
+        }
+    }
+
+    /** The peers currently exchanging packets with us, and their clients */
+    pub fn peers(&self) -> impl Iterator<Item = (&SocketAddr, &SerialClient)> {
+        self.peers.iter().map(|(addr, peer)| (addr, &peer.client))
+    }
+
+    /** Hands out the datagrams waiting on the socket to their peers' inboxes */
+    fn receive(&mut self) -> bool {
+        let mut did_work = false;
+        let mut buffer = [0u8; MAX_DATAGRAM];
+        loop {
+            let (size, addr) = match self.socket.recv_from(&mut buffer) {
+                Ok(received) => received,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                // ICMP port unreachable from a peer that went away, the timeout handles it
+                Err(e) if e.kind() == ErrorKind::ConnectionRefused => continue,
+                Err(e) => {
+                    eprintln!("UDP receive failed: {:?}", e);
+                    break;
+                }
+            };
+            did_work = true;
+
+            let peer = self.peers.entry(addr).or_insert_with(|| {
+                let inbox = Rc::new(RefCell::new(VecDeque::new()));
+                let link = UdpPeerLink {
+                    socket: Rc::clone(&self.socket),
+                    peer: addr,
+                    inbox: Rc::clone(&inbox),
+                };
+                let client =
+                    SerialClient::with_link(&addr.ip().to_string(), Box::new(link), "udp_stats");
+                self.router.borrow_mut().register_client(Rc::downgrade(&client.client));
+                println!("New UDP peer: {}", addr);
+                UdpPeer {
+                    client,
+                    inbox,
+                    last_heard: Instant::now(),
+                }
+            });
+            peer.inbox.borrow_mut().extend(&buffer[..size]);
+            peer.last_heard = Instant::now();
+        }
+        did_work
+    }
+
+    /** Returns true if any datagrams were received or any packets were sent. */
+    pub fn tick(&mut self) -> bool {
+        let mut did_work = self.receive();
+
+        for peer in self.peers.values_mut() {
+            // A peer's client reads until its inbox is empty
+            while peer.client.read() {
+                did_work = true;
+            }
+            did_work |= peer.client.tick();
+        }
+
+        let peer_timeout = self.peer_timeout;
+        self.peers.retain(|addr, peer| {
+            if !peer.client.is_alive || peer.last_heard.elapsed() > peer_timeout {
+                println!("UDP peer timed out: {}", addr);
+                false
+            } else {
+                true
+            }
+        });
+        did_work
+    }
+}
+
+impl Node for UdpTransport {
+    fn name(&self) -> &str {
+        "udp"
+    }
+
+    fn on_poll(&mut self, _ctx: &mut NodeContext) -> bool {
+        self.tick()
+    }
+}
@@ -1,16 +1,25 @@
 use std::collections::{HashMap, HashSet};
-use std::net::{TcpListener, TcpStream};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::PathBuf;
 use std::rc::Rc;
 use std::cell::RefCell;
-use std::time::Instant;
-use topics::{DiagnosticMsg, PacketData, PacketFormat, SubscriptionRequest};
-use tungstenite::{WebSocket, accept};
+use std::time::{Duration, Instant};
+use topics::{DiagnosticMsg, MAX_PACKET_SIZE, PacketData, PacketFormat, SubscriptionRequest};
+use tungstenite::handshake::server::{Request, Response};
+use tungstenite::http::HeaderValue;
+use tungstenite::{WebSocket, accept_hdr};
 use serde::{Serialize};
 use packet_encoding::decode_packet;
 use packet_router::{Client, Router};
 use heapless::{String as HString, format as hformat};
 use std::str::FromStr;
 
+use crate::config::WebsocketConfig;
+use crate::node::{Node, NodeContext};
+use crate::nodes::http::{
+    HttpError, HttpRequest, HttpResponse, MAX_REQUEST_BYTES, RestApi, parse_request, serve_static,
+};
 use crate::nodes::clock::get_current_time;
 
 #[derive(Serialize)]
@@ -27,7 +36,7 @@ pub struct WebsocketClientStats {
 
 impl WebsocketClientStats {
     fn to_log(&self) -> DiagnosticMsg {
-        let mut values = heapless::Vec::<topics::DiagnosticKeyValue, 8>::new();
+        let mut values = heapless::Vec::<topics::DiagnosticKeyValue, 16>::new();
         values
             .push(topics::DiagnosticKeyValue {
                 key: HString::from_str("decode_errors").unwrap(),
@@ -81,9 +90,60 @@ impl WebsocketClientStats {
 }
 
 
+/** Don't let too many queued packets build up for a slow client, drop the oldest instead */
+const QUEUE_LANE_LIMIT: usize = 256;
+
+/** How packets are sent to a websocket client. Clients can always send either. */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WireEncoding {
+    /** COBS framed CBOR in binary messages, as `packet_wasm` decodes for the web interface */
+    Cbor,
+    /** `PacketFormat` as JSON in text messages, the same JSON that `Log` prints */
+    Json,
+}
+
+impl WireEncoding {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "cbor" => Some(WireEncoding::Cbor),
+            "json" => Some(WireEncoding::Json),
+            _ => None,
+        }
+    }
+
+    /**
+     * Picks an encoding from a `Sec-WebSocket-Protocol` header: the first of `slambot.json` or
+     * `slambot.cbor` the client offers. Returns the encoding and the subprotocol to confirm.
+     */
+    pub fn from_subprotocols(header: &str) -> Option<(Self, &'static str)> {
+        header.split(',').find_map(|protocol| match protocol.trim() {
+            "slambot.json" => Some((WireEncoding::Json, "slambot.json")),
+            "slambot.cbor" => Some((WireEncoding::Cbor, "slambot.cbor")),
+            _ => None,
+        })
+    }
+}
+
+/**
+ * Parses a packet sent as JSON. Scripts can leave out `time` and `id`. A missing `time` is filled
+ * in as if the packet was sent now, and the bridges give every packet they forward a new id.
+ */
+pub fn parse_json_packet(
+    mut value: serde_json::Value,
+) -> Result<PacketFormat<PacketData>, serde_json::Error> {
+    if let Some(object) = value.as_object_mut() {
+        object
+            .entry("time")
+            .or_insert_with(|| get_current_time().into());
+        object.entry("id").or_insert_with(|| 0.into());
+    }
+    serde_json::from_value(value)
+}
+
 pub struct WebsocketClient {
     pub client: Rc<RefCell<Client<PacketFormat<PacketData>>>>,
     pub websocket: WebSocket<TcpStream>,
+    pub encoding: WireEncoding,
 
     pub stats: WebsocketClientStats,
     pub stats_send_time: Instant,
@@ -93,11 +153,16 @@ pub struct WebsocketClient {
 
 
 impl WebsocketClient {
-    pub fn new(websocket: WebSocket<TcpStream>) -> Self {
+    pub fn new(websocket: WebSocket<TcpStream>, encoding: WireEncoding) -> Self {
         let client = Rc::new(RefCell::new(Client::<PacketFormat<PacketData>>::default()));
+        client
+            .borrow_mut()
+            .router_to_client
+            .set_lane_limit(Some(QUEUE_LANE_LIMIT));
         WebsocketClient {
             client,
             websocket,
+            encoding,
             stats: WebsocketClientStats {
                 decode_error_count: 0,
                 tx_packets: 0,
@@ -123,40 +188,112 @@ impl WebsocketClient {
         }
     }
 
-    pub fn tick(&mut self) {
+    /**
+     * Browsers don't synchronize their clocks with the robot, so their packets are stamped with
+     * the robot's time as they arrive, like the other bridges do. Otherwise a skewed clock would
+     * make every motion command look stale, or a stale one look new. They get a new id too, as
+     * JSON clients can leave it out and every browser tab counts from 0.
+     */
+    fn receive_packet(
+        &mut self,
+        ctx: &mut NodeContext,
+        mut packet: PacketFormat<PacketData>,
+        size: usize,
+    ) {
+        packet.time = get_current_time();
+        packet.id = ctx.next_id();
+        self.stats.rx_packets += 1;
+        self.stats.rx_bytes += size as u32;
+        if let PacketData::SubscriptionRequest(sub_req) = &packet.data {
+            self.update_topics(sub_req);
+        } else {
+            self.client.borrow_mut().send(packet);
+        }
+    }
+
+    /** A COBS framed CBOR packet, as sent by the web interface */
+    fn receive_binary(&mut self, ctx: &mut NodeContext, mut data_raw: Vec<u8>) {
+        let data_len = data_raw.len();
+        if data_len < 2 {
+            self.stats.decode_error_count += 1;
+            return;
+        }
+        let without_zeros: &mut [u8] = data_raw.as_mut_slice()[1..data_len - 1].as_mut();
+        match decode_packet::<PacketFormat<PacketData>>(without_zeros) {
+            Ok(packet) => self.receive_packet(ctx, packet, data_len),
+            Err(err) => {
+                eprintln!("Failed to decode packet: {:?}", err);
+                self.stats.decode_error_count += 1;
+            }
+        }
+    }
+
+    /** A JSON packet, or an `{"encoding": "json"}` message choosing what we send back */
+    fn receive_text(&mut self, ctx: &mut NodeContext, text: &str) {
+        let value = match serde_json::from_str::<serde_json::Value>(text) {
+            Ok(value) => value,
+            Err(err) => return self.text_decode_error(err),
+        };
+        if let Some(name) = value.get("encoding").and_then(|name| name.as_str()) {
+            match WireEncoding::from_name(name) {
+                Some(encoding) => self.encoding = encoding,
+                None => self.reply_error(&format!("unknown encoding \"{}\"", name)),
+            }
+            return;
+        }
+        match parse_json_packet(value) {
+            Ok(packet) => self.receive_packet(ctx, packet, text.len()),
+            Err(err) => self.text_decode_error(err),
+        }
+    }
+
+    fn text_decode_error(&mut self, err: serde_json::Error) {
+        self.stats.decode_error_count += 1;
+        self.reply_error(&err.to_string());
+    }
+
+    fn reply_error(&mut self, message: &str) {
+        let error = serde_json::json!({ "error": message }).to_string();
+        if self.websocket.send(tungstenite::Message::Text(error.into())).is_err() {
+            self.stats.write_error_count += 1;
+        }
+    }
+
+    fn encode(&self, packet: &PacketFormat<PacketData>) -> Option<tungstenite::Message> {
+        match self.encoding {
+            WireEncoding::Cbor => {
+                let mut encode_buffer = [0u8; MAX_PACKET_SIZE];
+                encode_buffer[0] = 0; // COBS initial byte
+                let encoded_size = packet_encoding::encode_packet(
+                    packet,
+                    &mut encode_buffer[1..MAX_PACKET_SIZE - 1],
+                )
+                .ok()?;
+                encode_buffer[encoded_size + 1] = 0x00; // COBS final byte
+                Some(tungstenite::Message::Binary(tungstenite::Bytes::copy_from_slice(
+                    &encode_buffer[..encoded_size + 2],
+                )))
+            }
+            WireEncoding::Json => {
+                let text = serde_json::to_string(packet).ok()?;
+                Some(tungstenite::Message::Text(text.into()))
+            }
+        }
+    }
+
+    /** Returns true if anything was read or written. */
+    pub fn tick(&mut self, ctx: &mut NodeContext) -> bool {
+        let mut did_work = false;
+
         // Read from websocket into incoming queue
         match self.websocket.read() {
             Ok(msg) => {
-                // We do not want to send back ping/pong messages.
-                if msg.is_binary() {
-                    // For simplicity, we just echo the message back.
-                    let mut data_raw: Vec<u8> = msg.into_data().to_vec();
-                    let data_len = data_raw.len();
-                    let without_zeros: &mut [u8] = data_raw.as_mut_slice()[1..data_len-1].as_mut();
-                    let data = decode_packet::<PacketFormat<PacketData>>(without_zeros);
-                    match data {
-                        Ok(packet) => {
-
-                            self.stats.rx_packets += 1;
-                            self.stats.rx_bytes += data_raw.len() as u32;
-                            if let PacketData::SubscriptionRequest(sub_req) = &packet.data {
-                                self.update_topics(&sub_req);
-                            } else {
-                                self.client.borrow_mut().send(packet);
-                            }
-                        }
-                        Err(err) => {
-                            eprintln!("Failed to decode packet: {:?}", err);
-                            self.stats.decode_error_count += 1;
-                        }
-                    }
-                } else {
-                    // Respond telling client to use binary
-                    let warning_msg = tungstenite::Message::Text("Please send binary messages only.".into());
-                    eprintln!("Received non-binary websocket message.");
-                    if let Err(_) = self.websocket.write(warning_msg) {
-                        self.stats.decode_error_count += 1; // Counts as a failed message
-                    }
+                did_work = true;
+                match msg {
+                    tungstenite::Message::Binary(data) => self.receive_binary(ctx, data.to_vec()),
+                    tungstenite::Message::Text(text) => self.receive_text(ctx, text.as_str()),
+                    // Pings are answered by tungstenite, and closes show up as ConnectionClosed
+                    _ => {}
                 }
             }
             Err(tungstenite::Error::ConnectionClosed) => {
@@ -170,25 +307,34 @@ impl WebsocketClient {
             }
         }
 
-        // Write from outgoing queue to websocket
-        for packet in self.client.borrow_mut().fetch_all() {
-            // Encode packet
-            let mut encode_buffer = [0u8; 600];
-            encode_buffer[0] = 0; // COBS initial byte
-            let encoded_size =
-                match packet_encoding::encode_packet(&*packet, &mut encode_buffer[1..]) {
-                    Ok(size) => size,
-                    Err(_) => {
-                        self.stats.encode_error_count += 1;
-                        continue;
-                    }
-                };
-            encode_buffer[encoded_size + 1] = 0x00; // COBS final byte
-            let encode_sized = &encode_buffer[..encoded_size + 2];
+        // Write from outgoing queue to websocket. If the socket is backed up, leave packets in the
+        // priority lanes rather than buffering them in the websocket, so that higher priority
+        // packets can overtake them.
+        let mut backed_up = match self.websocket.flush() {
+            Err(tungstenite::Error::Io(e)) => e.kind() == std::io::ErrorKind::WouldBlock,
+            _ => false,
+        };
+        while !backed_up {
+            let Some(packet) = self.client.borrow_mut().fetch_next() else {
+                break;
+            };
+            did_work = true;
+            let Some(message) = self.encode(&packet) else {
+                self.stats.encode_error_count += 1;
+                continue;
+            };
+            let message_len = message.len() as u32;
 
             // Send over websocket
-            if let Err(err) = self.websocket.send(tungstenite::Message::Binary(tungstenite::Bytes::copy_from_slice(encode_sized))) {
+            if let Err(err) = self.websocket.send(message) {
                 match err {
+                    tungstenite::Error::Io(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                        // The message is queued inside the websocket, it just couldn't be flushed yet
+                        backed_up = true;
+                        self.stats.tx_packets += 1;
+                        self.stats.tx_bytes += message_len;
+                        continue;
+                    }
                     tungstenite::Error::ConnectionClosed => {
                         self.is_alive = false;
                     }
@@ -201,72 +347,243 @@ impl WebsocketClient {
 
             } else {
                 self.stats.tx_packets += 1;
-                self.stats.tx_bytes += encode_sized.len() as u32;
+                self.stats.tx_bytes += message_len;
             }
         }
 
         // Send stats once per second
         if self.stats_send_time.elapsed() >= std::time::Duration::from_secs(1) {
             let diag_msg: DiagnosticMsg = self.stats.to_log();
-            self.client
-                .borrow_mut()
-                .send(PacketFormat {
-                    to: None,
-                    from: None,
-                    data: PacketData::DiagnosticMsg(diag_msg),
-                    time: get_current_time(),
-                    id: 0,
-                });
+            let packet = ctx.new_packet(None, PacketData::DiagnosticMsg(diag_msg));
+            self.client.borrow_mut().send(packet);
             self.stats_send_time = Instant::now();
         }
+
+        did_work
     }
 }
 
 
 
 
+/** How long a new connection has to send its request headers */
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/** A new connection that hasn't sent its whole request yet */
+struct PendingConnection {
+    stream: TcpStream,
+    addr: SocketAddr,
+    accepted: Instant,
+}
+
+/** An HTTP response still being sent */
+struct HttpReply {
+    stream: TcpStream,
+    data: Vec<u8>,
+    sent: usize,
+}
+
+/**
+ * Listens for the web interface. WebSocket upgrades become `WebsocketClient`s, and plain HTTP
+ * requests are answered from the REST API (see `RestApi`) or the built web interface.
+ */
 pub struct WebsocketAcceptor {
     pub router: Rc<RefCell<Router<PacketFormat<PacketData>>>>,
     pub clients_by_ip: HashMap<String, WebsocketClient>,
     pub server: TcpListener,
+    static_dir: Option<PathBuf>,
+    rest_api: Option<RestApi>,
+    pending: Vec<PendingConnection>,
+    replies: Vec<HttpReply>,
 }
 
 
 impl WebsocketAcceptor {
-    pub fn new(router: Rc<RefCell<Router<PacketFormat<PacketData>>>>, address: &str) -> Self {
-        let server = TcpListener::bind(address).unwrap();
-        println!("Websocket server listening on {}", address);
+    pub fn new(
+        router: Rc<RefCell<Router<PacketFormat<PacketData>>>>,
+        config: WebsocketConfig,
+    ) -> Self {
+        let server = TcpListener::bind(&config.address).unwrap();
+        println!("Websocket server listening on {}", config.address);
         server.set_nonblocking(true).expect("Failed to set non-blocking");
         WebsocketAcceptor {
             router,
             clients_by_ip: HashMap::new(),
             server,
+            static_dir: config.static_dir.map(PathBuf::from),
+            rest_api: config.rest_api.then(RestApi::new),
+            pending: Vec::new(),
+            replies: Vec::new(),
         }
     }
 
-    pub fn tick(&mut self) {
-        let websocket = self.server.accept();
-        if let Ok((stream, addr)) = websocket {
-            stream.set_nonblocking(true).expect("Failed to set non-blocking");
-            if let Ok(websocket) = accept(stream) {
-                let peer_addr = addr.to_string();
-                let client = WebsocketClient::new(websocket);
-                self.router.borrow_mut().register_client(Rc::downgrade(&client.client));
-
-                self.clients_by_ip.insert(
-                    peer_addr.clone(),
-                    client,
-                );
-                println!("New websocket client connected: {}", peer_addr);
+    fn accept_websocket(&mut self, stream: TcpStream, addr: SocketAddr) {
+        let mut encoding = WireEncoding::Cbor;
+        // The error type is tungstenite's
+        #[allow(clippy::result_large_err)]
+        let choose_encoding = |request: &Request, mut response: Response| {
+            if let Some(protocols) = request
+                .headers()
+                .get("Sec-WebSocket-Protocol")
+                .and_then(|value| value.to_str().ok())
+                && let Some((chosen, protocol)) = WireEncoding::from_subprotocols(protocols)
+            {
+                encoding = chosen;
+                response
+                    .headers_mut()
+                    .insert("Sec-WebSocket-Protocol", HeaderValue::from_static(protocol));
             }
+            Ok(response)
+        };
+        if let Ok(websocket) = accept_hdr(stream, choose_encoding) {
+            let peer_addr = addr.to_string();
+            let client = WebsocketClient::new(websocket, encoding);
+            self.router.borrow_mut().register_client(Rc::downgrade(&client.client));
+
+            self.clients_by_ip.insert(
+                peer_addr.clone(),
+                client,
+            );
+            println!("New websocket client connected: {}", peer_addr);
+        }
+    }
+
+    /**
+     * Waits for each new connection's request headers, to tell WebSocket upgrades from plain HTTP.
+     * Peeking leaves the request in the socket for the WebSocket handshake to read. Returns true
+     * if any connection was handed on.
+     */
+    fn handle_pending(&mut self) -> bool {
+        let mut did_work = false;
+        let mut buffer = vec![0u8; MAX_REQUEST_BYTES];
+        for mut connection in std::mem::take(&mut self.pending) {
+            let peeked = match connection.stream.peek(&mut buffer) {
+                Ok(0) => continue,
+                Ok(peeked) => peeked,
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                    if connection.accepted.elapsed() < REQUEST_TIMEOUT {
+                        self.pending.push(connection);
+                    }
+                    continue;
+                }
+                Err(_) => continue,
+            };
+            let (response, used) = match parse_request(&buffer[..peeked]) {
+                Ok(None) => {
+                    if connection.accepted.elapsed() < REQUEST_TIMEOUT {
+                        self.pending.push(connection);
+                    }
+                    continue;
+                }
+                Ok(Some((request, _))) if request.is_websocket_upgrade() => {
+                    did_work = true;
+                    self.accept_websocket(connection.stream, connection.addr);
+                    continue;
+                }
+                Ok(Some((request, used))) => (self.answer(&request), used),
+                Err(err @ HttpError::TooLarge) => {
+                    (HttpResponse::error(413, &err.to_string()), peeked)
+                }
+                Err(err) => (HttpResponse::error(400, &err.to_string()), peeked),
+            };
+
+            // Take the request out of the socket, as closing it with unread data discards the
+            // response
+            did_work = true;
+            connection.stream.read_exact(&mut buffer[..used]).ok();
+            self.replies.push(HttpReply {
+                stream: connection.stream,
+                data: response.to_bytes(),
+                sent: 0,
+            });
         }
+        did_work
+    }
+
+    fn answer(&mut self, request: &HttpRequest) -> HttpResponse {
+        if let Some(rest_api) = &mut self.rest_api
+            && let Some(response) = rest_api.handle(request, &self.router.borrow())
+        {
+            return response;
+        }
+        match &self.static_dir {
+            Some(static_dir) => serve_static(static_dir, request),
+            None => HttpResponse::error(404, "not found"),
+        }
+    }
 
+    /** Sends as much of each HTTP response as the sockets will take. Returns true if any was sent. */
+    fn send_replies(&mut self) -> bool {
+        let mut did_work = false;
+        self.replies.retain_mut(|reply| {
+            while reply.sent < reply.data.len() {
+                match reply.stream.write(&reply.data[reply.sent..]) {
+                    Ok(0) => return false,
+                    Ok(written) => {
+                        did_work = true;
+                        reply.sent += written;
+                    }
+                    Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => return true,
+                    Err(_) => return false,
+                }
+            }
+            false
+        });
+        did_work
+    }
 
-        for (_ip, client) in self.clients_by_ip.iter_mut() {
-            client.tick();
+    /** Returns true if there was any activity on the socket or its clients. */
+    pub fn tick(&mut self, ctx: &mut NodeContext) -> bool {
+        let mut did_work = false;
+        if let Ok((stream, addr)) = self.server.accept() {
+            did_work = true;
+            stream.set_nonblocking(true).expect("Failed to set non-blocking");
+            self.pending.push(PendingConnection {
+                stream,
+                addr,
+                accepted: Instant::now(),
+            });
+        }
+        if !self.pending.is_empty() {
+            did_work |= self.handle_pending();
+        }
+        did_work |= self.send_replies();
+
+        for client in self.clients_by_ip.values_mut() {
+            did_work |= client.tick(ctx);
         }
 
         // Remove dead clients
         self.clients_by_ip.retain(|_ip, client| client.is_alive);
+        did_work
+    }
+}
+
+impl Node for WebsocketAcceptor {
+    fn name(&self) -> &str {
+        "websocket"
     }
-}
\ No newline at end of file
+
+    fn subscriptions(&self) -> Vec<String> {
+        match self.rest_api {
+            Some(_) => vec!["all".to_string()],
+            None => Vec::new(),
+        }
+    }
+
+    fn on_packet(&mut self, _ctx: &mut NodeContext, packet: &PacketFormat<PacketData>) {
+        if let Some(rest_api) = &mut self.rest_api {
+            rest_api.record(packet);
+        }
+    }
+
+    fn on_poll(&mut self, ctx: &mut NodeContext) -> bool {
+        let did_work = self.tick(ctx);
+        if let Some(rest_api) = &mut self.rest_api {
+            for data in rest_api.take_published() {
+                ctx.publish(data);
+            }
+        }
+        did_work
+    }
+}
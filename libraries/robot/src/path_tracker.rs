@@ -0,0 +1,318 @@
+/*!
+ * Pure pursuit path following. The path is a polyline from where the robot was when it was given
+ * the path through each waypoint. Every step the robot is projected onto the path, a point
+ * `lookahead_distance` further along is chosen, and the robot drives the arc that passes through
+ * it. Orientations have the same conventions as `OdometryDelta` (at orientation 0 the robot faces
+ * +y, positive rotation is anticlockwise).
+ */
+use std::f64::consts::FRAC_PI_2;
+
+use topics::PathStatus;
+
+use crate::config::MotionControllerConfig;
+use crate::ekf::wrap_angle;
+
+/** What to do this step, and how far along the path the robot is */
+#[derive(Debug, Clone, PartialEq)]
+pub struct PathStep {
+    /** m/s */
+    pub linear_velocity: f64,
+    /** rad/s */
+    pub angular_velocity: f64,
+    pub status: PathStatus,
+    /** Index of the waypoint being driven towards */
+    pub waypoint: usize,
+    /**
+     * Along the path, from the robot's projection onto it to the end (m). Once the end has been
+     * reached, the distance to it.
+     */
+    pub distance_remaining: f64,
+    /** From the robot's orientation to the final heading, or 0 if there isn't one (rad) */
+    pub heading_error: f64,
+    /** Distance from the robot to the path (m) */
+    pub cross_track_error: f64,
+}
+
+#[derive(Debug)]
+pub struct PathTracker {
+    /** Where the robot started, then the waypoints */
+    points: Vec<[f64; 2]>,
+    /** Path length from the start to each point */
+    cumulative: Vec<f64>,
+    final_heading: Option<f64>,
+    /** The segment the robot was last projected onto. Only moves forwards. */
+    segment: usize,
+    /** Path length from the start to the robot's projection. Only moves forwards. */
+    progress: f64,
+    /** Set once the end has been reached, so small drift while turning doesn't restart driving */
+    arrived: bool,
+}
+
+impl PathTracker {
+    /** `None` if there are no waypoints or any of them aren't finite */
+    pub fn new(
+        start: [f64; 2],
+        waypoints: &[[f64; 2]],
+        final_heading: Option<f64>,
+    ) -> Option<Self> {
+        let all_finite = waypoints
+            .iter()
+            .flatten()
+            .chain(final_heading.iter())
+            .all(|v| v.is_finite());
+        if waypoints.is_empty() || !all_finite {
+            return None;
+        }
+        let mut points = vec![start];
+        points.extend_from_slice(waypoints);
+        let mut cumulative = vec![0.0];
+        for segment in points.windows(2) {
+            let length = distance(segment[0], segment[1]);
+            cumulative.push(cumulative.last().unwrap() + length);
+        }
+        Some(PathTracker {
+            points,
+            cumulative,
+            final_heading,
+            segment: 0,
+            progress: 0.0,
+            arrived: false,
+        })
+    }
+
+    pub fn length(&self) -> f64 {
+        *self.cumulative.last().unwrap()
+    }
+
+    /** Works out the velocities to follow the path from `position` and `orientation` */
+    pub fn update(
+        &mut self,
+        position: [f64; 2],
+        orientation: f64,
+        config: &MotionControllerConfig,
+    ) -> PathStep {
+        let cross_track_error = self.project(position, config.lookahead_distance);
+        let end = *self.points.last().unwrap();
+        let distance_to_end = distance(position, end);
+        let distance_remaining = self.length() - self.progress;
+        let heading_error = self
+            .final_heading
+            .map_or(0.0, |h| wrap_angle(h - orientation));
+        let mut step = PathStep {
+            linear_velocity: 0.0,
+            angular_velocity: 0.0,
+            status: PathStatus::Following,
+            waypoint: self.segment,
+            distance_remaining,
+            heading_error,
+            cross_track_error,
+        };
+
+        if self.arrived || distance_to_end < config.position_tolerance {
+            // Then turn on the spot to the final heading
+            self.arrived = true;
+            step.waypoint = self.points.len() - 2;
+            step.distance_remaining = distance_to_end;
+            if heading_error.abs() < config.heading_tolerance {
+                step.status = PathStatus::Complete;
+            } else {
+                step.angular_velocity = turn_velocity(heading_error, config);
+            }
+            return step;
+        }
+
+        // The goal, in the robot's frame
+        let goal = self.point_at(self.progress + config.lookahead_distance);
+        let offset = [goal[0] - position[0], goal[1] - position[1]];
+        let forward = -offset[0] * orientation.sin() + offset[1] * orientation.cos();
+        let left = -offset[0] * orientation.cos() - offset[1] * orientation.sin();
+        let bearing = left.atan2(forward);
+        if bearing.abs() > FRAC_PI_2 {
+            // Behind the robot, so there's no sensible arc. Face it first.
+            step.angular_velocity = turn_velocity(bearing, config);
+            return step;
+        }
+
+        let curvature = 2.0 * left / (forward * forward + left * left);
+        // Slow down for the end of the path. `distance_to_end` keeps the robot moving if it has
+        // overshot to the side of the end.
+        let mut speed = approach_speed(distance_remaining.max(distance_to_end), config);
+        if (speed * curvature).abs() > config.max_angular_velocity {
+            speed = config.max_angular_velocity / curvature.abs();
+        }
+        step.linear_velocity = speed;
+        step.angular_velocity = speed * curvature;
+        step
+    }
+
+    /**
+     * Moves the projection along the path to the closest point to `position`, searching no
+     * further than `lookahead` ahead so that paths which come back near themselves aren't short
+     * cut. Returns the distance to the path.
+     */
+    fn project(&mut self, position: [f64; 2], lookahead: f64) -> f64 {
+        let search_end = self.progress + lookahead;
+        let mut best: Option<(f64, usize, f64)> = None;
+        for segment in self.segment..self.points.len() - 1 {
+            if self.cumulative[segment] > search_end {
+                break;
+            }
+            let (along, closest) =
+                closest_on_segment(self.points[segment], self.points[segment + 1], position);
+            let error = distance(position, closest);
+            if best.is_none_or(|(best_error, _, _)| error < best_error) {
+                best = Some((error, segment, self.cumulative[segment] + along));
+            }
+        }
+        let (error, segment, progress) = best.unwrap();
+        self.segment = segment;
+        self.progress = self.progress.max(progress);
+        error
+    }
+
+    /** The point `along` from the start of the path, clamped to the path's ends */
+    fn point_at(&self, along: f64) -> [f64; 2] {
+        let along = along.clamp(0.0, self.length());
+        let segment = self.cumulative[1..].partition_point(|&end| end < along);
+        let Some(&[start, end]) = self.points.get(segment..segment + 2) else {
+            return *self.points.last().unwrap();
+        };
+        let length = self.cumulative[segment + 1] - self.cumulative[segment];
+        if length == 0.0 {
+            return end;
+        }
+        let fraction = (along - self.cumulative[segment]) / length;
+        [
+            start[0] + (end[0] - start[0]) * fraction,
+            start[1] + (end[1] - start[1]) * fraction,
+        ]
+    }
+}
+
+/**
+ * How fast to drive with `distance` to go: slowing in proportion to the distance, and no faster
+ * than can be stopped from within it at `max_linear_acceleration`.
+ */
+pub fn approach_speed(distance: f64, config: &MotionControllerConfig) -> f64 {
+    let stoppable = (2.0 * config.max_linear_acceleration * distance).sqrt();
+    (config.kp_linear * distance).min(stoppable).min(config.max_linear_velocity)
+}
+
+fn distance(a: [f64; 2], b: [f64; 2]) -> f64 {
+    (b[0] - a[0]).hypot(b[1] - a[1])
+}
+
+/** How far along the segment the closest point to `position` is, and the point */
+fn closest_on_segment(start: [f64; 2], end: [f64; 2], position: [f64; 2]) -> (f64, [f64; 2]) {
+    let direction = [end[0] - start[0], end[1] - start[1]];
+    let length = direction[0].hypot(direction[1]);
+    if length == 0.0 {
+        return (0.0, start);
+    }
+    let along = ((position[0] - start[0]) * direction[0] + (position[1] - start[1]) * direction[1])
+        / length;
+    let along = along.clamp(0.0, length);
+    let fraction = along / length;
+    (
+        along,
+        [
+            start[0] + direction[0] * fraction,
+            start[1] + direction[1] * fraction,
+        ],
+    )
+}
+
+fn turn_velocity(heading_error: f64, config: &MotionControllerConfig) -> f64 {
+    (config.kp_angular * heading_error)
+        .clamp(-config.max_angular_velocity, config.max_angular_velocity)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /** Drives a perfect differential drive robot along the path until it completes */
+    fn simulate(
+        tracker: &mut PathTracker,
+        mut pose: [f64; 3],
+        config: &MotionControllerConfig,
+    ) -> (Vec<PathStep>, [f64; 3]) {
+        let dt = config.publish_interval_ms as f64 / 1000.0;
+        let mut steps = Vec::new();
+        for _ in 0..1000 {
+            let step = tracker.update([pose[0], pose[1]], pose[2], config);
+            pose[0] -= step.linear_velocity * pose[2].sin() * dt;
+            pose[1] += step.linear_velocity * pose[2].cos() * dt;
+            pose[2] += step.angular_velocity * dt;
+            let complete = step.status == PathStatus::Complete;
+            steps.push(step);
+            if complete {
+                return (steps, pose);
+            }
+        }
+        panic!("path not completed, ended at {:?}", pose);
+    }
+
+    #[test]
+    fn test_follows_path() {
+        let config = MotionControllerConfig::default();
+        let waypoints = [[0.0, 1.0], [1.0, 1.0], [1.0, 0.0]];
+        let mut tracker = PathTracker::new([0.0, 0.0], &waypoints, Some(FRAC_PI_2)).unwrap();
+        assert_eq!(tracker.length(), 3.0);
+        let (steps, pose) = simulate(&mut tracker, [0.0, 0.0, 0.0], &config);
+
+        // Ends on the last waypoint, facing the requested heading
+        assert!(distance([pose[0], pose[1]], [1.0, 0.0]) < config.position_tolerance);
+        assert!(wrap_angle(pose[2] - FRAC_PI_2).abs() < config.heading_tolerance);
+
+        // Cuts the corners by no more than the lookahead allows, and passes every waypoint
+        let max_error = steps
+            .iter()
+            .map(|step| step.cross_track_error)
+            .fold(0.0, f64::max);
+        assert!(max_error < config.lookahead_distance / 2.0, "{}", max_error);
+        let waypoints: Vec<usize> = steps.iter().map(|step| step.waypoint).collect();
+        assert!(waypoints.is_sorted());
+        assert_eq!(waypoints.first(), Some(&0));
+        assert_eq!(waypoints.last(), Some(&2));
+        let remaining: Vec<f64> = steps.iter().map(|step| step.distance_remaining).collect();
+        assert!(remaining.windows(2).all(|pair| pair[1] <= pair[0]));
+
+        for step in steps {
+            assert!(step.linear_velocity.abs() <= config.max_linear_velocity + 1e-9);
+            assert!(step.angular_velocity.abs() <= config.max_angular_velocity + 1e-9);
+        }
+    }
+
+    #[test]
+    fn test_turns_to_goal_behind() {
+        let config = MotionControllerConfig::default();
+        let mut tracker = PathTracker::new([0.0, 0.0], &[[0.0, -1.0]], None).unwrap();
+        let step = tracker.update([0.0, 0.0], 0.0, &config);
+        assert_eq!(step.linear_velocity, 0.0);
+        assert_eq!(step.angular_velocity.abs(), config.max_angular_velocity);
+
+        // Stops, without settling on a heading, when there isn't a final one
+        let (steps, _) = simulate(&mut tracker, [0.0, 0.0, 0.0], &config);
+        let last = steps.last().unwrap();
+        assert_eq!((last.linear_velocity, last.angular_velocity), (0.0, 0.0));
+    }
+
+    #[test]
+    fn test_loop_back_is_not_short_cut() {
+        let config = MotionControllerConfig::default();
+        // Out and back along nearly the same line
+        let waypoints = [[0.0, 2.0], [0.1, 2.0], [0.1, 0.0]];
+        let mut tracker = PathTracker::new([0.0, 0.0], &waypoints, None).unwrap();
+        let step = tracker.update([0.05, 0.1], 0.0, &config);
+        assert_eq!(step.waypoint, 0);
+        assert!((step.distance_remaining - 4.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_invalid_paths() {
+        assert!(PathTracker::new([0.0, 0.0], &[], None).is_none());
+        assert!(PathTracker::new([0.0, 0.0], &[[f64::NAN, 0.0]], None).is_none());
+        assert!(PathTracker::new([0.0, 0.0], &[[1.0, 0.0]], Some(f64::INFINITY)).is_none());
+    }
+}
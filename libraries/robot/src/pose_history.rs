@@ -0,0 +1,141 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/** A pose `[x, y, orientation]` at a time on the synchronized clock (us) */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StampedPose {
+    pub time: u64,
+    pub pose: [f64; 3],
+}
+
+/**
+ * The robot's recent poses, so that measurements taken in the past (scans, camera frames, late
+ * odometry) can be matched with where the robot was at the time. Orientations are not wrapped,
+ * so interpolating between them is linear.
+ */
+pub struct PoseHistory {
+    poses: VecDeque<StampedPose>,
+    max_age_us: u64,
+}
+
+impl PoseHistory {
+    /** Keeps poses up to `max_age` older than the newest */
+    pub fn new(max_age: Duration) -> Self {
+        PoseHistory {
+            poses: VecDeque::new(),
+            max_age_us: max_age.as_micros() as u64,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.poses.clear();
+    }
+
+    pub fn latest(&self) -> Option<StampedPose> {
+        self.poses.back().copied()
+    }
+
+    pub fn oldest(&self) -> Option<StampedPose> {
+        self.poses.front().copied()
+    }
+
+    /**
+     * Adds the newest pose. A pose stamped before the newest one is ignored, and one stamped at
+     * the same time replaces it.
+     */
+    pub fn push(&mut self, time: u64, pose: [f64; 3]) {
+        match self.poses.back_mut() {
+            Some(latest) if time < latest.time => return,
+            Some(latest) if time == latest.time => latest.pose = pose,
+            _ => self.poses.push_back(StampedPose { time, pose }),
+        }
+        let cutoff = time.saturating_sub(self.max_age_us);
+        // Keep one pose at or before the cutoff, so queries right at the cutoff still work
+        while self.poses.len() > 1 && self.poses[1].time <= cutoff {
+            self.poses.pop_front();
+        }
+    }
+
+    /**
+     * The pose at `time`, interpolated between the poses either side of it. `None` if `time` is
+     * outside the history.
+     */
+    pub fn pose_at(&self, time: u64) -> Option<[f64; 3]> {
+        let after = self.poses.partition_point(|stamped| stamped.time < time);
+        let next = self.poses.get(after)?;
+        if next.time == time {
+            return Some(next.pose);
+        }
+        let previous = self.poses.get(after.checked_sub(1)?)?;
+        let fraction = (time - previous.time) as f64 / (next.time - previous.time) as f64;
+        let mut pose = previous.pose;
+        for (value, next_value) in pose.iter_mut().zip(next.pose) {
+            *value += (next_value - *value) * fraction;
+        }
+        Some(pose)
+    }
+
+    /**
+     * Corrects every pose stamped at or after `time` when motion that happened before them is
+     * learnt about late. The robot was at `pivot` at `time` as far as they knew, and really
+     * moved by `change` from there. The travel since then turns with `change`'s rotation.
+     */
+    pub fn shift_from(&mut self, time: u64, pivot: [f64; 2], change: [f64; 3]) {
+        let (sin, cos) = change[2].sin_cos();
+        for stamped in self.poses.iter_mut().filter(|stamped| stamped.time >= time) {
+            let [x, y, orientation] = stamped.pose;
+            let [offset_x, offset_y] = [x - pivot[0], y - pivot[1]];
+            stamped.pose = [
+                pivot[0] + change[0] + offset_x * cos - offset_y * sin,
+                pivot[1] + change[1] + offset_x * sin + offset_y * cos,
+                orientation + change[2],
+            ];
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64::consts::FRAC_PI_2;
+
+    #[test]
+    fn test_interpolation() {
+        let mut history = PoseHistory::new(Duration::from_secs(10));
+        assert_eq!(history.pose_at(0), None);
+        history.push(1_000, [0.0, 0.0, 0.0]);
+        history.push(2_000, [1.0, 2.0, 4.0]);
+        history.push(1_500, [9.0, 9.0, 9.0]);
+        history.push(3_000, [1.0, 3.0, 4.0]);
+
+        assert_eq!(history.pose_at(1_000), Some([0.0, 0.0, 0.0]));
+        assert_eq!(history.pose_at(1_250), Some([0.25, 0.5, 1.0]));
+        assert_eq!(history.pose_at(2_500), Some([1.0, 2.5, 4.0]));
+        assert_eq!(history.pose_at(3_000), Some([1.0, 3.0, 4.0]));
+        assert_eq!(history.pose_at(999), None);
+        assert_eq!(history.pose_at(3_001), None);
+
+        history.shift_from(2_000, [1.0, 2.0], [1.0, 0.0, 0.0]);
+        assert_eq!(history.pose_at(1_500), Some([1.0, 1.0, 2.0]));
+        assert_eq!(history.latest().unwrap().pose, [2.0, 3.0, 4.0]);
+
+        // The travel after the pivot turns with it
+        history.shift_from(2_000, [2.0, 2.0], [0.0, 0.0, FRAC_PI_2]);
+        assert_eq!(history.pose_at(2_000), Some([2.0, 2.0, 4.0 + FRAC_PI_2]));
+        let [x, y, orientation] = history.latest().unwrap().pose;
+        assert!((x - 1.0).abs() < 1e-9 && (y - 2.0).abs() < 1e-9, "{} {}", x, y);
+        assert_eq!(orientation, 4.0 + FRAC_PI_2);
+    }
+
+    #[test]
+    fn test_old_poses_are_dropped() {
+        let mut history = PoseHistory::new(Duration::from_millis(1));
+        for time in 0..10 {
+            history.push(time * 500, [time as f64, 0.0, 0.0]);
+        }
+        // 1ms before the newest is still answerable
+        assert_eq!(history.oldest().unwrap().time, 3_500);
+        assert_eq!(history.pose_at(3_500), Some([7.0, 0.0, 0.0]));
+        assert_eq!(history.pose_at(3_499), None);
+    }
+}
@@ -0,0 +1,361 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use heapless::{String as HString, format as hformat};
+use packet_router::{Client, Router};
+use topics::{DiagnosticMsg, PacketData, PacketFormat};
+
+use crate::node::{Node, NodeContext};
+use crate::nodes::clock::get_current_time;
+
+#[derive(Default)]
+struct NodeTiming {
+    calls: u32,
+    total: Duration,
+    max: Duration,
+}
+
+impl NodeTiming {
+    fn record(&mut self, elapsed: Duration) {
+        self.calls += 1;
+        self.total += elapsed;
+        self.max = self.max.max(elapsed);
+    }
+}
+
+struct ScheduledNode {
+    node: Box<dyn Node>,
+    client: Rc<RefCell<Client<PacketFormat<PacketData>>>>,
+    message_id: u32,
+    next_timer: Option<Instant>,
+    timing: NodeTiming,
+}
+
+impl ScheduledNode {
+    /** Runs a callback with a context for this node, and adds the time it took to the node's timing. */
+    fn call<R>(&mut self, callback: impl FnOnce(&mut dyn Node, &mut NodeContext) -> R) -> R {
+        let mut ctx = NodeContext {
+            client: &self.client,
+            message_id: &mut self.message_id,
+        };
+        let start = Instant::now();
+        let result = callback(self.node.as_mut(), &mut ctx);
+        self.timing.record(start.elapsed());
+        result
+    }
+}
+
+/**
+ * Runs a set of nodes on a single thread. Each iteration polls the nodes' I/O, routes packets,
+ * delivers them and fires any timers that are due. When there is nothing to do it sleeps until
+ * the next timer, but for no longer than `io_poll_interval` so that sockets and serial ports are
+ * still serviced promptly.
+ *
+ * Once per `stats_period` it publishes a "scheduler" `DiagnosticMsg` with the average and
+ * maximum time (in microseconds) each node spent in its callbacks.
+ */
+pub struct Scheduler {
+    router: Rc<RefCell<Router<PacketFormat<PacketData>>>>,
+    nodes: Vec<ScheduledNode>,
+    started: bool,
+
+    pub io_poll_interval: Duration,
+    pub stats_period: Duration,
+    client: Rc<RefCell<Client<PacketFormat<PacketData>>>>,
+    stats_send_time: Instant,
+}
+
+impl Scheduler {
+    pub fn new(router: Rc<RefCell<Router<PacketFormat<PacketData>>>>) -> Self {
+        let client = Rc::new(RefCell::new(Client::default()));
+        router.borrow_mut().register_client(Rc::downgrade(&client));
+        Scheduler {
+            router,
+            nodes: Vec::new(),
+            started: false,
+            io_poll_interval: Duration::from_millis(2),
+            stats_period: Duration::from_secs(1),
+            client,
+            stats_send_time: Instant::now(),
+        }
+    }
+
+    pub fn add(&mut self, node: impl Node + 'static) {
+        self.add_boxed(Box::new(node));
+    }
+
+    pub fn add_boxed(&mut self, node: Box<dyn Node>) {
+        let client = Rc::new(RefCell::new(Client::default()));
+        client.borrow_mut().subscriptions.extend(node.subscriptions());
+        self.router
+            .borrow_mut()
+            .register_client(Rc::downgrade(&client));
+
+        let next_timer = node.timer_period().map(|period| Instant::now() + period);
+        println!("Scheduler: added node {}", node.name());
+        self.nodes.push(ScheduledNode {
+            node,
+            client,
+            message_id: 0,
+            next_timer,
+            timing: NodeTiming::default(),
+        });
+    }
+
+    fn start(&mut self) {
+        if !self.started {
+            self.started = true;
+            for node in self.nodes.iter_mut() {
+                node.call(|node, ctx| node.on_start(ctx));
+            }
+        }
+    }
+
+    /** Runs a single iteration. Returns true if any node did some work. */
+    pub fn step(&mut self) -> bool {
+        self.start();
+        let mut did_work = false;
+
+        for node in self.nodes.iter_mut() {
+            did_work |= node.call(|node, ctx| node.on_poll(ctx));
+        }
+
+        self.router.borrow_mut().poll();
+
+        for node in self.nodes.iter_mut() {
+            let packets = node.client.borrow_mut().fetch_all();
+            did_work |= !packets.is_empty();
+            for packet in packets {
+                node.call(|node, ctx| node.on_packet(ctx, &packet));
+            }
+        }
+
+        let now = Instant::now();
+        for node in self.nodes.iter_mut() {
+            if let Some(next_timer) = node.next_timer
+                && next_timer <= now
+            {
+                node.call(|node, ctx| node.on_timer(ctx));
+                // Skip ahead rather than firing repeatedly to catch up after a stall
+                let period = node.node.timer_period().unwrap_or_default();
+                node.next_timer = Some((next_timer + period).max(now));
+                did_work = true;
+            }
+        }
+
+        if self.stats_send_time.elapsed() >= self.stats_period {
+            self.send_stats();
+            self.stats_send_time = Instant::now();
+        }
+
+        did_work
+    }
+
+    /** How long the scheduler can sleep for before something needs doing. */
+    fn idle_time(&self) -> Duration {
+        let now = Instant::now();
+        self.nodes
+            .iter()
+            .filter_map(|node| node.next_timer)
+            .map(|next_timer| next_timer.saturating_duration_since(now))
+            .fold(self.io_poll_interval, Duration::min)
+    }
+
+    /** Runs until `stop` is set, then gives every node a chance to shut down cleanly. */
+    pub fn run_until(&mut self, stop: &AtomicBool) {
+        while !stop.load(Ordering::Relaxed) {
+            if !self.step() {
+                std::thread::sleep(self.idle_time());
+            }
+        }
+        self.shutdown();
+    }
+
+    /** Stops the nodes, unless they were never started */
+    fn shutdown(&mut self) {
+        if !self.started {
+            return;
+        }
+        for node in self.nodes.iter_mut() {
+            node.call(|node, ctx| node.on_stop(ctx));
+        }
+        // Deliver anything sent while stopping, eg a final stop command to the motors
+        self.router.borrow_mut().poll();
+        for node in self.nodes.iter_mut() {
+            node.call(|node, ctx| node.on_poll(ctx));
+        }
+    }
+
+    fn send_stats(&mut self) {
+        let mut values = heapless::Vec::<topics::DiagnosticKeyValue, 16>::new();
+        for node in self.nodes.iter_mut() {
+            let timing = std::mem::take(&mut node.timing);
+            let average = timing.total.as_micros() / timing.calls.max(1) as u128;
+            let mut key = HString::new();
+            for c in node.node.name().chars() {
+                if key.push(c).is_err() {
+                    break;
+                }
+            }
+            values
+                .push(topics::DiagnosticKeyValue {
+                    key,
+                    value: hformat!("{}/{}", average, timing.max.as_micros())
+                        .unwrap_or_default(),
+                })
+                .ok();
+        }
+
+        self.client.borrow_mut().send(PacketFormat {
+            to: None,
+            from: None,
+            data: PacketData::DiagnosticMsg(DiagnosticMsg {
+                level: topics::DiagnosticStatus::Ok,
+                name: HString::from_str("scheduler").unwrap(),
+                message: HString::from_str("avg/max us").unwrap(),
+                values,
+            }),
+            time: get_current_time(),
+            id: 0,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use topics::ClockRequest;
+
+    type Events = Rc<RefCell<Vec<String>>>;
+
+    struct TestNode {
+        name: &'static str,
+        events: Events,
+        subscriptions: Vec<String>,
+        timer_period: Option<Duration>,
+    }
+
+    impl TestNode {
+        fn new(name: &'static str, events: &Events) -> Self {
+            TestNode {
+                name,
+                events: Rc::clone(events),
+                subscriptions: Vec::new(),
+                timer_period: None,
+            }
+        }
+
+        fn log(&self, event: &str) {
+            self.events
+                .borrow_mut()
+                .push(format!("{}:{}", self.name, event));
+        }
+    }
+
+    impl Node for TestNode {
+        fn name(&self) -> &str {
+            self.name
+        }
+        fn subscriptions(&self) -> Vec<String> {
+            self.subscriptions.clone()
+        }
+        fn timer_period(&self) -> Option<Duration> {
+            self.timer_period
+        }
+        fn on_start(&mut self, _ctx: &mut NodeContext) {
+            self.log("start");
+        }
+        fn on_packet(&mut self, _ctx: &mut NodeContext, packet: &PacketFormat<PacketData>) {
+            self.log(&format!("packet {}", packet.id));
+        }
+        fn on_timer(&mut self, ctx: &mut NodeContext) {
+            self.log("timer");
+            ctx.publish(PacketData::ClockRequest(ClockRequest { request_time: 0 }));
+        }
+        fn on_stop(&mut self, _ctx: &mut NodeContext) {
+            self.log("stop");
+        }
+    }
+
+    fn new_scheduler() -> Scheduler {
+        let router = Rc::new(RefCell::new(Router::new()));
+        Scheduler::new(router)
+    }
+
+    #[test]
+    fn test_timer_publishes_to_subscriber() {
+        let events: Events = Rc::default();
+        let mut scheduler = new_scheduler();
+
+        let mut publisher = TestNode::new("publisher", &events);
+        publisher.timer_period = Some(Duration::from_millis(10));
+        scheduler.add(publisher);
+
+        let mut subscriber = TestNode::new("subscriber", &events);
+        subscriber.subscriptions = vec!["ClockRequest".to_string()];
+        scheduler.add(subscriber);
+
+        let timer_count = || {
+            events
+                .borrow()
+                .iter()
+                .filter(|e| *e == "publisher:timer")
+                .count()
+        };
+        let start = Instant::now();
+        while timer_count() < 3 {
+            assert!(start.elapsed() < Duration::from_secs(1));
+            if !scheduler.step() {
+                std::thread::sleep(scheduler.idle_time());
+            }
+        }
+        // The timers fired roughly on schedule
+        assert!(start.elapsed() >= Duration::from_millis(30));
+        // Deliver the last packet
+        scheduler.step();
+        scheduler.shutdown();
+
+        let events = events.borrow();
+        assert_eq!(events[0..2], ["publisher:start", "subscriber:start"]);
+        // Packet ids count up per node
+        assert!(events.contains(&"subscriber:packet 0".to_string()));
+        assert!(events.contains(&"subscriber:packet 2".to_string()));
+        assert_eq!(events[events.len() - 2..], ["publisher:stop", "subscriber:stop"]);
+    }
+
+    #[test]
+    fn test_idle_time_until_next_timer() {
+        let events: Events = Rc::default();
+        let mut scheduler = new_scheduler();
+        scheduler.io_poll_interval = Duration::from_secs(1);
+
+        let mut node = TestNode::new("node", &events);
+        node.timer_period = Some(Duration::from_millis(50));
+        scheduler.add(node);
+
+        let idle = scheduler.idle_time();
+        assert!(idle <= Duration::from_millis(50));
+        assert!(idle > Duration::from_millis(40));
+    }
+
+    #[test]
+    fn test_run_until_stops_nodes() {
+        let events: Events = Rc::default();
+        let mut scheduler = new_scheduler();
+        scheduler.add(TestNode::new("node", &events));
+
+        let stop = AtomicBool::new(true);
+        scheduler.run_until(&stop);
+        // Stopped before the first iteration, so the node was never started or stopped
+        assert!(events.borrow().is_empty());
+
+        let mut scheduler = new_scheduler();
+        scheduler.add(TestNode::new("node", &events));
+        scheduler.step();
+        scheduler.run_until(&stop);
+        assert_eq!(*events.borrow(), ["node:start", "node:stop"]);
+    }
+}
@@ -0,0 +1,423 @@
+use serde::Deserialize;
+use serde::de::{
+    self, DeserializeSeed, Deserializer, EnumAccess, IntoDeserializer, MapAccess, SeqAccess,
+    VariantAccess, Visitor,
+};
+use serde_json::{Map, Value, json};
+use topics::PacketData;
+
+/**
+ * JSON schemas for the messages in `topics`, for tools that show or build messages generically
+ * (Foxglove, rosbridge). The schemas are found by deserializing each message from a `Tracer`,
+ * which answers every request from the derived `Deserialize` impl with a placeholder value and
+ * writes down what was asked for. That keeps them in step with the message definitions without
+ * a schema derive in the `no_std` topics crate.
+ *
+ * Only what the topics use is supported: structs, numbers, strings, bools, options, sequences,
+ * fixed size arrays and enums without data.
+ */
+pub fn topic_schemas() -> Vec<(&'static str, Value)> {
+    let mut schemas = Vec::new();
+    let mut index = 0;
+    loop {
+        let mut topics: &'static [&'static str] = &[];
+        let mut schema = Value::Null;
+        let tracer = TopicTracer {
+            index,
+            topics: &mut topics,
+            schema: &mut schema,
+        };
+        if PacketData::deserialize(tracer).is_err() || index >= topics.len() {
+            break;
+        }
+        schemas.push((topics[index], schema));
+        index += 1;
+    }
+    schemas
+}
+
+/** The JSON schema of one topic's message, or `None` if there's no such topic */
+pub fn topic_schema(topic: &str) -> Option<Value> {
+    topic_schemas()
+        .into_iter()
+        .find(|(name, _)| *name == topic)
+        .map(|(_, schema)| schema)
+}
+
+type Error = serde_json::Error;
+
+fn unsupported(what: &str) -> Error {
+    de::Error::custom(format!("{} can't be described by a schema", what))
+}
+
+/** Picks one variant of `PacketData` and traces its message */
+struct TopicTracer<'a> {
+    index: usize,
+    topics: &'a mut &'static [&'static str],
+    schema: &'a mut Value,
+}
+
+impl<'de> Deserializer<'de> for TopicTracer<'_> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Error> {
+        Err(unsupported("PacketData"))
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        *self.topics = variants;
+        let Some(topic) = variants.get(self.index) else {
+            return Err(de::Error::custom("no more topics"));
+        };
+        visitor.visit_enum(VariantTracer {
+            variant: topic,
+            schema: self.schema,
+            data: true,
+        })
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 u8 u16 u32 u64 f32 f64 char str string bytes byte_buf option unit
+        unit_struct newtype_struct seq tuple tuple_struct map struct identifier ignored_any
+    }
+}
+
+/**
+ * Chooses `variant` of an enum. Variants of `PacketData` carry a message, which is traced into
+ * `schema`. Other enums are only described by their names, so their variants can't carry data.
+ */
+struct VariantTracer<'a> {
+    variant: &'static str,
+    schema: &'a mut Value,
+    data: bool,
+}
+
+impl<'de> EnumAccess<'de> for VariantTracer<'_> {
+    type Error = Error;
+    type Variant = Self;
+
+    fn variant_seed<S: DeserializeSeed<'de>>(self, seed: S) -> Result<(S::Value, Self), Error> {
+        let value = seed.deserialize(self.variant.into_deserializer())?;
+        Ok((value, self))
+    }
+}
+
+impl<'de> VariantAccess<'de> for VariantTracer<'_> {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<(), Error> {
+        match self.data {
+            true => Err(unsupported("a topic without a message")),
+            false => Ok(()),
+        }
+    }
+
+    fn newtype_variant_seed<S: DeserializeSeed<'de>>(self, seed: S) -> Result<S::Value, Error> {
+        match self.data {
+            true => seed.deserialize(Tracer {
+                schema: self.schema,
+            }),
+            false => Err(unsupported("an enum variant with data")),
+        }
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _len: usize, _visitor: V) -> Result<V::Value, Error> {
+        Err(unsupported("an enum variant with data"))
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        _visitor: V,
+    ) -> Result<V::Value, Error> {
+        Err(unsupported("an enum variant with data"))
+    }
+}
+
+/** Traces one value, writing its schema into `schema` */
+struct Tracer<'a> {
+    schema: &'a mut Value,
+}
+
+impl Tracer<'_> {
+    fn trace<'de, S: DeserializeSeed<'de>>(seed: S) -> Result<(S::Value, Value), Error> {
+        let mut schema = Value::Null;
+        let value = seed.deserialize(Tracer {
+            schema: &mut schema,
+        })?;
+        Ok((value, schema))
+    }
+
+    fn array<'de, V: Visitor<'de>>(
+        self,
+        len: Option<usize>,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        let mut items = Value::Null;
+        let value = visitor.visit_seq(SeqTracer {
+            remaining: len.unwrap_or(1),
+            items: &mut items,
+        })?;
+        *self.schema = json!({ "type": "array", "items": items });
+        if let Some(len) = len {
+            self.schema["minItems"] = len.into();
+            self.schema["maxItems"] = len.into();
+        }
+        Ok(value)
+    }
+}
+
+macro_rules! trace_primitive {
+    ($($method:ident => $visit:ident($($value:expr)?), $schema:tt;)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+                *self.schema = json!($schema);
+                visitor.$visit($($value)?)
+            }
+        )*
+    };
+}
+
+impl<'de> Deserializer<'de> for Tracer<'_> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Error> {
+        Err(unsupported("a self-describing value"))
+    }
+
+    trace_primitive! {
+        deserialize_bool => visit_bool(false), { "type": "boolean" };
+        deserialize_i8 => visit_i8(0), { "type": "integer" };
+        deserialize_i16 => visit_i16(0), { "type": "integer" };
+        deserialize_i32 => visit_i32(0), { "type": "integer" };
+        deserialize_i64 => visit_i64(0), { "type": "integer" };
+        deserialize_u8 => visit_u8(0), { "type": "integer", "minimum": 0 };
+        deserialize_u16 => visit_u16(0), { "type": "integer", "minimum": 0 };
+        deserialize_u32 => visit_u32(0), { "type": "integer", "minimum": 0 };
+        deserialize_u64 => visit_u64(0), { "type": "integer", "minimum": 0 };
+        deserialize_f32 => visit_f32(0.0), { "type": "number" };
+        deserialize_f64 => visit_f64(0.0), { "type": "number" };
+        deserialize_char => visit_char(' '), { "type": "string", "minLength": 1, "maxLength": 1 };
+        deserialize_str => visit_str(""), { "type": "string" };
+        deserialize_string => visit_str(""), { "type": "string" };
+        deserialize_bytes => visit_bytes(&[]), { "type": "array", "items": { "type": "integer" } };
+        deserialize_byte_buf => visit_bytes(&[]),
+            { "type": "array", "items": { "type": "integer" } };
+        deserialize_unit => visit_unit(), { "type": "null" };
+        deserialize_ignored_any => visit_unit(), {};
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        let (value, inner) = Self::trace(SomeSeed(visitor))?;
+        *self.schema = json!({ "anyOf": [inner, { "type": "null" }] });
+        Ok(value)
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_unit(visitor)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.array(None, visitor)
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value, Error> {
+        self.array(Some(len), visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.array(Some(len), visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        *self.schema = json!({ "type": "object" });
+        visitor.visit_map(StructTracer {
+            fields: &[],
+            next: 0,
+            properties: &mut Map::new(),
+        })
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        let mut properties = Map::new();
+        let value = visitor.visit_map(StructTracer {
+            fields,
+            next: 0,
+            properties: &mut properties,
+        })?;
+        *self.schema = json!({ "title": name, "type": "object", "properties": properties });
+        Ok(value)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        let Some(first) = variants.first() else {
+            return Err(unsupported("an enum without variants"));
+        };
+        *self.schema = json!({ "title": name, "type": "string", "enum": variants });
+        visitor.visit_enum(VariantTracer {
+            variant: first,
+            schema: &mut Value::Null,
+            data: false,
+        })
+    }
+
+    fn deserialize_identifier<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Error> {
+        Err(unsupported("an identifier"))
+    }
+}
+
+/** Traces the value inside an `Option` */
+struct SomeSeed<V>(V);
+
+impl<'de, V: Visitor<'de>> DeserializeSeed<'de> for SomeSeed<V> {
+    type Value = V::Value;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<V::Value, D::Error> {
+        self.0.visit_some(deserializer)
+    }
+}
+
+/**
+ * Gives `remaining` elements. All elements have the same type, so the schema of the first one
+ * is kept.
+ */
+struct SeqTracer<'a> {
+    remaining: usize,
+    items: &'a mut Value,
+}
+
+impl<'de> SeqAccess<'de> for SeqTracer<'_> {
+    type Error = Error;
+
+    fn next_element_seed<S: DeserializeSeed<'de>>(
+        &mut self,
+        seed: S,
+    ) -> Result<Option<S::Value>, Error> {
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+        self.remaining -= 1;
+        let (value, schema) = Tracer::trace(seed)?;
+        if self.items.is_null() {
+            *self.items = schema;
+        }
+        Ok(Some(value))
+    }
+}
+
+/** Gives every field of a struct in order, tracing each into `properties` */
+struct StructTracer<'a> {
+    fields: &'static [&'static str],
+    next: usize,
+    properties: &'a mut Map<String, Value>,
+}
+
+impl<'de> MapAccess<'de> for StructTracer<'_> {
+    type Error = Error;
+
+    fn next_key_seed<S: DeserializeSeed<'de>>(
+        &mut self,
+        seed: S,
+    ) -> Result<Option<S::Value>, Error> {
+        match self.fields.get(self.next) {
+            Some(field) => seed.deserialize(field.into_deserializer()).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<S: DeserializeSeed<'de>>(&mut self, seed: S) -> Result<S::Value, Error> {
+        let field = self.fields[self.next];
+        self.next += 1;
+        let (value, schema) = Tracer::trace(seed)?;
+        self.properties.insert(field.to_string(), schema);
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use topics::PacketDataTrait;
+
+    #[test]
+    fn test_every_topic_has_a_schema() {
+        let schemas = topic_schemas();
+        let velocity = PacketData::MotionVelocityRequest(topics::MotionVelocityRequest {
+            linear_velocity: 0.0,
+            angular_velocity: 0.0,
+        });
+        assert_eq!(schemas[0].0, "ClockRequest");
+        assert!(schemas.iter().any(|(topic, _)| *topic == velocity.topic()));
+        for (topic, schema) in schemas.iter() {
+            assert_eq!(schema["type"], "object", "{}", topic);
+            assert_eq!(schema["title"], *topic);
+        }
+    }
+
+    #[test]
+    fn test_message_schemas() {
+        let estimate = topic_schema("PositionEstimate").unwrap();
+        assert_eq!(
+            estimate["properties"]["position"],
+            json!({ "type": "array", "items": { "type": "number" }, "minItems": 2, "maxItems": 2 })
+        );
+        assert_eq!(
+            estimate["properties"]["timestamp"],
+            json!({ "type": "integer", "minimum": 0 })
+        );
+
+        let target = topic_schema("MotionTargetRequest").unwrap();
+        assert_eq!(
+            target["properties"]["motion_mode"]["enum"],
+            json!(["Velocity", "Position", "Stop", "Path"])
+        );
+
+        let subscription = topic_schema("SubscriptionRequest").unwrap();
+        assert_eq!(
+            subscription["properties"]["topics"],
+            json!({ "type": "array", "items": { "type": "string" } })
+        );
+
+        let ack = topic_schema("PacketAck").unwrap();
+        assert_eq!(ack["properties"]["from"]["anyOf"][1], json!({ "type": "null" }));
+
+        let diagnostic = topic_schema("DiagnosticMsg").unwrap();
+        assert_eq!(
+            diagnostic["properties"]["values"]["items"]["properties"]["key"]["type"],
+            "string"
+        );
+        assert!(topic_schema("NotATopic").is_none());
+    }
+}
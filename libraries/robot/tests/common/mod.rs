@@ -0,0 +1,64 @@
+//! Helpers shared by the integration tests: building packets and framing them as the links do.
+
+#![allow(dead_code)]
+
+use std::str::FromStr;
+use std::time::Duration;
+
+use packet_encoding::{PacketFinder, decode_packet, encode_packet};
+use topics::{MAX_PACKET_SIZE, PacketData, PacketFormat};
+
+pub const TIMEOUT: Duration = Duration::from_secs(5);
+
+pub fn packet(data: PacketData, id: u32) -> PacketFormat<PacketData> {
+    PacketFormat {
+        to: None,
+        from: None,
+        data,
+        time: robot::nodes::clock::get_current_time(),
+        id,
+    }
+}
+
+pub fn subscription_request(topics: &[String], id: u32) -> PacketFormat<PacketData> {
+    let mut request = topics::SubscriptionRequest {
+        topics: heapless::Vec::new(),
+        reliable_topics: heapless::Vec::new(),
+    };
+    for topic in topics {
+        request
+            .topics
+            .push(heapless::String::from_str(topic).unwrap())
+            .unwrap();
+    }
+    packet(PacketData::SubscriptionRequest(request), id)
+}
+
+/** A packet as it goes over the wire: COBS encoded between two zero bytes */
+pub fn frame(packet: &PacketFormat<PacketData>) -> Vec<u8> {
+    let mut buffer = [0u8; MAX_PACKET_SIZE];
+    let size = encode_packet(packet, &mut buffer[1..MAX_PACKET_SIZE - 1]).unwrap();
+    let mut framed = buffer[..size + 1].to_vec();
+    framed.push(0);
+    framed
+}
+
+/** Turns a byte stream back into packets */
+#[derive(Default)]
+pub struct Deframer {
+    finder: PacketFinder<MAX_PACKET_SIZE>,
+}
+
+impl Deframer {
+    pub fn push(&mut self, bytes: &[u8]) -> Vec<PacketFormat<PacketData>> {
+        let mut packets = Vec::new();
+        for byte in bytes {
+            if let Some(mut data) = self.finder.push_byte(*byte)
+                && let Ok(packet) = decode_packet(&mut data)
+            {
+                packets.push(packet);
+            }
+        }
+        packets
+    }
+}
@@ -0,0 +1,263 @@
+//! Drives `FoxgloveServer` over loopback with a minimal Foxglove WebSocket protocol client:
+//! channel advertisement, subscribing to a topic and publishing onto one.
+
+use std::cell::RefCell;
+use std::net::{SocketAddr, TcpStream};
+use std::rc::Rc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use packet_router::{Client, Router};
+use robot::nodes::foxglove::{FoxgloveServer, SUBPROTOCOL};
+use robot::scheduler::Scheduler;
+use serde_json::{Value, json};
+use topics::{PacketData, PacketFormat};
+use tungstenite::client::IntoClientRequest;
+use tungstenite::stream::MaybeTlsStream;
+use tungstenite::{Message, WebSocket};
+
+mod common;
+use common::{TIMEOUT, packet};
+
+type Probe = Rc<RefCell<Client<PacketFormat<PacketData>>>>;
+
+struct Harness {
+    scheduler: Scheduler,
+    probe: Probe,
+    address: SocketAddr,
+}
+
+impl Harness {
+    fn new() -> Self {
+        let router = Rc::new(RefCell::new(Router::new()));
+        let server = FoxgloveServer::new(Rc::clone(&router), "127.0.0.1:0");
+        let address = server.server.local_addr().unwrap();
+        let mut scheduler = Scheduler::new(Rc::clone(&router));
+        scheduler.add(server);
+
+        let probe = Rc::new(RefCell::new(Client::default()));
+        probe
+            .borrow_mut()
+            .subscriptions
+            .insert("MotionTargetRequest".to_string());
+        router.borrow_mut().register_client(Rc::downgrade(&probe));
+        Harness {
+            scheduler,
+            probe,
+            address,
+        }
+    }
+
+    /**
+     * Runs the scheduler while `client` talks to it from another thread, publishing a
+     * `PositionEstimate` from the probe every 20ms
+     */
+    fn run<T: Send + 'static>(
+        &mut self,
+        client: impl FnOnce(SocketAddr) -> T + Send + 'static,
+    ) -> T {
+        let address = self.address;
+        let thread: JoinHandle<T> = std::thread::spawn(move || client(address));
+        let start = Instant::now();
+        let mut last_publish = Instant::now();
+        while !thread.is_finished() {
+            assert!(start.elapsed() < TIMEOUT, "timed out");
+            if last_publish.elapsed() > Duration::from_millis(20) {
+                self.probe.borrow_mut().send(position_estimate());
+                last_publish = Instant::now();
+            }
+            self.scheduler.step();
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        thread.join().unwrap()
+    }
+
+    /** Keeps the scheduler running until the probe receives something */
+    fn wait_for_probe(&mut self) -> Vec<Rc<PacketFormat<PacketData>>> {
+        let start = Instant::now();
+        loop {
+            let received = self.probe.borrow_mut().fetch_all();
+            if !received.is_empty() {
+                return received;
+            }
+            assert!(start.elapsed() < TIMEOUT, "timed out");
+            self.scheduler.step();
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+}
+
+fn position_estimate() -> PacketFormat<PacketData> {
+    packet(
+        PacketData::PositionEstimate(topics::PositionEstimate {
+            timestamp: 7,
+            position: [1.5, -2.0],
+            orientation: 0.5,
+            covariance: [0.0; 9],
+        }),
+        0,
+    )
+}
+
+type Connection = WebSocket<MaybeTlsStream<TcpStream>>;
+
+fn connect(address: SocketAddr) -> Connection {
+    let mut request = format!("ws://{}/", address).into_client_request().unwrap();
+    request
+        .headers_mut()
+        .insert("Sec-WebSocket-Protocol", SUBPROTOCOL.parse().unwrap());
+    let (websocket, response) = tungstenite::connect(request).unwrap();
+    assert_eq!(
+        response.headers().get("Sec-WebSocket-Protocol").unwrap(),
+        SUBPROTOCOL
+    );
+    websocket
+}
+
+fn next_json(websocket: &mut Connection) -> Value {
+    loop {
+        if let Message::Text(text) = websocket.read().unwrap() {
+            return serde_json::from_str(&text).unwrap();
+        }
+    }
+}
+
+fn send_json(websocket: &mut Connection, message: Value) {
+    websocket.send(Message::Text(message.to_string().into())).unwrap();
+}
+
+/** Reads the server's introduction, returning the advertised channels */
+fn read_channels(websocket: &mut Connection) -> Vec<Value> {
+    let server_info = next_json(websocket);
+    assert_eq!(server_info["op"], "serverInfo");
+    assert!(
+        server_info["capabilities"]
+            .as_array()
+            .unwrap()
+            .contains(&json!("clientPublish"))
+    );
+    let advertise = next_json(websocket);
+    assert_eq!(advertise["op"], "advertise");
+    advertise["channels"].as_array().unwrap().clone()
+}
+
+fn channel_id(channels: &[Value], topic: &str) -> u64 {
+    channels
+        .iter()
+        .find(|channel| channel["topic"] == topic)
+        .and_then(|channel| channel["id"].as_u64())
+        .unwrap()
+}
+
+#[test]
+fn test_subscribe() {
+    let mut harness = Harness::new();
+    let (channels, subscription_id, log_time, message) = harness.run(|address| {
+        let mut websocket = connect(address);
+        let channels = read_channels(&mut websocket);
+        send_json(
+            &mut websocket,
+            json!({
+                "op": "subscribe",
+                "subscriptions": [
+                    { "id": 42, "channelId": channel_id(&channels, "PositionEstimate") },
+                ],
+            }),
+        );
+        let data = loop {
+            if let Message::Binary(data) = websocket.read().unwrap() {
+                break data;
+            }
+        };
+        assert_eq!(data[0], 0x01);
+        (
+            channels,
+            u32::from_le_bytes(data[1..5].try_into().unwrap()),
+            u64::from_le_bytes(data[5..13].try_into().unwrap()),
+            serde_json::from_slice::<Value>(&data[13..]).unwrap(),
+        )
+    });
+
+    // Every topic is a JSON channel with a schema
+    assert!(channels.len() >= 9);
+    for channel in channels.iter() {
+        assert_eq!(channel["encoding"], "json");
+        let schema: Value = serde_json::from_str(channel["schema"].as_str().unwrap()).unwrap();
+        assert_eq!(schema["type"], "object");
+    }
+
+    assert_eq!(subscription_id, 42);
+    assert!(log_time > 0);
+    assert_eq!(
+        message,
+        json!({
+            "timestamp": 7,
+            "position": [1.5, -2.0],
+            "orientation": 0.5,
+            "covariance": [0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0],
+        })
+    );
+}
+
+#[test]
+fn test_client_publish() {
+    let mut harness = Harness::new();
+    let status = harness.run(|address| {
+        let mut websocket = connect(address);
+        read_channels(&mut websocket);
+        send_json(
+            &mut websocket,
+            json!({
+                "op": "advertise",
+                "channels": [{
+                    "id": 3,
+                    "topic": "MotionTargetRequest",
+                    "encoding": "json",
+                    "schemaName": "MotionTargetRequest",
+                }],
+            }),
+        );
+        let mut data = vec![0x01];
+        data.extend_from_slice(&3u32.to_le_bytes());
+        data.extend_from_slice(
+            json!({ "linear": [1.0, 2.0], "angular": 0.5, "motion_mode": "Position" })
+                .to_string()
+                .as_bytes(),
+        );
+        websocket.send(Message::Binary(data.clone().into())).unwrap();
+        websocket.send(Message::Binary(data.into())).unwrap();
+
+        // Publishing on a channel that wasn't advertised is reported
+        let mut data = vec![0x01];
+        data.extend_from_slice(&9u32.to_le_bytes());
+        data.extend_from_slice(b"{}");
+        websocket.send(Message::Binary(data.into())).unwrap();
+        next_json(&mut websocket)
+    });
+    assert_eq!(status["op"], "status");
+    assert_eq!(status["level"], 2);
+
+    let received = harness.wait_for_probe();
+    match &received[0].data {
+        PacketData::MotionTargetRequest(target) => {
+            assert_eq!(target.linear, [1.0, 2.0]);
+            assert_eq!(target.angular, 0.5);
+        }
+        other => panic!("unexpected packet {:?}", other),
+    }
+    // Each gets its own id, so a reliable link doesn't take the second for a retransmission
+    assert_eq!(received.len(), 2);
+    assert_ne!(received[0].id, received[1].id);
+}
+
+#[test]
+fn test_other_subprotocols_are_rejected() {
+    let mut harness = Harness::new();
+    let result = harness.run(|address| {
+        tungstenite::connect(format!("ws://{}/", address)).map(|_| ())
+    });
+    match result {
+        Err(tungstenite::Error::Http(response)) => assert_eq!(response.status(), 400),
+        other => panic!("expected a rejection, got {:?}", other),
+    }
+}
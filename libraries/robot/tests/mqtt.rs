@@ -0,0 +1,377 @@
+//! Runs `MqttBridge` against a small in-process broker: publishing telemetry, taking commands,
+//! reconnecting after the broker drops it, and the last will.
+
+use std::cell::RefCell;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use packet_router::{Client, Router};
+use robot::config::{MqttConfig, MqttEncoding};
+use robot::nodes::mqtt::{MqttPacket, topic_matches};
+use robot::nodes::mqtt_bridge::MqttBridge;
+use robot::scheduler::Scheduler;
+use serde_json::Value;
+use topics::{DiagnosticStatus, PacketData, PacketFormat};
+
+mod common;
+use common::{TIMEOUT, packet};
+
+/** A message the broker was sent */
+#[derive(Debug, Clone)]
+struct Published {
+    topic: String,
+    payload: Vec<u8>,
+    retain: bool,
+}
+
+struct Session {
+    stream: TcpStream,
+    filters: Vec<String>,
+}
+
+#[derive(Default)]
+struct BrokerState {
+    connects: usize,
+    published: Vec<Published>,
+    sessions: Vec<Session>,
+}
+
+impl BrokerState {
+    fn deliver(&mut self, topic: &str, payload: &[u8]) {
+        let publish = MqttPacket::Publish {
+            topic: topic.to_string(),
+            payload: payload.to_vec(),
+            qos: 0,
+            retain: false,
+            packet_id: None,
+        };
+        for session in self.sessions.iter_mut() {
+            if session.filters.iter().any(|filter| topic_matches(filter, topic)) {
+                session.stream.write_all(&publish.encode().unwrap()).ok();
+            }
+        }
+    }
+
+    fn record(&mut self, topic: String, payload: Vec<u8>, retain: bool) {
+        self.deliver(&topic, &payload);
+        self.published.push(Published {
+            topic,
+            payload,
+            retain,
+        });
+    }
+}
+
+/** Just enough of an MQTT broker: QoS 0 routing, retained flags, pings and last wills */
+struct Broker {
+    address: SocketAddr,
+    state: Arc<Mutex<BrokerState>>,
+    stop: Arc<AtomicBool>,
+}
+
+impl Broker {
+    fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let broker = Broker {
+            address: listener.local_addr().unwrap(),
+            state: Arc::default(),
+            stop: Arc::default(),
+        };
+        let state = Arc::clone(&broker.state);
+        let stop = Arc::clone(&broker.stop);
+        std::thread::spawn(move || {
+            while !stop.load(Ordering::Relaxed) {
+                match listener.accept() {
+                    Ok((stream, _)) => {
+                        let state = Arc::clone(&state);
+                        let stop = Arc::clone(&stop);
+                        std::thread::spawn(move || serve(stream, state, stop));
+                    }
+                    Err(_) => std::thread::sleep(Duration::from_millis(1)),
+                }
+            }
+        });
+        broker
+    }
+
+    fn published_on(&self, topic: &str) -> Vec<Published> {
+        let state = self.state.lock().unwrap();
+        state
+            .published
+            .iter()
+            .filter(|published| published.topic == topic)
+            .cloned()
+            .collect()
+    }
+
+    fn connects(&self) -> usize {
+        self.state.lock().unwrap().connects
+    }
+
+    fn subscribed(&self, topic: &str) -> bool {
+        let state = self.state.lock().unwrap();
+        state
+            .sessions
+            .iter()
+            .any(|session| session.filters.iter().any(|filter| topic_matches(filter, topic)))
+    }
+
+    /** Publishes to the broker's clients, as another client would */
+    fn publish(&self, topic: &str, payload: &[u8]) {
+        self.state.lock().unwrap().deliver(topic, payload);
+    }
+
+    /** Drops every client connection */
+    fn kick(&self) {
+        let mut state = self.state.lock().unwrap();
+        for session in state.sessions.drain(..) {
+            session.stream.shutdown(std::net::Shutdown::Both).ok();
+        }
+    }
+}
+
+impl Drop for Broker {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+fn serve(mut stream: TcpStream, state: Arc<Mutex<BrokerState>>, stop: Arc<AtomicBool>) {
+    stream.set_read_timeout(Some(Duration::from_millis(10))).unwrap();
+    let writer = stream.try_clone().unwrap();
+    let mut buffer = Vec::new();
+    let mut will = None;
+    let mut session_index = None;
+    let mut clean = false;
+    while !stop.load(Ordering::Relaxed) {
+        let mut chunk = [0u8; 1024];
+        match stream.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(count) => buffer.extend_from_slice(&chunk[..count]),
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => continue,
+            Err(_) => break,
+        }
+        while let Some((packet, used)) = MqttPacket::decode(&buffer).unwrap() {
+            buffer.drain(..used);
+            let mut state = state.lock().unwrap();
+            let reply = match packet {
+                MqttPacket::Connect { will: last_will, .. } => {
+                    will = last_will;
+                    state.connects += 1;
+                    state.sessions.push(Session {
+                        stream: writer.try_clone().unwrap(),
+                        filters: Vec::new(),
+                    });
+                    session_index = Some(state.sessions.len() - 1);
+                    Some(MqttPacket::ConnAck {
+                        session_present: false,
+                        return_code: 0,
+                    })
+                }
+                MqttPacket::Subscribe { packet_id, filters } => {
+                    let return_codes = filters.iter().map(|_| 0).collect();
+                    if let Some(session) = session_index.and_then(|i| state.sessions.get_mut(i)) {
+                        session.filters.extend(filters.into_iter().map(|(filter, _)| filter));
+                    }
+                    Some(MqttPacket::SubAck {
+                        packet_id,
+                        return_codes,
+                    })
+                }
+                MqttPacket::Publish {
+                    topic,
+                    payload,
+                    retain,
+                    packet_id,
+                    ..
+                } => {
+                    state.record(topic, payload, retain);
+                    packet_id.map(|packet_id| MqttPacket::PubAck { packet_id })
+                }
+                MqttPacket::PingReq => Some(MqttPacket::PingResp),
+                MqttPacket::Disconnect => {
+                    clean = true;
+                    None
+                }
+                _ => None,
+            };
+            if let Some(reply) = reply {
+                (&writer).write_all(&reply.encode().unwrap()).ok();
+            }
+        }
+    }
+    if !clean && let Some(will) = will {
+        state.lock().unwrap().record(will.topic, will.payload, will.retain);
+    }
+}
+
+struct Harness {
+    scheduler: Scheduler,
+    probe: Rc<RefCell<Client<PacketFormat<PacketData>>>>,
+}
+
+impl Harness {
+    fn new(broker: &Broker, encoding: MqttEncoding) -> Self {
+        let router = Rc::new(RefCell::new(Router::new()));
+        let mut scheduler = Scheduler::new(Rc::clone(&router));
+        scheduler.add(MqttBridge::new(MqttConfig {
+            enabled: true,
+            broker: broker.address.to_string(),
+            robot: "r1".to_string(),
+            encoding,
+            reconnect_min_ms: 50,
+            ..Default::default()
+        }));
+
+        let probe = Rc::new(RefCell::new(Client::default()));
+        probe
+            .borrow_mut()
+            .subscriptions
+            .insert("MotionTargetRequest".to_string());
+        router.borrow_mut().register_client(Rc::downgrade(&probe));
+        Harness { scheduler, probe }
+    }
+
+    /** Runs the scheduler, publishing a `PositionEstimate` every 20ms, until `done` */
+    fn run_until(&mut self, what: &str, mut done: impl FnMut(&mut Self) -> bool) {
+        let start = Instant::now();
+        let mut last_publish = Instant::now();
+        while !done(self) {
+            assert!(start.elapsed() < TIMEOUT, "timed out waiting for {}", what);
+            if last_publish.elapsed() > Duration::from_millis(20) {
+                self.probe.borrow_mut().send(position_estimate());
+                last_publish = Instant::now();
+            }
+            self.scheduler.step();
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+}
+
+fn position_estimate() -> PacketFormat<PacketData> {
+    packet(
+        PacketData::PositionEstimate(topics::PositionEstimate {
+            timestamp: 5,
+            position: [1.5, -2.0],
+            orientation: 0.25,
+            covariance: [0.0; 9],
+        }),
+        0,
+    )
+}
+
+fn json(published: &Published) -> Value {
+    serde_json::from_slice(&published.payload).unwrap()
+}
+
+#[test]
+fn test_telemetry_and_commands() {
+    let broker = Broker::start();
+    let mut harness = Harness::new(&broker, MqttEncoding::Json);
+    harness.run_until("telemetry", |_| {
+        !broker.published_on("slambot/r1/PositionEstimate").is_empty()
+    });
+
+    let estimate = &broker.published_on("slambot/r1/PositionEstimate")[0];
+    assert!(!estimate.retain);
+    assert_eq!(json(estimate)["data"]["PositionEstimate"]["position"][0], 1.5);
+
+    // The retained status says the robot is online
+    let status = &broker.published_on("slambot/r1/DiagnosticMsg")[0];
+    assert!(status.retain);
+    assert_eq!(json(status)["data"]["DiagnosticMsg"]["name"], "mqtt");
+    assert_eq!(json(status)["data"]["DiagnosticMsg"]["level"], "Ok");
+
+    // Commands reach the router. Time and id can be left out.
+    harness.run_until("subscription", |_| {
+        broker.subscribed("slambot/r1/MotionTargetRequest")
+    });
+    for _ in 0..2 {
+        broker.publish(
+            "slambot/r1/MotionTargetRequest",
+            br#"{"data": {"MotionTargetRequest": {"linear": [1, 2], "angular": 0.5, "motion_mode": "Position"}}}"#,
+        );
+    }
+    let mut received = Vec::new();
+    harness.run_until("commands", |harness| {
+        received.extend(harness.probe.borrow_mut().fetch_all());
+        received.len() == 2
+    });
+    match &received[0].data {
+        PacketData::MotionTargetRequest(target) => assert_eq!(target.linear, [1.0, 2.0]),
+        other => panic!("unexpected packet {:?}", other),
+    }
+    // Each gets its own id, so a reliable link doesn't take the second for a retransmission
+    assert_ne!(received[0].id, received[1].id);
+}
+
+#[test]
+fn test_cbor() {
+    let broker = Broker::start();
+    let mut harness = Harness::new(&broker, MqttEncoding::Cbor);
+    harness.run_until("telemetry", |_| {
+        !broker.published_on("slambot/r1/PositionEstimate").is_empty()
+    });
+    let estimate = &broker.published_on("slambot/r1/PositionEstimate")[0];
+    let packet: PacketFormat<PacketData> = minicbor_serde::from_slice(&estimate.payload).unwrap();
+    match packet.data {
+        PacketData::PositionEstimate(estimate) => assert_eq!(estimate.orientation, 0.25),
+        other => panic!("unexpected packet {:?}", other),
+    }
+}
+
+#[test]
+fn test_reconnect_and_last_will() {
+    let broker = Broker::start();
+    let mut harness = Harness::new(&broker, MqttEncoding::Json);
+    harness.run_until("connection", |_| broker.connects() == 1);
+
+    // Dropped connections are retried. The broker publishes the will for the lost one.
+    broker.kick();
+    harness.run_until("reconnection", |_| broker.connects() == 2);
+    let statuses = broker.published_on("slambot/r1/DiagnosticMsg");
+    assert!(statuses.iter().any(|status| status.retain
+        && json(status)["data"]["DiagnosticMsg"]["level"] == "Stale"));
+
+    // A robot that disappears without disconnecting is marked stale by its will
+    harness.run_until("subscription", |_| {
+        broker.subscribed("slambot/r1/MotionTargetRequest")
+    });
+    drop(harness);
+    let start = Instant::now();
+    loop {
+        let statuses = broker.published_on("slambot/r1/DiagnosticMsg");
+        let last: topics::DiagnosticMsg = serde_json::from_value(
+            json(statuses.last().unwrap())["data"]["DiagnosticMsg"].clone(),
+        )
+        .unwrap();
+        if matches!(last.level, DiagnosticStatus::Stale) {
+            assert_eq!(last.message.as_str(), "disconnected");
+            break;
+        }
+        assert!(start.elapsed() < TIMEOUT, "timed out waiting for the last will");
+        std::thread::sleep(Duration::from_millis(1));
+    }
+}
+
+#[test]
+fn test_unreachable_broker_does_not_block() {
+    // Nothing answers on this address, so connecting waits for the timeout
+    let router = Rc::new(RefCell::new(Router::new()));
+    let mut scheduler = Scheduler::new(Rc::clone(&router));
+    scheduler.add(MqttBridge::new(MqttConfig {
+        enabled: true,
+        broker: "10.255.255.1:1883".to_string(),
+        ..Default::default()
+    }));
+    let start = Instant::now();
+    for _ in 0..10 {
+        scheduler.step();
+    }
+    assert!(start.elapsed() < Duration::from_millis(500));
+}
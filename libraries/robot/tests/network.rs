@@ -0,0 +1,180 @@
+//! Drives `TcpAcceptor` and `UdpTransport` over loopback sockets, with the test playing a device
+//! that speaks the same framing as the serial link.
+
+use std::cell::RefCell;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream, UdpSocket};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use packet_router::{Client, Router};
+use robot::nodes::tcp_acceptor::TcpAcceptor;
+use robot::nodes::udp_transport::UdpTransport;
+use topics::{PacketData, PacketFormat};
+
+mod common;
+use common::{Deframer, TIMEOUT, frame, packet, subscription_request};
+
+type SharedRouter = Rc<RefCell<Router<PacketFormat<PacketData>>>>;
+
+/** A probe client on the router, subscribed to what a device would publish */
+fn probe(router: &SharedRouter) -> Rc<RefCell<Client<PacketFormat<PacketData>>>> {
+    let probe = Rc::new(RefCell::new(Client::default()));
+    probe
+        .borrow_mut()
+        .subscriptions
+        .insert("OdometryDelta".to_string());
+    router.borrow_mut().register_client(Rc::downgrade(&probe));
+    probe
+}
+
+fn odometry(id: u32) -> PacketFormat<PacketData> {
+    packet(
+        PacketData::OdometryDelta(topics::OdometryDelta {
+            start_time: 0,
+            end_time: 0,
+            delta_position: [0.0, 0.01],
+            delta_orientation: 0.0,
+        }),
+        id,
+    )
+}
+
+fn velocity_request() -> PacketFormat<PacketData> {
+    packet(
+        PacketData::MotionVelocityRequest(topics::MotionVelocityRequest {
+            linear_velocity: 0.1,
+            angular_velocity: 0.0,
+        }),
+        0,
+    )
+}
+
+/** Calls `step` until it returns true, failing the test after `TIMEOUT` */
+fn wait_for(what: &str, mut step: impl FnMut() -> bool) {
+    let start = Instant::now();
+    while !step() {
+        assert!(start.elapsed() < TIMEOUT, "timed out waiting for {}", what);
+        std::thread::sleep(Duration::from_millis(1));
+    }
+}
+
+fn tcp_read(stream: &mut TcpStream, deframer: &mut Deframer) -> Vec<PacketFormat<PacketData>> {
+    let mut buffer = [0u8; 1024];
+    match stream.read(&mut buffer) {
+        Ok(count) => deframer.push(&buffer[..count]),
+        Err(_) => Vec::new(),
+    }
+}
+
+#[test]
+fn test_tcp_client() {
+    let router: SharedRouter = Rc::new(RefCell::new(Router::new()));
+    let mut acceptor = TcpAcceptor::new(Rc::clone(&router), "127.0.0.1:0");
+    let address = acceptor.server.local_addr().unwrap();
+    let probe = probe(&router);
+
+    let mut stream = TcpStream::connect(address).unwrap();
+    stream.set_nonblocking(true).unwrap();
+    let mut deframer = Deframer::default();
+
+    // Packets from the device reach the router
+    stream
+        .write_all(&frame(&subscription_request(&["MotionVelocityRequest".to_string()], 0)))
+        .unwrap();
+    stream.write_all(&frame(&odometry(1))).unwrap();
+    let mut seen = Vec::new();
+    wait_for("odometry", || {
+        acceptor.tick();
+        router.borrow_mut().poll();
+        seen.extend(probe.borrow_mut().fetch_all());
+        !seen.is_empty()
+    });
+
+    // Only subscribed topics are delivered to it
+    probe.borrow_mut().send(velocity_request());
+    probe.borrow_mut().send(odometry(2));
+    let mut received = Vec::new();
+    wait_for("velocity request", || {
+        acceptor.tick();
+        router.borrow_mut().poll();
+        received.extend(tcp_read(&mut stream, &mut deframer));
+        received
+            .iter()
+            .any(|p| matches!(p.data, PacketData::MotionVelocityRequest(_)))
+    });
+    assert!(
+        received
+            .iter()
+            .all(|p| !matches!(p.data, PacketData::OdometryDelta(_)))
+    );
+
+    // Garbage is counted, and the connection carries on
+    stream.write_all(&[0, 5, 1, 2, 3, 4, 0]).unwrap();
+    wait_for("decode error", || {
+        acceptor.tick();
+        acceptor
+            .clients_by_addr
+            .values()
+            .any(|client| client.stats.decode_error_count == 1)
+    });
+
+    drop(stream);
+    wait_for("disconnect", || {
+        acceptor.tick();
+        acceptor.clients_by_addr.is_empty()
+    });
+}
+
+#[test]
+fn test_udp_peer() {
+    let router: SharedRouter = Rc::new(RefCell::new(Router::new()));
+    let mut transport = UdpTransport::new(
+        Rc::clone(&router),
+        "127.0.0.1:0",
+        Duration::from_millis(200),
+    );
+    let address: SocketAddr = transport.socket.local_addr().unwrap();
+    let probe = probe(&router);
+
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.set_nonblocking(true).unwrap();
+    let mut deframer = Deframer::default();
+
+    // The first datagram introduces the peer
+    socket
+        .send_to(
+            &frame(&subscription_request(&["MotionVelocityRequest".to_string()], 0)),
+            address,
+        )
+        .unwrap();
+    socket.send_to(&frame(&odometry(1)), address).unwrap();
+    let mut seen = Vec::new();
+    wait_for("odometry", || {
+        transport.tick();
+        router.borrow_mut().poll();
+        seen.extend(probe.borrow_mut().fetch_all());
+        !seen.is_empty()
+    });
+    assert_eq!(transport.peers().count(), 1);
+
+    probe.borrow_mut().send(velocity_request());
+    let mut received = Vec::new();
+    wait_for("velocity request", || {
+        transport.tick();
+        router.borrow_mut().poll();
+        let mut buffer = [0u8; 2048];
+        while let Ok((count, _)) = socket.recv_from(&mut buffer) {
+            received.extend(deframer.push(&buffer[..count]));
+        }
+        received
+            .iter()
+            .any(|p| matches!(p.data, PacketData::MotionVelocityRequest(_)))
+    });
+
+    // Without a connection to close, silent peers are forgotten
+    wait_for("peer timeout", || {
+        transport.tick();
+        transport.peers().count() == 0
+    });
+}
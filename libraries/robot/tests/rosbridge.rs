@@ -0,0 +1,213 @@
+//! Drives `RosbridgeServer` over loopback with a stand-in for a rosbridge client such as
+//! roslibjs: topic discovery, subscribing, advertising and publishing.
+
+use std::cell::RefCell;
+use std::net::{SocketAddr, TcpStream};
+use std::rc::Rc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use packet_router::{Client, Router};
+use robot::nodes::rosbridge::RosbridgeServer;
+use robot::scheduler::Scheduler;
+use serde_json::{Value, json};
+use topics::{PacketData, PacketFormat};
+use tungstenite::stream::MaybeTlsStream;
+use tungstenite::{Message, WebSocket};
+
+mod common;
+use common::{TIMEOUT, packet};
+
+type Probe = Rc<RefCell<Client<PacketFormat<PacketData>>>>;
+
+struct Harness {
+    scheduler: Scheduler,
+    probe: Probe,
+    address: SocketAddr,
+}
+
+impl Harness {
+    fn new() -> Self {
+        let router = Rc::new(RefCell::new(Router::new()));
+        let server = RosbridgeServer::new(Rc::clone(&router), "127.0.0.1:0");
+        let address = server.server.local_addr().unwrap();
+        let mut scheduler = Scheduler::new(Rc::clone(&router));
+        scheduler.add(server);
+
+        let probe = Rc::new(RefCell::new(Client::default()));
+        probe
+            .borrow_mut()
+            .subscriptions
+            .insert("MotionVelocityRequest".to_string());
+        router.borrow_mut().register_client(Rc::downgrade(&probe));
+        Harness {
+            scheduler,
+            probe,
+            address,
+        }
+    }
+
+    /**
+     * Runs the scheduler while `client` talks to it from another thread, publishing a
+     * `PositionEstimate` from the probe every 20ms
+     */
+    fn run<T: Send + 'static>(
+        &mut self,
+        client: impl FnOnce(SocketAddr) -> T + Send + 'static,
+    ) -> T {
+        let address = self.address;
+        let thread: JoinHandle<T> = std::thread::spawn(move || client(address));
+        let start = Instant::now();
+        let mut last_publish = Instant::now();
+        while !thread.is_finished() {
+            assert!(start.elapsed() < TIMEOUT, "timed out");
+            if last_publish.elapsed() > Duration::from_millis(20) {
+                self.probe.borrow_mut().send(position_estimate());
+                last_publish = Instant::now();
+            }
+            self.scheduler.step();
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        thread.join().unwrap()
+    }
+
+    /** Keeps the scheduler running until the probe receives something */
+    fn wait_for_probe(&mut self) -> Vec<Rc<PacketFormat<PacketData>>> {
+        let start = Instant::now();
+        loop {
+            let received = self.probe.borrow_mut().fetch_all();
+            if !received.is_empty() {
+                return received;
+            }
+            assert!(start.elapsed() < TIMEOUT, "timed out");
+            self.scheduler.step();
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+}
+
+fn position_estimate() -> PacketFormat<PacketData> {
+    packet(
+        PacketData::PositionEstimate(topics::PositionEstimate {
+            timestamp: 3_000_001,
+            position: [1.5, -2.0],
+            orientation: 0.0,
+            covariance: [0.0; 9],
+        }),
+        0,
+    )
+}
+
+type Connection = WebSocket<MaybeTlsStream<TcpStream>>;
+
+fn connect(address: SocketAddr) -> Connection {
+    tungstenite::connect(format!("ws://{}/", address)).unwrap().0
+}
+
+fn send(websocket: &mut Connection, message: Value) {
+    websocket.send(Message::Text(message.to_string().into())).unwrap();
+}
+
+fn next_op(websocket: &mut Connection, op: &str) -> Value {
+    loop {
+        if let Message::Text(text) = websocket.read().unwrap() {
+            let message: Value = serde_json::from_str(&text).unwrap();
+            if message["op"] == op {
+                return message;
+            }
+        }
+    }
+}
+
+#[test]
+fn test_topics_service() {
+    let mut harness = Harness::new();
+    let response = harness.run(|address| {
+        let mut websocket = connect(address);
+        send(
+            &mut websocket,
+            json!({ "op": "call_service", "id": "call:1", "service": "/rosapi/topics" }),
+        );
+        next_op(&mut websocket, "service_response")
+    });
+    assert_eq!(response["id"], "call:1");
+    assert_eq!(response["result"], true);
+    let topics = response["values"]["topics"].as_array().unwrap();
+    let types = response["values"]["types"].as_array().unwrap();
+    let pose = topics.iter().position(|topic| topic == "/pose").unwrap();
+    assert_eq!(types[pose], "geometry_msgs/PoseStamped");
+    assert!(topics.contains(&json!("/OdometryDelta")));
+}
+
+#[test]
+fn test_subscribe() {
+    let mut harness = Harness::new();
+    let (pose, error) = harness.run(|address| {
+        let mut websocket = connect(address);
+        send(
+            &mut websocket,
+            json!({
+                "op": "subscribe",
+                "id": "sub:1",
+                "topic": "/pose",
+                "type": "geometry_msgs/msg/PoseStamped",
+            }),
+        );
+        let pose = next_op(&mut websocket, "publish");
+
+        send(
+            &mut websocket,
+            json!({ "op": "subscribe", "topic": "/pose", "type": "std_msgs/String" }),
+        );
+        let error = next_op(&mut websocket, "status");
+        (pose, error)
+    });
+    assert_eq!(pose["topic"], "/pose");
+    assert_eq!(
+        pose["msg"]["header"],
+        json!({ "stamp": { "sec": 3, "nanosec": 1000 }, "frame_id": "odom" })
+    );
+    assert_eq!(pose["msg"]["pose"]["position"], json!({ "x": 1.5, "y": -2.0, "z": 0.0 }));
+    assert_eq!(pose["msg"]["pose"]["orientation"]["w"], 1.0);
+    assert_eq!(error["level"], "error");
+}
+
+#[test]
+fn test_publish() {
+    let mut harness = Harness::new();
+    let error = harness.run(|address| {
+        let mut websocket = connect(address);
+        send(
+            &mut websocket,
+            json!({ "op": "advertise", "topic": "/cmd_vel", "type": "geometry_msgs/Twist" }),
+        );
+        for _ in 0..2 {
+            send(
+                &mut websocket,
+                json!({
+                    "op": "publish",
+                    "topic": "/cmd_vel",
+                    "msg": {
+                        "linear": { "x": 0.2, "y": 0.0, "z": 0.0 },
+                        "angular": { "x": 0.0, "y": 0.0, "z": 0.4 },
+                    },
+                }),
+            );
+        }
+        send(&mut websocket, json!({ "op": "publish", "topic": "/nowhere", "msg": {} }));
+        next_op(&mut websocket, "status")
+    });
+    assert!(error["msg"].as_str().unwrap().contains("/nowhere"));
+
+    let received = harness.wait_for_probe();
+    match &received[0].data {
+        PacketData::MotionVelocityRequest(request) => {
+            assert_eq!(request.linear_velocity, 0.2);
+            assert_eq!(request.angular_velocity, 0.4);
+        }
+        other => panic!("unexpected packet {:?}", other),
+    }
+    // Each gets its own id, so a reliable link doesn't take the second for a retransmission
+    assert_eq!(received.len(), 2);
+    assert_ne!(received[0].id, received[1].id);
+}
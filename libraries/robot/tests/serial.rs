@@ -0,0 +1,379 @@
+//! Drives `SerialAdapter` and `SerialClient` over pseudo-terminals, with a fake firmware on the
+//! other end speaking the real protocol.
+
+use std::cell::RefCell;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, mpsc};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use packet_router::{Client, Router};
+use robot::config::{SerialConfig, SerialDeviceConfig};
+use robot::nodes::serial_adapter::SerialAdapter;
+use robot::nodes::serial_client::SerialClient;
+use serialport::{SerialPort, TTYPort};
+use topics::{PacketData, PacketFormat};
+
+mod common;
+use common::{Deframer, TIMEOUT, frame, packet, subscription_request};
+
+type Received = Arc<Mutex<Vec<PacketFormat<PacketData>>>>;
+
+/**
+ * Pretends to be the motor controller on the master end of a pseudo-terminal. Like the real
+ * firmware it sends a `SubscriptionRequest` and a `ClockRequest` every second and streams
+ * `OdometryDelta` at 10 Hz. Everything it receives is kept in `received`.
+ *
+ * Dropping it closes the terminal, which looks like the device being unplugged.
+ */
+struct FakeFirmware {
+    path: String,
+    topics: Arc<Mutex<Vec<String>>>,
+    received: Received,
+    raw_tx: mpsc::Sender<Vec<u8>>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl FakeFirmware {
+    fn start(topics: &[&str]) -> Self {
+        let (mut master, slave) = TTYPort::pair().expect("Failed to create pseudo-terminal");
+        let path = slave.name().unwrap();
+        master.set_timeout(Duration::from_millis(1)).unwrap();
+
+        let topics = Arc::new(Mutex::new(topics.iter().map(|t| t.to_string()).collect::<Vec<_>>()));
+        let received: Received = Arc::default();
+        let stop = Arc::new(AtomicBool::new(false));
+        let (raw_tx, raw_rx) = mpsc::channel::<Vec<u8>>();
+
+        let thread = {
+            let topics = Arc::clone(&topics);
+            let received = Arc::clone(&received);
+            let stop = Arc::clone(&stop);
+            std::thread::spawn(move || {
+                // Hold the slave end open until the adapter opens it, so that nothing written
+                // before then is lost
+                let _slave = slave;
+                let mut deframer = Deframer::default();
+                let mut id = 0;
+                let mut last_second = Instant::now() - Duration::from_secs(1);
+                let mut last_odometry = Instant::now();
+
+                while !stop.load(Ordering::Relaxed) {
+                    let mut outgoing = Vec::new();
+                    if last_second.elapsed() >= Duration::from_secs(1) {
+                        last_second = Instant::now();
+                        outgoing.push(subscription_request(&topics.lock().unwrap(), id));
+                        outgoing.push(packet(
+                            PacketData::ClockRequest(topics::ClockRequest { request_time: 42 }),
+                            id + 1,
+                        ));
+                        id += 2;
+                    }
+                    if last_odometry.elapsed() >= Duration::from_millis(100) {
+                        last_odometry = Instant::now();
+                        outgoing.push(packet(
+                            PacketData::OdometryDelta(topics::OdometryDelta {
+                                start_time: 0,
+                                end_time: 0,
+                                delta_position: [0.0, 0.01],
+                                delta_orientation: 0.0,
+                            }),
+                            id,
+                        ));
+                        id += 1;
+                    }
+                    for packet in outgoing.iter() {
+                        master.write_all(&frame(packet)).ok();
+                    }
+                    while let Ok(raw) = raw_rx.try_recv() {
+                        master.write_all(&raw).ok();
+                    }
+
+                    let mut buffer = [0u8; 256];
+                    if let Ok(count) = master.read(&mut buffer) {
+                        received.lock().unwrap().extend(deframer.push(&buffer[..count]));
+                    } else {
+                        std::thread::sleep(Duration::from_millis(1));
+                    }
+                }
+            })
+        };
+
+        FakeFirmware {
+            path,
+            topics,
+            received,
+            raw_tx,
+            stop,
+            thread: Some(thread),
+        }
+    }
+
+    fn set_topics(&self, topics: &[&str]) {
+        *self.topics.lock().unwrap() = topics.iter().map(|t| t.to_string()).collect();
+    }
+
+    fn send_raw(&self, bytes: &[u8]) {
+        self.raw_tx.send(bytes.to_vec()).unwrap();
+    }
+
+    fn has_received(&self, matches: impl Fn(&PacketFormat<PacketData>) -> bool) -> bool {
+        self.received.lock().unwrap().iter().any(matches)
+    }
+}
+
+impl Drop for FakeFirmware {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+/** The robot side: a router, the adapter under test, and a probe client to watch traffic with */
+struct Harness {
+    router: Rc<RefCell<Router<PacketFormat<PacketData>>>>,
+    adapter: SerialAdapter,
+    probe: Rc<RefCell<Client<PacketFormat<PacketData>>>>,
+    seen: Vec<Rc<PacketFormat<PacketData>>>,
+}
+
+impl Harness {
+    /** Connects a device called "motors" at the given path, which may be a glob */
+    fn new(path: &str) -> Self {
+        let router = Rc::new(RefCell::new(Router::new()));
+        let adapter = SerialAdapter::new(
+            Rc::clone(&router),
+            SerialConfig {
+                enabled: true,
+                scan_interval_ms: 20,
+                devices: vec![SerialDeviceConfig {
+                    name: "motors".to_string(),
+                    path: Some(path.to_string()),
+                    ..Default::default()
+                }],
+            },
+        );
+        let probe = Rc::new(RefCell::new(Client::default()));
+        for topic in ["OdometryDelta", "ClockRequest"] {
+            probe.borrow_mut().subscriptions.insert(topic.to_string());
+        }
+        router.borrow_mut().register_client(Rc::downgrade(&probe));
+        Harness {
+            router,
+            adapter,
+            probe,
+            seen: Vec::new(),
+        }
+    }
+
+    fn step(&mut self) {
+        self.adapter.tick();
+        self.router.borrow_mut().poll();
+        let packets = self.probe.borrow_mut().fetch_all();
+        self.seen.extend(packets);
+        std::thread::sleep(Duration::from_millis(1));
+    }
+
+    /** Steps until `done` returns true, failing the test after `TIMEOUT` */
+    fn run_until(&mut self, what: &str, mut done: impl FnMut(&mut Self) -> bool) {
+        let start = Instant::now();
+        while !done(self) {
+            assert!(start.elapsed() < TIMEOUT, "timed out waiting for {}", what);
+            self.step();
+        }
+    }
+
+    fn publish(&self, data: PacketData) {
+        self.probe.borrow_mut().send(packet(data, 0));
+    }
+
+    fn client(&self) -> Option<&SerialClient> {
+        self.adapter.clients_by_name.get("motors")
+    }
+
+    fn odometry_count(&self) -> usize {
+        self.seen
+            .iter()
+            .filter(|p| matches!(p.data, PacketData::OdometryDelta(_)))
+            .count()
+    }
+}
+
+fn temp_dir(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("slambot_{}_{}", name, std::process::id()));
+    std::fs::remove_dir_all(&path).ok();
+    std::fs::create_dir_all(&path).unwrap();
+    path
+}
+
+#[test]
+fn test_firmware_traffic_reaches_router() {
+    let firmware = FakeFirmware::start(&[]);
+    let mut harness = Harness::new(&firmware.path);
+
+    harness.run_until("odometry", |h| h.odometry_count() >= 3);
+    harness.run_until("clock request", |h| {
+        h.seen
+            .iter()
+            .any(|p| matches!(p.data, PacketData::ClockRequest(_)))
+    });
+
+    // Addressed replies find their way back to the device
+    let request = harness
+        .seen
+        .iter()
+        .find(|p| matches!(p.data, PacketData::ClockRequest(_)))
+        .unwrap();
+    let mut response = packet(
+        PacketData::ClockResponse(topics::ClockResponse {
+            request_time: 42,
+            recieved_time: 1234,
+        }),
+        request.id,
+    );
+    response.to = request.from;
+    harness.probe.borrow_mut().send(response);
+    harness.run_until("clock response", |_| {
+        firmware.has_received(|p| matches!(p.data, PacketData::ClockResponse(_)))
+    });
+
+    let stats = &harness.client().unwrap().stats;
+    assert!(stats.rx_packets >= 5);
+    assert_eq!(stats.decode_error_count, 0);
+}
+
+#[test]
+fn test_subscription_updates() {
+    let firmware = FakeFirmware::start(&["MotionVelocityRequest"]);
+    let path = firmware.path.clone();
+    let mut harness = Harness::new(&path);
+
+    harness.run_until("subscription", |h| {
+        h.client()
+            .is_some_and(|c| c.client.borrow().subscriptions.contains("MotionVelocityRequest"))
+    });
+    harness.publish(PacketData::MotionVelocityRequest(topics::MotionVelocityRequest {
+        linear_velocity: 0.1,
+        angular_velocity: 0.0,
+    }));
+    harness.run_until("velocity request", |_| {
+        firmware.has_received(|p| matches!(p.data, PacketData::MotionVelocityRequest(_)))
+    });
+
+    // A new subscription request replaces the old topics
+    firmware.set_topics(&["PositionEstimate"]);
+    harness.run_until("subscription change", |h| {
+        let client = h.client().unwrap().client.borrow();
+        client.subscriptions.contains("PositionEstimate")
+            && !client.subscriptions.contains("MotionVelocityRequest")
+    });
+}
+
+#[test]
+fn test_decode_errors_are_counted() {
+    let firmware = FakeFirmware::start(&[]);
+    let path = firmware.path.clone();
+    let mut harness = Harness::new(&path);
+    harness.run_until("connection", |h| h.odometry_count() >= 1);
+
+    // A well formed COBS frame with a bad CRC, and then some plain garbage
+    firmware.send_raw(&[0, 5, 1, 2, 3, 4, 0]);
+    firmware.send_raw(&[0, 1, 1, 1, 0]);
+    harness.run_until("decode errors", |h| {
+        h.client().unwrap().stats.decode_error_count >= 2
+    });
+
+    // The link carries on working
+    let count = harness.odometry_count();
+    harness.run_until("more odometry", |h| h.odometry_count() > count);
+    assert!(harness.client().unwrap().is_alive);
+}
+
+#[test]
+fn test_disconnect_is_detected() {
+    let firmware = FakeFirmware::start(&[]);
+    let port = serialport::new(&firmware.path, 115_200)
+        .timeout(Duration::from_millis(1))
+        .open()
+        .unwrap();
+    let mut client = SerialClient::new("motors", port);
+
+    let start = Instant::now();
+    while client.stats.rx_packets == 0 {
+        assert!(start.elapsed() < TIMEOUT, "timed out waiting for packets");
+        client.tick();
+    }
+    assert!(client.is_alive);
+
+    drop(firmware);
+    let start = Instant::now();
+    while client.is_alive {
+        assert!(start.elapsed() < TIMEOUT, "disconnect not detected");
+        client.tick();
+    }
+}
+
+#[test]
+fn test_adapter_reconnects_under_new_path() {
+    // Symlinks stand in for the device nodes, so that the device can come back under a different
+    // path like a re-enumerated USB device does
+    let dir = temp_dir("reconnect");
+    let pattern = dir.join("ttyACM*").to_string_lossy().to_string();
+    let mut harness = Harness::new(&pattern);
+
+    let firmware = FakeFirmware::start(&[]);
+    std::os::unix::fs::symlink(&firmware.path, dir.join("ttyACM0")).unwrap();
+    harness.run_until("first connection", |h| h.odometry_count() >= 1);
+    assert!(harness.client().unwrap().path().unwrap().ends_with("ttyACM0"));
+
+    drop(firmware);
+    std::fs::remove_file(dir.join("ttyACM0")).unwrap();
+    harness.run_until("disconnect", |h| h.client().is_none());
+
+    let firmware = FakeFirmware::start(&[]);
+    std::os::unix::fs::symlink(&firmware.path, dir.join("ttyACM1")).unwrap();
+    let count = harness.odometry_count();
+    harness.run_until("reconnection", |h| {
+        h.client().is_some() && h.odometry_count() > count
+    });
+    let client = harness.client().unwrap();
+    assert_eq!(client.name, "motors");
+    assert!(client.path().unwrap().ends_with("ttyACM1"));
+
+    drop(firmware);
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+#[test]
+fn test_device_is_only_connected_once() {
+    // Two rules that both match the same device: only the first gets it
+    let firmware = FakeFirmware::start(&[]);
+    let router = Rc::new(RefCell::new(Router::new()));
+    let rule = |name: &str| SerialDeviceConfig {
+        name: name.to_string(),
+        path: Some(firmware.path.clone()),
+        ..Default::default()
+    };
+    let mut adapter = SerialAdapter::new(
+        router,
+        SerialConfig {
+            enabled: true,
+            scan_interval_ms: 20,
+            devices: vec![rule("first"), rule("second")],
+        },
+    );
+
+    let start = Instant::now();
+    while start.elapsed() < Duration::from_millis(200) {
+        adapter.tick();
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    assert!(adapter.clients_by_name.contains_key("first"));
+    assert!(!adapter.clients_by_name.contains_key("second"));
+}
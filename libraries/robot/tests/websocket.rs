@@ -0,0 +1,347 @@
+//! Drives `WebsocketAcceptor` over loopback: the static web interface, the REST API and WebSocket
+//! upgrades sharing one port, and the JSON text mode for WebSocket clients.
+
+use std::cell::RefCell;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use packet_router::{Client, Router};
+use robot::config::WebsocketConfig;
+use robot::nodes::websocket_client::WebsocketAcceptor;
+use robot::scheduler::Scheduler;
+use tungstenite::client::IntoClientRequest;
+use tungstenite::stream::MaybeTlsStream;
+use topics::{PacketData, PacketFormat};
+
+mod common;
+use common::{TIMEOUT, frame, packet};
+
+struct Harness {
+    scheduler: Scheduler,
+    router: Rc<RefCell<Router<PacketFormat<PacketData>>>>,
+    probe: Rc<RefCell<Client<PacketFormat<PacketData>>>>,
+    address: SocketAddr,
+}
+
+impl Harness {
+    fn new(static_dir: Option<PathBuf>) -> Self {
+        let router = Rc::new(RefCell::new(Router::new()));
+        let acceptor = WebsocketAcceptor::new(
+            Rc::clone(&router),
+            WebsocketConfig {
+                address: "127.0.0.1:0".to_string(),
+                static_dir: static_dir.map(|dir| dir.to_string_lossy().to_string()),
+                ..Default::default()
+            },
+        );
+        let address = acceptor.server.local_addr().unwrap();
+        let mut scheduler = Scheduler::new(Rc::clone(&router));
+        scheduler.add(acceptor);
+
+        let probe = Rc::new(RefCell::new(Client::default()));
+        probe
+            .borrow_mut()
+            .subscriptions
+            .insert("MotionVelocityRequest".to_string());
+        router.borrow_mut().register_client(Rc::downgrade(&probe));
+        Harness {
+            scheduler,
+            router,
+            probe,
+            address,
+        }
+    }
+
+    /** Runs the scheduler while `client` talks to it from another thread */
+    fn run<T: Send + 'static>(
+        &mut self,
+        client: impl FnOnce(SocketAddr) -> T + Send + 'static,
+    ) -> T {
+        self.run_publishing(None, client)
+    }
+
+    /** Like `run`, but also publishes `packet` from the probe every 20ms */
+    fn run_publishing<T: Send + 'static>(
+        &mut self,
+        packet: Option<fn() -> PacketFormat<PacketData>>,
+        client: impl FnOnce(SocketAddr) -> T + Send + 'static,
+    ) -> T {
+        let address = self.address;
+        let thread: JoinHandle<T> = std::thread::spawn(move || client(address));
+        let start = Instant::now();
+        let mut last_publish = Instant::now();
+        while !thread.is_finished() {
+            assert!(start.elapsed() < TIMEOUT, "timed out");
+            if let Some(packet) = packet
+                && last_publish.elapsed() > Duration::from_millis(20)
+            {
+                self.probe.borrow_mut().send(packet());
+                last_publish = Instant::now();
+            }
+            self.scheduler.step();
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        thread.join().unwrap()
+    }
+}
+
+struct Response {
+    status: u16,
+    headers: String,
+    body: String,
+}
+
+fn request(address: SocketAddr, method: &str, path: &str, body: &str) -> Response {
+    let mut stream = TcpStream::connect(address).unwrap();
+    write!(
+        stream,
+        "{} {} HTTP/1.1\r\nHost: robot\r\nContent-Length: {}\r\n\r\n{}",
+        method,
+        path,
+        body.len(),
+        body
+    )
+    .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let (headers, body) = response.split_once("\r\n\r\n").unwrap();
+    Response {
+        status: headers[9..12].parse().unwrap(),
+        headers: headers.to_string(),
+        body: body.to_string(),
+    }
+}
+
+fn velocity_request(linear_velocity: f32) -> PacketFormat<PacketData> {
+    packet(
+        PacketData::MotionVelocityRequest(topics::MotionVelocityRequest {
+            linear_velocity,
+            angular_velocity: 0.0,
+        }),
+        0,
+    )
+}
+
+#[test]
+fn test_static_files() {
+    let dir = std::env::temp_dir().join(format!("slambot_web_{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("assets")).unwrap();
+    std::fs::write(dir.join("index.html"), "<html>robot</html>").unwrap();
+    std::fs::write(dir.join("assets/app.js"), "console.log(1)").unwrap();
+    let mut harness = Harness::new(Some(dir.clone()));
+
+    let responses = harness.run(|address| {
+        [
+            request(address, "GET", "/", ""),
+            request(address, "GET", "/assets/app.js", ""),
+            request(address, "GET", "/map", ""),
+            request(address, "GET", "/missing.js", ""),
+            request(address, "GET", "/../index.html", ""),
+        ]
+    });
+    assert_eq!(responses[0].status, 200);
+    assert_eq!(responses[0].body, "<html>robot</html>");
+    assert!(responses[0].headers.contains("Content-Type: text/html"));
+    assert_eq!(responses[1].body, "console.log(1)");
+    assert!(responses[1].headers.contains("Content-Type: text/javascript"));
+    // The UI's own routes get index.html, missing files don't
+    assert_eq!(responses[2].body, "<html>robot</html>");
+    assert_eq!(responses[3].status, 404);
+    assert_eq!(responses[4].status, 404);
+
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+#[test]
+fn test_rest_api() {
+    let mut harness = Harness::new(None);
+    harness.probe.borrow_mut().send(velocity_request(0.25));
+    harness.router.borrow_mut().poll();
+    harness.probe.borrow_mut().fetch_all();
+
+    let body = r#"{"linear_velocity": 0.5, "angular_velocity": 0.0}"#;
+    let responses = harness.run(move |address| {
+        [
+            request(address, "GET", "/topics", ""),
+            request(address, "GET", "/latest/MotionVelocityRequest", ""),
+            request(address, "GET", "/clients", ""),
+            request(address, "POST", "/publish/MotionVelocityRequest", body),
+            request(address, "GET", "/", ""),
+        ]
+    });
+
+    // The acceptor's stats can be in the list too, if the test was slow
+    let topics: serde_json::Value = serde_json::from_str(&responses[0].body).unwrap();
+    let velocity = topics
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|topic| topic["topic"] == "MotionVelocityRequest")
+        .unwrap();
+    assert_eq!(velocity["count"], 1);
+    let latest: serde_json::Value = serde_json::from_str(&responses[1].body).unwrap();
+    assert_eq!(latest["data"]["MotionVelocityRequest"]["linear_velocity"], 0.25);
+    let clients: serde_json::Value = serde_json::from_str(&responses[2].body).unwrap();
+    assert!(
+        clients
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|client| client["subscriptions"][0] == "MotionVelocityRequest")
+    );
+    assert_eq!(responses[3].status, 200, "{}", responses[3].body);
+    // Nothing to serve without a static_dir
+    assert_eq!(responses[4].status, 404);
+
+    // The published packet reaches the router
+    let start = Instant::now();
+    let mut published = Vec::new();
+    while published.is_empty() {
+        assert!(start.elapsed() < TIMEOUT, "timed out waiting for published packet");
+        harness.scheduler.step();
+        published = harness.probe.borrow_mut().fetch_all();
+    }
+    let PacketData::MotionVelocityRequest(request) = &published[0].data else {
+        panic!("unexpected packet {:?}", published[0]);
+    };
+    assert_eq!(request.linear_velocity, 0.5);
+}
+
+#[test]
+fn test_websocket_on_same_port() {
+    let mut harness = Harness::new(None);
+    harness.run(|address| {
+        let (mut websocket, _) = tungstenite::connect(format!("ws://{}/", address)).unwrap();
+        // Stamped by a browser whose clock is an hour behind the robot's
+        let mut request = velocity_request(0.75);
+        request.time -= 3_600_000_000;
+        // Sent twice with the same id, as from two browser tabs
+        for _ in 0..2 {
+            websocket
+                .send(tungstenite::Message::Binary(frame(&request).into()))
+                .unwrap();
+        }
+        websocket.flush().unwrap();
+        // Give the server a moment to read it before closing
+        std::thread::sleep(Duration::from_millis(50));
+    });
+
+    let start = Instant::now();
+    let mut received = Vec::new();
+    while received.len() < 2 {
+        assert!(start.elapsed() < TIMEOUT, "timed out waiting for websocket packets");
+        harness.scheduler.step();
+        received.extend(harness.probe.borrow_mut().fetch_all());
+    }
+    assert!(matches!(received[0].data, PacketData::MotionVelocityRequest(_)));
+    // Restamped with the robot's time and a new id as they arrived
+    let age = robot::nodes::clock::get_current_time() - received[0].time;
+    assert!(age < TIMEOUT.as_micros() as u64, "{}", age);
+    assert_ne!(received[0].id, received[1].id);
+}
+
+/** Reads messages until a text message arrives */
+fn next_text(websocket: &mut tungstenite::WebSocket<MaybeTlsStream<TcpStream>>) -> String {
+    loop {
+        if let tungstenite::Message::Text(text) = websocket.read().unwrap() {
+            return text.to_string();
+        }
+    }
+}
+
+fn connect(
+    address: SocketAddr,
+    subprotocol: Option<&'static str>,
+) -> tungstenite::WebSocket<MaybeTlsStream<TcpStream>> {
+    let mut request = format!("ws://{}/", address).into_client_request().unwrap();
+    if let Some(subprotocol) = subprotocol {
+        request
+            .headers_mut()
+            .insert("Sec-WebSocket-Protocol", subprotocol.parse().unwrap());
+    }
+    let (websocket, response) = tungstenite::connect(request).unwrap();
+    assert_eq!(
+        response
+            .headers()
+            .get("Sec-WebSocket-Protocol")
+            .and_then(|value| value.to_str().ok()),
+        subprotocol
+    );
+    websocket
+}
+
+const SUBSCRIBE_JSON: &str =
+    r#"{"data": {"SubscriptionRequest": {"topics": ["MotionVelocityRequest"]}}}"#;
+
+fn velocity_request_quarter() -> PacketFormat<PacketData> {
+    velocity_request(0.25)
+}
+
+#[test]
+fn test_json_subprotocol() {
+    let mut harness = Harness::new(None);
+    let text = harness.run_publishing(Some(velocity_request_quarter), |address| {
+        let mut websocket = connect(address, Some("slambot.json"));
+        websocket
+            .send(tungstenite::Message::Text(SUBSCRIBE_JSON.into()))
+            .unwrap();
+        next_text(&mut websocket)
+    });
+
+    // The same JSON as Log prints
+    let packet: serde_json::Value = serde_json::from_str(&text).unwrap();
+    assert_eq!(packet["data"]["MotionVelocityRequest"]["linear_velocity"], 0.25);
+    assert!(packet["time"].as_u64().unwrap() > 0);
+}
+
+#[test]
+fn test_json_handshake_message() {
+    let mut harness = Harness::new(None);
+    let (text, error) = harness.run_publishing(Some(velocity_request_quarter), |address| {
+        let mut websocket = connect(address, None);
+        for message in [
+            r#"{"encoding": "json"}"#,
+            SUBSCRIBE_JSON,
+            // No time or id, they're filled in
+            r#"{"data": {"MotionVelocityRequest": {"linear_velocity": 0.75, "angular_velocity": 0}}}"#,
+        ] {
+            websocket
+                .send(tungstenite::Message::Text(message.into()))
+                .unwrap();
+        }
+        let text = next_text(&mut websocket);
+
+        websocket
+            .send(tungstenite::Message::Text("{\"data\": 1}".into()))
+            .unwrap();
+        let error = loop {
+            let text = next_text(&mut websocket);
+            if text.contains("error") {
+                break text;
+            }
+        };
+        (text, error)
+    });
+
+    let packet: serde_json::Value = serde_json::from_str(&text).unwrap();
+    assert!(packet["data"]["MotionVelocityRequest"].is_object());
+    let error: serde_json::Value = serde_json::from_str(&error).unwrap();
+    assert!(error["error"].is_string());
+
+    // The probe saw the packet sent as JSON
+    let sent_as_json = |packet: &Rc<PacketFormat<PacketData>>| {
+        matches!(&packet.data, PacketData::MotionVelocityRequest(request)
+            if request.linear_velocity == 0.75)
+    };
+    let mut seen = Vec::new();
+    let start = Instant::now();
+    while !seen.iter().any(sent_as_json) {
+        assert!(start.elapsed() < TIMEOUT, "timed out waiting for JSON packet");
+        harness.scheduler.step();
+        seen.extend(harness.probe.borrow_mut().fetch_all());
+    }
+}
@@ -6,9 +6,16 @@ pub use ros::*;
 
 mod packet_container;
 pub use packet_container::{PacketDataTrait, PacketFormat};
+pub use packet_trait::Priority;
 
 mod packet_data;
 
+/**
+ * Room for any packet once encoded, including the zero bytes framing it. A `Mission` with every
+ * step filled in is the largest.
+ */
+pub const MAX_PACKET_SIZE: usize = 2304;
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ClockRequest {
     pub request_time: u64,
@@ -23,6 +30,22 @@ pub struct ClockResponse {
 #[derive(Serialize, Deserialize, Debug)]
 pub struct SubscriptionRequest {
     pub topics: heapless::Vec<heapless::String<32>, 8>,
+    /**
+     * Topics that should be delivered reliably (acknowledged and retransmitted) in both directions
+     * on the link this request was received on.
+     */
+    #[serde(default)]
+    pub reliable_topics: heapless::Vec<heapless::String<32>, 4>,
+}
+
+/**
+ * Acknowledges a reliably delivered packet. Identifies the packet by the `from` and `id` fields
+ * it had when it crossed the link.
+ */
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PacketAck {
+    pub from: Option<u16>,
+    pub id: u32,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -33,6 +56,22 @@ pub struct OdometryDelta {
     pub delta_orientation: f32,
 }
 
+/**
+ * One reading from an inertial measurement unit, in the robot's frame: x to the right, y forward
+ * and z up, so a positive yaw rate is anticlockwise as for `OdometryDelta`.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ImuSample {
+    /** When the sample was taken, on the synchronized clock (us) */
+    pub timestamp: u64,
+    /** rad/s about x, y and z */
+    pub angular_velocity: [f32; 3],
+    /** m/s^2, including gravity */
+    pub acceleration: [f32; 3],
+    /** `[x, y, z, w]`, for IMUs that estimate their own orientation */
+    pub orientation: Option<[f32; 4]>,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct MotionVelocityRequest {
     pub linear_velocity: f32,
@@ -44,18 +83,59 @@ pub struct PositionEstimate {
     pub timestamp: u64,
     pub position: [f32; 2],
     pub orientation: f32,
+    /**
+     * Row-major covariance of `[x, y, orientation]`. Defaults to zero (unknown) for senders that
+     * don't estimate it.
+     */
+    #[serde(default)]
+    pub covariance: [f32; 9],
 }
 
 
 
+/**
+ * Sets the position estimate, eg to re-zero it between test runs. The estimator answers with a
+ * `PoseReset`.
+ */
 #[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SetPose {
+    pub position: [f32; 2],
+    pub orientation: f32,
+    /** Row-major covariance of `[x, y, orientation]`. The pose is taken as exact if left out. */
+    #[serde(default)]
+    pub covariance: Option<[f32; 9]>,
+}
+
+/**
+ * Sent when the position estimate jumps to a new pose rather than moving there, so that anything
+ * holding positions from before (motion targets, plotted trails) can drop them.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PoseReset {
+    /** The time the new pose is for, on the synchronized clock (us) */
+    pub timestamp: u64,
+    pub position: [f32; 2],
+    pub orientation: f32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum MotionRequestMode {
     Velocity = 0,
     Position = 1,
     Stop = 2,
+    /**
+     * Carry on with the last `Path`, eg after it was interrupted. `linear` and `angular` are
+     * unused.
+     */
+    Path = 3,
 }
 
 
+/**
+ * In `Velocity` mode `linear[0]` is the forward speed (m/s) and `angular` the turn rate (rad/s).
+ * In `Position` mode the robot drives to `linear` (m), then turns on the spot to face `angular`
+ * (rad).
+ */
 #[derive(Serialize, Deserialize, Debug)]
 pub struct MotionTargetRequest {
     pub linear: [f64; 2],
@@ -63,13 +143,255 @@ pub struct MotionTargetRequest {
     pub motion_mode: MotionRequestMode,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum MotionState {
+    /** Stopped, without a goal */
+    Idle,
+    /** Driving at a velocity, or towards a position or along a path */
+    Moving,
+    /** At the goal position and heading, and holding there */
+    Arrived,
+    /** The goal was replaced by another request before it was reached, or the pose was reset */
+    Aborted,
+    /** The goal can't be driven to, eg it isn't finite */
+    Failed,
+}
+
+/** What the motion controller is doing, sent when it changes and every tick while moving */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MotionStatus {
+    pub state: MotionState,
+    pub mode: MotionRequestMode,
+    /** Distance to the goal (m), along the path in `Path` mode */
+    pub distance_error: f32,
+    /** From the robot's orientation to the goal heading (rad) */
+    pub heading_error: f32,
+    /**
+     * The target position (m) and heading (rad) in `Position` mode, so a sender can tell which
+     * request the status is about. Zero in other modes.
+     */
+    pub goal: [f32; 2],
+    pub goal_heading: f32,
+}
+
+/**
+ * Follow a path through `waypoints`, starting from wherever the robot is. Progress is sent back to
+ * the sender as `PathProgress`.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Path {
+    /** Chosen by the sender, and echoed in `PathProgress` */
+    pub id: u32,
+    pub waypoints: heapless::Vec<[f32; 2], 32>,
+    /** Turn to face this way at the end of the path */
+    pub final_heading: Option<f32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum PathStatus {
+    Following,
+    Complete,
+    /** Replaced by another motion request, or the pose was reset */
+    Aborted,
+    /** Empty, or has non-finite values */
+    Rejected,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PathProgress {
+    pub id: u32,
+    pub status: PathStatus,
+    /** Index of the waypoint being driven towards */
+    pub waypoint: u16,
+    /** Along the path, in m */
+    pub distance_remaining: f32,
+    /** Distance from the robot to the path, in m */
+    pub cross_track_error: f32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum MissionAction {
+    /**
+     * Drive to `position`, then turn to `heading`. Without a heading the robot ends facing the
+     * way it drove.
+     */
+    GoTo,
+    /** Turn on the spot to `heading` */
+    Face,
+    /** Stay still for `duration_ms` */
+    Wait,
+}
+
+/** One step of a `Mission`. Fields an action doesn't use can be left out. */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MissionStep {
+    pub action: MissionAction,
+    /** m */
+    #[serde(default)]
+    pub position: [f32; 2],
+    /** rad */
+    #[serde(default)]
+    pub heading: Option<f32>,
+    #[serde(default)]
+    pub duration_ms: u32,
+}
+
+/**
+ * Steps for the mission executor to carry out in order, replacing any mission it's running.
+ * Progress is published as `MissionProgress`.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Mission {
+    /** Chosen by the sender, and echoed in `MissionProgress` */
+    #[serde(default)]
+    pub id: u32,
+    pub steps: heapless::Vec<MissionStep, 32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum MissionCommand {
+    /** Stop, and hold the current step until resumed */
+    Pause,
+    /** Carry on with the current step from wherever the robot is now */
+    Resume,
+    /** Stop, and drop the mission */
+    Cancel,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MissionControl {
+    pub command: MissionCommand,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum MissionState {
+    Running,
+    /** Paused by a `MissionControl`, or because something else took over the motion controller */
+    Paused,
+    Complete,
+    Cancelled,
+    /** The mission has invalid steps, or the motion controller couldn't carry one out */
+    Failed,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MissionProgress {
+    pub id: u32,
+    pub state: MissionState,
+    /** Index of the step being carried out */
+    pub step: u16,
+    pub steps: u16,
+}
+
+/**
+ * Stops the robot and holds it stopped until an `EmergencyStop` with `engaged: false` resets it.
+ * Nothing else, including the condition that caused it going away, releases it.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EmergencyStop {
+    pub engaged: bool,
+}
+
+/**
+ * Sent regularly by a client (eg the web UI) so the safety supervisor can stop the robot if the
+ * client goes away while it's driving.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Heartbeat {
+    /** Treat the client as gone if the next heartbeat doesn't come within this. 0 signs off. */
+    pub timeout_ms: u32,
+}
+
 packet_data_enum!(
     ClockRequest,
     ClockResponse,
-    DiagnosticMsg,
+    DiagnosticMsg: Low,
     OdometryDelta,
+    ImuSample,
     SubscriptionRequest,
-    MotionVelocityRequest,
+    MotionVelocityRequest: High,
     PositionEstimate,
-    MotionTargetRequest,
+    SetPose,
+    PoseReset,
+    MotionTargetRequest: High,
+    Path: High,
+    PathProgress,
+    MotionStatus,
+    Mission: High,
+    MissionControl: High,
+    MissionProgress,
+    EmergencyStop: High,
+    Heartbeat,
+    PacketAck: High,
 );
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /** Encodes the packet with the largest values its fields can take, and decodes it again */
+    fn round_trip(data: PacketData) -> PacketData {
+        let packet = PacketFormat {
+            to: Some(u16::MAX),
+            from: Some(u16::MAX),
+            data,
+            time: u64::MAX,
+            id: u32::MAX,
+        };
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+        let size = packet_encoding::encode_packet(&packet, &mut buffer[1..MAX_PACKET_SIZE - 1])
+            .expect("Packet should fit in MAX_PACKET_SIZE");
+        let decoded: PacketFormat<PacketData> =
+            packet_encoding::decode_packet(&mut buffer[1..size + 1]).unwrap();
+        decoded.data
+    }
+
+    #[test]
+    fn test_full_mission_fits() {
+        let step = MissionStep {
+            action: MissionAction::GoTo,
+            position: [-1234.567, 1234.567],
+            heading: Some(-core::f32::consts::PI),
+            duration_ms: u32::MAX,
+        };
+        let mut steps = heapless::Vec::new();
+        while steps.push(step.clone()).is_ok() {}
+        let data = PacketData::Mission(Mission {
+            id: u32::MAX,
+            steps,
+        });
+        let PacketData::Mission(mission) = round_trip(data) else {
+            panic!("expected a Mission");
+        };
+        assert_eq!(mission.steps.len(), mission.steps.capacity());
+        assert_eq!(mission.steps[31].position, step.position);
+    }
+
+    /** A string as long as it can be */
+    fn full_string<const N: usize>() -> heapless::String<N> {
+        heapless::String::try_from(&"xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx"[..N]).unwrap()
+    }
+
+    #[test]
+    fn test_full_diagnostic_fits() {
+        let mut values = heapless::Vec::new();
+        while values
+            .push(DiagnosticKeyValue {
+                key: full_string(),
+                value: full_string(),
+            })
+            .is_ok()
+        {}
+        let data = PacketData::DiagnosticMsg(DiagnosticMsg {
+            level: DiagnosticStatus::Stale,
+            name: full_string(),
+            message: full_string(),
+            values,
+        });
+        let PacketData::DiagnosticMsg(diagnostic) = round_trip(data) else {
+            panic!("expected a DiagnosticMsg");
+        };
+        assert_eq!(diagnostic.values.len(), diagnostic.values.capacity());
+        assert_eq!(diagnostic.message.len(), 32);
+    }
+}
@@ -1,8 +1,9 @@
-use packet_trait::PacketTrait;
+use packet_trait::{PacketTrait, Priority};
 use serde::{Deserialize, Serialize};
 
 pub trait PacketDataTrait {
     fn topic(&self) -> &'static str;
+    fn priority(&self) -> Priority;
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -24,4 +25,7 @@ impl<T: PacketDataTrait> PacketTrait for PacketFormat<T> {
     fn set_from(&mut self, from: u16) {
         self.from = Some(from);
     }
+    fn get_priority(&self) -> Priority {
+        self.data.priority()
+    }
 }
@@ -1,8 +1,14 @@
+/**
+ * Generates the `PacketData` enum from a list of message types. Each type can optionally be
+ * followed by a priority (`MotionVelocityRequest: High`), otherwise it is `Normal`.
+ */
 #[macro_export]
 macro_rules! packet_data_enum {
-    ($($variant:ident),* $(,)?) => {
+    ($($variant:ident $(: $priority:ident)?),* $(,)?) => {
         #[derive(Serialize, Deserialize, Debug)]
         #[non_exhaustive]
+        // Variants can't be boxed in no_std, and packets are short lived anyway
+        #[allow(clippy::large_enum_variant)]
         pub enum PacketData {
             $(
                 $variant($variant),
@@ -18,6 +24,25 @@ macro_rules! packet_data_enum {
                     )*
                 }
             }
+
+            fn priority(&self) -> $crate::Priority {
+                match self {
+                    $(
+                        Self::$variant(_) => $crate::packet_priority!($($priority)?),
+                    )*
+                }
+            }
         }
     };
 }
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! packet_priority {
+    () => {
+        $crate::Priority::Normal
+    };
+    ($priority:ident) => {
+        $crate::Priority::$priority
+    };
+}
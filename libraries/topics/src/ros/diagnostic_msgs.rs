@@ -20,5 +20,5 @@ pub struct DiagnosticMsg {
     pub level: DiagnosticStatus,
     pub name: String<16>,
     pub message: String<32>,
-    pub values: Vec<DiagnosticKeyValue, 8>,
+    pub values: Vec<DiagnosticKeyValue, 16>,
 }
@@ -15,6 +15,11 @@ impl Clock {
         }
     }
 
+    /** True once a clock response from the host has been received, so `get_time` is host time */
+    pub fn is_synchronized(&self) -> bool {
+        self.offset.is_some()
+    }
+
     fn get_raw_time(&self) -> u64 {
         Instant::now().duration_since_epoch().as_micros()
     }
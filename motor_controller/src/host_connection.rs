@@ -4,7 +4,8 @@ use esp_hal::time::{Duration, Instant};
 use esp_hal::usb_serial_jtag::UsbSerialJtag;
 use heapless::{String, Vec};
 use packet_encoding::encode_packet;
-use topics::PacketFormat;
+use packet_reliability::{DuplicateFilter, PacketKey, ReliableSender, RetransmitConfig};
+use topics::{PacketAck, PacketDataTrait, PacketFormat};
 use core::str::FromStr;
 use crate::PacketData;
 
@@ -81,8 +82,9 @@ fn send_message(
 ) -> Result<(), SendError> {
     let mut encode_buffer = [0u8; 600];
     encode_buffer[0] = 0; // COBS initial byte
+    let end = encode_buffer.len() - 1; // Leave room for the final byte
     let encoded_size =
-        encode_packet(message, &mut encode_buffer[1..]).map_err(|_| SendError::EncodeError)?;
+        encode_packet(message, &mut encode_buffer[1..end]).map_err(|_| SendError::EncodeError)?;
     encode_buffer[encoded_size + 1] = 0x00; // COBS final byte
     let encode_sized = &encode_buffer[..encoded_size + 2];
 
@@ -92,11 +94,29 @@ fn send_message(
 pub struct HostConnection<'a> {
     usb: NonBlockingJtagUart<'a>,
     packet_finder: packet_encoding::PacketFinder,
+    /** Numbers every packet sent over the link, which ACKs and duplicate detection go by */
     message_id: u32,
     decode_errors: u32,
 
     pub subscribed_topics: Vec<&'static str, 16>,
     subscription_packet_send_time: Instant,
+
+    /**
+     * Topics that are acknowledged and retransmitted in both directions. These are sent to the
+     * host along with the subscribed topics so both ends of the link agree.
+     */
+    pub reliable_topics: Vec<&'static str, 4>,
+    reliable_sender: ReliableSender<PacketFormat<PacketData>, 4>,
+    duplicate_filter: DuplicateFilter<8>,
+
+    pub retransmits: u32,
+    pub ack_timeouts: u32,
+    pub duplicates: u32,
+}
+
+/** Monotonic time used for retransmission timers. Unlike `Clock::get_time` it never jumps. */
+fn link_time() -> u64 {
+    Instant::now().duration_since_epoch().as_micros()
 }
 
 
@@ -112,9 +132,19 @@ impl<'a> HostConnection<'a> {
             decode_errors: 0,
             subscribed_topics: Vec::new(),
             subscription_packet_send_time: Instant::now(),
+            reliable_topics: Vec::new(),
+            reliable_sender: ReliableSender::new(RetransmitConfig::default()),
+            duplicate_filter: DuplicateFilter::new(core::time::Duration::from_secs(5)),
+            retransmits: 0,
+            ack_timeouts: 0,
+            duplicates: 0,
         }
     }
 
+    pub fn decode_errors(&self) -> u32 {
+        self.decode_errors
+    }
+
     pub fn send_packet(
         &mut self,
         clock: &Clock,
@@ -129,7 +159,58 @@ impl<'a> HostConnection<'a> {
             id: self.message_id,
         };
         self.message_id = self.message_id.wrapping_add(1);
-        send_message(&mut self.usb, &packet)
+        let result = send_message(&mut self.usb, &packet);
+
+        // Track even if sending failed, retransmission will take care of it
+        let topic = packet.data.topic();
+        if self.reliable_topics.contains(&topic) {
+            let key = PacketKey {
+                from: packet.from,
+                id: packet.id,
+            };
+            self.reliable_sender.track(key, topic, packet, link_time()).ok();
+        }
+        result
+    }
+
+    /**
+     * Handles the link-level side of reliable delivery for a received packet. Returns the packet
+     * if it should be passed on to the application.
+     */
+    fn handle_reliable_rx(
+        &mut self,
+        packet: PacketFormat<PacketData>,
+    ) -> Option<PacketFormat<PacketData>> {
+        if let PacketData::PacketAck(ack) = &packet.data {
+            self.reliable_sender.acknowledge(PacketKey {
+                from: ack.from,
+                id: ack.id,
+            });
+            return None;
+        }
+        if !self.reliable_topics.contains(&packet.data.topic()) {
+            return Some(packet);
+        }
+
+        // Always ACK, even duplicates, as it means our previous ACK went missing
+        let _ = self.send_packet(
+            &Clock::new(),
+            PacketData::PacketAck(PacketAck {
+                from: packet.from,
+                id: packet.id,
+            }),
+            None,
+        );
+        let key = PacketKey {
+            from: packet.from,
+            id: packet.id,
+        };
+        if self.duplicate_filter.accept(key, link_time()) {
+            Some(packet)
+        } else {
+            self.duplicates = self.duplicates.wrapping_add(1);
+            None
+        }
     }
 
 
@@ -140,12 +221,19 @@ impl<'a> HostConnection<'a> {
                 &Clock::new(),
                 PacketData::SubscriptionRequest(topics::SubscriptionRequest {
                     topics: self.subscribed_topics.iter().map(|s| String::from_str(*s).expect("Failed to convert &str to String")).collect(),
+                    reliable_topics: self.reliable_topics.iter().map(|s| String::from_str(*s).expect("Failed to convert &str to String")).collect(),
                 }),
                 None,
             );
             self.subscription_packet_send_time = Instant::now();
         }
 
+        let result = self.reliable_sender.poll(link_time(), |packet| {
+            let _ = send_message(&mut self.usb, packet);
+        });
+        self.retransmits = self.retransmits.wrapping_add(result.retransmitted);
+        self.ack_timeouts = self.ack_timeouts.wrapping_add(result.expired);
+
         while let Ok(byte) = self.usb.read_byte() {
             if let Some(mut packet) = self.packet_finder.push_byte(byte)
                 && !packet.is_empty()
@@ -153,7 +241,9 @@ impl<'a> HostConnection<'a> {
                 if let Ok(packet) =
                     packet_encoding::decode_packet::<PacketFormat<PacketData>>(&mut packet)
                 {
-                    return Some(packet);
+                    if let Some(packet) = self.handle_reliable_rx(packet) {
+                        return Some(packet);
+                    }
                 } else {
                     self.decode_errors = self.decode_errors.wrapping_add(1);
                 }
@@ -14,7 +14,6 @@ use esp_hal::ledc::timer::TimerIFace;
 use esp_hal::ledc::{LSGlobalClkSource, Ledc, LowSpeed, channel, timer};
 use esp_hal::main;
 use esp_hal::time::{Duration, Instant, Rate};
-use libm::{cosf, sinf};
 
 use esp_hal::gpio::{Input, InputConfig, Io, Level, Output, OutputConfig};
 
@@ -36,13 +35,16 @@ use encoders::{ENCODER_STATE, Encoder, Encoders};
 mod motor_controller;
 use motor_controller::{MotorControllers, MotorDriver};
 
+use drive_base::slew::SlewLimiter;
+
 mod packet_data;
 use packet_data::PacketData;
 
 use packet_trait::PacketTrait;
 
-mod consts;
-use consts::{WHEEL_CIRCUMFERENCE, WHEEL_BASE_WIDTH, ENCODER_TICKS_PER_REVOLUTION};
+use drive_base::command_age::CommandAgeCheck;
+use drive_base::consts::{MAX_COMMAND_AGE, MAX_OUTPUT_RATE};
+use drive_base::odometry::update_odometry;
 
 #[main]
 fn main() -> ! {
@@ -57,6 +59,7 @@ fn main() -> ! {
         Duration::from_millis(100),
     ));
     host_connection.subscribed_topics.push("MotionVelocityRequest").ok();
+    host_connection.reliable_topics.push("MotionVelocityRequest").ok();
 
     let mut led = Output::new(peripherals.GPIO8, Level::High, OutputConfig::default());
 
@@ -122,6 +125,12 @@ fn main() -> ! {
             invert: true,
         },
         set_velocity_time: Instant::now(),
+        left_target: 0.0,
+        right_target: 0.0,
+        left_output: SlewLimiter::new(MAX_OUTPUT_RATE),
+        right_output: SlewLimiter::new(MAX_OUTPUT_RATE),
+        tick_time: Instant::now(),
+        command_age: CommandAgeCheck::new(MAX_COMMAND_AGE),
     };
 
     motor_controllers
@@ -194,6 +203,26 @@ fn main() -> ! {
                 });
             lastClockSyncTime = loop_start_time;
             led.toggle();
+
+            let mut values: Vec<topics::DiagnosticKeyValue, 16> = Vec::new();
+            values.push(diag_value("send_errors", &packet_send_errors)).ok();
+            values.push(diag_value("decode_errors", &host_connection.decode_errors())).ok();
+            values.push(diag_value("retransmits", &host_connection.retransmits)).ok();
+            values.push(diag_value("ack_timeouts", &host_connection.ack_timeouts)).ok();
+            values.push(diag_value("duplicates", &host_connection.duplicates)).ok();
+            values.push(diag_value("stale_cmds", &motor_controllers.command_age.stale_commands)).ok();
+            host_connection
+                .send_packet(
+                    &clock,
+                    PacketData::DiagnosticMsg(topics::DiagnosticMsg {
+                        level: DiagnosticStatus::Ok,
+                        name: String::from_str("mc_link").unwrap(),
+                        message: String::from_str("").unwrap(),
+                        values,
+                    }),
+                    None,
+                )
+                .ok();
         }
         if lastEncoderSendTime.elapsed() >= Duration::from_millis(100) {
             odometryTracker.end_time = clock.get_time();
@@ -216,7 +245,7 @@ fn main() -> ! {
             match packet.data {
                 PacketData::ClockResponse(resp) => {
                     let round_trip_time = clock.handle_clock_response(&resp);
-                    let mut values: Vec<topics::DiagnosticKeyValue, 8> = Vec::new();
+                    let mut values: Vec<topics::DiagnosticKeyValue, 16> = Vec::new();
                     values
                         .push(diag_value("offset", &clock.offset.unwrap_or(0)))
                         .ok();
@@ -235,7 +264,7 @@ fn main() -> ! {
                         .ok();
                 }
                 PacketData::MotionVelocityRequest(req) => {
-                    motor_controllers.handle_speed_request(&req);
+                    motor_controllers.handle_speed_request(&req, packet.time, &clock);
                 }
                 _ => {}
             }
@@ -256,14 +285,3 @@ fn diag_value(key: &str, value: &impl core::fmt::Display) -> topics::DiagnosticK
         value: format!("{}", value).unwrap(),
     }
 }
-
-fn update_odometry(odometry: &mut OdometryDelta, left_count: i64, right_count: i64) {
-    let left_distance = left_count as f32 * WHEEL_CIRCUMFERENCE / ENCODER_TICKS_PER_REVOLUTION;
-    let right_distance = -right_count as f32 * WHEEL_CIRCUMFERENCE / ENCODER_TICKS_PER_REVOLUTION;
-    let delta_distance = (left_distance + right_distance) / 2.0;
-    let delta_theta = (right_distance - left_distance) / WHEEL_BASE_WIDTH;
-
-    odometry.delta_position[0] -= delta_distance * sinf(odometry.delta_orientation);
-    odometry.delta_position[1] += delta_distance * cosf(odometry.delta_orientation);
-    odometry.delta_orientation += delta_theta;
-}
@@ -4,11 +4,11 @@ use esp_hal::{ledc::{
     channel::{Channel, ChannelHW},
 }, time::{Duration, Instant}};
 
-use crate::consts::{
-    WHEEL_BASE_WIDTH,
-    WHEEL_CIRCUMFERENCE,
-    NOMINAL_MAX_RPM,
-};
+use crate::clock::Clock;
+use drive_base::command_age::CommandAgeCheck;
+use drive_base::consts::WHEEL_BASE_WIDTH;
+use drive_base::motor::{motor_output, velocity_to_speed_percent};
+use drive_base::slew::SlewLimiter;
 use topics::MotionVelocityRequest;
 
 pub struct MotorDriver<'a> {
@@ -19,9 +19,9 @@ pub struct MotorDriver<'a> {
 
 impl<'a> MotorDriver<'a> {
     pub fn set_speed(&mut self, speed: f32) {
-        let clamped_speed = speed.clamp(-1.0, 1.0);
-        let output_speed = if self.invert { -clamped_speed } else { clamped_speed };
-        if output_speed < 0.01 && output_speed > -0.01 {
+        let output_speed = motor_output(speed);
+        let output_speed = if self.invert { -output_speed } else { output_speed };
+        if output_speed == 0.0 {
             self.a.set_duty_hw(0);
             self.b.set_duty_hw(0);
             return;
@@ -44,22 +44,39 @@ pub struct MotorControllers<'a> {
     pub left: MotorDriver<'a>,
     pub right: MotorDriver<'a>,
     pub set_velocity_time: Instant,
+
+    /**
+     * The speeds from the last request. The motors are ramped towards them in `tick` so that
+     * the wheels don't slip.
+     */
+    pub left_target: f32,
+    pub right_target: f32,
+    pub left_output: SlewLimiter,
+    pub right_output: SlewLimiter,
+    pub tick_time: Instant,
+
+    /** Rejects motion commands stamped too long before the synchronized clock */
+    pub command_age: CommandAgeCheck,
 }
 
 
 impl<'a> MotorControllers<'a> {
 
-    pub fn velocity_to_speed_percent(&self, velocity: f32) -> f32 {
-        let wheel_rpm = (velocity / WHEEL_CIRCUMFERENCE) * 60.0;
-        let speed_percent = wheel_rpm / NOMINAL_MAX_RPM;
-        speed_percent
-    }
+    /**
+     * Applies a velocity request, unless it is stale. Until the clock has been synchronized with the
+     * host there is no way to tell how old a command is, so only stop commands are accepted.
+     */
+    pub fn handle_speed_request(&mut self, request: &MotionVelocityRequest, packet_time: u64, clock: &Clock) -> bool {
+        let is_stop = request.linear_velocity == 0.0 && request.angular_velocity == 0.0;
+        let now = clock.is_synchronized().then(|| clock.get_time());
+        if !self.command_age.accept(is_stop, packet_time, now) {
+            return false;
+        }
 
-    pub fn handle_speed_request(&mut self, request: &MotionVelocityRequest) {
         let w = request.angular_velocity;
 
-        let mut vel = f32::clamp(self.velocity_to_speed_percent(request.linear_velocity), -1.0, 1.0);
-        let right_add_vel = f32::clamp(self.velocity_to_speed_percent(w * WHEEL_BASE_WIDTH / 2.0), -1.0, 1.0);
+        let mut vel = f32::clamp(velocity_to_speed_percent(request.linear_velocity), -1.0, 1.0);
+        let right_add_vel = f32::clamp(velocity_to_speed_percent(w * WHEEL_BASE_WIDTH / 2.0), -1.0, 1.0);
         let left_add_vel = -right_add_vel;
 
         // We want to prioritize angular velocity if the combined speeds exceed 100% or -100%
@@ -72,20 +89,28 @@ impl<'a> MotorControllers<'a> {
             }
         }
         
-        let left_speed = vel + left_add_vel;
-        let right_speed = vel + right_add_vel;
-
-        self.left.set_speed(left_speed);
-        self.right.set_speed(right_speed);
+        // Clamped here as well as by the driver, so ramping down doesn't start beyond full speed
+        self.left_target = (vel + left_add_vel).clamp(-1.0, 1.0);
+        self.right_target = (vel + right_add_vel).clamp(-1.0, 1.0);
 
         self.set_velocity_time = Instant::now();
-
+        true
     }
 
+    /**
+     * Ramps the motors towards the requested speeds. If no command has arrived for a second the
+     * motors are stopped straight away rather than ramped, as the host may have gone.
+     */
     pub fn tick(&mut self) {
+        let dt = self.tick_time.elapsed().as_micros() as f32 / 1_000_000.0;
+        self.tick_time = Instant::now();
         if self.set_velocity_time.elapsed() >= Duration::from_millis(1000) {
-            self.left.set_speed(0.0);
-            self.right.set_speed(0.0);
+            self.left_target = 0.0;
+            self.right_target = 0.0;
+            self.left_output.value = 0.0;
+            self.right_output.value = 0.0;
         }
+        self.left.set_speed(self.left_output.step(self.left_target, dt));
+        self.right.set_speed(self.right_output.step(self.right_target, dt));
     }
 }
\ No newline at end of file
@@ -1,14 +1,17 @@
 use serde::{Deserialize, Serialize};
 use topics::packet_data_enum;
 use topics::{
-    ClockRequest, ClockResponse, DiagnosticMsg, OdometryDelta, PacketDataTrait, SubscriptionRequest, MotionVelocityRequest
+    ClockRequest, ClockResponse, DiagnosticMsg, OdometryDelta, PacketDataTrait, SubscriptionRequest, MotionVelocityRequest,
+    PacketAck, ImuSample,
 };
 
 packet_data_enum! {
     ClockRequest,
     ClockResponse,
-    DiagnosticMsg,
+    DiagnosticMsg: Low,
     OdometryDelta,
+    ImuSample,
     SubscriptionRequest,
-    MotionVelocityRequest,
+    MotionVelocityRequest: High,
+    PacketAck: High,
 }
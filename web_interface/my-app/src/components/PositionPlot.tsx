@@ -1,20 +1,64 @@
 import { useEffect, useRef, useState } from 'react'
 import type { AnyPacketEntry, PacketEntry } from '../logTypes'
-import type { PositionEstimate, AnyPacketFormat, MotionTargetRequest, PacketFormat } from '../messageFormat'
+import type { PositionEstimate, AnyPacketFormat, MotionTargetRequest, PacketFormat, SetPose, MotionStatus, EmergencyStop } from '../messageFormat'
+import { currentPacketTime } from '../messageFormat'
 
 interface PositionPlotProps {
   packets: AnyPacketEntry[]
   send?: (message: AnyPacketFormat) => boolean
+  /** Changes whenever the estimator's pose is reset */
+  resetCount?: number
+  /** The motion controller's latest status */
+  motionStatus?: MotionStatus['MotionStatus'] | null
 }
 
 function isPositionEstimate(data: AnyPacketEntry): data is PacketEntry<PositionEstimate> {
   return 'PositionEstimate' in data.packet.data
 }
 
-function PositionPlot({ packets, send }: PositionPlotProps) {
+function PositionPlot({ packets, send, resetCount, motionStatus }: PositionPlotProps) {
   const canvasRef = useRef<HTMLCanvasElement>(null)
   const [targetPosition, setTargetPosition] = useState<[number, number] | null>(null)
 
+  // The motion controller drops position targets when the pose is reset
+  useEffect(() => {
+    setTargetPosition(null)
+  }, [resetCount])
+
+  const handleResetPose = () => {
+    if (!send) return
+
+    const message: PacketFormat<SetPose> = {
+      to: null,
+      from: null,
+      time: currentPacketTime(),
+      id: 0,
+      data: {
+        SetPose: {
+          position: [0.0, 0.0],
+          orientation: 0.0,
+          covariance: null,
+        },
+      },
+    }
+    send(message)
+  }
+
+  const sendEmergencyStop = (engaged: boolean) => {
+    if (!send) return
+
+    const message: PacketFormat<EmergencyStop> = {
+      to: null,
+      from: null,
+      time: currentPacketTime(),
+      id: 0,
+      data: {
+        EmergencyStop: { engaged },
+      },
+    }
+    send(message)
+  }
+
   const handleCanvasClick = (event: React.MouseEvent<HTMLCanvasElement>) => {
     if (!send) return
 
@@ -36,16 +80,22 @@ function PositionPlot({ packets, send }: PositionPlotProps) {
 
     setTargetPosition([worldX, worldY])
 
+    // Arrive facing the way the robot drove, so it doesn't turn on the spot at the end
+    const lastEstimate = packets.filter(isPositionEstimate).at(-1)?.packet.data.PositionEstimate
+    const heading = lastEstimate
+      ? -Math.atan2(worldX - lastEstimate.position[0], worldY - lastEstimate.position[1])
+      : 0.0
+
     // Send MotionTargetRequest
     const message: PacketFormat<MotionTargetRequest> = {
       to: null,
       from: null,
-      time: BigInt(Date.now()),
+      time: currentPacketTime(),
       id: 0,
       data: {
         MotionTargetRequest: {
           linear: [worldX, worldY],
-          angular: 0.0,
+          angular: heading,
           motion_mode: "Position",
         },
       },
@@ -172,6 +222,10 @@ function PositionPlot({ packets, send }: PositionPlotProps) {
       ctx.fillText(`Position: (${lastPos.position[0].toFixed(2)}m, ${lastPos.position[1].toFixed(2)}m)`, 10, 20)
       ctx.fillText(`Orientation: ${(lastPos.orientation * 180 / Math.PI).toFixed(1)}°`, 10, 40)
       ctx.fillText(`Points: ${positions.length}`, 10, 60)
+      if (motionStatus) {
+        const headingError = (motionStatus.heading_error * 180 / Math.PI).toFixed(1)
+        ctx.fillText(`Goal: ${motionStatus.state} (${motionStatus.distance_error.toFixed(2)}m, ${headingError}°)`, 10, 80)
+      }
     }
 
     // Draw target position marker
@@ -199,7 +253,7 @@ function PositionPlot({ packets, send }: PositionPlotProps) {
       ctx.font = '12px monospace'
       ctx.fillText(`Target: (${targetPosition[0].toFixed(2)}m, ${targetPosition[1].toFixed(2)}m)`, targetCanvasX + 20, targetCanvasY - 10)
     }
-  }, [packets, targetPosition])
+  }, [packets, targetPosition, motionStatus])
 
   return (
     <div style={{ margin: '20px', padding: '10px', border: '1px solid #ccc', borderRadius: '4px' }}>
@@ -207,6 +261,19 @@ function PositionPlot({ packets, send }: PositionPlotProps) {
       <p style={{ color: '#666', fontSize: '14px', margin: '10px 0' }}>
         Click on the plot to send the robot to that position
       </p>
+      <button onClick={handleResetPose} disabled={!send} style={{ marginBottom: '10px' }}>
+        Reset pose to origin
+      </button>
+      <button
+        onClick={() => sendEmergencyStop(true)}
+        disabled={!send}
+        style={{ marginBottom: '10px', marginLeft: '10px', backgroundColor: '#c00', color: 'white' }}
+      >
+        Emergency stop
+      </button>
+      <button onClick={() => sendEmergencyStop(false)} disabled={!send} style={{ marginBottom: '10px', marginLeft: '10px' }}>
+        Release emergency stop
+      </button>
       <canvas
         ref={canvasRef}
         width={800}
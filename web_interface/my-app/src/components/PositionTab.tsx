@@ -1,5 +1,5 @@
 import { useEffect, useState } from 'react'
-import type { AnyPacketFormat } from '../messageFormat'
+import type { AnyPacketFormat, MotionStatus } from '../messageFormat'
 import type { AnyPacketEntry } from '../logTypes'
 import PositionPlot from './PositionPlot'
 
@@ -10,6 +10,8 @@ interface PositionTabProps {
 
 function PositionTab({ registerCallback, send }: PositionTabProps) {
   const [packets, setPackets] = useState<AnyPacketEntry[]>([])
+  const [resetCount, setResetCount] = useState(0)
+  const [motionStatus, setMotionStatus] = useState<MotionStatus['MotionStatus'] | null>(null)
 
   useEffect(() => {
     const handleMessage = (message: AnyPacketFormat) => {
@@ -22,9 +24,25 @@ function PositionTab({ registerCallback, send }: PositionTabProps) {
     return registerCallback('PositionEstimate', handleMessage)
   }, [registerCallback])
 
+  useEffect(() => {
+    return registerCallback('MotionStatus', (message: AnyPacketFormat) => {
+      if ('MotionStatus' in message.data) {
+        setMotionStatus((message.data as MotionStatus).MotionStatus)
+      }
+    })
+  }, [registerCallback])
+
+  // The old trail is in a different frame after the estimator's pose is reset
+  useEffect(() => {
+    return registerCallback('PoseReset', () => {
+      setPackets([])
+      setResetCount((count) => count + 1)
+    })
+  }, [registerCallback])
+
   return (
     <div>
-      <PositionPlot packets={packets} send={send} />
+      <PositionPlot packets={packets} send={send} resetCount={resetCount} motionStatus={motionStatus} />
     </div>
   )
 }
@@ -33,6 +33,7 @@ export interface DiagnosticMsg {
 export interface SubscriptionRequest {
     SubscriptionRequest: {
         topics: string[];
+        reliable_topics?: string[];
     }
 }
 
@@ -41,20 +42,128 @@ export interface  PositionEstimate {
         timestamp: bigint;
         position: [number, number];
         orientation: number;
+        /** Row-major covariance of [x, y, orientation] */
+        covariance?: number[];
     }
 }
 
 
+export interface ImuSample {
+    ImuSample: {
+        timestamp: bigint;
+        angular_velocity: [number, number, number];
+        acceleration: [number, number, number];
+        orientation: [number, number, number, number] | null;
+    }
+}
+
 export interface MotionTargetRequest {
     MotionTargetRequest: {
         linear: [number, number];
         angular: number;
-        motion_mode: 'Position' | 'Velocity' | 'Stop';
+        motion_mode: 'Position' | 'Velocity' | 'Stop' | 'Path';
+    }
+}
+
+export interface SetPose {
+    SetPose: {
+        position: [number, number];
+        orientation: number;
+        /** Row-major covariance of [x, y, orientation]. The pose is exact if null. */
+        covariance: number[] | null;
+    }
+}
+
+/** Sent by the estimator when its pose jumps, so old positions can be dropped */
+export interface PoseReset {
+    PoseReset: {
+        timestamp: bigint;
+        position: [number, number];
+        orientation: number;
+    }
+}
+
+export interface Path {
+    Path: {
+        id: number;
+        waypoints: [number, number][];
+        final_heading: number | null;
+    }
+}
+
+export interface PathProgress {
+    PathProgress: {
+        id: number;
+        status: 'Following' | 'Complete' | 'Aborted' | 'Rejected';
+        waypoint: number;
+        distance_remaining: number;
+        cross_track_error: number;
+    }
+}
+
+export interface MotionStatus {
+    MotionStatus: {
+        state: 'Idle' | 'Moving' | 'Arrived' | 'Aborted' | 'Failed';
+        mode: 'Position' | 'Velocity' | 'Stop' | 'Path';
+        distance_error: number;
+        heading_error: number;
+        /** The target in Position mode, zero otherwise */
+        goal: [number, number];
+        goal_heading: number;
+    }
+}
+
+export interface MissionStep {
+    action: 'GoTo' | 'Face' | 'Wait';
+    position: [number, number];
+    heading: number | null;
+    duration_ms: number;
+}
+
+export interface Mission {
+    Mission: {
+        id: number;
+        steps: MissionStep[];
+    }
+}
+
+export interface MissionControl {
+    MissionControl: {
+        command: 'Pause' | 'Resume' | 'Cancel';
+    }
+}
+
+export interface MissionProgress {
+    MissionProgress: {
+        id: number;
+        state: 'Running' | 'Paused' | 'Complete' | 'Cancelled' | 'Failed';
+        step: number;
+        steps: number;
+    }
+}
+
+/** Latches the robot stopped. Sending it with `engaged: false` is the only way to release it. */
+export interface EmergencyStop {
+    EmergencyStop: {
+        engaged: boolean;
+    }
+}
+
+/** The robot is stopped if a client that has been driving it misses its heartbeat timeout */
+export interface Heartbeat {
+    Heartbeat: {
+        timeout_ms: number;
     }
 }
 
 export interface UnknownPacket { [key: string]: unknown }
 
+/**
+ * Packet times are microseconds since the unix epoch, the same as the robot's clock. The browser's
+ * clock isn't synchronized with the robot's, so the robot restamps packets from here on arrival.
+ */
+export const currentPacketTime = (): bigint => BigInt(Date.now()) * 1000n
+
 export interface PacketFormat<T> {
     to: number | null;
     from: number | null;
@@ -63,5 +172,5 @@ export interface PacketFormat<T> {
     data: T;
 }
 
-export type AnyPacketData = OdometryDelta | DiagnosticMsg | SubscriptionRequest | PositionEstimate | MotionTargetRequest | UnknownPacket
+export type AnyPacketData = OdometryDelta | DiagnosticMsg | SubscriptionRequest | PositionEstimate | ImuSample | MotionTargetRequest | SetPose | PoseReset | Path | PathProgress | MotionStatus | Mission | MissionControl | MissionProgress | EmergencyStop | Heartbeat | UnknownPacket
 export type AnyPacketFormat = PacketFormat<AnyPacketData>;
\ No newline at end of file
@@ -113,7 +113,8 @@ export class PacketFinder {
   private buffer: number[]
   private maxBufferSize: number
 
-  constructor(maxBufferSize = 512) {
+  // Matches MAX_PACKET_SIZE in the topics crate, room for the largest packet the robot sends
+  constructor(maxBufferSize = 2304) {
     this.buffer = []
     this.maxBufferSize = maxBufferSize
   }
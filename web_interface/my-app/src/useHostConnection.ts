@@ -2,6 +2,11 @@ import { useCallback, useEffect, useRef } from 'react'
 import { useWebSocket } from './useWebSocket'
 import type { WebSocketStatus } from './useWebSocket'
 import type { AnyPacketFormat } from './messageFormat'
+import { currentPacketTime } from './messageFormat'
+
+/** How often heartbeats are sent, and how long the robot waits for one before stopping */
+const HEARTBEAT_INTERVAL_MS = 250
+const HEARTBEAT_TIMEOUT_MS = 1000
 
 type MessageCallback = (message: AnyPacketFormat) => void
 type SubscriptionMap = Map<string, Set<MessageCallback>>
@@ -51,7 +56,7 @@ export const useHostConnection = (url?: string) => {
     const message: AnyPacketFormat = {
       to: null,
       from: null,
-      time: BigInt(Date.now()),
+      time: currentPacketTime(),
       id: Date.now() % 0xffffffff,
       data: {
         SubscriptionRequest: {
@@ -116,5 +121,22 @@ export const useHostConnection = (url?: string) => {
     }
   }, [status, sendSubscriptionRequest])
 
+  // Let the robot know we're still here, so it stops if this page goes away while driving it
+  useEffect(() => {
+    if (status !== 'open') {
+      return
+    }
+    const interval = window.setInterval(() => {
+      sendRef.current?.({
+        to: null,
+        from: null,
+        time: currentPacketTime(),
+        id: Date.now() % 0xffffffff,
+        data: { Heartbeat: { timeout_ms: HEARTBEAT_TIMEOUT_MS } },
+      })
+    }, HEARTBEAT_INTERVAL_MS)
+    return () => window.clearInterval(interval)
+  }, [status])
+
   return { send, status, registerCallback }
 }